@@ -1,64 +1,234 @@
 use crate::{
-    db::DbRepo,
+    db::{DbRepo, Retention},
     error::AppError,
-    schema::{Color, LocalData, Room},
-    util::{create_env_dir, get_unique_id, hash_passwd, passwd_input, setup_logger},
+    network::{
+        audit::AuditLog,
+        client::{ChatClient, ClientError},
+        server::ChatServer,
+        User,
+    },
+    schema::{Backup, Color, LocalData, RememberedPasswd, Room, RoomExport, Theme, Timezone},
+    tui::{chat_app::ChatApp, room_picker::RoomPicker, ui::ChatStyle},
+    util::{
+        confirm, create_env_dir, get_unique_id, hash_passwd, lan_ip, passwd_input, setup_logger,
+        systime_to_string, use_color, verify_passwd, DEFAULT_AWAY_AFTER, DEFAULT_CONNECT_TIMEOUT,
+        DEFAULT_LAYOUT_RATIO, DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_MISSED_PONGS, DEFAULT_MAX_MSG_LEN,
+        DEFAULT_MENTION_PREFIX, DEFAULT_MSG_BURST, DEFAULT_MSG_RATE, DEFAULT_PING_INTERVAL,
+        DEFAULT_RECONNECT_ATTEMPTS, DEFAULT_TIMESTAMP_FORMAT, LAYOUT_RATIOS, PASSWD_VAR,
+        ROOM_PASSWD_VAR,
+    },
+};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use clap_complete::{generate, Shell};
+use humantime::format_rfc3339_seconds;
+use log::LevelFilter;
+use polodb_core::{
+    bson::{doc, to_bson, Bson},
+    Error as pdbError, Result as pdbResult,
 };
-use clap::{Arg, ArgMatches, Command};
-use crossterm::style::Stylize;
-use polodb_core::{bson::doc, Result as pdbResult};
 use std::{
-    env,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::Path,
+    cmp::Reverse,
+    collections::BTreeMap,
+    env, fs,
+    io::{self, BufRead, IsTerminal, Write},
+    iter,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
 };
+use tokio::net::lookup_host;
 
-pub fn run(cmd_req: CommandRequest, open_memory: bool) -> Result<(), AppError> {
+pub async fn run(
+    cmd_req: CommandRequest,
+    log_level: LevelFilter,
+    open_memory: bool,
+) -> Result<(), AppError> {
     let path = create_env_dir("kioto")?;
 
     let log_path = path.join("errors.log");
-    setup_logger(&log_path).expect(format!("{}", "Failed to set up logger.".red()).as_str());
+    if let Err(err) = setup_logger(&log_path, log_level, !cmd_req.is_tui()) {
+        log::warn!("{}", err);
+    }
+
+    if cmd_req == CommandRequest::ConfigPath {
+        println!("{}", path.display());
+        return Ok(());
+    }
 
     let mut db = if open_memory {
         db_init(None)?
     } else {
-        db_init(Some(&path))?
+        db_init(Some(&path.join("kioto.db")))?
     };
 
-    run_option(cmd_req, &mut db)?;
+    match cmd_req {
+        CommandRequest::Join {
+            id_or_address,
+            username,
+            also,
+            color,
+            history,
+            alt_screen,
+            spectate,
+        } => {
+            join_room(
+                &db,
+                id_or_address,
+                also,
+                username,
+                color,
+                history,
+                alt_screen,
+                spectate,
+            )
+            .await?
+        }
+        CommandRequest::Host {
+            room_id,
+            alt_screen,
+        } => host_room(db, &room_id, alt_screen).await?,
+        cmd_req => run_option(cmd_req, &mut db)?,
+    }
 
     Ok(())
 }
 
+/// Runs the commands which only operate on the local database.
 fn run_option(cmd_req: CommandRequest, db: &mut DbRepo) -> Result<(), AppError> {
     match cmd_req {
         CommandRequest::Create {
             room_id,
             ip,
             password,
-        } => create_room(db, &room_id, ip, password)?,
-        CommandRequest::Join {
-            id_or_address,
-            username,
-            color,
-        } => join_room(id_or_address, username, color)?,
-        CommandRequest::Delete { room_id } => delete_room(db, &room_id)?,
-        CommandRequest::List => list_rooms_and_local_data(&db)?,
-        CommandRequest::Set { option, value } => set_local_data(db, &option, &value)?,
-        CommandRequest::Invalid => return Err(AppError::InvalidCommand),
+            max_users,
+            topic,
+            allow_duplicate_addr,
+            dry_run: true,
+        } => {
+            let room = new_room(
+                db,
+                &room_id,
+                ip,
+                None,
+                max_users,
+                topic,
+                allow_duplicate_addr,
+            )?;
+            describe_new_room(&room, password.is_some(), &mut io::stdout())?
+        }
+        CommandRequest::Create {
+            room_id,
+            ip,
+            password,
+            max_users,
+            topic,
+            allow_duplicate_addr,
+            dry_run: false,
+        } => {
+            let passwd = password
+                .map(|source| new_room_passwd(source, &mut io::stdin().lock()))
+                .transpose()?;
+            create_room(
+                db,
+                &room_id,
+                ip,
+                passwd,
+                max_users,
+                topic,
+                allow_duplicate_addr,
+            )?
+        }
+        CommandRequest::Delete {
+            room_id,
+            force,
+            password,
+        } => delete_room(
+            db,
+            &room_id,
+            force,
+            password.as_deref(),
+            &mut io::stdin().lock(),
+            &mut io::stdout(),
+        )?,
+        CommandRequest::Forget { room_id } => forget_passwd(db, &room_id)?,
+        CommandRequest::Passwd {
+            room_id,
+            new_password,
+            password,
+        } => change_passwd(
+            db,
+            &room_id,
+            password.as_deref(),
+            new_password,
+            &mut io::stdin().lock(),
+        )?,
+        CommandRequest::Clear { room_id, yes } => clear_room(db, &room_id, yes)?,
+        CommandRequest::List { sort } => list_rooms_and_local_data(db, sort, &mut io::stdout())?,
+        CommandRequest::WhoAmI { json } => whoami(db, json, &mut io::stdout())?,
+        CommandRequest::Bans { room_id, json } => list_bans(db, &room_id, json, &mut io::stdout())?,
+        CommandRequest::Stats { json } => stats(db, json, &mut io::stdout())?,
+        CommandRequest::Info { room_id, json } => room_info(db, &room_id, json, &mut io::stdout())?,
+        CommandRequest::Unban { room_id, addr } => unban(db, &room_id, addr.as_deref())?,
+        CommandRequest::Completions { shell } => print_completions(shell, &mut io::stdout()),
+        CommandRequest::Set {
+            option,
+            value,
+            room_id: Some(room_id),
+        } => set_room_option(db, &room_id, &option, &value)?,
+        CommandRequest::Set {
+            option,
+            value,
+            room_id: None,
+        } => set_local_data(db, &option, &value)?,
+        CommandRequest::Export {
+            room_id,
+            path,
+            with_secret,
+        } => export_room(db, &room_id, &path, with_secret)?,
+        CommandRequest::Import {
+            path,
+            overwrite,
+            allow_duplicate_addr,
+        } => import_room(db, &path, overwrite, allow_duplicate_addr)?,
+        CommandRequest::Backup { path } => backup_db(db, &path)?,
+        CommandRequest::Restore { path, overwrite } => restore_db(db, &path, overwrite)?,
+        CommandRequest::Join { .. }
+        | CommandRequest::Host { .. }
+        | CommandRequest::ConfigPath
+        | CommandRequest::Invalid => return Err(AppError::InvalidCommand),
     }
 
     Ok(())
 }
 
-pub fn db_init(db_path: Option<&Path>) -> pdbResult<DbRepo> {
-    if db_path.is_none() {
-        return Ok(DbRepo::memory_init()?);
+/// Attempts at opening a database another process holds, waiting longer after each one.
+const DB_OPEN_ATTEMPTS: u32 = 4;
+const DB_OPEN_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// Waits out short-lived commands of another process, a hosting one keeps the lock though.
+fn open_db(path: &Path) -> pdbResult<DbRepo> {
+    let mut attempt = 0;
+    loop {
+        match DbRepo::init(path) {
+            Err(pdbError::DatabaseOccupied | pdbError::Busy) if attempt + 1 < DB_OPEN_ATTEMPTS => {
+                thread::sleep(DB_OPEN_BASE_DELAY * 2u32.pow(attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
     }
+}
 
-    let db = DbRepo::init(db_path.unwrap())?;
+pub fn db_init(db_path: Option<&Path>) -> pdbResult<DbRepo> {
+    let db = match db_path {
+        Some(path) => open_db(path)?,
+        None => DbRepo::memory_init()?,
+    };
 
+    db.migrate()?;
     if db.local_data.count_documents()? == 0 {
         db.local_data.insert_one(LocalData {
             default_user_id: get_unique_id(),
@@ -66,9 +236,44 @@ pub fn db_init(db_path: Option<&Path>) -> pdbResult<DbRepo> {
             default_color: Color::White,
             remember_passwords: false,
             light_mode: false,
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+            bell: false,
+            bell_mentions_only: false,
+            msg_rate: DEFAULT_MSG_RATE,
+            msg_burst: DEFAULT_MSG_BURST,
+            max_msg_len: DEFAULT_MAX_MSG_LEN,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+            away_after: DEFAULT_AWAY_AFTER,
+            timezone: Timezone::Local,
+            ignored: vec![],
+            markdown: false,
+            input_border: None,
+            input_highlight: None,
+            input_placeholder: None,
+            retention_messages: 0,
+            retention_days: 0,
+            theme: None,
+            alt_screen: true,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            mention_prefix: DEFAULT_MENTION_PREFIX,
+            audit_log: false,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            layout_ratio: DEFAULT_LAYOUT_RATIO,
         })?;
     }
 
+    if let Some(local_data) = db.local_data.find_one(None)? {
+        let pruned = db.prune_messages(Retention::from(&local_data), SystemTime::now())?;
+        if pruned > 0 {
+            log::info!(
+                "Pruned {} stored messages past the retention limits",
+                pruned
+            );
+        }
+    }
+
     Ok(db)
 }
 
@@ -76,375 +281,4048 @@ fn create_room(
     db: &mut DbRepo,
     room_id: &str,
     room_ip: Option<String>,
-    password: bool,
+    passwd: Option<String>,
+    max_users: Option<u32>,
+    topic: Option<String>,
+    allow_duplicate_addr: bool,
 ) -> Result<(), AppError> {
-    if db.rooms.find_one(doc! {"id": room_id})?.is_some() {
+    let room = new_room(
+        db,
+        room_id,
+        room_ip,
+        passwd,
+        max_users,
+        topic,
+        allow_duplicate_addr,
+    )?;
+    db.rooms.insert_one(&room)?;
+
+    Ok(())
+}
+
+/// Runs every check of creating a room, without storing it.
+fn new_room(
+    db: &DbRepo,
+    room_id: &str,
+    room_ip: Option<String>,
+    passwd: Option<String>,
+    max_users: Option<u32>,
+    topic: Option<String>,
+    allow_duplicate_addr: bool,
+) -> Result<Room, AppError> {
+    if db.rooms.find_one(doc! {"_id": room_id})?.is_some() {
         return Err(AppError::AlreadyExistingId);
     }
 
-    let addr = match room_ip {
-        Some(ip) => SocketAddr::from_str(&ip).unwrap(),
-        None => {
-            db.local_data
-                .find_one(None)?
-                .ok_or(AppError::DataNotFound)?
-                .default_room_addr
+    let default_addr = || -> Result<SocketAddr, AppError> {
+        Ok(db
+            .local_data
+            .find_one(None)?
+            .ok_or(AppError::DataNotFound)?
+            .default_room_addr)
+    };
+
+    let addr = match room_ip.as_deref() {
+        Some(LAN_ADDR) => {
+            let ip = lan_ip().map_err(|err| AppError::LanAddrNotFound(err.to_string()))?;
+            SocketAddr::new(ip.into(), default_addr()?.port())
         }
+        Some(ip) => parse_room_addr(ip)?,
+        None => default_addr()?,
     };
+    let host = room_ip.filter(|addr| is_host_addr(addr));
 
-    let passwd = if password { Some(passwd_input()) } else { None };
+    if !allow_duplicate_addr {
+        check_addr_free(db, room_id, &addr)?;
+    }
 
-    db.rooms.insert_one(&Room {
+    Ok(Room {
         _id: room_id.into(),
         addr,
-        passwd,
+        passwd: passwd.map(|passwd| hash_passwd(&passwd)),
         banned_addrs: vec![],
         is_owner: true,
-    })?;
+        max_users,
+        topic,
+        created_at: SystemTime::now(),
+        host,
+        last_joined: None,
+        filter_words: vec![],
+    })
+}
+
+fn describe_new_room(room: &Room, password: bool, out: &mut impl Write) -> Result<(), AppError> {
+    write!(out, "OK, would create room {} at {}", room._id, room.addr)?;
+    if password {
+        write!(out, ", password protected")?;
+    }
+    if let Some(max_users) = room.max_users {
+        write!(out, ", for at most {} users", max_users)?;
+    }
+    if let Some(topic) = &room.topic {
+        write!(out, ", about {:?}", topic)?;
+    }
+    writeln!(out)?;
 
     Ok(())
 }
 
-fn delete_room(db: &mut DbRepo, room_id: &str) -> Result<(), AppError> {
-    if let Some(room) = db.rooms.find_one(doc! {"room_id": room_id})? {
-        if room.is_owner {
-            if let Some(passwd) = room.passwd {
-                hash_passwd(&passwd);
-                if passwd_input() != passwd {
-                    return Err(AppError::InvalidPassword);
-                }
-            }
+/// Owned rooms sharing an address would bind the same port when hosted.
+fn check_addr_free(db: &DbRepo, room_id: &str, addr: &SocketAddr) -> Result<(), AppError> {
+    for room in db.rooms.find(doc! {"is_owner": true})? {
+        let room = room?;
+        if room._id != room_id && &room.addr == addr {
+            return Err(AppError::AddressInUse(room._id));
         }
-    } else {
-        return Err(AppError::NotExistingId);
     }
 
-    db.rooms.delete_one(doc! {"room_id": room_id})?;
     Ok(())
 }
 
-fn list_rooms_and_local_data(db: &DbRepo) -> Result<(), AppError> {
-    let local_data = db.rooms.find_one(None)?.ok_or(AppError::DataNotFound)?;
+/// Room address value standing for the detected LAN ip with the default port.
+const LAN_ADDR: &str = "lan";
+
+/// Where the password of a new room is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswdSource {
+    /// `KIOTO_ROOM_PASSWORD` if set, the interactive prompt otherwise.
+    Prompt,
+    /// A single line of the standard input.
+    Stdin,
+}
 
-    println!("{:#?}", local_data);
+fn new_room_passwd(source: PasswdSource, input: &mut impl BufRead) -> Result<String, AppError> {
+    let passwd = match source {
+        PasswdSource::Prompt => match env::var(ROOM_PASSWD_VAR) {
+            Ok(passwd) if !passwd.is_empty() => passwd,
+            _ => passwd_input()?,
+        },
+        PasswdSource::Stdin => {
+            let mut line = String::new();
+            input.read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_owned()
+        }
+    };
 
-    let mut rooms = db.rooms.find(None)?;
-    if !rooms.any(|el| {
-        let room = el.unwrap();
-        println!("{}: {}", room._id, room.addr);
-        true
-    }) {
-        return Err(AppError::NoAnyRoom);
+    if passwd.is_empty() {
+        return Err(AppError::InvalidValue("empty password".into()));
     }
 
-    Ok(())
+    Ok(passwd)
 }
 
-fn join_room(
-    id_or_addr: IdOrAddr,
-    username: Option<String>,
-    color: Option<Color>,
-) -> Result<(), AppError> {
-    todo!("if there is no such id then join, but store info temporary")
+/// `hostname:port` rather than a numeric address. Only dotted names and localhost count, so
+/// room ids like `room:1` stay ids.
+fn is_host_addr(addr: &str) -> bool {
+    SocketAddr::from_str(addr).is_err()
+        && addr.rsplit_once(':').is_some_and(|(host, port)| {
+            (host == "localhost" || host.contains('.'))
+                && host.split('.').all(|label| {
+                    !label.is_empty()
+                        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                })
+                && port.parse::<u16>().is_ok()
+        })
 }
 
-fn set_local_data(db: &mut DbRepo, option: &str, value: &str) -> Result<(), AppError> {
-    db.local_data.update_one(
-        doc! {},
-        doc! {"$set": doc! {
-            option: value
-        }},
-    )?;
+fn parse_room_addr(addr: &str) -> Result<SocketAddr, AppError> {
+    addr.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| AppError::InvalidAddress(addr.into()))
+}
 
+/// Asks for confirmation unless forced. A forced delete of a protected room never prompts
+/// for the password, it has to be given instead.
+fn delete_room(
+    db: &mut DbRepo,
+    room_id: &str,
+    force: bool,
+    passwd: Option<&str>,
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+
+    if !force && !confirm(&format!("Delete room {}?", room_id), input, out)? {
+        return Ok(());
+    }
+
+    if room.is_owner {
+        match (passwd, &room.passwd) {
+            (Some(passwd), Some(hash)) if !verify_passwd(passwd, hash) => {
+                return Err(AppError::InvalidPassword)
+            }
+            (Some(_), _) => (),
+            (None, Some(_)) if force && !is_passwd_remembered(db, &room)? => {
+                return Err(AppError::PasswordRequired)
+            }
+            (None, _) => verify_room_passwd(db, &room)?,
+        }
+    }
+
+    db.rooms.delete_one(doc! {"_id": room_id})?;
+    forget_passwd(db, room_id)?;
     Ok(())
 }
 
-#[derive(Debug)]
-pub enum IdOrAddr {
-    Id(String),
-    Addr(String),
-}
+/// Replaces the password of an owned room, `None` removes it. The current one has to be
+/// given or entered first, unless remembered, the remembered one is forgotten afterwards.
+/// The stored messages are deleted, they're sealed with the key of the old password.
+fn change_passwd(
+    db: &mut DbRepo,
+    room_id: &str,
+    passwd: Option<&str>,
+    new_passwd: Option<PasswdSource>,
+    input: &mut impl BufRead,
+) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+    if !room.is_owner {
+        return Err(AppError::NotOwner);
+    }
 
-#[derive(Debug)]
-pub enum CommandRequest {
-    Create {
-        room_id: String,
-        ip: Option<String>,
-        password: bool,
-    },
-    Join {
-        id_or_address: IdOrAddr,
-        username: Option<String>,
-        color: Option<Color>,
-    },
-    Delete {
-        room_id: String,
-    },
-    List,
-    Set {
-        option: String,
-        value: String,
-    },
-    Invalid,
+    match (passwd, &room.passwd) {
+        (Some(passwd), Some(hash)) if !verify_passwd(passwd, hash) => {
+            return Err(AppError::InvalidPassword)
+        }
+        (Some(_), _) => (),
+        (None, _) => verify_room_passwd(db, &room)?,
+    }
+
+    let hash = new_passwd
+        .map(|source| new_room_passwd(source, input))
+        .transpose()?
+        .map(|passwd| hash_passwd(&passwd));
+    if room.passwd.is_some() || hash.is_some() {
+        db.messages.delete_many(doc! {"room_id": room_id})?;
+    }
+    db.rooms.update_one(
+        doc! {"_id": room_id},
+        doc! {"$set": {"passwd": to_bson(&hash).unwrap()}},
+    )?;
+    forget_passwd(db, room_id)
 }
 
-pub fn get_command_request() -> CommandRequest {
-    match config_clap().subcommand() {
-        Some(("create", create_matches)) => {
-            let room_id = create_matches
-                .get_one::<String>("room_id")
-                .unwrap()
-                .to_owned();
+fn clear_room(db: &mut DbRepo, room_id: &str, yes: bool) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
 
-            let room_ip = if let Some(room_ip) = create_matches.get_one::<String>("room_ip") {
-                Some(room_ip)
-            } else {
-                None
-            };
+    if !yes
+        && !confirm(
+            &format!("Delete all messages of room {}?", room_id),
+            &mut io::stdin().lock(),
+            &mut io::stdout(),
+        )?
+    {
+        return Ok(());
+    }
 
-            let password = create_matches.get_flag("password");
-            CommandRequest::Create {
-                room_id,
-                ip: room_ip.cloned(),
-                password,
-            }
-        }
-        Some(("join", join_matches)) => {
-            let id_or_addr_ = join_matches
-                .get_one::<String>("id_or_addr")
-                .unwrap()
-                .to_owned();
+    if room.is_owner {
+        verify_room_passwd(db, &room)?;
+    }
 
-            let id_or_addr = if Ipv4Addr::from_str(&id_or_addr_).is_ok() {
-                IdOrAddr::Addr(id_or_addr_)
-            } else {
-                IdOrAddr::Id(id_or_addr_)
-            };
+    db.messages.delete_many(doc! {"room_id": room_id})?;
+    Ok(())
+}
 
-            let username = if let Some(username) = join_matches.get_one::<String>("username") {
-                Some(username)
-            } else {
-                None
-            };
+/// Prompts for the room password unless it has been remembered already.
+fn verify_room_passwd(db: &DbRepo, room: &Room) -> Result<(), AppError> {
+    let Some(passwd) = &room.passwd else {
+        return Ok(());
+    };
 
-            let color = if let Some(color) = join_matches.get_one::<String>("color") {
-                Some(Color::from_str(color).unwrap())
-            } else {
-                None
-            };
+    if is_passwd_remembered(db, room)? {
+        return Ok(());
+    }
 
-            CommandRequest::Join {
-                id_or_address: id_or_addr,
-                username: username.cloned(),
-                color,
-            }
-        }
-        Some(("delete", delete_matches)) => {
-            let room_id = delete_matches
-                .get_one::<String>("room_id")
-                .unwrap()
-                .to_owned();
-            CommandRequest::Delete { room_id }
-        }
-        Some(("list", _)) => CommandRequest::List,
-        Some(("set", set_matches)) => {
-            let option_str = set_matches.get_one::<String>("option").unwrap();
-            let value_str = set_matches.get_one::<String>("value").unwrap();
-            CommandRequest::Set {
-                option: option_str.to_string(),
-                value: value_str.to_string(),
-            }
-        }
-        _ => CommandRequest::Invalid,
+    if !verify_passwd(&passwd_input()?, passwd) {
+        return Err(AppError::InvalidPassword);
     }
+
+    remember_passwd(db, room)
 }
 
-fn config_clap() -> ArgMatches {
-    Command::new("kioto")
-        .about("Yet another tui chat.")
-        .version(env!("CARGO_PKG_VERSION"))
-        .subcommand_required(true)
-        .arg_required_else_help(true)
-        .subcommand(
-            Command::new("create")
-                .long_flag("create")
-                .short_flag('c')
-                .about("Creates a new room")
-                .arg(
-                    Arg::new("password")
-                        .long("password")
-                        .short('p')
-                        .num_args(0)
-                        .required(false),
-                )
-                .arg(Arg::new("room_id").required(true))
-                .arg(Arg::new("room_ip").required(false)),
-        )
-        .subcommand(
-            Command::new("join")
-                .long_flag("join")
-                .short_flag('j')
-                .about("Joins a room")
-                .arg(Arg::new("id_or_addr").required(true))
-                .arg(Arg::new("username").required(false))
-                .arg(Arg::new("color").required(false)),
-        )
-        .subcommand(
-            Command::new("delete")
-                .long_flag("delete")
-                .short_flag('d')
-                .about("Deletes a room")
-                .arg(Arg::new("room_id").required(true)),
-        )
-        .subcommand(
-            Command::new("list")
-                .about("Lists all rooms")
-                .long_flag("list")
-                .short_flag('l'),
-        )
-        .subcommand(
-            Command::new("set")
-                .long_flag("set")
-                .short_flag('s')
-                .about("Sets an application option")
-                .arg(Arg::new("option").required(true))
-                .arg(Arg::new("value").required(true)),
-        )
-        .get_matches()
+/// Remembered passwords count only while `remember_passwords` is on.
+fn is_passwd_remembered(db: &DbRepo, room: &Room) -> Result<bool, AppError> {
+    let local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
+    if !local_data.remember_passwords {
+        return Ok(false);
+    }
+
+    let remembered = db.passwords.find_one(doc! {"_id": &room._id})?;
+    Ok(remembered.is_some_and(|remembered| Some(remembered.passwd) == room.passwd))
 }
 
-#[cfg(test)]
-mod test {
-    use std::{net::SocketAddr, str::FromStr};
+fn remember_passwd(db: &DbRepo, room: &Room) -> Result<(), AppError> {
+    let local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
 
-    use crate::app::{db_init, run_option};
+    if let (true, Some(passwd)) = (local_data.remember_passwords, &room.passwd) {
+        forget_passwd(db, &room._id)?;
+        db.passwords.insert_one(RememberedPasswd {
+            _id: room._id.clone(),
+            passwd: passwd.clone(),
+        })?;
+    }
 
-    use super::{Color, CommandRequest, LocalData, Room};
-    use polodb_core::bson::doc;
+    Ok(())
+}
 
-    #[test]
-    fn new_room_creation() {
-        let mut db = db_init(None).unwrap();
+fn forget_passwd(db: &DbRepo, room_id: &str) -> Result<(), AppError> {
+    db.passwords.delete_one(doc! {"_id": room_id})?;
+    Ok(())
+}
 
-        let room_with_custom_values = Room {
-            _id: "someroom".into(),
-            addr: SocketAddr::from_str("192.168.0.2:12345".into()).unwrap(),
-            passwd: None,
-            banned_addrs: vec![],
-            is_owner: true,
-        };
+/// Order of the listed rooms, newest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomSort {
+    Created,
+    Recent,
+}
 
-        run_option(
-            CommandRequest::Create {
-                room_id: room_with_custom_values._id.clone(),
-                ip: Some(room_with_custom_values.addr.ip().to_string()),
-                password: false,
-            },
-            &mut db,
-        )
-        .unwrap();
+fn list_rooms_and_local_data(
+    db: &DbRepo,
+    sort: Option<RoomSort>,
+    out: &mut impl Write,
+) -> Result<(), AppError> {
+    let local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
 
-        assert_eq!(
-            db.rooms.find_one(doc! {"id": "someroom"}).unwrap().unwrap(),
-            room_with_custom_values
-        );
+    writeln!(out, "{:#?}", local_data)?;
 
-        let room_with_default_values = Room {
-            _id: "anotheroom".into(),
-            addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
-            passwd: None,
-            banned_addrs: vec![],
+    // a corrupt document shouldn't hide the other rooms
+    let mut rooms = db.readable_rooms()?;
+    if rooms.is_empty() {
+        return Err(AppError::NoAnyRoom);
+    }
+
+    match sort {
+        Some(RoomSort::Created) => rooms.sort_by_key(|room| Reverse(room.created_at)),
+        Some(RoomSort::Recent) => rooms.sort_by_key(|room| Reverse(room.last_joined)),
+        None => (),
+    }
+
+    let (format, timezone) = (&local_data.timestamp_format, local_data.timezone);
+    for room in rooms {
+        writeln!(
+            out,
+            "{}: {} (created {}, last joined {})",
+            room._id,
+            room.addr,
+            systime_to_string(room.created_at, format, timezone),
+            room.last_joined
+                .map_or(String::from("never"), |time| systime_to_string(
+                    time, format, timezone
+                )),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn whoami(db: &DbRepo, json: bool, out: &mut impl Write) -> Result<(), AppError> {
+    let local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
+
+    if json {
+        writeln!(
+            out,
+            "{}",
+            serde_json::json!({
+                "username": local_data.default_user_id,
+                "addr": local_data.default_room_addr,
+                "color": local_data.default_color.to_string(),
+            })
+        )?;
+    } else {
+        writeln!(out, "username: {}", local_data.default_user_id)?;
+        writeln!(out, "addr: {}", local_data.default_room_addr)?;
+        writeln!(out, "color: {}", local_data.default_color)?;
+    }
+
+    Ok(())
+}
+
+fn list_bans(db: &DbRepo, room_id: &str, json: bool, out: &mut impl Write) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+
+    if json {
+        writeln!(out, "{}", serde_json::json!(room.banned_addrs))?;
+    } else {
+        for addr in room.banned_addrs {
+            writeln!(out, "{}", addr)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts of the saved rooms and the messages stored for each.
+fn stats(db: &DbRepo, json: bool, out: &mut impl Write) -> Result<(), AppError> {
+    let mut rooms = db.readable_rooms()?;
+    rooms.sort_by(|a, b| a._id.cmp(&b._id));
+
+    let owned = rooms.iter().filter(|room| room.is_owner).count();
+    let protected = rooms.iter().filter(|room| room.passwd.is_some()).count();
+    let messages = rooms
+        .iter()
+        .map(|room| Ok((room._id.as_str(), db.room_messages(&room._id, None)?.len())))
+        .collect::<Result<Vec<(&str, usize)>, AppError>>()?;
+
+    if json {
+        writeln!(
+            out,
+            "{}",
+            serde_json::json!({
+                "rooms": rooms.len(),
+                "owned": owned,
+                "joined": rooms.len() - owned,
+                "password_protected": protected,
+                "messages": messages.into_iter().collect::<BTreeMap<&str, usize>>(),
+            })
+        )?;
+    } else {
+        writeln!(out, "rooms: {}", rooms.len())?;
+        writeln!(out, "owned: {}", owned)?;
+        writeln!(out, "joined: {}", rooms.len() - owned)?;
+        writeln!(out, "password protected: {}", protected)?;
+        writeln!(out, "messages:")?;
+        for (room_id, count) in messages {
+            writeln!(out, "  {}: {}", room_id, count)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn room_info(db: &DbRepo, room_id: &str, json: bool, out: &mut impl Write) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+    let messages = db.room_messages(&room._id, None)?.len();
+
+    if json {
+        writeln!(
+            out,
+            "{}",
+            serde_json::json!({
+                "id": room._id,
+                "addr": room.addr,
+                "host": room.host,
+                "owner": room.is_owner,
+                "password_protected": room.passwd.is_some(),
+                "bans": room.banned_addrs.len(),
+                "max_users": room.max_users,
+                "topic": room.topic,
+                "filter_words": room.filter_words,
+                "created_at": format_rfc3339_seconds(room.created_at).to_string(),
+                "last_joined": room
+                    .last_joined
+                    .map(|time| format_rfc3339_seconds(time).to_string()),
+                "messages": messages,
+            })
+        )?;
+        return Ok(());
+    }
+
+    let local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
+    let time = |time| systime_to_string(time, &local_data.timestamp_format, local_data.timezone);
+
+    writeln!(out, "id: {}", room._id)?;
+    writeln!(out, "addr: {}", room.addr)?;
+    writeln!(out, "host: {}", room.host.as_deref().unwrap_or("none"))?;
+    writeln!(out, "owner: {}", room.is_owner)?;
+    writeln!(out, "password protected: {}", room.passwd.is_some())?;
+    writeln!(out, "bans: {}", room.banned_addrs.len())?;
+    writeln!(
+        out,
+        "max users: {}",
+        room.max_users
+            .map_or(String::from("unlimited"), |max| max.to_string())
+    )?;
+    writeln!(out, "topic: {}", room.topic.as_deref().unwrap_or("none"))?;
+    writeln!(out, "filtered words: {}", room.filter_words.join(", "))?;
+    writeln!(out, "created: {}", time(room.created_at))?;
+    writeln!(
+        out,
+        "last joined: {}",
+        room.last_joined.map_or(String::from("never"), time)
+    )?;
+    writeln!(out, "messages: {}", messages)?;
+
+    Ok(())
+}
+
+/// Lifts the bans of the given ip, or all of them without one.
+fn unban(db: &mut DbRepo, room_id: &str, addr: Option<&str>) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+
+    if !room.is_owner {
+        return Err(AppError::NotOwner);
+    }
+
+    let ip = addr
+        .map(|addr| {
+            IpAddr::from_str(addr)
+                .or_else(|_| SocketAddr::from_str(addr).map(|addr| addr.ip()))
+                .map_err(|_| AppError::InvalidAddress(addr.into()))
+        })
+        .transpose()?;
+
+    verify_room_passwd(db, &room)?;
+
+    let banned_addrs = match ip {
+        Some(ip) => room
+            .banned_addrs
+            .into_iter()
+            .filter(|banned| banned.ip() != ip)
+            .collect(),
+        None => vec![],
+    };
+    db.rooms.update_one(
+        doc! {"_id": room_id},
+        doc! {"$set": {"banned_addrs": to_bson(&banned_addrs).unwrap()}},
+    )?;
+
+    Ok(())
+}
+
+fn print_completions(shell: Shell, out: &mut impl Write) {
+    generate(shell, &mut build_cli(), "kioto", out);
+}
+
+/// Joins every given room, the first one is shown at start. Without one, it's picked from
+/// the saved rooms.
+#[allow(clippy::too_many_arguments)]
+async fn join_room(
+    db: &DbRepo,
+    id_or_addr: Option<IdOrAddr>,
+    also: Vec<IdOrAddr>,
+    username: Option<String>,
+    color: Option<Color>,
+    history: Option<usize>,
+    alt_screen: bool,
+    spectate: bool,
+) -> Result<(), AppError> {
+    let mut local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
+    local_data.alt_screen &= alt_screen;
+
+    let id_or_addr = match id_or_addr {
+        Some(id_or_addr) => id_or_addr,
+        None => {
+            let rooms = db.readable_rooms()?;
+            if rooms.is_empty() {
+                return Err(AppError::NoAnyRoom);
+            }
+            let mut picker = RoomPicker::new(rooms, ChatStyle::themed(local_data.current_theme()));
+            picker.alt_screen = local_data.alt_screen;
+            match picker.run()? {
+                Some(room_id) => IdOrAddr::Id(room_id),
+                None => return Ok(()),
+            }
+        }
+    };
+
+    let user = local_user(&local_data, username, color, spectate);
+
+    let mut clients = vec![];
+    for id_or_addr in iter::once(id_or_addr).chain(also) {
+        clients.push(connect_room(db, &local_data, id_or_addr, user.clone(), history).await?);
+    }
+
+    let mut app = ChatApp::new(clients, &local_data);
+    app.run().await?;
+
+    let ignored = app.ignored_users(&local_data.ignored);
+    if ignored != local_data.ignored {
+        db.local_data.update_one(
+            doc! {"default_user_id": &local_data.default_user_id},
+            doc! {"$set": {"ignored": to_bson(&ignored).unwrap()}},
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The user joining as, the flags take precedence over the local data.
+fn local_user(
+    local_data: &LocalData,
+    username: Option<String>,
+    color: Option<Color>,
+    spectator: bool,
+) -> User {
+    User {
+        _id: username.unwrap_or(local_data.default_user_id.clone()),
+        addr: None,
+        color: color.or(Some(local_data.default_color.clone())),
+        away: false,
+        spectator,
+    }
+}
+
+async fn connect_room(
+    db: &DbRepo,
+    local_data: &LocalData,
+    id_or_addr: IdOrAddr,
+    user: User,
+    history: Option<usize>,
+) -> Result<ChatClient, AppError> {
+    let saved_room_id = match &id_or_addr {
+        IdOrAddr::Id(room_id) => Some(room_id.clone()),
+        IdOrAddr::Addr(_) => None,
+    };
+
+    let room = room_to_join(db, local_data, id_or_addr).await?;
+
+    let mut client = ChatClient::new(room, user);
+    client.history = history;
+    client.connect_timeout = Duration::from_secs(local_data.connect_timeout.into());
+    client.ping_interval = Duration::from_secs(local_data.ping_interval.into());
+    client.reconnect_attempts = local_data.reconnect_attempts;
+    match client.connect().await {
+        Err(ClientError::PasswordRequired) => {
+            client.passwd = Some(passwd_input()?);
+            client.connect().await?;
+        }
+        result => result?,
+    }
+
+    if let Some(room_id) = saved_room_id {
+        mark_joined(db, &room_id)?;
+    }
+
+    Ok(client)
+}
+
+/// Hostnames are resolved on every join, they may point elsewhere since the room was saved.
+async fn room_to_join(
+    db: &DbRepo,
+    local_data: &LocalData,
+    id_or_addr: IdOrAddr,
+) -> Result<Room, AppError> {
+    Ok(match id_or_addr {
+        IdOrAddr::Id(room_id) => {
+            let mut room = db
+                .rooms
+                .find_one(doc! {"_id": &room_id})?
+                .ok_or(AppError::NotExistingId)?;
+            if let Some(host) = &room.host {
+                room.addr = resolve_room_addr(host).await?;
+            }
+            room
+        }
+        IdOrAddr::Addr(addr) => Room {
+            _id: addr.clone(),
+            addr: parse_join_addr(&addr, local_data.default_room_addr.port()).await?,
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: false,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::now(),
+            host: is_host_addr(&addr).then_some(addr),
+            last_joined: None,
+            filter_words: vec![],
+        },
+    })
+}
+
+fn mark_joined(db: &DbRepo, room_id: &str) -> Result<(), AppError> {
+    db.rooms.update_one(
+        doc! {"_id": room_id},
+        doc! {"$set": {"last_joined": to_bson(&SystemTime::now()).unwrap()}},
+    )?;
+
+    Ok(())
+}
+
+/// The audit log in the data `dir`, `None` unless turned on.
+fn audit_log(local_data: &LocalData, dir: &Path) -> io::Result<Option<AuditLog>> {
+    local_data
+        .audit_log
+        .then(|| AuditLog::open(&dir.join("audit.log")))
+        .transpose()
+}
+
+/// Accepts a bare ip too, using the default port then.
+async fn parse_join_addr(addr: &str, default_port: u16) -> Result<SocketAddr, AppError> {
+    match IpAddr::from_str(addr) {
+        Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+        Err(_) => resolve_room_addr(addr).await,
+    }
+}
+
+async fn resolve_room_addr(addr: &str) -> Result<SocketAddr, AppError> {
+    lookup_host(addr)
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| AppError::InvalidAddress(addr.into()))
+}
+
+async fn host_room(db: DbRepo, room_id: &str, alt_screen: bool) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+
+    if !room.is_owner {
+        return Err(AppError::NotOwner);
+    }
+
+    // the plain password is needed for the message key, so it's never taken from memory
+    let passwd = match &room.passwd {
+        Some(hash) => {
+            let passwd = passwd_input()?;
+            if !verify_passwd(&passwd, hash) {
+                return Err(AppError::InvalidPassword);
+            }
+            Some(passwd)
+        }
+        None => None,
+    };
+
+    let mut local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
+    local_data.alt_screen &= alt_screen;
+
+    let mut server = ChatServer::new(room.clone(), Arc::new(Mutex::new(db))).await?;
+    server.audit = audit_log(&local_data, &create_env_dir("kioto")?)?;
+    server
+        .run()
+        .await
+        .map_err(|err| AppError::hosting(err, room.addr))?;
+    server.stop_on_interrupt();
+
+    let user = local_user(&local_data, None, None, false);
+
+    let mut client = ChatClient::new(
+        Room {
+            addr: server.local_addr().unwrap_or(room.addr),
+            ..room
+        },
+        user,
+    );
+    client.passwd = passwd;
+    client.owner_token = Some(server.owner_token().into());
+    client.ping_interval = Duration::from_secs(local_data.ping_interval.into());
+    client.reconnect_attempts = local_data.reconnect_attempts;
+    let result = match client.connect().await {
+        Ok(()) => {
+            let mut app = ChatApp::new(vec![client], &local_data);
+            app.hosting = true;
+            app.run().await.map_err(AppError::from)
+        }
+        Err(err) => Err(err.into()),
+    };
+
+    server.stop();
+    result
+}
+
+fn set_local_data(db: &mut DbRepo, option: &str, value: &str) -> Result<(), AppError> {
+    let (option, value) = match option {
+        "color" | "default_color" => {
+            let color = Color::from_str(value).map_err(AppError::InvalidValue)?;
+            ("default_color", to_bson(&color).unwrap())
+        }
+        "topic" | "filter_words" => {
+            return Err(AppError::InvalidValue(format!(
+                "{} is a room option, pass the room with --room",
+                option
+            )))
+        }
+        // an empty value brings back the theme's color
+        "input_border" | "input_highlight" | "input_placeholder" => match value.trim() {
+            "" => (option, Bson::Null),
+            value => (
+                option,
+                to_bson(&Color::from_str(value).map_err(AppError::InvalidValue)?).unwrap(),
+            ),
+        },
+        "bell" | "bell_mentions_only" | "remember_passwords" | "light_mode" | "markdown"
+        | "alt_screen" | "audit_log" => (option, Bson::Boolean(parse_switch(value)?)),
+        "msg_rate" | "msg_burst" | "max_msg_len" | "ping_interval" | "max_missed_pongs"
+        | "connect_timeout" | "reconnect_attempts" | "max_file_size" => {
+            match value.parse::<u32>() {
+                Ok(limit) if limit > 0 => (option, Bson::Int64(limit.into())),
+                _ => {
+                    return Err(AppError::InvalidValue(format!(
+                        "{} is not a positive number",
+                        value
+                    )))
+                }
+            }
+        }
+        "layout_ratio" => match value.parse::<u16>() {
+            Ok(ratio) if LAYOUT_RATIOS.contains(&ratio) => (option, Bson::Int64(ratio.into())),
+            _ => {
+                return Err(AppError::InvalidValue(format!(
+                    "{} is not a percent between {} and {}",
+                    value,
+                    LAYOUT_RATIOS.start(),
+                    LAYOUT_RATIOS.end()
+                )))
+            }
+        },
+        // 0 lifts the limit
+        "retention_messages" | "retention_days" => match value.parse::<u32>() {
+            Ok(limit) => (option, Bson::Int64(limit.into())),
+            _ => return Err(AppError::InvalidValue(format!("{} is not a number", value))),
+        },
+        "away_after" => match value.parse::<u32>() {
+            Ok(secs) => (option, Bson::Int64(secs.into())),
+            _ => {
+                return Err(AppError::InvalidValue(format!(
+                    "{} is not a number of seconds",
+                    value
+                )))
+            }
+        },
+        "addr" | "default_room_addr" => (
+            "default_room_addr",
+            to_bson(&parse_room_addr(value)?).unwrap(),
+        ),
+        "username" | "default_user_id" => ("default_user_id", Bson::String(value.into())),
+        "timestamp_format" => (option, Bson::String(value.into())),
+        "mention_prefix" => {
+            let mut chars = value.trim().chars();
+            match (chars.next(), chars.next()) {
+                (Some(prefix), None) if !prefix.is_alphanumeric() && prefix != '_' => {
+                    (option, Bson::String(prefix.into()))
+                }
+                _ => {
+                    return Err(AppError::InvalidValue(format!(
+                        "{} is not a single symbol",
+                        value
+                    )))
+                }
+            }
+        }
+        "theme" => (
+            option,
+            to_bson(&Theme::from_str(value).map_err(AppError::InvalidValue)?).unwrap(),
+        ),
+        "timezone" => (
+            option,
+            to_bson(&Timezone::from_str(value).map_err(AppError::InvalidValue)?).unwrap(),
+        ),
+        _ => {
+            return Err(AppError::InvalidValue(format!(
+                "{} is not an option",
+                option
+            )))
+        }
+    };
+
+    let local_data = db
+        .local_data
+        .find_one(None)?
+        .ok_or(AppError::DataNotFound)?;
+
+    db.local_data.update_one(
+        doc! {"default_user_id": local_data.default_user_id},
+        doc! {"$set": doc! {
+            option: value
+        }},
+    )?;
+
+    Ok(())
+}
+
+fn set_room_option(
+    db: &mut DbRepo,
+    room_id: &str,
+    option: &str,
+    value: &str,
+) -> Result<(), AppError> {
+    let value = match option {
+        // an empty value clears the topic
+        "topic" => match value.trim() {
+            "" => Bson::Null,
+            topic => Bson::String(topic.into()),
+        },
+        // comma separated, an empty value clears the filter
+        "filter_words" => Bson::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|word| !word.is_empty())
+                .map(|word| Bson::String(word.into()))
+                .collect(),
+        ),
+        _ => {
+            return Err(AppError::InvalidValue(format!(
+                "{} is not a room option",
+                option
+            )))
+        }
+    };
+
+    db.rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+
+    db.rooms
+        .update_one(doc! {"_id": room_id}, doc! {"$set": {option: value}})?;
+
+    Ok(())
+}
+
+fn parse_switch(value: &str) -> Result<bool, AppError> {
+    match value.to_lowercase().as_str() {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(AppError::InvalidValue(format!(
+            "{} is neither on nor off",
+            value
+        ))),
+    }
+}
+
+fn export_room(db: &DbRepo, room_id: &str, path: &Path, with_secret: bool) -> Result<(), AppError> {
+    let room = db
+        .rooms
+        .find_one(doc! {"_id": room_id})?
+        .ok_or(AppError::NotExistingId)?;
+
+    fs::write(path, room_to_toml(&room, with_secret)?)?;
+
+    Ok(())
+}
+
+fn room_to_toml(room: &Room, with_secret: bool) -> Result<String, AppError> {
+    Ok(toml::to_string(&RoomExport::new(room, with_secret))?)
+}
+
+fn import_room(
+    db: &mut DbRepo,
+    path: &Path,
+    overwrite: bool,
+    allow_duplicate_addr: bool,
+) -> Result<(), AppError> {
+    let room = room_from_toml(&fs::read_to_string(path)?)?;
+
+    if room.is_owner && !allow_duplicate_addr {
+        check_addr_free(db, &room._id, &room.addr)?;
+    }
+
+    if db.rooms.find_one(doc! {"_id": &room._id})?.is_some() {
+        if !overwrite {
+            return Err(AppError::AlreadyExistingId);
+        }
+        db.rooms.delete_one(doc! {"_id": &room._id})?;
+    }
+
+    db.rooms.insert_one(&room)?;
+
+    Ok(())
+}
+
+fn backup_db(db: &DbRepo, path: &Path) -> Result<(), AppError> {
+    let backup = Backup {
+        local_data: db.local_data.find_one(None)?,
+        rooms: db.rooms.find(None)?.collect::<pdbResult<Vec<Room>>>()?,
+    };
+
+    fs::write(path, toml::to_string(&backup)?)?;
+
+    Ok(())
+}
+
+/// Conflicting rooms and the existing settings are replaced only with `overwrite`.
+fn restore_db(db: &mut DbRepo, path: &Path, overwrite: bool) -> Result<(), AppError> {
+    let backup = toml::from_str::<Backup>(&fs::read_to_string(path)?)
+        .map_err(|err| AppError::InvalidValue(err.message().into()))?;
+
+    if !overwrite {
+        for room in backup.rooms.iter() {
+            if db.rooms.find_one(doc! {"_id": &room._id})?.is_some() {
+                return Err(AppError::AlreadyExistingId);
+            }
+        }
+    }
+
+    for room in backup.rooms.iter() {
+        db.rooms.delete_one(doc! {"_id": &room._id})?;
+        db.rooms.insert_one(room)?;
+    }
+
+    if let Some(local_data) = backup.local_data {
+        match db.local_data.find_one(None)? {
+            Some(current) if overwrite => {
+                db.local_data
+                    .delete_one(doc! {"default_user_id": current.default_user_id})?;
+                db.local_data.insert_one(local_data)?;
+            }
+            Some(_) => (),
+            None => {
+                db.local_data.insert_one(local_data)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn room_from_toml(content: &str) -> Result<Room, AppError> {
+    let exported = toml::from_str::<RoomExport>(content)
+        .map_err(|err| AppError::InvalidValue(err.message().into()))?;
+
+    if exported.has_password && exported.passwd.is_none() {
+        return Err(AppError::InvalidValue(
+            "the room has a password but it was exported without --with-secret".into(),
+        ));
+    }
+
+    Ok(Room {
+        _id: exported.id,
+        addr: exported.addr,
+        passwd: exported.passwd,
+        banned_addrs: exported.banned_addrs,
+        is_owner: exported.is_owner,
+        max_users: exported.max_users,
+        topic: exported.topic,
+        created_at: SystemTime::now(),
+        host: exported.host,
+        last_joined: None,
+        filter_words: exported.filter_words,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IdOrAddr {
+    Id(String),
+    Addr(String),
+}
+
+impl IdOrAddr {
+    pub fn parse(value: String) -> Self {
+        if SocketAddr::from_str(&value).is_ok()
+            || IpAddr::from_str(&value).is_ok()
+            || is_host_addr(&value)
+        {
+            IdOrAddr::Addr(value)
+        } else {
+            IdOrAddr::Id(value)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandRequest {
+    Create {
+        room_id: String,
+        ip: Option<String>,
+        password: Option<PasswdSource>,
+        max_users: Option<u32>,
+        topic: Option<String>,
+        allow_duplicate_addr: bool,
+        dry_run: bool,
+    },
+    Join {
+        id_or_address: Option<IdOrAddr>,
+        /// Further rooms joined in the same session.
+        also: Vec<IdOrAddr>,
+        username: Option<String>,
+        color: Option<Color>,
+        history: Option<usize>,
+        /// False with `--no-alt-screen`, the chat is then drawn inline.
+        alt_screen: bool,
+        /// Joins read-only, without showing up in the user list.
+        spectate: bool,
+    },
+    Delete {
+        room_id: String,
+        force: bool,
+        password: Option<String>,
+    },
+    Forget {
+        room_id: String,
+    },
+    Passwd {
+        room_id: String,
+        /// Where the new password is read from, `None` removes the password.
+        new_password: Option<PasswdSource>,
+        /// The current password, prompted for when not given.
+        password: Option<String>,
+    },
+    Clear {
+        room_id: String,
+        yes: bool,
+    },
+    Host {
+        room_id: String,
+        alt_screen: bool,
+    },
+    List {
+        sort: Option<RoomSort>,
+    },
+    ConfigPath,
+    WhoAmI {
+        json: bool,
+    },
+    Bans {
+        room_id: String,
+        json: bool,
+    },
+    Unban {
+        room_id: String,
+        addr: Option<String>,
+    },
+    Stats {
+        json: bool,
+    },
+    Info {
+        room_id: String,
+        json: bool,
+    },
+    Set {
+        option: String,
+        value: String,
+        room_id: Option<String>,
+    },
+    Export {
+        room_id: String,
+        path: PathBuf,
+        with_secret: bool,
+    },
+    Import {
+        path: PathBuf,
+        overwrite: bool,
+        allow_duplicate_addr: bool,
+    },
+    Backup {
+        path: PathBuf,
+    },
+    Restore {
+        path: PathBuf,
+        overwrite: bool,
+    },
+    Completions {
+        shell: Shell,
+    },
+    Invalid,
+}
+
+impl CommandRequest {
+    /// Whether the command draws on the terminal, nothing else may write to it meanwhile.
+    pub fn is_tui(&self) -> bool {
+        matches!(
+            self,
+            CommandRequest::Join { .. } | CommandRequest::Host { .. }
+        )
+    }
+}
+
+pub fn get_command_request() -> (CommandRequest, LevelFilter, bool) {
+    let matches = config_clap();
+    (
+        command_request_from(&matches),
+        log_level_from(&matches),
+        color_from(&matches),
+    )
+}
+
+/// Whether the output outside the TUI is colored.
+fn color_from(matches: &ArgMatches) -> bool {
+    use_color(
+        matches.get_one::<String>("color_output").unwrap(),
+        env::var_os("NO_COLOR"),
+        io::stderr().is_terminal(),
+    )
+}
+
+/// Errors only by default, each `-v` raises the level up to trace.
+fn log_level_from(matches: &ArgMatches) -> LevelFilter {
+    match matches.get_count("verbose") {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+fn command_request_from(matches: &ArgMatches) -> CommandRequest {
+    match matches.subcommand() {
+        Some(("create", create_matches)) => {
+            let room_id = create_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned();
+
+            let room_ip = if create_matches.get_flag("lan") {
+                Some(LAN_ADDR.to_owned())
+            } else {
+                create_matches.get_one::<String>("room_ip").cloned()
+            };
+
+            let password = if create_matches.get_flag("password_stdin") {
+                Some(PasswdSource::Stdin)
+            } else if create_matches.get_flag("password") {
+                Some(PasswdSource::Prompt)
+            } else {
+                None
+            };
+            CommandRequest::Create {
+                room_id,
+                ip: room_ip,
+                password,
+                max_users: create_matches.get_one::<u32>("max_users").copied(),
+                topic: create_matches.get_one::<String>("topic").cloned(),
+                allow_duplicate_addr: create_matches.get_flag("allow_duplicate_addr"),
+                dry_run: create_matches.get_flag("dry_run"),
+            }
+        }
+        Some(("join", join_matches)) => {
+            let id_or_addr = join_matches
+                .get_one::<String>("id_or_addr")
+                .map(|id_or_addr| IdOrAddr::parse(id_or_addr.to_owned()));
+
+            let username = join_matches.get_one::<String>("username");
+
+            let color = join_matches.get_one::<Color>("color").cloned();
+
+            let history = join_matches.get_one::<usize>("history").copied();
+
+            let also = join_matches
+                .get_many::<String>("also")
+                .unwrap_or_default()
+                .map(|id_or_addr| IdOrAddr::parse(id_or_addr.to_owned()))
+                .collect();
+
+            CommandRequest::Join {
+                id_or_address: id_or_addr,
+                also,
+                username: username.cloned(),
+                color,
+                history,
+                alt_screen: !join_matches.get_flag("no_alt_screen"),
+                spectate: join_matches.get_flag("spectate"),
+            }
+        }
+        Some(("delete", delete_matches)) => {
+            let room_id = delete_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned();
+            CommandRequest::Delete {
+                room_id,
+                force: delete_matches.get_flag("force"),
+                password: delete_matches
+                    .get_one::<String>("password")
+                    .cloned()
+                    .or_else(|| env::var(PASSWD_VAR).ok()),
+            }
+        }
+        Some(("passwd", passwd_matches)) => {
+            let room_id = passwd_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned();
+            let new_password = if passwd_matches.get_flag("clear") {
+                None
+            } else if passwd_matches.get_flag("new_password_stdin") {
+                Some(PasswdSource::Stdin)
+            } else {
+                Some(PasswdSource::Prompt)
+            };
+            CommandRequest::Passwd {
+                room_id,
+                new_password,
+                password: passwd_matches
+                    .get_one::<String>("password")
+                    .cloned()
+                    .or_else(|| env::var(PASSWD_VAR).ok()),
+            }
+        }
+        Some(("forget", forget_matches)) => {
+            let room_id = forget_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned();
+            CommandRequest::Forget { room_id }
+        }
+        Some(("clear", clear_matches)) => {
+            let room_id = clear_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned();
+            let yes = clear_matches.get_flag("yes");
+            CommandRequest::Clear { room_id, yes }
+        }
+        Some(("host", host_matches)) => {
+            let room_id = host_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned();
+            CommandRequest::Host {
+                room_id,
+                alt_screen: !host_matches.get_flag("no_alt_screen"),
+            }
+        }
+        Some(("list", list_matches)) => CommandRequest::List {
+            sort: list_matches
+                .get_one::<String>("sort")
+                .map(|sort| match sort.as_str() {
+                    "created" => RoomSort::Created,
+                    _ => RoomSort::Recent,
+                }),
+        },
+        Some(("config-path", _)) => CommandRequest::ConfigPath,
+        Some(("whoami", whoami_matches)) => CommandRequest::WhoAmI {
+            json: whoami_matches.get_flag("json"),
+        },
+        Some(("bans", bans_matches)) => CommandRequest::Bans {
+            room_id: bans_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned(),
+            json: bans_matches.get_flag("json"),
+        },
+        Some(("stats", stats_matches)) => CommandRequest::Stats {
+            json: stats_matches.get_flag("json"),
+        },
+        Some(("info", info_matches)) => CommandRequest::Info {
+            room_id: info_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned(),
+            json: info_matches.get_flag("json"),
+        },
+        Some(("unban", unban_matches)) => CommandRequest::Unban {
+            room_id: unban_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned(),
+            addr: unban_matches.get_one::<String>("addr").cloned(),
+        },
+        Some(("set", set_matches)) => {
+            let option_str = set_matches.get_one::<String>("option").unwrap();
+            let value_str = set_matches.get_one::<String>("value").unwrap();
+            CommandRequest::Set {
+                option: option_str.to_string(),
+                value: value_str.to_string(),
+                room_id: set_matches.get_one::<String>("room_id").cloned(),
+            }
+        }
+        Some(("export", export_matches)) => {
+            let room_id = export_matches
+                .get_one::<String>("room_id")
+                .unwrap()
+                .to_owned();
+            let path = export_matches
+                .get_one::<PathBuf>("path")
+                .unwrap()
+                .to_owned();
+            let with_secret = export_matches.get_flag("with_secret");
+            CommandRequest::Export {
+                room_id,
+                path,
+                with_secret,
+            }
+        }
+        Some(("import", import_matches)) => {
+            let path = import_matches
+                .get_one::<PathBuf>("path")
+                .unwrap()
+                .to_owned();
+            let overwrite = import_matches.get_flag("overwrite");
+            CommandRequest::Import {
+                path,
+                overwrite,
+                allow_duplicate_addr: import_matches.get_flag("allow_duplicate_addr"),
+            }
+        }
+        Some(("backup", backup_matches)) => CommandRequest::Backup {
+            path: backup_matches
+                .get_one::<PathBuf>("path")
+                .unwrap()
+                .to_owned(),
+        },
+        Some(("restore", restore_matches)) => {
+            let path = restore_matches
+                .get_one::<PathBuf>("path")
+                .unwrap()
+                .to_owned();
+            let overwrite = restore_matches.get_flag("overwrite");
+            CommandRequest::Restore { path, overwrite }
+        }
+        Some(("completions", completions_matches)) => CommandRequest::Completions {
+            shell: *completions_matches.get_one::<Shell>("shell").unwrap(),
+        },
+        _ => CommandRequest::Invalid,
+    }
+}
+
+fn config_clap() -> ArgMatches {
+    build_cli().get_matches()
+}
+
+fn build_cli() -> Command {
+    Command::new("kioto")
+        .about("Yet another tui chat.")
+        .version(env!("CARGO_PKG_VERSION"))
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .short('v')
+                .action(ArgAction::Count)
+                .global(true),
+        )
+        .arg(
+            Arg::new("color_output")
+                .long("color")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto")
+                .global(true),
+        )
+        .subcommand(
+            Command::new("create")
+                .long_flag("create")
+                .short_flag('c')
+                .about("Creates a new room")
+                .arg(
+                    Arg::new("password")
+                        .long("password")
+                        .short('p')
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("password_stdin")
+                        .long("password-stdin")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("max_users")
+                        .long("max-users")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .required(false),
+                )
+                .arg(Arg::new("topic").long("topic").required(false))
+                .arg(
+                    Arg::new("allow_duplicate_addr")
+                        .long("allow-duplicate-addr")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(Arg::new("room_id").required(true))
+                .arg(
+                    Arg::new("lan")
+                        .long("lan")
+                        .num_args(0)
+                        .conflicts_with("room_ip")
+                        .required(false),
+                )
+                .arg(Arg::new("room_ip").required(false)),
+        )
+        .subcommand(
+            Command::new("join")
+                .long_flag("join")
+                .short_flag('j')
+                .about("Joins a room")
+                .arg(Arg::new("id_or_addr").required(false))
+                .arg(Arg::new("username").required(false))
+                .arg(
+                    Arg::new("color")
+                        .value_parser(Color::from_str)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("history")
+                        .long("history")
+                        .help("Number of recent messages to load")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("also")
+                        .long("also")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("no_alt_screen")
+                        .long("no-alt-screen")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("spectate")
+                        .long("spectate")
+                        .num_args(0)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("delete")
+                .long_flag("delete")
+                .short_flag('d')
+                .about("Deletes a room")
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(Arg::new("password").long("password").required(false))
+                .arg(Arg::new("room_id").required(true)),
+        )
+        .subcommand(
+            Command::new("passwd")
+                .about("Changes or removes the password of an owned room, deleting its messages")
+                .arg(Arg::new("password").long("password").required(false))
+                .arg(
+                    Arg::new("new_password_stdin")
+                        .long("new-password-stdin")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("clear")
+                        .long("clear")
+                        .num_args(0)
+                        .conflicts_with("new_password_stdin")
+                        .required(false),
+                )
+                .arg(Arg::new("room_id").required(true)),
+        )
+        .subcommand(
+            Command::new("forget")
+                .about("Forgets a remembered room password")
+                .arg(Arg::new("room_id").required(true)),
+        )
+        .subcommand(
+            Command::new("clear")
+                .about("Deletes the message history of a room")
+                .arg(
+                    Arg::new("yes")
+                        .long("yes")
+                        .short('y')
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(Arg::new("room_id").required(true)),
+        )
+        .subcommand(
+            Command::new("host")
+                .about("Hosts an owned room and joins it")
+                .arg(Arg::new("room_id").required(true))
+                .arg(
+                    Arg::new("no_alt_screen")
+                        .long("no-alt-screen")
+                        .num_args(0)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("Lists all rooms")
+                .long_flag("list")
+                .short_flag('l')
+                .arg(
+                    Arg::new("sort")
+                        .long("sort")
+                        .value_parser(["created", "recent"])
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("config-path").about("Prints the directory of the database and logs"),
+        )
+        .subcommand(
+            Command::new("whoami")
+                .about("Prints the local user data")
+                .arg(Arg::new("json").long("json").num_args(0).required(false)),
+        )
+        .subcommand(
+            Command::new("bans")
+                .about("Lists the banned addresses of a room")
+                .arg(Arg::new("json").long("json").num_args(0).required(false))
+                .arg(Arg::new("room_id").required(true)),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Counts the saved rooms and their stored messages")
+                .arg(Arg::new("json").long("json").num_args(0).required(false)),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Prints everything known about a room")
+                .arg(Arg::new("json").long("json").num_args(0).required(false))
+                .arg(Arg::new("room_id").required(true)),
+        )
+        .subcommand(
+            Command::new("unban")
+                .about("Lifts a ban, or all of them")
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .num_args(0)
+                        .required(false)
+                        .conflicts_with("addr"),
+                )
+                .arg(Arg::new("room_id").required(true))
+                .arg(Arg::new("addr").required_unless_present("all")),
+        )
+        .subcommand(
+            Command::new("set")
+                .long_flag("set")
+                .short_flag('s')
+                .about("Sets an application option")
+                .arg(Arg::new("option").required(true))
+                .arg(Arg::new("value").required(true))
+                .arg(Arg::new("room_id").long("room").required(false)),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Exports a room to a TOML file")
+                .arg(
+                    Arg::new("with_secret")
+                        .long("with-secret")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(Arg::new("room_id").required(true))
+                .arg(
+                    Arg::new("path")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Imports a room from a TOML file")
+                .arg(
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("allow_duplicate_addr")
+                        .long("allow-duplicate-addr")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("path")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("Saves all rooms and settings to a TOML file")
+                .arg(
+                    Arg::new("path")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restores rooms and settings from a backup")
+                .arg(
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("path")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Generates a shell completion script")
+                .arg(
+                    Arg::new("shell")
+                        .value_parser(value_parser!(Shell))
+                        .required(true),
+                ),
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::app::{
+        audit_log, build_cli, change_passwd, command_request_from, create_room, db_init,
+        delete_room, describe_new_room, host_room, is_passwd_remembered, join_room, list_bans,
+        list_rooms_and_local_data, local_user, log_level_from, mark_joined, new_room,
+        new_room_passwd, parse_join_addr, parse_room_addr, print_completions, remember_passwd,
+        room_from_toml, room_info, room_to_join, room_to_toml, run, run_option, stats, unban,
+        verify_room_passwd, whoami,
+    };
+    use crate::db::{DbRepo, Retention};
+    use crate::error::AppError;
+
+    use super::{
+        Color, CommandRequest, IdOrAddr, LocalData, PasswdSource, Room, RoomExport, RoomSort,
+        Timezone, DEFAULT_AWAY_AFTER, DEFAULT_CONNECT_TIMEOUT, DEFAULT_LAYOUT_RATIO,
+        DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_MISSED_PONGS, DEFAULT_MAX_MSG_LEN,
+        DEFAULT_MENTION_PREFIX, DEFAULT_MSG_BURST, DEFAULT_MSG_RATE, DEFAULT_PING_INTERVAL,
+        DEFAULT_RECONNECT_ATTEMPTS,
+    };
+    use crate::db::SCHEMA_VERSION;
+    use crate::network::{
+        client::ChatClient,
+        crypto::{derive_key, encrypt, passwd_salt},
+        message::{MessageType, ServerMsg},
+        server::ChatServer,
+        User,
+    };
+    use crate::schema::{TextMessage, Theme};
+    use crate::util::{hash_passwd, verify_passwd, DATA_DIR_VAR, ENV_LOCK, ROOM_PASSWD_VAR};
+    use clap_complete::Shell;
+    use log::LevelFilter;
+    use polodb_core::{
+        bson::{doc, Bson, Document},
+        Database,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    /// Fetches a room, checking `created_at` was stamped and resetting it for comparison.
+    fn stored_room(db: &DbRepo, room_id: &str) -> Room {
+        let room = db.rooms.find_one(doc! {"_id": room_id}).unwrap().unwrap();
+        assert!(room.created_at.elapsed().unwrap() < Duration::from_secs(60));
+        Room {
+            created_at: SystemTime::UNIX_EPOCH,
+            ..room
+        }
+    }
+
+    #[test]
+    fn new_room_creation() {
+        let mut db = db_init(None).unwrap();
+
+        let room_with_custom_values = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("192.168.0.2:12345").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: Some(10),
+            topic: Some("weekly sync".into()),
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+
+        run_option(
+            CommandRequest::Create {
+                room_id: room_with_custom_values._id.clone(),
+                ip: Some(room_with_custom_values.addr.to_string()),
+                password: None,
+                max_users: Some(10),
+                topic: Some("weekly sync".into()),
+                allow_duplicate_addr: false,
+                dry_run: false,
+            },
+            &mut db,
+        )
+        .unwrap();
+
+        assert_eq!(stored_room(&db, "someroom"), room_with_custom_values);
+
+        let room_with_default_values = Room {
+            _id: "anotheroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+
+        run_option(
+            CommandRequest::Create {
+                room_id: room_with_default_values._id.clone(),
+                ip: None,
+                password: None,
+                max_users: None,
+                topic: None,
+                allow_duplicate_addr: false,
+                dry_run: false,
+            },
+            &mut db,
+        )
+        .unwrap();
+
+        assert_eq!(stored_room(&db, "anotheroom"), room_with_default_values);
+    }
+
+    #[test]
+    fn room_password_is_read_non_interactively() {
+        let mut db = db_init(None).unwrap();
+        let verifies = |db: &DbRepo, room_id: &str, passwd: &str| {
+            let room = db.rooms.find_one(doc! {"_id": room_id}).unwrap().unwrap();
+            verify_passwd(passwd, &room.passwd.unwrap())
+        };
+
+        let passwd = new_room_passwd(PasswdSource::Stdin, &mut &b"from stdin\r\nrest"[..]).unwrap();
+        create_room(&mut db, "stdinroom", None, Some(passwd), None, None, false).unwrap();
+        assert!(verifies(&db, "stdinroom", "from stdin"));
+
+        let env_lock = ENV_LOCK.blocking_lock();
+        env::set_var(ROOM_PASSWD_VAR, "from env");
+        let passwd = new_room_passwd(PasswdSource::Prompt, &mut &b""[..]);
+        env::remove_var(ROOM_PASSWD_VAR);
+        drop(env_lock);
+        create_room(
+            &mut db,
+            "envroom",
+            Some("127.0.0.1:23456".into()),
+            Some(passwd.unwrap()),
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        assert!(verifies(&db, "envroom", "from env"));
+        assert!(!verifies(&db, "envroom", "from stdin"));
+
+        assert!(matches!(
+            new_room_passwd(PasswdSource::Stdin, &mut &b"\n"[..]),
+            Err(AppError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn lan_room_address() {
+        let mut db = db_init(None).unwrap();
+
+        match create_room(
+            &mut db,
+            "lanroom",
+            Some("lan".into()),
+            None,
+            None,
+            None,
+            false,
+        ) {
+            Ok(()) => {
+                let room = db.rooms.find_one(doc! {"_id": "lanroom"}).unwrap().unwrap();
+                assert!(!room.addr.ip().is_loopback());
+                assert_eq!(room.addr.port(), 12345);
+            }
+            Err(err) => assert!(matches!(err, AppError::LanAddrNotFound(_))),
+        }
+    }
+
+    #[test]
+    fn dry_run_creation_stores_nothing() {
+        let mut db = db_init(None).unwrap();
+        let create = |db: &mut DbRepo, ip: &str| {
+            run_option(
+                CommandRequest::Create {
+                    room_id: "someroom".into(),
+                    ip: Some(ip.into()),
+                    password: Some(PasswdSource::Stdin),
+                    max_users: Some(3),
+                    topic: None,
+                    allow_duplicate_addr: false,
+                    dry_run: true,
+                },
+                db,
+            )
+        };
+
+        create(&mut db, "127.0.0.1:4000").unwrap();
+        assert!(matches!(
+            create(&mut db, "127.0.0.1"),
+            Err(AppError::InvalidAddress(_))
+        ));
+        assert_eq!(db.rooms.count_documents().unwrap(), 0);
+
+        let room = new_room(
+            &db,
+            "someroom",
+            Some("127.0.0.1:4000".into()),
+            None,
+            Some(3),
+            None,
+            false,
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        describe_new_room(&room, true, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "OK, would create room someroom at 127.0.0.1:4000, password protected, for at most 3 users\n"
+        );
+    }
+
+    #[test]
+    fn room_deletion() {
+        let mut db = db_init(None).unwrap();
+
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("192.168.0.2:12345").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+
+        run_option(
+            CommandRequest::Create {
+                room_id: room._id.clone(),
+                ip: Some(room.addr.to_string()),
+                password: None,
+                max_users: None,
+                topic: None,
+                allow_duplicate_addr: false,
+                dry_run: false,
+            },
+            &mut db,
+        )
+        .unwrap();
+
+        assert_eq!(stored_room(&db, "someroom"), room);
+
+        run_option(
+            CommandRequest::Delete {
+                room_id: "someroom".into(),
+                force: true,
+                password: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+
+        assert_eq!(db.rooms.find_one(doc! {"_id": "someroom"}).unwrap(), None);
+    }
+
+    fn protected_room(db: &DbRepo) {
+        db.rooms
+            .insert_one(Room {
+                _id: "someroom".into(),
+                addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+                passwd: Some(hash_passwd("secret")),
+                banned_addrs: vec![],
+                is_owner: true,
+                max_users: None,
+                topic: None,
+                created_at: SystemTime::UNIX_EPOCH,
+                host: None,
+                last_joined: None,
+                filter_words: vec![],
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn declined_deletion_keeps_room() {
+        let mut db = db_init(None).unwrap();
+        protected_room(&db);
+
+        let mut out = Vec::new();
+        delete_room(&mut db, "someroom", false, None, &mut &b"n\n"[..], &mut out).unwrap();
+
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("Delete room someroom?"));
+        assert_eq!(db.rooms.count_documents().unwrap(), 1);
+    }
+
+    #[test]
+    fn forced_deletion_skips_confirmation() {
+        let mut db = db_init(None).unwrap();
+        protected_room(&db);
+        let mut delete = |passwd| {
+            let mut out = Vec::new();
+            let result = delete_room(&mut db, "someroom", true, passwd, &mut &b""[..], &mut out);
+            assert!(out.is_empty());
+            result
+        };
+
+        assert!(matches!(delete(None), Err(AppError::PasswordRequired)));
+        assert!(matches!(
+            delete(Some("wrong")),
+            Err(AppError::InvalidPassword)
+        ));
+        delete(Some("secret")).unwrap();
+
+        assert_eq!(db.rooms.count_documents().unwrap(), 0);
+    }
+
+    #[test]
+    fn room_history_clearing() {
+        let mut db = DbRepo::memory_init().unwrap();
+        let addr = SocketAddr::from_str("127.0.0.1:12345").unwrap();
+        let room_ids = ["someroom", "anotheroom"];
+
+        for room_id in room_ids {
+            db.rooms
+                .insert_one(Room {
+                    _id: room_id.into(),
+                    addr,
+                    passwd: None,
+                    banned_addrs: vec![],
+                    is_owner: true,
+                    max_users: None,
+                    topic: None,
+                    created_at: SystemTime::UNIX_EPOCH,
+                    host: None,
+                    last_joined: None,
+                    filter_words: vec![],
+                })
+                .unwrap();
+        }
+        for room_id in room_ids {
+            for content in ["first", "second"] {
+                db.messages
+                    .insert_one(TextMessage::new(&addr, room_id, content))
+                    .unwrap();
+            }
+        }
+
+        run_option(
+            CommandRequest::Clear {
+                room_id: "someroom".into(),
+                yes: true,
+            },
+            &mut db,
+        )
+        .unwrap();
+
+        assert!(db.room_messages("someroom", None).unwrap().is_empty());
+        assert_eq!(db.room_messages("anotheroom", None).unwrap().len(), 2);
+
+        assert!(matches!(
+            run_option(
+                CommandRequest::Clear {
+                    room_id: "nonexistent".into(),
+                    yes: true,
+                },
+                &mut db,
+            ),
+            Err(AppError::NotExistingId)
+        ));
+    }
+
+    #[tokio::test]
+    async fn hosting_requires_an_owned_room() {
+        let db = db_init(None).unwrap();
+        let mut room = room_with_secret();
+        room.is_owner = false;
+        db.rooms.insert_one(&room).unwrap();
+
+        assert!(matches!(
+            host_room(db, "someroom", true).await,
+            Err(AppError::NotOwner)
+        ));
+        assert!(matches!(
+            host_room(db_init(None).unwrap(), "someroom", true).await,
+            Err(AppError::NotExistingId)
+        ));
+    }
+
+    #[tokio::test]
+    async fn picking_a_room_needs_saved_ones() {
+        let db = db_init(None).unwrap();
+
+        assert!(matches!(
+            join_room(&db, None, vec![], None, None, None, true, false).await,
+            Err(AppError::NoAnyRoom)
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_address_defaults_port() {
+        assert_eq!(
+            parse_join_addr("192.168.0.2", 12345).await.unwrap(),
+            SocketAddr::from_str("192.168.0.2:12345").unwrap()
+        );
+        assert_eq!(
+            parse_join_addr("192.168.0.2:4000", 12345).await.unwrap(),
+            SocketAddr::from_str("192.168.0.2:4000").unwrap()
+        );
+        assert!(parse_join_addr("nonsense", 12345).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hostnames_are_resolved_on_join() {
+        assert_eq!(
+            IdOrAddr::parse("example.com:12345".into()),
+            IdOrAddr::Addr("example.com:12345".into())
+        );
+        assert_eq!(
+            IdOrAddr::parse("localhost:4000".into()),
+            IdOrAddr::Addr("localhost:4000".into())
+        );
+        for id in [
+            "myroom",
+            "room:4000",
+            "chat.room:notaport",
+            "chat.room:99999",
+            "my room.com:4000",
+            "chat..room:4000",
+            ":4000",
+        ] {
+            assert_eq!(IdOrAddr::parse(id.into()), IdOrAddr::Id(id.into()));
+        }
+
+        let db = db_init(None).unwrap();
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+
+        let room = room_to_join(&db, &local_data, IdOrAddr::Addr("localhost:4000".into()))
+            .await
+            .unwrap();
+        assert!(room.addr.ip().is_loopback());
+        assert_eq!(room.addr.port(), 4000);
+        assert_eq!(room.host.as_deref(), Some("localhost:4000"));
+
+        // a saved hostname is looked up again instead of using the stored address
+        let mut db = db;
+        create_room(
+            &mut db,
+            "hostroom",
+            Some("localhost:4001".into()),
+            None,
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        db.rooms
+            .update_one(
+                doc! {"_id": "hostroom"},
+                doc! {"$set": {"addr": "10.0.0.1:4001"}},
+            )
+            .unwrap();
+        let room = room_to_join(&db, &local_data, IdOrAddr::Id("hostroom".into()))
+            .await
+            .unwrap();
+        assert!(room.addr.ip().is_loopback());
+        assert_eq!(room.host.as_deref(), Some("localhost:4001"));
+
+        assert!(matches!(
+            room_to_join(
+                &db,
+                &local_data,
+                IdOrAddr::Addr("nonexistent.invalid:4000".into())
+            )
+            .await,
+            Err(AppError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn local_data_update() {
+        let mut db = db_init(None).unwrap();
+
+        let _local_data = LocalData {
+            default_user_id: "*".into(),
+            default_room_addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            default_color: Color::White,
+            remember_passwords: false,
+            light_mode: false,
+            timestamp_format: "%H:%M".into(),
+            bell: false,
+            bell_mentions_only: false,
+            msg_rate: DEFAULT_MSG_RATE,
+            msg_burst: DEFAULT_MSG_BURST,
+            max_msg_len: DEFAULT_MAX_MSG_LEN,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+            away_after: DEFAULT_AWAY_AFTER,
+            timezone: Timezone::Local,
+            ignored: vec![],
+            markdown: false,
+            input_border: None,
+            input_highlight: None,
+            input_placeholder: None,
+            retention_messages: 0,
+            retention_days: 0,
+            theme: None,
+            alt_screen: true,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            mention_prefix: DEFAULT_MENTION_PREFIX,
+            audit_log: false,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            layout_ratio: DEFAULT_LAYOUT_RATIO,
+        };
+
+        assert!(run_option(CommandRequest::Invalid, &mut db).is_err());
+
+        let local_data_from_db = db.local_data.find_one(None).unwrap().unwrap();
+
+        assert_eq!(
+            local_data_from_db.default_room_addr,
+            local_data_from_db.default_room_addr
+        );
+        assert_eq!(
+            local_data_from_db.default_color,
+            local_data_from_db.default_color
+        );
+        assert_eq!(
+            local_data_from_db.remember_passwords,
+            local_data_from_db.remember_passwords
+        );
+        assert_eq!(local_data_from_db.light_mode, local_data_from_db.light_mode);
+    }
+
+    #[test]
+    fn users_get_the_default_color_unless_one_is_given() {
+        let mut db = db_init(None).unwrap();
+        run_option(
+            CommandRequest::Set {
+                option: "color".into(),
+                value: "red".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+
+        let user = local_user(&local_data, None, None, false);
+        assert_eq!(user._id, local_data.default_user_id);
+        assert_eq!(user.color, Some(Color::Red));
+
+        let user = local_user(&local_data, Some("bob".into()), Some(Color::Blue), true);
+        assert_eq!(user._id, "bob");
+        assert_eq!(user.color, Some(Color::Blue));
+        assert!(user.spectator);
+    }
+
+    #[test]
+    fn color_option_setting() {
+        let mut db = db_init(None).unwrap();
+
+        run_option(
+            CommandRequest::Set {
+                option: "color".into(),
+                value: "rgb(10, 20, 30)".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+        assert_eq!(local_data.default_color, Color::Rgb(10, 20, 30));
+
+        assert!(matches!(
+            run_option(
+                CommandRequest::Set {
+                    option: "color".into(),
+                    value: "#12345".into(),
+                    room_id: None,
+                },
+                &mut db,
+            ),
+            Err(AppError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn default_addr_setting() {
+        let mut db = db_init(None).unwrap();
+        let set = |db: &mut DbRepo, option: &str, value: &str| {
+            run_option(
+                CommandRequest::Set {
+                    option: option.into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                db,
+            )
+        };
+
+        set(&mut db, "addr", "0.0.0.0:9000").unwrap();
+        create_room(&mut db, "someroom", None, None, None, None, false).unwrap();
+        assert_eq!(
+            stored_room(&db, "someroom").addr,
+            SocketAddr::from_str("0.0.0.0:9000").unwrap()
+        );
+
+        assert!(matches!(
+            set(&mut db, "addr", "0.0.0.0"),
+            Err(AppError::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            set(&mut db, "no_such_option", "value"),
+            Err(AppError::InvalidValue(_))
+        ));
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+        assert_eq!(
+            local_data.default_room_addr,
+            SocketAddr::from_str("0.0.0.0:9000").unwrap()
+        );
+    }
+
+    #[test]
+    fn bell_option_setting() {
+        let mut db = db_init(None).unwrap();
+
+        for (option, value) in [("bell", "on"), ("bell_mentions_only", "true")] {
+            run_option(
+                CommandRequest::Set {
+                    option: option.into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                &mut db,
+            )
+            .unwrap();
+        }
+
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+        assert!(local_data.bell);
+        assert!(local_data.bell_mentions_only);
+
+        assert!(matches!(
+            run_option(
+                CommandRequest::Set {
+                    option: "bell".into(),
+                    value: "loud".into(),
+                    room_id: None,
+                },
+                &mut db,
+            ),
+            Err(AppError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn alt_screen_setting() {
+        let mut db = db_init(None).unwrap();
+        assert!(db.local_data.find_one(None).unwrap().unwrap().alt_screen);
+
+        run_option(
+            CommandRequest::Set {
+                option: "alt_screen".into(),
+                value: "off".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert!(!db.local_data.find_one(None).unwrap().unwrap().alt_screen);
+    }
+
+    #[test]
+    fn rate_limit_option_setting() {
+        let mut db = db_init(None).unwrap();
+
+        run_option(
+            CommandRequest::Set {
+                option: "msg_rate".into(),
+                value: "2".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(db.local_data.find_one(None).unwrap().unwrap().msg_rate, 2);
+
+        run_option(
+            CommandRequest::Set {
+                option: "max_msg_len".into(),
+                value: "500".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().max_msg_len,
+            500
+        );
+
+        run_option(
+            CommandRequest::Set {
+                option: "ping_interval".into(),
+                value: "30".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().ping_interval,
+            30
+        );
+
+        run_option(
+            CommandRequest::Set {
+                option: "reconnect_attempts".into(),
+                value: "3".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(
+            db.local_data
+                .find_one(None)
+                .unwrap()
+                .unwrap()
+                .reconnect_attempts,
+            3
+        );
+
+        run_option(
+            CommandRequest::Set {
+                option: "layout_ratio".into(),
+                value: "70".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().layout_ratio,
+            70
+        );
+        for value in ["10", "100", "big"] {
+            assert!(run_option(
+                CommandRequest::Set {
+                    option: "layout_ratio".into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                &mut db,
+            )
+            .is_err());
+        }
+
+        // zero turns the away status off
+        run_option(
+            CommandRequest::Set {
+                option: "away_after".into(),
+                value: "0".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(db.local_data.find_one(None).unwrap().unwrap().away_after, 0);
+
+        for value in ["0", "-1", "fast"] {
+            assert!(matches!(
+                run_option(
+                    CommandRequest::Set {
+                        option: "msg_burst".into(),
+                        value: value.into(),
+                        room_id: None,
+                    },
+                    &mut db,
+                ),
+                Err(AppError::InvalidValue(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn old_databases_are_upgraded() {
+        let path = env::temp_dir().join(format!("kioto-test-{}", Uuid::new_v4()));
+        {
+            let db = Database::open_file(&path).unwrap();
+            db.collection::<Document>("rooms")
+                .insert_one(doc! {
+                    "_id": "oldroom",
+                    "addr": "127.0.0.1:12345",
+                    "passwd": null,
+                    "banned_addrs": [],
+                    "is_owner": true,
+                })
+                .unwrap();
+            db.collection::<Document>("local_data")
+                .insert_one(doc! {
+                    "default_user_id": "olduser",
+                    "default_room_addr": "127.0.0.1:12345",
+                    "default_color": "Red",
+                    "remember_passwords": false,
+                    "light_mode": true,
+                })
+                .unwrap();
+        }
+
+        let db = db_init(Some(&path)).unwrap();
+        assert_eq!(
+            db.meta
+                .find_one(doc! {"_id": "schema"})
+                .unwrap()
+                .unwrap()
+                .schema_version,
+            SCHEMA_VERSION
+        );
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+        assert_eq!(local_data.default_user_id, "olduser");
+        assert_eq!(local_data.msg_rate, DEFAULT_MSG_RATE);
+        drop(db);
+
+        let db = Database::open_file(&path).unwrap();
+        let room = db
+            .collection::<Document>("rooms")
+            .find_one(doc! {"_id": "oldroom"})
+            .unwrap()
+            .unwrap();
+        for field in ["max_users", "topic", "created_at", "host", "last_joined"] {
+            assert!(room.contains_key(field), "{} wasn't backfilled", field);
+        }
+        assert_eq!(room.get("topic"), Some(&Bson::Null));
+        let local_data = db
+            .collection::<Document>("local_data")
+            .find_one(None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            local_data.get_i64("max_msg_len").ok(),
+            Some(DEFAULT_MAX_MSG_LEN.into())
+        );
+        assert_eq!(local_data.get_str("timezone").ok(), Some("Local"));
+
+        drop(db);
+        _ = fs::remove_dir_all(&path).or_else(|_| fs::remove_file(&path));
+    }
+
+    #[test]
+    fn timezone_setting() {
+        let mut db = db_init(None).unwrap();
+        let set_timezone = |db: &mut DbRepo, value: &str| {
+            run_option(
+                CommandRequest::Set {
+                    option: "timezone".into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                db,
+            )
+        };
+
+        set_timezone(&mut db, "utc").unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().timezone,
+            Timezone::Utc
+        );
+
+        set_timezone(&mut db, "+02:00").unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().timezone,
+            Timezone::Fixed(2 * 3600)
+        );
+
+        assert!(matches!(
+            set_timezone(&mut db, "mars"),
+            Err(AppError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn retention_settings() {
+        let mut db = db_init(None).unwrap();
+        for (option, value) in [("retention_messages", "1000"), ("retention_days", "30")] {
+            run_option(
+                CommandRequest::Set {
+                    option: option.into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                &mut db,
+            )
+            .unwrap();
+        }
+
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+        assert_eq!(
+            Retention::from(&local_data),
+            Retention {
+                max_messages: Some(1000),
+                max_age: Some(Duration::from_secs(30 * 24 * 3600)),
+            }
+        );
+
+        assert!(matches!(
+            run_option(
+                CommandRequest::Set {
+                    option: "retention_days".into(),
+                    value: "-1".into(),
+                    room_id: None,
+                },
+                &mut db,
+            ),
+            Err(AppError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn input_color_settings() {
+        let mut db = db_init(None).unwrap();
+        let set_color = |db: &mut DbRepo, value: &str| {
+            run_option(
+                CommandRequest::Set {
+                    option: "input_border".into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                db,
+            )
+        };
+
+        set_color(&mut db, "magenta").unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().input_border,
+            Some(Color::Magenta)
+        );
+
+        set_color(&mut db, "").unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().input_border,
+            None
+        );
+
+        assert!(matches!(
+            set_color(&mut db, "plaid"),
+            Err(AppError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn mention_prefix_setting() {
+        let mut db = db_init(None).unwrap();
+        let set_prefix = |db: &mut DbRepo, value: &str| {
+            run_option(
+                CommandRequest::Set {
+                    option: "mention_prefix".into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                db,
+            )
+        };
+        assert_eq!(
+            db.local_data
+                .find_one(None)
+                .unwrap()
+                .unwrap()
+                .mention_prefix,
+            '@'
+        );
+
+        set_prefix(&mut db, "#").unwrap();
+        assert_eq!(
+            db.local_data
+                .find_one(None)
+                .unwrap()
+                .unwrap()
+                .mention_prefix,
+            '#'
+        );
+
+        for invalid in ["", "##", "a", "_"] {
+            assert!(matches!(
+                set_prefix(&mut db, invalid),
+                Err(AppError::InvalidValue(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn audit_log_setting() {
+        let mut db = db_init(None).unwrap();
+        let dir = env::temp_dir().join(format!("kioto-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let local_data = |db: &DbRepo| db.local_data.find_one(None).unwrap().unwrap();
+
+        // off by default, nothing is written then
+        assert!(audit_log(&local_data(&db), &dir).unwrap().is_none());
+        assert!(!dir.join("audit.log").exists());
+
+        run_option(
+            CommandRequest::Set {
+                option: "audit_log".into(),
+                value: "on".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert!(audit_log(&local_data(&db), &dir).unwrap().is_some());
+        assert!(dir.join("audit.log").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn theme_setting() {
+        let mut db = db_init(None).unwrap();
+        let set_theme = |db: &mut DbRepo, value: &str| {
+            run_option(
+                CommandRequest::Set {
+                    option: "theme".into(),
+                    value: value.into(),
+                    room_id: None,
+                },
+                db,
+            )
+        };
+        let current_theme = |db: &DbRepo| {
+            db.local_data
+                .find_one(None)
+                .unwrap()
+                .unwrap()
+                .current_theme()
+        };
+        assert_eq!(current_theme(&db), Theme::Dark);
+
+        set_theme(&mut db, "deuteranopia").unwrap();
+        assert_eq!(
+            db.local_data.find_one(None).unwrap().unwrap().theme,
+            Some(Theme::Deuteranopia)
+        );
+        set_theme(&mut db, "high-contrast").unwrap();
+        assert_eq!(current_theme(&db), Theme::HighContrast);
+
+        assert!(matches!(
+            set_theme(&mut db, "solarized"),
+            Err(AppError::InvalidValue(_))
+        ));
+        assert_eq!(current_theme(&db), Theme::HighContrast);
+    }
+
+    #[test]
+    fn room_topic_setting() {
+        let mut db = db_init(None).unwrap();
+        db.rooms.insert_one(room_with_secret()).unwrap();
+
+        let set_topic = |db: &mut DbRepo, option: &str, value: &str, room_id: Option<&str>| {
+            run_option(
+                CommandRequest::Set {
+                    option: option.into(),
+                    value: value.into(),
+                    room_id: room_id.map(String::from),
+                },
+                db,
+            )
+        };
+        let topic = |db: &DbRepo| {
+            db.rooms
+                .find_one(doc! {"_id": "someroom"})
+                .unwrap()
+                .unwrap()
+                .topic
+        };
+
+        set_topic(&mut db, "topic", "release planning", Some("someroom")).unwrap();
+        assert_eq!(topic(&db), Some("release planning".into()));
+
+        set_topic(&mut db, "topic", "", Some("someroom")).unwrap();
+        assert_eq!(topic(&db), None);
+
+        assert!(matches!(
+            set_topic(&mut db, "topic", "x", Some("otherroom")),
+            Err(AppError::NotExistingId)
+        ));
+        assert!(matches!(
+            set_topic(&mut db, "topic", "x", None),
+            Err(AppError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            set_topic(&mut db, "addr", "x", Some("someroom")),
+            Err(AppError::InvalidValue(_))
+        ));
+
+        let filter_words = |db: &DbRepo| {
+            db.rooms
+                .find_one(doc! {"_id": "someroom"})
+                .unwrap()
+                .unwrap()
+                .filter_words
+        };
+        set_topic(&mut db, "filter_words", "heck, darn it,", Some("someroom")).unwrap();
+        assert_eq!(filter_words(&db), ["heck", "darn it"]);
+        set_topic(&mut db, "filter_words", "", Some("someroom")).unwrap();
+        assert!(filter_words(&db).is_empty());
+        assert!(matches!(
+            set_topic(&mut db, "filter_words", "heck", None),
+            Err(AppError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn verbosity_sets_log_level() {
+        let level =
+            |args: &[&str]| log_level_from(&build_cli().try_get_matches_from(args).unwrap());
+
+        assert_eq!(level(&["kioto", "list"]), LevelFilter::Error);
+        assert_eq!(level(&["kioto", "-v", "list"]), LevelFilter::Info);
+        assert_eq!(level(&["kioto", "list", "-vv"]), LevelFilter::Debug);
+        assert_eq!(
+            level(&["kioto", "--verbose", "-vvv", "list"]),
+            LevelFilter::Trace
+        );
+    }
+
+    #[test]
+    fn only_the_tui_commands_keep_logs_off_the_terminal() {
+        let is_tui = |args: &[&str]| {
+            command_request_from(&build_cli().try_get_matches_from(args).unwrap()).is_tui()
+        };
+
+        assert!(is_tui(&["kioto", "-v", "join", "someroom"]));
+        assert!(is_tui(&["kioto", "-v", "host", "someroom"]));
+        assert!(!is_tui(&["kioto", "-v", "list"]));
+        assert!(!is_tui(&["kioto", "-v", "create", "someroom"]));
+    }
+
+    #[test]
+    fn color_can_be_chosen() {
+        let choice = |args: &[&str]| {
+            build_cli()
+                .try_get_matches_from(args)
+                .unwrap()
+                .get_one::<String>("color_output")
+                .cloned()
+                .unwrap()
+        };
+
+        assert_eq!(choice(&["kioto", "list"]), "auto");
+        assert_eq!(choice(&["kioto", "list", "--color", "never"]), "never");
+        assert_eq!(choice(&["kioto", "--color", "always", "list"]), "always");
+        // apart from the user color of join
+        assert_eq!(
+            choice(&["kioto", "join", "room", "bob", "red", "--color", "never"]),
+            "never"
+        );
+        assert!(build_cli()
+            .try_get_matches_from(["kioto", "--color", "sometimes", "list"])
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn data_dir_can_be_overridden() {
+        let dir = env::temp_dir()
+            .join(format!("kioto-test-{}", Uuid::new_v4()))
+            .join("kioto");
+        let _env = ENV_LOCK.lock().await;
+        env::set_var(DATA_DIR_VAR, &dir);
+
+        run(
+            CommandRequest::WhoAmI { json: false },
+            LevelFilter::Error,
+            false,
+        )
+        .await
+        .unwrap();
+        env::remove_var(DATA_DIR_VAR);
+
+        assert!(dir.join("errors.log").is_file());
+        assert!(dir.join("kioto.db").exists());
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unwritable_data_dir_is_reported() {
+        // a file in the way fails even for root, which permissions don't stop
+        let base = env::temp_dir().join(format!("kioto-test-{}", Uuid::new_v4()));
+        fs::write(&base, "").unwrap();
+        let dir = base.join("kioto");
+        let _env = ENV_LOCK.lock().await;
+        env::set_var(DATA_DIR_VAR, &dir);
+
+        let result = run(
+            CommandRequest::WhoAmI { json: false },
+            LevelFilter::Error,
+            true,
+        )
+        .await;
+        env::remove_var(DATA_DIR_VAR);
+
+        assert!(matches!(
+            result,
+            Err(AppError::DataDirNotWritable(path, _)) if path == dir.display().to_string()
+        ));
+        fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn backup_round_trip() {
+        let mut db = db_init(None).unwrap();
+        let mut rooms = vec![room_with_secret()];
+        for (i, room_id) in ["firstroom", "secondroom"].into_iter().enumerate() {
+            rooms.push(Room {
+                _id: room_id.into(),
+                addr: SocketAddr::from_str(&format!("10.0.0.{}:12345", i + 1)).unwrap(),
+                passwd: None,
+                banned_addrs: vec![],
+                is_owner: false,
+                max_users: None,
+                topic: None,
+                created_at: SystemTime::UNIX_EPOCH,
+                host: None,
+                last_joined: None,
+                filter_words: vec![],
+            });
+        }
+        for room in rooms.iter() {
+            db.rooms.insert_one(room).unwrap();
+        }
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+
+        let path = env::temp_dir().join(format!("kioto-test-{}.toml", Uuid::new_v4()));
+        run_option(CommandRequest::Backup { path: path.clone() }, &mut db).unwrap();
+
+        let mut db = DbRepo::memory_init().unwrap();
+        let restore = |db: &mut DbRepo, overwrite: bool| {
+            run_option(
+                CommandRequest::Restore {
+                    path: path.clone(),
+                    overwrite,
+                },
+                db,
+            )
+        };
+        restore(&mut db, false).unwrap();
+
+        let mut restored = db
+            .rooms
+            .find(None)
+            .unwrap()
+            .collect::<Result<Vec<Room>, _>>()
+            .unwrap();
+        restored.sort_by(|a, b| a._id.cmp(&b._id));
+        rooms.sort_by(|a, b| a._id.cmp(&b._id));
+        assert_eq!(restored, rooms);
+        assert_eq!(db.local_data.find_one(None).unwrap().unwrap(), local_data);
+
+        assert!(matches!(
+            restore(&mut db, false),
+            Err(AppError::AlreadyExistingId)
+        ));
+        restore(&mut db, true).unwrap();
+        assert_eq!(db.rooms.count_documents().unwrap(), 3);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn room_joining() {}
+
+    fn room_with_secret() -> Room {
+        Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("192.168.0.2:12345").unwrap(),
+            passwd: Some("somehash".into()),
+            banned_addrs: vec![SocketAddr::from_str("10.0.0.1:4000").unwrap()],
+            is_owner: true,
+            max_users: Some(5),
+            topic: Some("some topic".into()),
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        }
+    }
+
+    #[test]
+    fn room_export_round_trip() {
+        let room = room_with_secret();
+
+        let exported = toml::from_str::<RoomExport>(&room_to_toml(&room, false).unwrap()).unwrap();
+        assert_eq!(exported, RoomExport::new(&room, false));
+        assert_eq!(exported.id, room._id);
+        assert_eq!(exported.addr, room.addr);
+        assert_eq!(exported.banned_addrs, room.banned_addrs);
+        assert!(exported.has_password);
+        assert_eq!(exported.passwd, None);
+
+        let exported = toml::from_str::<RoomExport>(&room_to_toml(&room, true).unwrap()).unwrap();
+        assert_eq!(exported.passwd, room.passwd);
+    }
+
+    #[test]
+    fn room_export_to_file() {
+        let mut db = db_init(None).unwrap();
+        let room = room_with_secret();
+        db.rooms.insert_one(&room).unwrap();
+
+        let path = env::temp_dir().join(format!("kioto-test-{}.toml", Uuid::new_v4()));
+
+        run_option(
+            CommandRequest::Export {
+                room_id: room._id.clone(),
+                path: path.clone(),
+                with_secret: false,
+            },
+            &mut db,
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            toml::from_str::<RoomExport>(&content).unwrap(),
+            RoomExport::new(&room, false)
+        );
+        assert!(!content.contains("somehash"));
+
+        assert!(matches!(
+            run_option(
+                CommandRequest::Export {
+                    room_id: "anotheroom".into(),
+                    path,
+                    with_secret: false,
+                },
+                &mut db,
+            ),
+            Err(AppError::NotExistingId)
+        ));
+    }
+
+    fn write_temp_toml(content: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("kioto-test-{}.toml", Uuid::new_v4()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn held_database_is_reported_or_waited_for() {
+        let path = env::temp_dir().join(format!("kioto-test-{}", Uuid::new_v4()));
+        let holder = db_init(Some(&path)).unwrap();
+
+        assert!(matches!(
+            db_init(Some(&path)).map_err(AppError::from),
+            Err(AppError::DatabaseLocked)
+        ));
+
+        // released while the next one is still retrying
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(150));
+            drop(holder);
+        });
+        let db = db_init(Some(&path)).unwrap();
+        release.join().unwrap();
+        assert!(db.local_data.find_one(None).unwrap().is_some());
+
+        drop(db);
+        _ = fs::remove_dir_all(&path).or_else(|_| fs::remove_file(&path));
+    }
+
+    #[test]
+    fn corrupt_rooms_are_skipped_when_listing() {
+        let path = env::temp_dir().join(format!("kioto-test-{}", Uuid::new_v4()));
+        {
+            let db = Database::open_file(&path).unwrap();
+            let rooms = db.collection::<Document>("rooms");
+            rooms
+                .insert_one(doc! {"_id": "broken", "addr": 42, "is_owner": "yes"})
+                .unwrap();
+            rooms
+                .insert_one(doc! {
+                    "_id": "fine",
+                    "addr": "127.0.0.1:12345",
+                    "passwd": null,
+                    "banned_addrs": [],
+                    "is_owner": true,
+                })
+                .unwrap();
+        }
+
+        let db = db_init(Some(&path)).unwrap();
+        let mut out = Vec::new();
+        list_rooms_and_local_data(&db, None, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("fine: 127.0.0.1:12345"));
+        assert!(!out.contains("broken"));
+
+        drop(db);
+        _ = fs::remove_dir_all(&path).or_else(|_| fs::remove_file(&path));
+    }
+
+    #[test]
+    fn rooms_are_sorted_by_time() {
+        let db = db_init(None).unwrap();
+        let room = |id: &str, secs| Room {
+            _id: id.into(),
+            addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
             is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
         };
+        db.rooms.insert_one(room("older", 60)).unwrap();
+        db.rooms.insert_one(room("newer", 120)).unwrap();
 
-        run_option(
-            CommandRequest::Create {
-                room_id: room_with_default_values._id.clone(),
-                ip: None,
-                password: false,
+        mark_joined(&db, "older").unwrap();
+        let older = db.rooms.find_one(doc! {"_id": "older"}).unwrap().unwrap();
+        assert!(older.last_joined.unwrap().elapsed().unwrap() < Duration::from_secs(60));
+
+        let listed = |sort| {
+            let mut out = Vec::new();
+            list_rooms_and_local_data(&db, Some(sort), &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.lines()
+                .filter(|line| line.contains("(created"))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        let by_created = listed(RoomSort::Created);
+        assert!(by_created[0].starts_with("newer:"));
+        assert!(by_created[0].ends_with("last joined never)"));
+        assert!(by_created[1].starts_with("older:"));
+
+        let by_recent = listed(RoomSort::Recent);
+        assert!(by_recent[0].starts_with("older:"));
+        assert!(by_recent[1].starts_with("newer:"));
+    }
+
+    #[test]
+    fn duplicate_room_addresses_are_rejected() {
+        let mut db = db_init(None).unwrap();
+        let create = |db: &mut DbRepo, room_id: &str, ip: &str, allow_duplicate_addr| {
+            create_room(
+                db,
+                room_id,
+                Some(ip.into()),
+                None,
+                None,
+                None,
+                allow_duplicate_addr,
+            )
+        };
+
+        create(&mut db, "first", "127.0.0.1:4000", false).unwrap();
+        create(&mut db, "second", "127.0.0.1:4001", false).unwrap();
+        assert!(matches!(
+            create(&mut db, "third", "127.0.0.1:4000", false),
+            Err(AppError::AddressInUse(room_id)) if room_id == "first"
+        ));
+        create(&mut db, "third", "127.0.0.1:4000", true).unwrap();
+
+        let mut room = room_with_secret();
+        room.addr = SocketAddr::from_str("127.0.0.1:4001").unwrap();
+        let path = write_temp_toml(&room_to_toml(&room, true).unwrap());
+        let import = |allow_duplicate_addr| CommandRequest::Import {
+            path: path.clone(),
+            overwrite: false,
+            allow_duplicate_addr,
+        };
+
+        assert!(matches!(
+            run_option(import(false), &mut db),
+            Err(AppError::AddressInUse(_))
+        ));
+        run_option(import(true), &mut db).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(db.rooms.count_documents().unwrap(), 4);
+    }
+
+    #[test]
+    fn room_import() {
+        let mut db = db_init(None).unwrap();
+        let room = room_with_secret();
+        let path = write_temp_toml(&room_to_toml(&room, true).unwrap());
+
+        let import = |overwrite| CommandRequest::Import {
+            path: path.clone(),
+            overwrite,
+            allow_duplicate_addr: false,
+        };
+
+        run_option(import(false), &mut db).unwrap();
+        assert_eq!(stored_room(&db, "someroom"), room);
+
+        assert!(matches!(
+            run_option(import(false), &mut db),
+            Err(AppError::AlreadyExistingId)
+        ));
+
+        run_option(import(true), &mut db).unwrap();
+        assert_eq!(db.rooms.count_documents().unwrap(), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn room_import_rejects_malformed_values() {
+        let malformed_addr = "id = \"someroom\"\naddr = \"192.168.0.2\"\nbanned_addrs = []\nis_owner = true\nhas_password = false\n";
+        assert!(matches!(
+            room_from_toml(malformed_addr),
+            Err(AppError::InvalidValue(_))
+        ));
+
+        let malformed_ban = "id = \"someroom\"\naddr = \"192.168.0.2:12345\"\nbanned_addrs = [\"nonsense\"]\nis_owner = true\nhas_password = false\n";
+        assert!(matches!(
+            room_from_toml(malformed_ban),
+            Err(AppError::InvalidValue(_))
+        ));
+
+        let missing_secret = room_to_toml(&room_with_secret(), false).unwrap();
+        assert!(matches!(
+            room_from_toml(&missing_secret),
+            Err(AppError::InvalidValue(_))
+        ));
+
+        let mut db = db_init(None).unwrap();
+        let path = write_temp_toml(malformed_addr);
+        assert!(run_option(
+            CommandRequest::Import {
+                path: path.clone(),
+                overwrite: false,
+                allow_duplicate_addr: false,
             },
-            &mut db,
+            &mut db
         )
-        .unwrap();
+        .is_err());
+        assert_eq!(db.rooms.count_documents().unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn join_target_classification() {
+        for addr in [
+            "127.0.0.1:12345",
+            "192.168.0.2",
+            "[::1]:12345",
+            "::1",
+            "[fe80::1]:80",
+        ] {
+            assert_eq!(IdOrAddr::parse(addr.into()), IdOrAddr::Addr(addr.into()));
+        }
 
+        for id in ["someroom", "room:1", "[::1]"] {
+            assert_eq!(IdOrAddr::parse(id.into()), IdOrAddr::Id(id.into()));
+        }
+    }
+
+    #[test]
+    fn room_address_validation() {
         assert_eq!(
-            db.rooms
-                .find_one(doc! {"id": "anotheroom"})
-                .unwrap()
-                .unwrap(),
-            room_with_default_values
+            parse_room_addr("192.168.0.2:12345").unwrap(),
+            SocketAddr::from_str("192.168.0.2:12345").unwrap()
         );
+        assert_eq!(parse_room_addr("localhost:12345").unwrap().port(), 12345);
+
+        for addr in ["192.168.0.2", "localhost", "192.168.0.2:", "nonsense:port"] {
+            assert!(matches!(
+                parse_room_addr(addr),
+                Err(AppError::InvalidAddress(invalid)) if invalid == addr
+            ));
+        }
+
+        let mut db = db_init(None).unwrap();
+        assert!(matches!(
+            run_option(
+                CommandRequest::Create {
+                    room_id: "someroom".into(),
+                    ip: Some("192.168.0.2".into()),
+                    password: None,
+                    max_users: None,
+                    topic: None,
+                    allow_duplicate_addr: false,
+                    dry_run: false,
+                },
+                &mut db,
+            ),
+            Err(AppError::InvalidAddress(_))
+        ));
+        assert_eq!(db.rooms.count_documents().unwrap(), 0);
     }
 
     #[test]
-    fn room_deletion() {
+    fn passwords_are_remembered_only_when_enabled() {
         let mut db = db_init(None).unwrap();
+        let room = room_with_secret();
 
-        let room = Room {
-            _id: "someroom".into(),
-            addr: SocketAddr::from_str("192.168.0.2:12345").unwrap(),
-            passwd: None,
-            banned_addrs: vec![],
-            is_owner: true,
-        };
+        remember_passwd(&db, &room).unwrap();
+        assert_eq!(db.passwords.count_documents().unwrap(), 0);
+        assert!(!is_passwd_remembered(&db, &room).unwrap());
+
+        let user_id = db
+            .local_data
+            .find_one(None)
+            .unwrap()
+            .unwrap()
+            .default_user_id;
+        db.local_data
+            .update_one(
+                doc! {"default_user_id": user_id},
+                doc! {"$set": {"remember_passwords": true}},
+            )
+            .unwrap();
+
+        remember_passwd(&db, &room).unwrap();
+        remember_passwd(&db, &room).unwrap();
+        assert_eq!(db.passwords.count_documents().unwrap(), 1);
+        assert!(is_passwd_remembered(&db, &room).unwrap());
+        verify_room_passwd(&db, &room).unwrap();
+
+        let mut changed_room = room.clone();
+        changed_room.passwd = Some("anotherhash".into());
+        assert!(!is_passwd_remembered(&db, &changed_room).unwrap());
 
         run_option(
-            CommandRequest::Create {
+            CommandRequest::Set {
+                option: "remember_passwords".into(),
+                value: "off".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert!(!is_passwd_remembered(&db, &room).unwrap());
+        run_option(
+            CommandRequest::Set {
+                option: "remember_passwords".into(),
+                value: "on".into(),
+                room_id: None,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert!(is_passwd_remembered(&db, &room).unwrap());
+
+        run_option(
+            CommandRequest::Forget {
                 room_id: room._id.clone(),
-                ip: Some(room.addr.ip().to_string()),
-                password: false,
             },
             &mut db,
         )
         .unwrap();
+        assert_eq!(db.passwords.count_documents().unwrap(), 0);
+        assert!(!is_passwd_remembered(&db, &room).unwrap());
+    }
+
+    #[test]
+    fn room_passwords_are_changed_after_verifying_the_current_one() {
+        let mut db = db_init(None).unwrap();
+        protected_room(&db);
+        let stored_hash = |db: &DbRepo| {
+            let room = db.rooms.find_one(doc! {"_id": "someroom"}).unwrap();
+            room.unwrap().passwd
+        };
+
+        assert!(matches!(
+            change_passwd(
+                &mut db,
+                "someroom",
+                Some("wrong"),
+                Some(PasswdSource::Stdin),
+                &mut &b"fresh\n"[..],
+            ),
+            Err(AppError::InvalidPassword)
+        ));
+        assert!(verify_passwd("secret", &stored_hash(&db).unwrap()));
+
+        change_passwd(
+            &mut db,
+            "someroom",
+            Some("secret"),
+            Some(PasswdSource::Stdin),
+            &mut &b"fresh\n"[..],
+        )
+        .unwrap();
+        let hash = stored_hash(&db).unwrap();
+        assert!(verify_passwd("fresh", &hash));
+        assert!(!verify_passwd("secret", &hash));
+
+        // the old one no longer opens the room
+        assert!(matches!(
+            change_passwd(&mut db, "someroom", Some("secret"), None, &mut &b""[..]),
+            Err(AppError::InvalidPassword)
+        ));
+        change_passwd(&mut db, "someroom", Some("fresh"), None, &mut &b""[..]).unwrap();
+        assert_eq!(stored_hash(&db), None);
+    }
+
+    #[test]
+    fn changed_passwords_are_forgotten() {
+        let mut db = db_init(None).unwrap();
+        let user_id = db
+            .local_data
+            .find_one(None)
+            .unwrap()
+            .unwrap()
+            .default_user_id;
+        db.local_data
+            .update_one(
+                doc! {"default_user_id": user_id},
+                doc! {"$set": {"remember_passwords": true}},
+            )
+            .unwrap();
+        protected_room(&db);
+        let room = db
+            .rooms
+            .find_one(doc! {"_id": "someroom"})
+            .unwrap()
+            .unwrap();
+        remember_passwd(&db, &room).unwrap();
+
+        // remembered, so not asked for
+        change_passwd(
+            &mut db,
+            "someroom",
+            None,
+            Some(PasswdSource::Stdin),
+            &mut &b"fresh\n"[..],
+        )
+        .unwrap();
+        let room = db
+            .rooms
+            .find_one(doc! {"_id": "someroom"})
+            .unwrap()
+            .unwrap();
+        assert!(verify_passwd("fresh", &room.passwd.unwrap()));
+        assert_eq!(db.passwords.count_documents().unwrap(), 0);
+
+        db.rooms
+            .update_one(doc! {"_id": "someroom"}, doc! {"$set": {"is_owner": false}})
+            .unwrap();
+        assert!(matches!(
+            change_passwd(&mut db, "someroom", Some("fresh"), None, &mut &b""[..]),
+            Err(AppError::NotOwner)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rooms_are_joined_after_changing_the_password() {
+        let mut db = db_init(None).unwrap();
+        protected_room(&db);
+        let room = db
+            .rooms
+            .find_one(doc! {"_id": "someroom"})
+            .unwrap()
+            .unwrap();
+        // sealed with the old password, so no longer readable
+        let salt = passwd_salt(room.passwd.as_deref().unwrap()).unwrap();
+        let mut sealed = TextMessage::new(&room.addr, "someroom", "");
+        sealed.set_content(encrypt(&derive_key("secret", &salt).unwrap(), "old"));
+        db.messages.insert_one(&sealed).unwrap();
+
+        change_passwd(
+            &mut db,
+            "someroom",
+            Some("secret"),
+            Some(PasswdSource::Stdin),
+            &mut &b"fresh\n"[..],
+        )
+        .unwrap();
+        assert_eq!(db.messages.count_documents().unwrap(), 0);
+
+        let room = Room {
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            ..db.rooms
+                .find_one(doc! {"_id": "someroom"})
+                .unwrap()
+                .unwrap()
+        };
+        let mut server = ChatServer::new(room.clone(), Arc::new(Mutex::new(db)))
+            .await
+            .unwrap();
+        server.run().await.unwrap();
+        let mut client = ChatClient::new(
+            Room {
+                addr: server.local_addr().unwrap(),
+                passwd: None,
+                ..room
+            },
+            User {
+                _id: "someuser".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        client.passwd = Some("fresh".into());
+        client.connect().await.unwrap();
+        client.sync().await.unwrap();
+
+        let messages = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match client.recv_msg().await {
+                    Some(MessageType::Server(ServerMsg::Sync { messages, .. })) => return messages,
+                    Some(MessageType::Server(ServerMsg::AuthFailure)) => panic!("not admitted"),
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(messages.is_empty());
+        assert!(!client.is_disconnected());
+
+        server.stop();
+        client.close_connection();
+    }
+    #[test]
+    fn passwd_flags_pick_the_new_password() {
+        let parse =
+            |args: &[&str]| command_request_from(&build_cli().try_get_matches_from(args).unwrap());
 
         assert_eq!(
-            db.rooms.find_one(doc! {"id": "someroom"}).unwrap().unwrap(),
-            room
+            parse(&[
+                "kioto",
+                "passwd",
+                "someroom",
+                "--password",
+                "secret",
+                "--clear"
+            ]),
+            CommandRequest::Passwd {
+                room_id: "someroom".into(),
+                new_password: None,
+                password: Some("secret".into()),
+            }
+        );
+        assert_eq!(
+            parse(&[
+                "kioto",
+                "passwd",
+                "someroom",
+                "--password",
+                "secret",
+                "--new-password-stdin"
+            ]),
+            CommandRequest::Passwd {
+                room_id: "someroom".into(),
+                new_password: Some(PasswdSource::Stdin),
+                password: Some("secret".into()),
+            }
+        );
+        assert!(build_cli()
+            .try_get_matches_from([
+                "kioto",
+                "passwd",
+                "someroom",
+                "--clear",
+                "--new-password-stdin"
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn whoami_prints_local_data() {
+        let db = db_init(None).unwrap();
+        let local_data = db.local_data.find_one(None).unwrap().unwrap();
+
+        let mut out = Vec::new();
+        whoami(&db, false, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&local_data.default_user_id));
+        assert!(out.contains("127.0.0.1:12345"));
+        assert!(out.contains("white"));
+
+        let mut out = Vec::new();
+        whoami(&db, true, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["username"], local_data.default_user_id);
+        assert_eq!(json["addr"], "127.0.0.1:12345");
+        assert_eq!(json["color"], "white");
+
+        assert!(matches!(
+            whoami(&DbRepo::memory_init().unwrap(), false, &mut Vec::new()),
+            Err(AppError::DataNotFound)
+        ));
+    }
+
+    #[test]
+    fn bans_are_listed_and_lifted() {
+        let mut db = db_init(None).unwrap();
+        let banned_addrs = ["10.0.0.1:4000", "10.0.0.2:4000", "10.0.0.3:5000"]
+            .map(|addr| SocketAddr::from_str(addr).unwrap())
+            .to_vec();
+        db.rooms
+            .insert_one(Room {
+                banned_addrs: banned_addrs.clone(),
+                passwd: None,
+                ..room_with_secret()
+            })
+            .unwrap();
+
+        let mut out = Vec::new();
+        list_bans(&db, "someroom", false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "10.0.0.1:4000\n10.0.0.2:4000\n10.0.0.3:5000\n"
+        );
+
+        // bans are matched by ip, so the port doesn't matter
+        unban(&mut db, "someroom", Some("10.0.0.2:1234")).unwrap();
+        let mut out = Vec::new();
+        list_bans(&db, "someroom", true, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json, serde_json::json!(["10.0.0.1:4000", "10.0.0.3:5000"]));
+
+        assert!(matches!(
+            unban(&mut db, "someroom", Some("nonsense")),
+            Err(AppError::InvalidAddress(_))
+        ));
+
+        unban(&mut db, "someroom", None).unwrap();
+        let mut out = Vec::new();
+        list_bans(&db, "someroom", true, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[]\n");
+        assert!(db
+            .rooms
+            .find_one(doc! {"_id": "someroom"})
+            .unwrap()
+            .unwrap()
+            .banned_addrs
+            .is_empty());
+    }
+
+    #[test]
+    fn stats_count_the_stored_data() {
+        let db = db_init(None).unwrap();
+        for (id, is_owner, passwd) in [
+            ("hosted", true, Some("somehash".to_string())),
+            ("joined", false, None),
+            ("locked", false, Some("somehash".to_string())),
+        ] {
+            db.rooms
+                .insert_one(Room {
+                    _id: id.into(),
+                    is_owner,
+                    passwd,
+                    ..room_with_secret()
+                })
+                .unwrap();
+        }
+        let sender = SocketAddr::from_str("127.0.0.1:4000").unwrap();
+        for room_id in ["hosted", "hosted", "locked"] {
+            db.messages
+                .insert_one(TextMessage::new(&sender, room_id, "hi"))
+                .unwrap();
+        }
+
+        let mut out = Vec::new();
+        stats(&db, true, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "rooms": 3,
+                "owned": 1,
+                "joined": 2,
+                "password_protected": 2,
+                "messages": {"hosted": 2, "joined": 0, "locked": 1},
+            })
+        );
+
+        let mut out = Vec::new();
+        stats(&db, false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "rooms: 3\nowned: 1\njoined: 2\npassword protected: 2\nmessages:\n  hosted: 2\n  joined: 0\n  locked: 1\n"
         );
 
+        assert_eq!(
+            command_request_from(
+                &build_cli()
+                    .try_get_matches_from(["kioto", "stats", "--json"])
+                    .unwrap()
+            ),
+            CommandRequest::Stats { json: true }
+        );
+    }
+
+    #[test]
+    fn info_shows_every_room_detail() {
+        let mut db = db_init(None).unwrap();
         run_option(
-            CommandRequest::Delete {
-                room_id: "someroom".into(),
+            CommandRequest::Set {
+                option: "timezone".into(),
+                value: "utc".into(),
+                room_id: None,
             },
             &mut db,
         )
         .unwrap();
+        db.rooms
+            .insert_one(Room {
+                filter_words: vec!["heck".into()],
+                last_joined: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(60)),
+                ..room_with_secret()
+            })
+            .unwrap();
+
+        // read before storing messages, polodb can lose the local data on later inserts
+        let mut out = Vec::new();
+        room_info(&db, "someroom", false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id: someroom\naddr: 192.168.0.2:12345\nhost: none\nowner: true\n\
+             password protected: true\nbans: 1\nmax users: 5\ntopic: some topic\n\
+             filtered words: heck\ncreated: 1970-01-01 00:00\nlast joined: 1970-01-01 00:01\n\
+             messages: 0\n"
+        );
+
+        let sender = SocketAddr::from_str("127.0.0.1:4000").unwrap();
+        for content in ["hi", "bye"] {
+            db.messages
+                .insert_one(TextMessage::new(&sender, "someroom", content))
+                .unwrap();
+        }
+
+        let mut out = Vec::new();
+        room_info(&db, "someroom", true, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "id": "someroom",
+                "addr": "192.168.0.2:12345",
+                "host": null,
+                "owner": true,
+                "password_protected": true,
+                "bans": 1,
+                "max_users": 5,
+                "topic": "some topic",
+                "filter_words": ["heck"],
+                "created_at": "1970-01-01T00:00:00Z",
+                "last_joined": "1970-01-01T00:01:00Z",
+                "messages": 2,
+            })
+        );
 
-        assert_eq!(db.rooms.find_one(doc! {"id": "someroom"}).unwrap(), None);
+        assert!(matches!(
+            room_info(&db, "nonexisting", false, &mut Vec::new()),
+            Err(AppError::NotExistingId)
+        ));
+        assert_eq!(
+            command_request_from(
+                &build_cli()
+                    .try_get_matches_from(["kioto", "info", "someroom", "--json"])
+                    .unwrap()
+            ),
+            CommandRequest::Info {
+                room_id: "someroom".into(),
+                json: true,
+            }
+        );
     }
 
     #[test]
-    fn local_data_update() {
+    fn only_owners_can_unban() {
         let mut db = db_init(None).unwrap();
+        db.rooms
+            .insert_one(Room {
+                is_owner: false,
+                ..room_with_secret()
+            })
+            .unwrap();
 
-        let _local_data = LocalData {
-            default_user_id: "*".into(),
-            default_room_addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
-            default_color: Color::White,
-            remember_passwords: false,
-            light_mode: false,
-        };
+        assert!(matches!(
+            unban(&mut db, "someroom", None),
+            Err(AppError::NotOwner)
+        ));
+        assert!(matches!(
+            unban(&mut db, "otherroom", None),
+            Err(AppError::NotExistingId)
+        ));
+    }
 
-        assert!(run_option(CommandRequest::Invalid, &mut db).is_err());
+    #[test]
+    fn bash_completions_cover_subcommands() {
+        let mut out = Vec::new();
+        print_completions(Shell::Bash, &mut out);
+        let script = String::from_utf8(out).unwrap();
 
-        let local_data_from_db = db.local_data.find_one(None).unwrap().unwrap();
+        for subcommand in ["create", "join", "delete", "config-path"] {
+            assert!(script.contains(subcommand));
+        }
+    }
 
+    #[test]
+    fn cli_arguments_are_parsed() {
+        let matches = build_cli()
+            .try_get_matches_from([
+                "kioto",
+                "create",
+                "myroom",
+                "-p",
+                "--max-users",
+                "2",
+                "--topic",
+                "standup",
+            ])
+            .unwrap();
+        let (_, create_matches) = matches.subcommand().unwrap();
+        assert!(create_matches.get_flag("password"));
         assert_eq!(
-            local_data_from_db.default_room_addr,
-            local_data_from_db.default_room_addr
+            command_request_from(&matches),
+            CommandRequest::Create {
+                room_id: "myroom".into(),
+                ip: None,
+                password: Some(PasswdSource::Prompt),
+                max_users: Some(2),
+                topic: Some("standup".into()),
+                allow_duplicate_addr: false,
+                dry_run: false,
+            }
         );
+
+        let matches = build_cli()
+            .try_get_matches_from([
+                "kioto",
+                "join",
+                "127.0.0.1:12345",
+                "user",
+                "red",
+                "--history",
+                "20",
+                "--spectate",
+            ])
+            .unwrap();
         assert_eq!(
-            local_data_from_db.default_color,
-            local_data_from_db.default_color
+            command_request_from(&matches),
+            CommandRequest::Join {
+                id_or_address: Some(IdOrAddr::Addr("127.0.0.1:12345".into())),
+                also: vec![],
+                username: Some("user".into()),
+                color: Some(Color::Red),
+                history: Some(20),
+                alt_screen: true,
+                spectate: true,
+            }
+        );
+
+        let err = build_cli()
+            .try_get_matches_from(["kioto", "join", "myroom", "user", "notacolor"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+        assert!(err.to_string().contains("notacolor is not a color"));
+        assert!(err.to_string().contains("lightred"));
+
+        let matches = build_cli()
+            .try_get_matches_from([
+                "kioto",
+                "join",
+                "myroom",
+                "--also",
+                "otherroom",
+                "--also",
+                "10.0.0.1",
+            ])
+            .unwrap();
+        assert!(matches!(
+            command_request_from(&matches),
+            CommandRequest::Join { also, .. }
+                if also == [IdOrAddr::Id("otherroom".into()), IdOrAddr::Addr("10.0.0.1".into())]
+        ));
+
+        // the room is picked interactively then
+        let matches = build_cli().try_get_matches_from(["kioto", "join"]).unwrap();
+        assert!(matches!(
+            command_request_from(&matches),
+            CommandRequest::Join {
+                id_or_address: None,
+                ..
+            }
+        ));
+
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "export", "myroom", "room.toml", "--with-secret"])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::Export {
+                room_id: "myroom".into(),
+                path: "room.toml".into(),
+                with_secret: true,
+            }
         );
+
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "clear", "myroom", "--yes"])
+            .unwrap();
         assert_eq!(
-            local_data_from_db.remember_passwords,
-            local_data_from_db.remember_passwords
+            command_request_from(&matches),
+            CommandRequest::Clear {
+                room_id: "myroom".into(),
+                yes: true,
+            }
         );
-        assert_eq!(local_data_from_db.light_mode, local_data_from_db.light_mode);
-    }
 
-    #[test]
-    fn room_joining() {}
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "bans", "myroom", "--json"])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::Bans {
+                room_id: "myroom".into(),
+                json: true,
+            }
+        );
+
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "unban", "--all", "myroom"])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::Unban {
+                room_id: "myroom".into(),
+                addr: None,
+            }
+        );
+        assert!(build_cli()
+            .try_get_matches_from(["kioto", "unban", "myroom"])
+            .is_err());
+        assert!(build_cli()
+            .try_get_matches_from(["kioto", "unban", "--all", "myroom", "10.0.0.1"])
+            .is_err());
+
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "host", "myroom", "--no-alt-screen"])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::Host {
+                room_id: "myroom".into(),
+                alt_screen: false,
+            }
+        );
+
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "create", "myroom", "--lan"])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::Create {
+                room_id: "myroom".into(),
+                ip: Some("lan".into()),
+                password: None,
+                max_users: None,
+                topic: None,
+                allow_duplicate_addr: false,
+                dry_run: false,
+            }
+        );
+        assert!(build_cli()
+            .try_get_matches_from(["kioto", "create", "myroom", "--lan", "127.0.0.1:1234"])
+            .is_err());
+
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "create", "myroom", "--password-stdin"])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::Create {
+                room_id: "myroom".into(),
+                ip: None,
+                password: Some(PasswdSource::Stdin),
+                max_users: None,
+                topic: None,
+                allow_duplicate_addr: false,
+                dry_run: false,
+            }
+        );
+
+        let matches = build_cli()
+            .try_get_matches_from([
+                "kioto",
+                "delete",
+                "myroom",
+                "--force",
+                "--password",
+                "secret",
+            ])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::Delete {
+                room_id: "myroom".into(),
+                force: true,
+                password: Some("secret".into()),
+            }
+        );
+
+        let matches = build_cli()
+            .try_get_matches_from(["kioto", "list", "--sort", "recent"])
+            .unwrap();
+        assert_eq!(
+            command_request_from(&matches),
+            CommandRequest::List {
+                sort: Some(RoomSort::Recent),
+            }
+        );
+
+        assert!(build_cli().try_get_matches_from(["kioto"]).is_err());
+        assert!(build_cli()
+            .try_get_matches_from(["kioto", "join", "myroom", "--history", "many"])
+            .is_err());
+        assert!(build_cli()
+            .try_get_matches_from(["kioto", "list", "--sort", "oldest"])
+            .is_err());
+    }
 }
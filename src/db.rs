@@ -1,12 +1,43 @@
-use crate::schema::{LocalData, Room, TextMessage};
-use polodb_core::{Collection, Database, Result as pdbResult};
-use std::path::Path;
+use crate::schema::{LocalData, Meta, RememberedPasswd, Room, TextMessage};
+use polodb_core::{
+    bson::{doc, from_document, to_document, Document},
+    Collection, Database, Result as pdbResult,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Bumped whenever stored documents gain fields.
+pub const SCHEMA_VERSION: u32 = 15;
+
+/// Limits of the stored messages of every room, nothing is pruned when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_messages: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+impl From<&LocalData> for Retention {
+    fn from(local_data: &LocalData) -> Self {
+        Self {
+            max_messages: (local_data.retention_messages > 0)
+                .then_some(local_data.retention_messages as usize),
+            max_age: (local_data.retention_days > 0)
+                .then(|| Duration::from_secs(u64::from(local_data.retention_days) * 24 * 3600)),
+        }
+    }
+}
 
 pub struct DbRepo {
     pub rooms: Collection<Room>,
     pub messages: Collection<TextMessage>,
     pub local_data: Collection<LocalData>,
-    _db: Database,
+    pub passwords: Collection<RememberedPasswd>,
+    pub meta: Collection<Meta>,
+    db: Database,
 }
 
 impl DbRepo {
@@ -17,7 +48,9 @@ impl DbRepo {
             rooms: db.collection::<Room>("rooms"),
             messages: db.collection::<TextMessage>("messages"),
             local_data: db.collection::<LocalData>("local_data"),
-            _db: db,
+            passwords: db.collection::<RememberedPasswd>("passwords"),
+            meta: db.collection::<Meta>("meta"),
+            db,
         })
     }
 
@@ -28,7 +61,278 @@ impl DbRepo {
             rooms: db.collection::<Room>("rooms"),
             messages: db.collection::<TextMessage>("messages"),
             local_data: db.collection::<LocalData>("local_data"),
-            _db: db,
+            passwords: db.collection::<RememberedPasswd>("passwords"),
+            meta: db.collection::<Meta>("meta"),
+            db,
         })
     }
+
+    /// Fills in the fields added since the database was written, with their defaults.
+    pub fn migrate(&self) -> pdbResult<()> {
+        let version = self
+            .meta
+            .find_one(doc! {"_id": "schema"})?
+            .map_or(0, |meta| meta.schema_version);
+        if version > SCHEMA_VERSION {
+            log::warn!(
+                "The database was written by a newer version (schema {}), some settings may be ignored.",
+                version
+            );
+            return Ok(());
+        }
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        self.backfill::<Room>("rooms")?;
+        self.backfill::<LocalData>("local_data")?;
+        self.backfill::<TextMessage>("messages")?;
+
+        self.meta.delete_many(doc! {"_id": "schema"})?;
+        self.meta.insert_one(Meta {
+            _id: "schema".into(),
+            schema_version: SCHEMA_VERSION,
+        })?;
+        Ok(())
+    }
+
+    /// Stores the serde defaults of the fields missing from the documents of `collection`.
+    fn backfill<T: Serialize + DeserializeOwned>(&self, collection: &str) -> pdbResult<()> {
+        for (document, filled) in self.readable::<T>(collection)? {
+            let missing = to_document(&filled)?
+                .into_iter()
+                .filter(|(key, _)| !document.contains_key(key))
+                .collect::<Document>();
+            if !missing.is_empty() {
+                self.db.collection::<Document>(collection).update_one(
+                    doc! {"_id": document.get("_id").cloned()},
+                    doc! {"$set": missing},
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the documents of `collection`, skipping the unreadable ones the typed cursors
+    /// would panic on.
+    fn readable<T: DeserializeOwned>(&self, collection: &str) -> pdbResult<Vec<(Document, T)>> {
+        let stored = self
+            .db
+            .collection::<Document>(collection)
+            .find(None)?
+            .collect::<pdbResult<Vec<Document>>>()?;
+
+        Ok(stored
+            .into_iter()
+            .filter_map(|document| match from_document::<T>(document.clone()) {
+                Ok(decoded) => Some((document, decoded)),
+                Err(err) => {
+                    log::warn!("Skipping an unreadable document of {}: {}", collection, err);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Every room that can be decoded.
+    pub fn readable_rooms(&self) -> pdbResult<Vec<Room>> {
+        Ok(self
+            .readable::<Room>("rooms")?
+            .into_iter()
+            .map(|(_, room)| room)
+            .collect())
+    }
+
+    /// Deletes the messages past the retention limits, returns how many.
+    pub fn prune_messages(&self, retention: Retention, now: SystemTime) -> pdbResult<usize> {
+        if retention == Retention::default() {
+            return Ok(0);
+        }
+
+        let mut by_room = HashMap::<String, Vec<(Document, TextMessage)>>::new();
+        for (document, msg) in self.readable::<TextMessage>("messages")? {
+            by_room
+                .entry(msg.room_id().clone())
+                .or_default()
+                .push((document, msg));
+        }
+
+        let oldest_kept = retention
+            .max_age
+            .and_then(|max_age| now.checked_sub(max_age));
+        let messages = self.db.collection::<Document>("messages");
+        let mut pruned = 0;
+        for mut stored in by_room.into_values() {
+            stored.sort_by_key(|(_, msg)| *msg.timestamp());
+            let over_limit = retention
+                .max_messages
+                .map_or(0, |max| stored.len().saturating_sub(max));
+
+            for (i, (document, msg)) in stored.iter().enumerate() {
+                let is_stale = oldest_kept.is_some_and(|oldest| *msg.timestamp() < oldest);
+                if i < over_limit || is_stale {
+                    messages.delete_one(doc! {"_id": document.get("_id").cloned()})?;
+                    pruned += 1;
+                }
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    /// Returns messages of the given room in timestamp order, limited to the newest `limit` ones.
+    pub fn room_messages(
+        &self,
+        room_id: &str,
+        limit: Option<usize>,
+    ) -> pdbResult<Vec<TextMessage>> {
+        let mut messages = self
+            .messages
+            .find(doc! {"room_id": room_id})?
+            .collect::<pdbResult<Vec<TextMessage>>>()?;
+        messages.sort_by_key(|msg| *msg.timestamp());
+
+        if let Some(limit) = limit {
+            messages.drain(..messages.len().saturating_sub(limit));
+        }
+
+        Ok(messages)
+    }
+
+    /// Returns up to `limit` messages of the given room in timestamp order, the ones preceding
+    /// its newest `offset` messages.
+    pub fn room_messages_page(
+        &self,
+        room_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> pdbResult<Vec<TextMessage>> {
+        let mut messages = self.room_messages(room_id, None)?;
+        let end = messages.len().saturating_sub(offset);
+        messages.truncate(end);
+        messages.drain(..end.saturating_sub(limit));
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DbRepo, Retention};
+    use crate::schema::TextMessage;
+    use std::{
+        env, fs,
+        net::SocketAddr,
+        str::FromStr,
+        thread::sleep,
+        time::{Duration, SystemTime},
+    };
+    use uuid::Uuid;
+
+    #[test]
+    fn room_messages_survive_reopening() {
+        let path = env::temp_dir().join(format!("kioto-test-{}", Uuid::new_v4()));
+        let addr = SocketAddr::from_str("127.0.0.1:12345").unwrap();
+
+        let msgs = (0..3)
+            .map(|i| {
+                sleep(Duration::from_millis(2));
+                TextMessage::new(&addr, "someroom", &format!("message {}", i))
+            })
+            .collect::<Vec<TextMessage>>();
+
+        {
+            let db = DbRepo::init(&path).unwrap();
+            db.messages
+                .insert_many([&msgs[1], &msgs[2], &msgs[0]])
+                .unwrap();
+            db.messages
+                .insert_one(TextMessage::new(&addr, "anotheroom", "other room"))
+                .unwrap();
+        }
+
+        let db = DbRepo::init(&path).unwrap();
+        assert_eq!(db.room_messages("someroom", None).unwrap(), msgs);
+        assert_eq!(db.room_messages("someroom", Some(2)).unwrap(), msgs[1..]);
+        assert_eq!(db.room_messages("someroom", Some(10)).unwrap(), msgs);
+
+        drop(db);
+        _ = fs::remove_dir_all(&path).or_else(|_| fs::remove_file(&path));
+    }
+
+    #[test]
+    fn room_messages_are_paged_from_the_newest() {
+        let db = DbRepo::memory_init().unwrap();
+        let addr = SocketAddr::from_str("127.0.0.1:12345").unwrap();
+
+        let msgs = (0..7)
+            .map(|i| {
+                sleep(Duration::from_millis(2));
+                TextMessage::new(&addr, "someroom", &format!("message {}", i))
+            })
+            .collect::<Vec<TextMessage>>();
+        for msg in msgs.iter().rev() {
+            db.messages.insert_one(msg).unwrap();
+        }
+
+        assert_eq!(db.room_messages_page("someroom", 0, 3).unwrap(), msgs[4..]);
+        assert_eq!(db.room_messages_page("someroom", 3, 3).unwrap(), msgs[1..4]);
+        assert_eq!(db.room_messages_page("someroom", 6, 3).unwrap(), msgs[..1]);
+        assert!(db.room_messages_page("someroom", 7, 3).unwrap().is_empty());
+        assert!(db.room_messages_page("someroom", 20, 3).unwrap().is_empty());
+        assert_eq!(db.room_messages_page("someroom", 2, 10).unwrap(), msgs[..5]);
+        assert!(db
+            .room_messages_page("anotheroom", 0, 3)
+            .unwrap()
+            .is_empty());
+    }
+
+    fn stored_msgs(db: &DbRepo, room_id: &str, count: usize) -> Vec<TextMessage> {
+        let addr = SocketAddr::from_str("127.0.0.1:12345").unwrap();
+        (0..count)
+            .map(|i| {
+                sleep(Duration::from_millis(2));
+                let msg = TextMessage::new(&addr, room_id, &format!("message {}", i));
+                db.messages.insert_one(&msg).unwrap();
+                msg
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_messages() {
+        let db = DbRepo::memory_init().unwrap();
+        let msgs = stored_msgs(&db, "someroom", 7);
+        let other_msgs = stored_msgs(&db, "anotheroom", 2);
+
+        let retention = Retention {
+            max_messages: Some(3),
+            max_age: None,
+        };
+        assert_eq!(db.prune_messages(retention, SystemTime::now()).unwrap(), 4);
+        assert_eq!(db.room_messages("someroom", None).unwrap(), msgs[4..]);
+        assert_eq!(db.room_messages("anotheroom", None).unwrap(), other_msgs);
+
+        assert_eq!(db.prune_messages(retention, SystemTime::now()).unwrap(), 0);
+        assert_eq!(
+            db.prune_messages(Retention::default(), SystemTime::now())
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn pruning_drops_only_stale_messages() {
+        let db = DbRepo::memory_init().unwrap();
+        let msgs = stored_msgs(&db, "someroom", 5);
+
+        let max_age = Duration::from_secs(24 * 3600);
+        let retention = Retention {
+            max_messages: None,
+            max_age: Some(max_age),
+        };
+        let now = *msgs[2].timestamp() + max_age;
+        assert_eq!(db.prune_messages(retention, now).unwrap(), 2);
+        assert_eq!(db.room_messages("someroom", None).unwrap(), msgs[2..]);
+    }
 }
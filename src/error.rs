@@ -1,6 +1,12 @@
+use crate::network::client::ClientError;
 use polodb_core::Error as pdbError;
-use std::io::Error as ioError;
+use std::{
+    io::{Error as ioError, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
 use thiserror::Error;
+use toml::ser::Error as tomlSerError;
 
 #[derive(Error, Debug)]
 pub enum AppError {
@@ -8,28 +14,211 @@ pub enum AppError {
     PdbError(pdbError),
     #[error("{0}")]
     IoError(ioError),
+    #[error("{0}")]
+    TomlSerError(tomlSerError),
+    #[error("{0}")]
+    ConnectionError(ClientError),
+    #[error("Connection failed: {0}")]
+    ConnectionFailed(String),
+    #[error("The room didn't answer within {0:?}, is it hosted at that address?")]
+    ConnectionTimeout(Duration),
+    #[error("The address is already in use: {0}")]
+    PortInUse(String),
+    #[error("{0} is taken, host the room on another port or stop the program using it, like an earlier kioto session.")]
+    HostAddrTaken(SocketAddr),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Unable to use {0} as the data directory ({1}), fix its permissions or set KIOTO_DATA_DIR to another one.")]
+    DataDirNotWritable(String, String),
+    #[error("The database is used by another kioto process.")]
+    DatabaseLocked,
     #[error("This id already exists.")]
     AlreadyExistingId,
+    #[error("Room {0} already uses this address, pass --allow-duplicate-addr to reuse it.")]
+    AddressInUse(String),
     #[error("Data not found in database.")]
     DataNotFound,
     #[error("Invalid password.")]
     InvalidPassword,
+    #[error("The room is password protected, pass --password or set KIOTO_PASSWORD.")]
+    PasswordRequired,
     #[error("No such room")]
     NotExistingId,
     #[error("There is no any room yet")]
     NoAnyRoom,
     #[error("Invalid command.")]
     InvalidCommand,
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+    #[error("Invalid address {0}, expected host:port.")]
+    InvalidAddress(String),
+    #[error("Unable to detect a LAN address: {0}")]
+    LanAddrNotFound(String),
+    #[error("Only the owner of the room can do that.")]
+    NotOwner,
+}
+
+impl AppError {
+    /// Names the address a room can't be hosted at, other failures map as usual.
+    pub fn hosting(err: ioError, addr: SocketAddr) -> Self {
+        match err.kind() {
+            ErrorKind::AddrInUse => AppError::HostAddrTaken(addr),
+            _ => err.into(),
+        }
+    }
 }
 
 impl From<pdbError> for AppError {
     fn from(value: pdbError) -> Self {
-        AppError::PdbError(value)
+        match value {
+            pdbError::IOErr(err) => err.source.into(),
+            pdbError::DatabaseOccupied | pdbError::Busy => AppError::DatabaseLocked,
+            value => AppError::PdbError(value),
+        }
     }
 }
 
+/// Maps the common failure kinds to their own variants, keeping the rest as is.
 impl From<ioError> for AppError {
     fn from(value: ioError) -> Self {
-        AppError::IoError(value)
+        match value.kind() {
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::TimedOut
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable => AppError::ConnectionFailed(value.to_string()),
+            ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable => {
+                AppError::PortInUse(value.to_string())
+            }
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => {
+                AppError::PermissionDenied(value.to_string())
+            }
+            _ => AppError::IoError(value),
+        }
+    }
+}
+
+impl From<tomlSerError> for AppError {
+    fn from(value: tomlSerError) -> Self {
+        AppError::TomlSerError(value)
+    }
+}
+
+impl From<ClientError> for AppError {
+    fn from(value: ClientError) -> Self {
+        match value {
+            ClientError::Timeout(after) => AppError::ConnectionTimeout(after),
+            value => AppError::ConnectionError(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AppError;
+    use crate::{
+        db::DbRepo,
+        network::{client::ClientError, server::ChatServer},
+        schema::Room,
+    };
+    use polodb_core::Error as pdbError;
+    use std::{
+        io::{Error as ioError, ErrorKind},
+        net::SocketAddr,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn io_errors_map_to_specific_variants() {
+        let from_kind = |kind| AppError::from(ioError::new(kind, "oops"));
+
+        assert!(matches!(
+            from_kind(ErrorKind::ConnectionRefused),
+            AppError::ConnectionFailed(_)
+        ));
+        assert!(matches!(
+            from_kind(ErrorKind::TimedOut),
+            AppError::ConnectionFailed(_)
+        ));
+        assert!(matches!(
+            from_kind(ErrorKind::AddrInUse),
+            AppError::PortInUse(_)
+        ));
+        assert!(matches!(
+            from_kind(ErrorKind::PermissionDenied),
+            AppError::PermissionDenied(msg) if msg == "oops"
+        ));
+        assert!(matches!(
+            from_kind(ErrorKind::NotFound),
+            AppError::IoError(_)
+        ));
+    }
+
+    #[test]
+    fn client_timeouts_have_their_own_variant() {
+        assert!(matches!(
+            AppError::from(ClientError::Timeout(Duration::from_secs(5))),
+            AppError::ConnectionTimeout(after) if after == Duration::from_secs(5)
+        ));
+        assert!(matches!(
+            AppError::from(ClientError::RoomFull),
+            AppError::ConnectionError(ClientError::RoomFull)
+        ));
+    }
+
+    #[test]
+    fn db_errors_map_to_specific_variants() {
+        assert!(matches!(
+            AppError::from(pdbError::DatabaseOccupied),
+            AppError::DatabaseLocked
+        ));
+        assert!(matches!(
+            AppError::from(pdbError::Busy),
+            AppError::DatabaseLocked
+        ));
+        assert!(matches!(
+            AppError::from(pdbError::from(ioError::from(ErrorKind::PermissionDenied))),
+            AppError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            AppError::from(pdbError::DbIsClosed),
+            AppError::PdbError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn hosting_on_a_taken_port_names_the_address() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        let room = Room {
+            _id: "someroom".into(),
+            addr,
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let db = Arc::new(Mutex::new(DbRepo::memory_init().unwrap()));
+        let mut server = ChatServer::new(room, db).await.unwrap();
+
+        let err = AppError::hosting(server.run().await.unwrap_err(), addr);
+        assert!(matches!(err, AppError::HostAddrTaken(taken) if taken == addr));
+        assert!(err.to_string().starts_with(&format!("{} is taken", addr)));
+
+        let other = SocketAddr::from_str("127.0.0.1:4000").unwrap();
+        assert!(matches!(
+            AppError::hosting(ioError::from(ErrorKind::PermissionDenied), other),
+            AppError::PermissionDenied(_)
+        ));
     }
 }
@@ -0,0 +1,118 @@
+use humantime::format_rfc3339_seconds;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Membership changes of a hosted room, message contents are never recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEvent {
+    Joined {
+        user_id: String,
+        addr: SocketAddr,
+        spectator: bool,
+    },
+    Left {
+        user_id: String,
+        addr: SocketAddr,
+    },
+    Banned {
+        addr: SocketAddr,
+        by: String,
+    },
+    Kicked {
+        user_id: String,
+        by: String,
+    },
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditEvent::Joined {
+                user_id,
+                addr,
+                spectator: false,
+            } => write!(f, "join {} {}", user_id, addr),
+            AuditEvent::Joined {
+                user_id,
+                addr,
+                spectator: true,
+            } => write!(f, "join {} {} spectating", user_id, addr),
+            AuditEvent::Left { user_id, addr } => write!(f, "leave {} {}", user_id, addr),
+            AuditEvent::Banned { addr, by } => write!(f, "ban {} by {}", addr.ip(), by),
+            AuditEvent::Kicked { user_id, by } => write!(f, "kick {} by {}", user_id, by),
+        }
+    }
+}
+
+/// Appends the events of a hosted room to a file, one timestamped line each.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let line = format!(
+            "[{}] {}\n",
+            format_rfc3339_seconds(SystemTime::now()),
+            event
+        );
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            log::warn!("Failed to write the audit log: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditEvent, AuditLog};
+    use std::{env, fs, net::SocketAddr, str::FromStr};
+    use uuid::Uuid;
+
+    #[test]
+    fn events_are_appended_as_lines() {
+        let path = env::temp_dir().join(format!("kioto-audit-{}.log", Uuid::new_v4()));
+        let addr = SocketAddr::from_str("10.0.0.2:4000").unwrap();
+
+        let audit = AuditLog::open(&path).unwrap();
+        audit.record(AuditEvent::Joined {
+            user_id: "alice".into(),
+            addr,
+            spectator: true,
+        });
+        // reopening keeps the earlier lines
+        AuditLog::open(&path).unwrap().record(AuditEvent::Banned {
+            addr,
+            by: "owner".into(),
+        });
+
+        let content = fs::read_to_string(&path).unwrap();
+        let events = content
+            .lines()
+            .map(|line| line.split_once("] ").unwrap().1)
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            events,
+            [
+                "join alice 10.0.0.2:4000 spectating",
+                "ban 10.0.0.2 by owner"
+            ]
+        );
+        assert!(content.starts_with('['));
+        fs::remove_file(path).unwrap();
+    }
+}
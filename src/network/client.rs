@@ -1,114 +1,529 @@
 use super::{
-    message::{Message, MessageType, UserMsg, UserReqMsg},
+    crypto::{
+        auth_hash, auth_key, auth_proof, author_key, decrypt, derive_key, encrypt, CryptoError,
+    },
+    message::{Message, MessageType, ServerMsg, UserMsg, UserReqMsg},
+    protocol::ProtocolError,
+    transfer::FileChunk,
     User,
 };
-use crate::schema::Room;
-use futures_util::{SinkExt, StreamExt};
+use crate::{
+    schema::{Room, TextMessage},
+    util::{
+        strip_control, DEFAULT_CONNECT_TIMEOUT, DEFAULT_PING_INTERVAL, DEFAULT_RECONNECT_ATTEMPTS,
+    },
+};
+use chacha20poly1305::Key;
+use futures_util::{FutureExt, SinkExt, StreamExt};
 use std::{
     net::SocketAddr,
     sync::{Arc, Mutex},
 };
+use thiserror::Error;
 use tokio::{
     sync::mpsc::{self, error::SendError, Receiver, Sender},
-    task::JoinHandle,
+    task::{JoinError, JoinHandle},
+    time::{self, sleep, timeout, Duration, Instant},
 };
 use tokio_tungstenite::{
     connect_async,
     tungstenite::{Error as TtError, Message as TtMessage},
 };
 
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(250);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(8);
+/// Shown instead of content which doesn't decrypt with the room password.
+pub const UNREADABLE_CONTENT: &str = "[This message can't be decrypted.]";
+/// How long a clean exit waits for the queued messages to be sent.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+/// Round-trip time to the room, measured with the keepalive pings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Latency {
+    /// No ping was answered yet.
+    Unknown,
+    Rtt(Duration),
+    /// A ping went unanswered for a whole interval.
+    Lost,
+}
+
+/// Time between sending the ping carrying `payload` and receiving its pong.
+fn rtt(payload: &[u8], epoch: Instant, received: Instant) -> Option<Duration> {
+    let sent = epoch + Duration::from_nanos(u64::from_be_bytes(payload.try_into().ok()?));
+    received.checked_duration_since(sent)
+}
+
+/// Pings carry their send time, as nanoseconds since `epoch`, which the pongs echo.
 #[derive(Debug)]
-pub struct ChatClient {
-    pub room: Arc<Mutex<Room>>,
-    pub user: User,
-    event_loop_handle: Option<JoinHandle<()>>,
-    transceiver: Option<Sender<TtMessage>>,
-    in_receiver: Option<Receiver<TtMessage>>,
+struct PingTracker {
+    epoch: Instant,
+    unanswered_since: Option<Instant>,
+    rtt: Option<Duration>,
 }
 
-impl ChatClient {
-    pub fn new(room: Room, user: User) -> Self {
+impl PingTracker {
+    fn new(epoch: Instant) -> Self {
         Self {
-            room: Arc::new(Mutex::new(room)),
-            user,
-            event_loop_handle: None,
-            transceiver: None,
-            in_receiver: None,
+            epoch,
+            unanswered_since: None,
+            rtt: None,
         }
     }
 
-    pub async fn connect(&mut self) -> Result<(), TtError> {
-        let (ws_stream, _) =
-            connect_async(format!("ws://{}/", self.room.lock().unwrap().addr)).await?;
-        let (write, read) = ws_stream.split();
+    /// Returns the payload of a ping sent at `now`.
+    fn ping(&mut self, now: Instant) -> Vec<u8> {
+        self.unanswered_since.get_or_insert(now);
+        let nanos = now.duration_since(self.epoch).as_nanos() as u64;
+        nanos.to_be_bytes().to_vec()
+    }
+
+    fn pong(&mut self, payload: &[u8], now: Instant) {
+        if let Some(rtt) = rtt(payload, self.epoch, now) {
+            self.rtt = Some(rtt);
+            self.unanswered_since = None;
+        }
+    }
+
+    fn latency(&self, now: Instant, interval: Duration) -> Latency {
+        match (self.unanswered_since, self.rtt) {
+            (Some(since), _) if now.duration_since(since) >= interval => Latency::Lost,
+            (_, Some(rtt)) => Latency::Rtt(rtt),
+            (_, None) => Latency::Unknown,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("{0}")]
+    Connection(Box<TtError>),
+    #[error("{0}")]
+    Crypto(CryptoError),
+    #[error("The room requires a password.")]
+    PasswordRequired,
+    #[error("Invalid handshake from the server.")]
+    InvalidHandshake,
+    #[error("The room speaks protocol version {found}, this kioto speaks {expected}.")]
+    VersionMismatch { expected: u8, found: u8 },
+    #[error("The room is full.")]
+    RoomFull,
+    #[error("The room didn't answer within {0:?}.")]
+    Timeout(Duration),
+    #[error("Reconnecting stopped: {0}")]
+    Reconnect(JoinError),
+}
+
+impl From<TtError> for ClientError {
+    fn from(value: TtError) -> Self {
+        ClientError::Connection(Box::new(value))
+    }
+}
+
+impl From<CryptoError> for ClientError {
+    fn from(value: CryptoError) -> Self {
+        ClientError::Crypto(value)
+    }
+}
+
+/// What opening a connection to the room needs, so it can be done in the background.
+#[derive(Debug, Clone)]
+struct Connector {
+    room: Arc<Mutex<Room>>,
+    user: User,
+    passwd: Option<String>,
+    owner_token: Option<String>,
+    session_token: Option<String>,
+    connect_timeout: Duration,
+    ping_interval: Duration,
+}
+
+impl Connector {
+    async fn open(&self) -> Result<Connection, ClientError> {
+        let addr = self.room.lock().unwrap().addr;
+        let timed_out = |_| ClientError::Timeout(self.connect_timeout);
+        let (ws_stream, _) = timeout(
+            self.connect_timeout,
+            connect_async(format!("ws://{}/", addr)),
+        )
+        .await
+        .map_err(timed_out)??;
+        let (write, mut read) = ws_stream.split();
+
+        let handshake = timeout(self.connect_timeout, read.next())
+            .await
+            .map_err(timed_out)?;
+        let (salt, challenge) = match handshake {
+            Some(Ok(msg)) => match Message::try_from(msg) {
+                Ok(Message {
+                    msg_type:
+                        MessageType::Server(ServerMsg::Handshake {
+                            salt,
+                            topic,
+                            challenge,
+                        }),
+                }) => {
+                    self.room.lock().unwrap().topic = topic;
+                    (salt, challenge)
+                }
+                Ok(Message {
+                    msg_type: MessageType::Server(ServerMsg::RoomFull),
+                }) => return Err(ClientError::RoomFull),
+                Err(ProtocolError::VersionMismatch { expected, found }) => {
+                    return Err(ClientError::VersionMismatch { expected, found })
+                }
+                _ => return Err(ClientError::InvalidHandshake),
+            },
+            Some(Err(err)) => return Err(err.into()),
+            None => return Err(ClientError::InvalidHandshake),
+        };
+
+        let mut key = None;
+        let proof = match salt {
+            Some(salt) => {
+                let passwd = self
+                    .passwd
+                    .as_deref()
+                    .ok_or(ClientError::PasswordRequired)?;
+                key = Some(derive_key(passwd, &salt)?);
+                let auth_key =
+                    auth_key(&auth_hash(passwd, &salt)?).ok_or(CryptoError::InvalidSalt)?;
+                Some(auth_proof(&auth_key, &challenge))
+            }
+            None => None,
+        };
 
         let (tx, mut rx) = mpsc::channel::<TtMessage>(100);
         let (tx_in, rx_in) = mpsc::channel::<TtMessage>(100);
 
+        if let Some(proof) = proof {
+            tx.send(Message::from(UserReqMsg::AuthReq { proof }).to_ttmessage())
+                .await
+                .unwrap();
+        }
+        if let Some(token) = &self.owner_token {
+            let proof = auth_proof(token.as_bytes(), &challenge);
+            tx.send(Message::from(UserReqMsg::OwnerReq { proof }).to_ttmessage())
+                .await
+                .unwrap();
+        }
+        if let Some(token) = &self.session_token {
+            tx.send(
+                Message::from(UserReqMsg::ResumeReq {
+                    token: token.clone(),
+                })
+                .to_ttmessage(),
+            )
+            .await
+            .unwrap();
+        }
         tx.send(
-            Message::from((
-                UserMsg::UserJoined {
-                    user: self.user.clone(),
-                },
-                self.room.lock().unwrap().passwd.clone(),
-            ))
+            Message::from(UserMsg::UserJoined {
+                user: self.user.clone(),
+            })
             .to_ttmessage(),
         )
         .await
         .unwrap();
 
-        self.transceiver = Some(tx);
-        self.in_receiver = Some(rx_in);
-
-        let joinhandle = tokio::spawn(async move {
-            tokio::spawn(async move {
-                let mut read = read;
-                while let Some(msg) = read.next().await {
-                    match msg {
-                        Ok(msg) => {
-                            if tx_in.send(msg).await.is_err() {
-                                eprintln!("Receiver dropped");
-                                return;
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading message: {}", e);
-                            return;
-                        }
-                    }
+        // weak so that dropping the transceiver still finishes the writer
+        let tx_ping = tx.downgrade();
 
-                    tokio::task::yield_now().await;
+        let pings = Arc::new(Mutex::new(PingTracker::new(Instant::now())));
+        let ping_interval = self.ping_interval;
+        let pings_sent = pings.clone();
+        let pings_read = pings.clone();
+        let pinger = tokio::spawn(async move {
+            let mut interval = time::interval(ping_interval);
+            loop {
+                interval.tick().await;
+                let Some(tx) = tx_ping.upgrade() else {
+                    return;
+                };
+                let payload = pings_sent.lock().unwrap().ping(Instant::now());
+                if tx.send(TtMessage::Ping(payload)).await.is_err() {
+                    return;
                 }
-            });
+            }
+        });
 
-            tokio::spawn(async move {
-                let mut write = write;
-                while let Some(msg) = rx.recv().await {
-                    if let Err(e) = write.send(msg).await {
-                        eprintln!("Error sending message: {}", e);
+        let reader = tokio::spawn(async move {
+            let mut read = read;
+            while let Some(msg) = read.next().await {
+                match msg {
+                    Ok(TtMessage::Pong(payload)) => {
+                        pings_read.lock().unwrap().pong(&payload, Instant::now());
+                    }
+                    // pings are answered by the stream itself
+                    Ok(msg) if msg.is_ping() => (),
+                    Ok(msg) => {
+                        if tx_in.send(msg).await.is_err() {
+                            log::warn!("Receiver dropped");
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Error reading message: {}", e);
                         return;
                     }
-                    tokio::task::yield_now().await;
                 }
-            });
+
+                tokio::task::yield_now().await;
+            }
         });
 
-        self.event_loop_handle = Some(joinhandle);
+        let writer = tokio::spawn(async move {
+            let mut write = write;
+            while let Some(msg) = rx.recv().await {
+                if let Err(e) = write.send(msg).await {
+                    log::warn!("Error sending message: {}", e);
+                    return;
+                }
+                tokio::task::yield_now().await;
+            }
+            let _ = write.close().await;
+        });
 
+        Ok(Connection {
+            key,
+            pings,
+            // the writer goes last, leaving waits for it
+            event_loop_handles: vec![pinger, reader, writer],
+            transceiver: tx,
+            in_receiver: rx_in,
+        })
+    }
+
+    /// Opens the connection again, waiting longer after every failed attempt.
+    async fn reopen(self, attempts: u32) -> Result<Connection, ClientError> {
+        let mut attempt = 0;
+        loop {
+            sleep(reconnect_delay(attempt)).await;
+
+            match self.open().await {
+                Ok(connection) => return Ok(connection),
+                Err(ClientError::Connection(_) | ClientError::Timeout(_))
+                    if attempt + 1 < attempts =>
+                {
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    key: Option<Key>,
+    pings: Arc<Mutex<PingTracker>>,
+    event_loop_handles: Vec<JoinHandle<()>>,
+    transceiver: Sender<TtMessage>,
+    in_receiver: Receiver<TtMessage>,
+}
+
+#[derive(Debug)]
+pub struct ChatClient {
+    pub room: Arc<Mutex<Room>>,
+    pub user: User,
+    pub history: Option<usize>,
+    /// Plain room password, used to authenticate and to derive the message key.
+    pub passwd: Option<String>,
+    /// Given by the hosted room, lets the user ban.
+    pub owner_token: Option<String>,
+    /// Bounds every connection attempt, reconnects included.
+    pub connect_timeout: Duration,
+    /// How often the room is pinged to measure the latency.
+    pub ping_interval: Duration,
+    /// Attempts at getting a dropped connection back, the delay doubles after each one.
+    pub reconnect_attempts: u32,
+    /// Given by the room on joining, resumed on reconnecting so the messages sent before stay
+    /// the user's.
+    pub session_token: Option<String>,
+    key: Option<Key>,
+    pings: Arc<Mutex<PingTracker>>,
+    event_loop_handles: Vec<JoinHandle<()>>,
+    closed: bool,
+    transceiver: Option<Sender<TtMessage>>,
+    in_receiver: Option<Receiver<TtMessage>>,
+    reconnecting: Option<JoinHandle<Result<Connection, ClientError>>>,
+}
+
+impl ChatClient {
+    pub fn new(room: Room, user: User) -> Self {
+        Self {
+            room: Arc::new(Mutex::new(room)),
+            user,
+            history: None,
+            passwd: None,
+            owner_token: None,
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT.into()),
+            ping_interval: Duration::from_secs(DEFAULT_PING_INTERVAL.into()),
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            session_token: None,
+            key: None,
+            pings: Arc::new(Mutex::new(PingTracker::new(Instant::now()))),
+            event_loop_handles: vec![],
+            closed: false,
+            transceiver: None,
+            in_receiver: None,
+            reconnecting: None,
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<(), ClientError> {
+        let connection = self.connector().open().await?;
+        self.install(connection);
         Ok(())
     }
 
+    fn connector(&self) -> Connector {
+        Connector {
+            room: self.room.clone(),
+            user: self.user.clone(),
+            passwd: self.passwd.clone(),
+            owner_token: self.owner_token.clone(),
+            session_token: self.session_token.clone(),
+            connect_timeout: self.connect_timeout,
+            ping_interval: self.ping_interval,
+        }
+    }
+
+    fn install(&mut self, connection: Connection) {
+        self.key = connection.key;
+        self.pings = connection.pings;
+        self.event_loop_handles = connection.event_loop_handles;
+        self.transceiver = Some(connection.transceiver);
+        self.in_receiver = Some(connection.in_receiver);
+        self.closed = false;
+    }
+
     pub fn close_connection(&mut self) {
-        if let Some(handle) = &self.event_loop_handle {
+        for handle in self.event_loop_handles.drain(..) {
             handle.abort();
         }
+        if let Some(reconnecting) = self.reconnecting.take() {
+            reconnecting.abort();
+        }
+        self.transceiver = None;
+        self.in_receiver = None;
+        self.closed = true;
     }
 
-    pub async fn send_msg(&self, msg: Message) -> Result<(), SendError<TtMessage>> {
-        if let Some(transceiver) = &self.transceiver {
-            transceiver.send(msg.to_ttmessage()).await?
+    /// Fails once the connection is closed, rather than dropping the message.
+    async fn send(&self, msg: Message) -> Result<(), SendError<TtMessage>> {
+        match &self.transceiver {
+            Some(transceiver) => transceiver.send(msg.to_ttmessage()).await,
+            None if self.closed || self.is_reconnecting() => Err(SendError(msg.to_ttmessage())),
+            None => Ok(()),
         }
-        Ok(())
+    }
+
+    /// Tells the room the user leaves, then closes the connection once that's sent.
+    pub async fn leave(&mut self) {
+        if let Some(transceiver) = self.transceiver.take() {
+            let _ = transceiver
+                .send(Message::from(UserReqMsg::LeaveReq).to_ttmessage())
+                .await;
+        }
+        // the writer finishes once the transceiver is dropped
+        if let Some(writer) = self.event_loop_handles.pop() {
+            let _ = timeout(LEAVE_TIMEOUT, writer).await;
+        }
+        self.close_connection();
+    }
+
+    /// Whether the connection dropped without being closed on purpose, it stays so while
+    /// reconnecting.
+    pub fn is_disconnected(&self) -> bool {
+        self.is_reconnecting() || (!self.closed && self.has_dropped())
+    }
+
+    fn has_dropped(&self) -> bool {
+        self.in_receiver
+            .as_ref()
+            .is_some_and(|receiver| receiver.is_closed() && receiver.is_empty())
+    }
+
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnecting.is_some()
+    }
+
+    /// Author key of the messages the user sent in this room.
+    pub fn author(&self) -> Option<String> {
+        self.session_token.as_deref().map(author_key)
+    }
+
+    pub fn latency(&self) -> Latency {
+        if self.is_disconnected() {
+            return Latency::Lost;
+        }
+        self.pings
+            .lock()
+            .unwrap()
+            .latency(Instant::now(), self.ping_interval)
+    }
+
+    /// Starts connecting again in the background, waiting longer after every failed attempt.
+    fn start_reconnect(&mut self) {
+        self.close_connection();
+        self.closed = false;
+        let attempts = self.reconnect_attempts;
+        self.reconnecting = Some(tokio::spawn(self.connector().reopen(attempts)));
+    }
+
+    /// Starts reconnecting once the connection dropped, and returns how it went when it's over.
+    pub fn poll_reconnect(&mut self) -> Option<Result<(), ClientError>> {
+        let Some(mut reconnecting) = self.reconnecting.take() else {
+            if !self.closed && self.has_dropped() {
+                self.start_reconnect();
+            }
+            return None;
+        };
+        match (&mut reconnecting).now_or_never() {
+            Some(Ok(Ok(connection))) => {
+                self.install(connection);
+                Some(Ok(()))
+            }
+            Some(Ok(Err(err))) => {
+                self.closed = true;
+                Some(Err(err))
+            }
+            // the task panicked or was cancelled, it fails like any other attempt
+            Some(Err(err)) => {
+                self.closed = true;
+                Some(Err(ClientError::Reconnect(err)))
+            }
+            None => {
+                self.reconnecting = Some(reconnecting);
+                None
+            }
+        }
+    }
+
+    pub async fn send_msg(&self, mut msg: Message) -> Result<(), SendError<TtMessage>> {
+        if let Some(key) = &self.key {
+            match &mut msg.msg_type {
+                MessageType::User(UserMsg::Normal { msg: text_msg }) => {
+                    text_msg.set_content(encrypt(key, text_msg.content()));
+                }
+                MessageType::User(UserMsg::Edit { content, .. }) => {
+                    *content = encrypt(key, content);
+                }
+                MessageType::User(UserMsg::File { chunk, .. }) => {
+                    chunk.name = encrypt(key, &chunk.name);
+                    chunk.data = encrypt(key, &chunk.data);
+                }
+                _ => (),
+            }
+        }
+
+        self.send(msg).await
     }
 
     pub async fn recv_msg(&mut self) -> Option<MessageType> {
@@ -117,38 +532,245 @@ impl ChatClient {
                 return None;
             }
 
-            return Some(Message::from(receiver.recv().await.unwrap()).msg_type);
+            let msg_type = match Message::try_from(receiver.recv().await.unwrap()) {
+                Ok(msg) => msg.msg_type,
+                Err(err) => {
+                    log::warn!("Received an invalid message: {}", err);
+                    return None;
+                }
+            };
+            if let MessageType::Server(ServerMsg::Session { token }) = &msg_type {
+                self.session_token = Some(token.clone());
+            }
+
+            return self.open_msg(msg_type);
         }
         None
     }
 
+    /// Decrypts the contents in password rooms, then strips what could mess up the terminal.
+    /// Anyone in the room can send what doesn't decrypt, so it's shown as unreadable, or
+    /// dropped for files, rather than taken for a wrong password.
+    fn open_msg(&self, msg_type: MessageType) -> Option<MessageType> {
+        let open_content = |content: &str| -> String {
+            match &self.key {
+                Some(key) => decrypt(key, content).map_or_else(
+                    |err| {
+                        log::warn!("{}", err);
+                        String::from(UNREADABLE_CONTENT)
+                    },
+                    |content| strip_control(&content),
+                ),
+                None => strip_control(content),
+            }
+        };
+        let open = |mut msg: TextMessage| -> TextMessage {
+            msg.set_content(open_content(msg.content()));
+            msg
+        };
+
+        Some(match msg_type {
+            MessageType::User(UserMsg::Normal { msg }) => {
+                MessageType::User(UserMsg::Normal { msg: open(msg) })
+            }
+            MessageType::User(UserMsg::Edit { id, content }) => MessageType::User(UserMsg::Edit {
+                id,
+                content: open_content(&content),
+            }),
+            MessageType::User(UserMsg::File { addr, mut chunk }) => {
+                if let Some(key) = &self.key {
+                    let opened = decrypt(key, &chunk.name)
+                        .and_then(|name| Ok((name, decrypt(key, &chunk.data)?)));
+                    match opened {
+                        Ok((name, data)) => (chunk.name, chunk.data) = (name, data),
+                        Err(err) => {
+                            log::warn!("Dropping a file chunk: {}", err);
+                            return None;
+                        }
+                    }
+                }
+                chunk.name = strip_control(&chunk.name);
+                MessageType::User(UserMsg::File { addr, chunk })
+            }
+            MessageType::Server(ServerMsg::Sync { messages, users }) => {
+                MessageType::Server(ServerMsg::Sync {
+                    messages: messages.into_iter().map(open).collect(),
+                    users,
+                })
+            }
+            MessageType::Server(ServerMsg::Page { offset, messages }) => {
+                MessageType::Server(ServerMsg::Page {
+                    offset,
+                    messages: messages.into_iter().map(open).collect(),
+                })
+            }
+            msg_type => msg_type,
+        })
+    }
+
     pub async fn sync(&self) -> Result<(), SendError<TtMessage>> {
-        if let Some(transceiver) = &self.transceiver {
+        self.send(Message::from(UserReqMsg::SyncReq {
+            history: self.history,
+        }))
+        .await
+    }
+
+    /// Asks for older messages than the newest `offset` ones.
+    pub async fn request_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(), SendError<TtMessage>> {
+        self.send(Message::from(UserReqMsg::PageReq { offset, limit }))
+            .await
+    }
+
+    pub async fn ban(&self, addr: &SocketAddr) -> Result<(), SendError<TtMessage>> {
+        self.send(Message::from(UserReqMsg::BanReq { addr: *addr }))
+            .await
+    }
+
+    /// Tells the others whether the user is composing a message.
+    pub async fn typing(&self, is_typing: bool) -> Result<(), SendError<TtMessage>> {
+        if self.user.spectator {
+            return Ok(());
+        }
+        if let (Some(transceiver), Some(addr)) = (&self.transceiver, self.user.addr) {
             transceiver
-                .send(
-                    Message::from((
-                        UserReqMsg::SyncReq,
-                        self.room.lock().unwrap().passwd.clone(),
-                    ))
-                    .to_ttmessage(),
-                )
+                .send(Message::from(UserMsg::Typing { addr, is_typing }).to_ttmessage())
                 .await?
         }
         Ok(())
     }
 
-    pub async fn ban(&self, addr: &SocketAddr) -> Result<(), SendError<TtMessage>> {
-        if let Some(transceiver) = &self.transceiver {
+    /// Tells the others whether the user went idle.
+    pub async fn set_away(&mut self, away: bool) -> Result<(), SendError<TtMessage>> {
+        // kept for the introduction after a reconnect
+        self.user.away = away;
+        if self.user.spectator {
+            return Ok(());
+        }
+        if let (Some(transceiver), Some(addr)) = (&self.transceiver, self.user.addr) {
             transceiver
-                .send(
-                    Message::from((
-                        UserReqMsg::BanReq { addr: addr.clone() },
-                        self.room.lock().unwrap().passwd.clone(),
-                    ))
-                    .to_ttmessage(),
-                )
+                .send(Message::from(UserMsg::Status { addr, away }).to_ttmessage())
                 .await?
         }
         Ok(())
     }
+
+    /// Sends the chunks of a file in their order.
+    pub async fn send_file(&self, chunks: Vec<FileChunk>) -> Result<(), SendError<TtMessage>> {
+        let Some(addr) = self.user.addr else {
+            return Ok(());
+        };
+        for chunk in chunks {
+            self.send_msg(Message::from(UserMsg::File { addr, chunk }))
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn kick(&self, user_id: &str) -> Result<(), SendError<TtMessage>> {
+        self.send(Message::from(UserReqMsg::KickReq {
+            user_id: user_id.into(),
+        }))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rtt, ChatClient, ClientError, Latency, PingTracker};
+    use crate::{
+        network::User,
+        schema::{Color, Room},
+    };
+    use std::{future, net::SocketAddr, str::FromStr, time::SystemTime};
+    use tokio::time::{sleep, Duration, Instant};
+
+    #[test]
+    fn rtt_is_measured_from_the_echoed_send_time() {
+        let epoch = Instant::now();
+        let sent = 1_500_000_000u64.to_be_bytes();
+
+        assert_eq!(
+            rtt(&sent, epoch, epoch + Duration::from_millis(1_542)),
+            Some(Duration::from_millis(42))
+        );
+        // a pong can't come before its ping
+        assert_eq!(rtt(&sent, epoch, epoch + Duration::from_secs(1)), None);
+        assert_eq!(rtt(b"junk", epoch, epoch + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn unanswered_pings_are_lost_after_an_interval() {
+        let epoch = Instant::now();
+        let interval = Duration::from_secs(15);
+        let mut pings = PingTracker::new(epoch);
+        assert_eq!(pings.latency(epoch, interval), Latency::Unknown);
+
+        let payload = pings.ping(epoch);
+        pings.pong(&payload, epoch + Duration::from_millis(30));
+        assert_eq!(
+            pings.latency(epoch + Duration::from_millis(30), interval),
+            Latency::Rtt(Duration::from_millis(30))
+        );
+
+        // the next ping keeps the earliest unanswered one's time
+        let later = epoch + interval;
+        pings.ping(later);
+        pings.ping(later + interval);
+        assert_eq!(
+            pings.latency(later + Duration::from_secs(1), interval),
+            Latency::Rtt(Duration::from_millis(30))
+        );
+        assert_eq!(pings.latency(later + interval, interval), Latency::Lost);
+
+        let payload = pings.ping(later + interval * 2);
+        pings.pong(&payload, later + interval * 2 + Duration::from_millis(80));
+        assert_eq!(
+            pings.latency(later + interval * 2 + Duration::from_secs(1), interval),
+            Latency::Rtt(Duration::from_millis(80))
+        );
+    }
+
+    #[tokio::test]
+    async fn stopped_reconnects_are_reported() {
+        let mut client = ChatClient::new(
+            Room {
+                _id: "someroom".into(),
+                addr: SocketAddr::from_str("127.0.0.1:1").unwrap(),
+                passwd: None,
+                banned_addrs: vec![],
+                is_owner: false,
+                max_users: None,
+                topic: None,
+                created_at: SystemTime::UNIX_EPOCH,
+                host: None,
+                last_joined: None,
+                filter_words: vec![],
+            },
+            User {
+                _id: "user1".into(),
+                addr: None,
+                color: Some(Color::LightRed),
+                away: false,
+                spectator: false,
+            },
+        );
+        let reconnecting = tokio::spawn(future::pending());
+        reconnecting.abort();
+        while !reconnecting.is_finished() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        client.reconnecting = Some(reconnecting);
+
+        assert!(matches!(
+            client.poll_reconnect(),
+            Some(Err(ClientError::Reconnect(err))) if err.is_cancelled()
+        ));
+        // given up like any other failed reconnect
+        assert!(!client.is_reconnecting());
+        assert!(client.poll_reconnect().is_none());
+    }
 }
@@ -0,0 +1,188 @@
+use argon2::{
+    password_hash::{PasswordHash, SaltString},
+    Argon2, PasswordHasher,
+};
+use base64ct::{Base64, Encoding};
+use blake2::{
+    digest::{consts::U32, Mac},
+    Blake2bMac,
+};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const CHALLENGE_LEN: usize = 32;
+
+type AuthMac = Blake2bMac<U32>;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    #[error("Invalid salt.")]
+    InvalidSalt,
+    #[error("Message could not be decrypted.")]
+    Decryption,
+}
+
+/// Salt of the room password hash, sent to clients at handshake.
+pub fn passwd_salt(hash: &str) -> Option<String> {
+    PasswordHash::new(hash)
+        .ok()
+        .and_then(|hash| hash.salt)
+        .map(|salt| salt.to_string())
+}
+
+/// Reproduces the room password hash, which clients authenticate with.
+pub fn auth_hash(passwd: &str, salt: &str) -> Result<String, CryptoError> {
+    let salt = SaltString::from_b64(salt).map_err(|_| CryptoError::InvalidSalt)?;
+    Argon2::default()
+        .hash_password(passwd.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| CryptoError::InvalidSalt)
+}
+
+/// Key of the proofs answering a challenge, the output of the room password hash.
+pub fn auth_key(hash: &str) -> Option<Vec<u8>> {
+    PasswordHash::new(hash)
+        .ok()
+        .and_then(|hash| hash.hash)
+        .map(|output| output.as_bytes().to_vec())
+}
+
+/// Random challenge sent to every connection at handshake.
+pub fn challenge() -> String {
+    let mut challenge = [0; CHALLENGE_LEN];
+    OsRng.fill_bytes(&mut challenge);
+    Base64::encode_string(&challenge)
+}
+
+/// Marks the messages of a session, without giving away the token that resumes it.
+pub fn author_key(session_token: &str) -> String {
+    Base64::encode_string(&Sha256::digest(session_token.as_bytes()))
+}
+
+fn challenge_mac(key: &[u8], challenge: &str) -> AuthMac {
+    let mut mac = <AuthMac as KeyInit>::new_from_slice(key).unwrap();
+    mac.update(challenge.as_bytes());
+    mac
+}
+
+/// Proves knowing the key to whoever sent the challenge, without giving the key away.
+pub fn auth_proof(key: &[u8], challenge: &str) -> String {
+    let proof = challenge_mac(key, challenge).finalize();
+    Base64::encode_string(&proof.into_bytes())
+}
+
+pub fn verify_proof(key: &[u8], challenge: &str, proof: &str) -> bool {
+    Base64::decode_vec(proof)
+        .is_ok_and(|proof| challenge_mac(key, challenge).verify_slice(&proof).is_ok())
+}
+
+/// Derives the message key, with its own salt so it can't be recovered from the auth hash.
+pub fn derive_key(passwd: &str, salt: &str) -> Result<Key, CryptoError> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(
+            passwd.as_bytes(),
+            format!("{}:key", salt).as_bytes(),
+            &mut key,
+        )
+        .map_err(|_| CryptoError::InvalidSalt)?;
+    Ok(key)
+}
+
+pub fn encrypt(key: &Key, content: &str) -> String {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        ChaCha20Poly1305::new(key)
+            .encrypt(&nonce, content.as_bytes())
+            .unwrap(),
+    );
+    Base64::encode_string(&sealed)
+}
+
+/// Length of the encoded sealed content for a plain content of `content_len` bytes.
+pub fn sealed_len(content_len: usize) -> usize {
+    (NONCE_LEN + content_len + TAG_LEN).div_ceil(3) * 4
+}
+
+/// Length of the plain content sealed in `content`, without decrypting it.
+pub fn plain_len(content: &str) -> usize {
+    let padding = content.bytes().rev().take_while(|&b| b == b'=').count();
+    (content.len() / 4 * 3).saturating_sub(padding + NONCE_LEN + TAG_LEN)
+}
+
+pub fn decrypt(key: &Key, content: &str) -> Result<String, CryptoError> {
+    let sealed = Base64::decode_vec(content).map_err(|_| CryptoError::Decryption)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Decryption);
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let content = ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CryptoError::Decryption)?;
+    String::from_utf8(content).map_err(|_| CryptoError::Decryption)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        auth_hash, auth_key, auth_proof, challenge, decrypt, derive_key, encrypt, passwd_salt,
+        verify_proof, CryptoError,
+    };
+    use crate::util::hash_passwd;
+
+    #[test]
+    fn auth_hash_matches_room_hash() {
+        let hash = hash_passwd("password");
+        let salt = passwd_salt(&hash).unwrap();
+
+        assert_eq!(auth_hash("password", &salt).unwrap(), hash);
+        assert_ne!(auth_hash("wrongpassword", &salt).unwrap(), hash);
+        assert_eq!(passwd_salt("password"), None);
+    }
+
+    #[test]
+    fn proofs_only_answer_their_challenge() {
+        let hash = hash_passwd("password");
+        let key = auth_key(&hash).unwrap();
+        let salt = passwd_salt(&hash).unwrap();
+        assert_eq!(
+            auth_key(&auth_hash("password", &salt).unwrap()),
+            Some(key.clone())
+        );
+        assert_eq!(auth_key("password"), None);
+
+        let sent = challenge();
+        assert_ne!(challenge(), sent);
+        let proof = auth_proof(&key, &sent);
+        assert!(!proof.contains(&hash));
+        assert!(verify_proof(&key, &sent, &proof));
+        assert!(!verify_proof(&key, &challenge(), &proof));
+        assert!(!verify_proof(&key, &sent, "not base64!"));
+
+        let other_key = auth_key(&hash_passwd("password")).unwrap();
+        assert!(!verify_proof(&other_key, &sent, &proof));
+    }
+
+    #[test]
+    fn messages_decrypt_only_with_matching_key() {
+        let salt = passwd_salt(&hash_passwd("password")).unwrap();
+        let key = derive_key("password", &salt).unwrap();
+
+        let sealed = encrypt(&key, "some message");
+        assert!(!sealed.contains("some message"));
+        assert_ne!(encrypt(&key, "some message"), sealed);
+        assert_eq!(decrypt(&key, &sealed).unwrap(), "some message");
+
+        let wrong_key = derive_key("wrongpassword", &salt).unwrap();
+        assert_eq!(decrypt(&wrong_key, &sealed), Err(CryptoError::Decryption));
+        assert_eq!(decrypt(&key, "some message"), Err(CryptoError::Decryption));
+    }
+}
@@ -1,55 +1,44 @@
-use super::User;
+use super::{
+    protocol::{decode, encode, ProtocolError},
+    transfer::FileChunk,
+    User,
+};
 use crate::schema::TextMessage;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::SystemTime};
 use tokio_tungstenite::tungstenite::Message as TtMessage;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Message {
     pub msg_type: MessageType,
-    pub passwd: Option<String>,
 }
 
-impl Message {
-    pub fn new(msg_type: MessageType, passwd: Option<String>) -> Self {
-        Self { msg_type, passwd }
+impl From<MessageType> for Message {
+    fn from(msg_type: MessageType) -> Self {
+        Self { msg_type }
     }
 }
 
-impl From<(MessageType, Option<String>)> for Message {
-    fn from(value: (MessageType, Option<String>)) -> Self {
-        let (msg_type, passwd) = value;
-        Self { msg_type, passwd }
-    }
-}
-
-impl From<(UserMsg, Option<String>)> for Message {
-    fn from(value: (UserMsg, Option<String>)) -> Self {
-        let (user_msg, passwd) = value;
+impl From<UserMsg> for Message {
+    fn from(user_msg: UserMsg) -> Self {
         Self {
             msg_type: MessageType::User(user_msg),
-            passwd,
         }
     }
 }
 
-impl From<(UserReqMsg, Option<String>)> for Message {
-    fn from(value: (UserReqMsg, Option<String>)) -> Self {
-        let (user_req, passwd) = value;
+impl From<UserReqMsg> for Message {
+    fn from(user_req: UserReqMsg) -> Self {
         Self {
             msg_type: MessageType::UserReq(user_req),
-            passwd,
         }
     }
 }
 
-impl From<(ServerMsg, Option<String>)> for Message {
-    fn from(value: (ServerMsg, Option<String>)) -> Self {
-        let (server_msg, passwd) = value;
+impl From<ServerMsg> for Message {
+    fn from(server_msg: ServerMsg) -> Self {
         Self {
             msg_type: MessageType::Server(server_msg),
-            passwd,
         }
     }
 }
@@ -63,23 +52,119 @@ pub enum MessageType {
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum UserMsg {
-    Normal { msg: TextMessage },
-    UserJoined { user: User },
+    Normal {
+        msg: TextMessage,
+    },
+    UserJoined {
+        user: User,
+    },
+    Typing {
+        addr: SocketAddr,
+        is_typing: bool,
+    },
+    Status {
+        addr: SocketAddr,
+        away: bool,
+    },
+    /// Replaces the content of a message of the sender, rebroadcast once the room accepts it.
+    Edit {
+        id: String,
+        content: String,
+    },
+    Delete {
+        id: String,
+    },
+    /// Part of a file, the room fills in the sender's address.
+    File {
+        addr: SocketAddr,
+        chunk: FileChunk,
+    },
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum UserReqMsg {
-    SyncReq,
-    BanReq { addr: SocketAddr },
+    /// First message in protected rooms, answering the challenge of the handshake.
+    AuthReq {
+        proof: String,
+    },
+    SyncReq {
+        history: Option<usize>,
+    },
+    /// Sent on reconnecting with the token of the earlier session, which keeps authoring the
+    /// messages sent before.
+    ResumeReq {
+        token: String,
+    },
+    /// Up to `limit` messages preceding the newest `offset` ones.
+    PageReq {
+        offset: usize,
+        limit: usize,
+    },
+    /// Proves holding the owner token the room gave its host, answering the challenge.
+    OwnerReq {
+        proof: String,
+    },
+    BanReq {
+        addr: SocketAddr,
+    },
+    KickReq {
+        user_id: String,
+    },
+    /// Sent on a clean exit, so the others don't wait for the keepalive.
+    LeaveReq,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum ServerMsg {
+    Handshake {
+        salt: Option<String>,
+        topic: Option<String>,
+        /// Proven with the room password, so it never goes over the wire.
+        challenge: String,
+    },
     AuthFailure,
+    RoomFull,
+    RateLimited,
+    /// The message had no content besides whitespace.
+    EmptyMessage,
+    MessageTooLong {
+        max_len: u32,
+    },
+    /// The file has more bytes than the room relays.
+    FileTooLarge {
+        max_size: u64,
+    },
+    /// The sender's message with this timestamp was stored and relayed.
+    Ack {
+        timestamp: SystemTime,
+    },
+    /// The sender only spectates the room.
+    ReadOnly,
+    /// Only the owner of the room may do what the sender asked for.
+    NotOwner,
+    /// The owner kicked the receiver out, its connection closes next.
+    Kicked,
+    /// Identifies the session over reconnects, resumed with `ResumeReq`.
+    Session {
+        token: String,
+    },
+    /// The name the sender joined with was taken, it goes by this one instead.
+    Renamed {
+        user_id: String,
+    },
+    /// The message doesn't exist or wasn't sent by the requester.
+    EditRejected {
+        id: String,
+    },
     Sync {
         messages: Vec<TextMessage>,
         users: Vec<User>,
     },
+    Page {
+        offset: usize,
+        messages: Vec<TextMessage>,
+    },
     UserLeft {
         addr: SocketAddr,
     },
@@ -89,14 +174,19 @@ pub enum ServerMsg {
     ServerShutdown,
 }
 
-impl From<TtMessage> for Message {
-    fn from(value: TtMessage) -> Self {
-        from_str(&value.to_string()).unwrap()
+impl TryFrom<TtMessage> for Message {
+    type Error = ProtocolError;
+
+    fn try_from(value: TtMessage) -> Result<Self, Self::Error> {
+        match value {
+            TtMessage::Binary(frame) => decode(&frame),
+            _ => Err(ProtocolError::UnexpectedFrame),
+        }
     }
 }
 
 impl Message {
     pub fn to_ttmessage(&self) -> TtMessage {
-        TtMessage::text(to_string(self).unwrap())
+        TtMessage::binary(encode(self))
     }
 }
@@ -1,6 +1,10 @@
+pub mod audit;
 pub mod client;
+pub mod crypto;
 pub mod message;
+pub mod protocol;
 pub mod server;
+pub mod transfer;
 
 use crate::schema::Color;
 use serde::{Deserialize, Serialize};
@@ -10,7 +14,14 @@ use std::net::SocketAddr;
 pub struct User {
     pub _id: String,
     pub addr: Option<SocketAddr>,
-    pub color: Color,
+    /// Left empty to have the server pick a color unused in the room.
+    pub color: Option<Color>,
+    /// Set after a while without input.
+    #[serde(default)]
+    pub away: bool,
+    /// Only reads the room, left out of the user list and unable to send.
+    #[serde(default)]
+    pub spectator: bool,
 }
 
 #[cfg(test)]
@@ -19,158 +30,2204 @@ mod test {
     use crate::{
         db::DbRepo,
         network::message::{Message, ServerMsg, UserMsg, UserReqMsg},
-        schema::{Color, Room, TextMessage},
-        util::hash_passwd,
-        ChatClient, ChatServer, User,
+        network::{
+            audit::AuditLog,
+            client::{ChatClient, ClientError, Latency, UNREADABLE_CONTENT},
+            crypto::{auth_key, auth_proof, decrypt, derive_key, passwd_salt},
+            protocol::{encode, PROTOCOL_VERSION},
+            server::ChatServer,
+            transfer::{split, IncomingFile, CHUNK_SIZE},
+            User,
+        },
+        schema::{Color, LocalData, Room, TextMessage, Timezone},
+        util::{
+            hash_passwd, DEFAULT_AWAY_AFTER, DEFAULT_CONNECT_TIMEOUT, DEFAULT_LAYOUT_RATIO,
+            DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_MISSED_PONGS, DEFAULT_MAX_MSG_LEN,
+            DEFAULT_MENTION_PREFIX, DEFAULT_MSG_BURST, DEFAULT_MSG_RATE, DEFAULT_PING_INTERVAL,
+            DEFAULT_RECONNECT_ATTEMPTS, DEFAULT_TIMESTAMP_FORMAT,
+        },
     };
+    use futures_util::{SinkExt, StreamExt};
     use std::{
-        collections::HashMap,
-        net::SocketAddr,
+        env, fs,
+        net::{IpAddr, SocketAddr},
         str::FromStr,
         sync::{Arc, Mutex},
-        time::Duration,
+        time::{Duration, SystemTime},
     };
-    use tokio::time::sleep;
+    use tokio::{
+        net::{TcpListener, TcpSocket, TcpStream},
+        time::{sleep, timeout},
+    };
+    use tokio_tungstenite::{
+        accept_async, client_async,
+        tungstenite::{Error as TtError, Message as TtMessage},
+        WebSocketStream,
+    };
+    use uuid::Uuid;
+
+    async fn next_msg(client: &mut ChatClient) -> MessageType {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(msg) = client.recv_msg().await {
+                    return msg;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn join(client: &mut ChatClient) {
+        client.connect().await.unwrap();
+        loop {
+            if let MessageType::User(UserMsg::UserJoined { user }) = next_msg(client).await {
+                if user._id == client.user._id {
+                    client.user.addr = user.addr;
+                    return;
+                }
+            }
+        }
+    }
 
     #[tokio::test]
-    async fn messages_are_correct() {
-        let passwd = String::from("password");
-        hash_passwd(&passwd);
+    async fn server_relays_messages_between_clients() {
         let room = Room {
-            _id: "firstroom".into(),
-            addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
-            passwd: Some(passwd),
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
             banned_addrs: vec![],
             is_owner: true,
+            max_users: None,
+            topic: Some("greetings".into()),
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
         };
+        let (server, room) = start_server(room).await;
+        assert_ne!(room.addr.port(), 0);
 
-        let mut room2 = room.clone();
-        room2.is_owner = false;
+        let mut clients = vec![];
+        for (username, color) in [("user1", Color::LightRed), ("user2", Color::LightGreen)] {
+            let color = Some(color);
+            let mut client = ChatClient::new(
+                Room {
+                    topic: None,
+                    ..room.clone()
+                },
+                User {
+                    _id: username.into(),
+                    addr: None,
+                    color,
+                    away: false,
+                    spectator: false,
+                },
+            );
+            join(&mut client).await;
+            assert_eq!(client.room.lock().unwrap().topic, Some("greetings".into()));
+            clients.push(client);
+        }
 
-        let mut user = User {
-            _id: "user1".into(),
-            addr: None,
-            color: Color::LightRed,
-        };
+        clients[1].sync().await.unwrap();
+        if let MessageType::Server(ServerMsg::Sync {
+            messages,
+            mut users,
+        }) = next_msg(&mut clients[1]).await
+        {
+            users.sort_by(|a, b| a._id.cmp(&b._id));
+            assert!(messages.is_empty());
+            assert_eq!(
+                users,
+                vec![clients[0].user.clone(), clients[1].user.clone()]
+            );
+        } else {
+            panic!("expected sync response");
+        }
 
-        let mut user2 = User {
-            _id: "user2".into(),
-            addr: None,
-            color: Color::LightGreen,
-        };
+        clients[0].typing(true).await.unwrap();
+        assert_eq!(
+            next_msg(&mut clients[1]).await,
+            MessageType::User(UserMsg::Typing {
+                addr: clients[0].user.addr.unwrap(),
+                is_typing: true,
+            })
+        );
+
+        clients[0].set_away(true).await.unwrap();
+        assert_eq!(
+            next_msg(&mut clients[1]).await,
+            MessageType::User(UserMsg::Status {
+                addr: clients[0].user.addr.unwrap(),
+                away: true,
+            })
+        );
+        clients[1].sync().await.unwrap();
+        if let MessageType::Server(ServerMsg::Sync { users, .. }) = next_msg(&mut clients[1]).await
+        {
+            assert!(users.iter().any(|user| user._id == "user1" && user.away));
+        } else {
+            panic!("expected sync response");
+        }
 
-        let room_ = room.clone();
+        let sent_msg = TextMessage::new(&clients[0].user.addr.unwrap(), &room._id, "hello");
+        clients[0]
+            .send_msg(Message::from(UserMsg::Normal {
+                msg: sent_msg.clone(),
+            }))
+            .await
+            .unwrap();
 
-        let db = Arc::new(Mutex::new(DbRepo::memory_init().unwrap()));
+        // relayed with the author the room knows the sender as
+        let mut sent_msg = sent_msg;
+        sent_msg.set_sender(*sent_msg.sender_addr(), clients[0].author());
+        assert_eq!(
+            next_msg(&mut clients[1]).await,
+            MessageType::User(UserMsg::Normal { msg: sent_msg })
+        );
+
+        server.stop();
+    }
 
-        let mut peermap = HashMap::<SocketAddr, User>::new();
+    async fn start_server(room: Room) -> (ChatServer, Room) {
+        start_audited_server(room, None).await
+    }
 
+    async fn start_audited_server(room: Room, audit: Option<AuditLog>) -> (ChatServer, Room) {
+        let db = Arc::new(Mutex::new(DbRepo::memory_init().unwrap()));
         let mut server = ChatServer::new(room.clone(), db).await.unwrap();
+        server.audit = audit;
         server.run().await.unwrap();
-        sleep(Duration::from_secs(1)).await;
-        let mut client = ChatClient::new(room_, user.clone());
-        client.connect().await.unwrap();
-        sleep(Duration::from_secs(1)).await;
-        server.stop();
 
-        if let MessageType::User(UserMsg::UserJoined { user: user_ }) =
-            client.recv_msg().await.unwrap()
-        {
-            user.addr = user_.addr;
-            peermap.insert(user.addr.unwrap(), user.clone()).unwrap();
+        let room = Room {
+            addr: server.local_addr().unwrap(),
+            is_owner: false,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+            ..room
+        };
+        (server, room)
+    }
+
+    async fn connect_from(
+        ip: &str,
+        server_addr: SocketAddr,
+    ) -> Result<WebSocketStream<TcpStream>, TtError> {
+        let socket = TcpSocket::new_v4()?;
+        socket.bind(SocketAddr::new(IpAddr::from_str(ip).unwrap(), 0))?;
+        let stream = socket.connect(server_addr).await?;
+        let (ws_stream, _) = client_async(format!("ws://{}/", server_addr), stream).await?;
+        Ok(ws_stream)
+    }
+
+    async fn handshake_challenge(ws_stream: &mut WebSocketStream<TcpStream>) -> String {
+        match next_server_msg(ws_stream).await {
+            MessageType::Server(ServerMsg::Handshake { challenge, .. }) => challenge,
+            msg_type => panic!("expected a handshake, got {:?}", msg_type),
         }
+    }
 
-        let sended_msg = TextMessage::new(&user.addr.unwrap(), &room._id, "some short message");
+    async fn next_server_msg(ws_stream: &mut WebSocketStream<TcpStream>) -> MessageType {
+        timeout(Duration::from_secs(5), async {
+            while let Some(Ok(msg)) = ws_stream.next().await {
+                if let Ok(msg) = Message::try_from(msg) {
+                    return msg.msg_type;
+                }
+            }
+            panic!("connection closed");
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_are_correct() {
+        let room = Room {
+            _id: "firstroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: Some(hash_passwd("password")),
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let mut client = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "user1".into(),
+                addr: None,
+                color: Some(Color::LightRed),
+                away: false,
+                spectator: false,
+            },
+        );
+        client.passwd = Some("password".into());
+        join(&mut client).await;
 
+        let mut sent_msg = TextMessage::new(&client.user.addr.unwrap(), &room._id, "some message");
         client
-            .send_msg(Message::from((
-                UserMsg::Normal {
-                    msg: sended_msg.clone(),
-                },
-                Some(room.passwd.clone().unwrap()),
-            )))
+            .send_msg(Message::from(UserMsg::Normal {
+                msg: sent_msg.clone(),
+            }))
             .await
             .unwrap();
+        // stored with the author the room knows the sender as
+        sent_msg.set_sender(*sent_msg.sender_addr(), client.author());
+        assert_eq!(
+            next_msg(&mut client).await,
+            MessageType::Server(ServerMsg::Ack {
+                timestamp: *sent_msg.timestamp()
+            })
+        );
 
-        let mut client2 = ChatClient::new(room2.clone(), user2.clone());
-        client2.connect().await.unwrap();
+        let mut client2 = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "user2".into(),
+                addr: None,
+                color: Some(Color::LightGreen),
+                away: false,
+                spectator: false,
+            },
+        );
+        client2.passwd = Some("password".into());
+        join(&mut client2).await;
 
-        if let MessageType::User(UserMsg::UserJoined { user: user_ }) =
-            client2.recv_msg().await.unwrap()
-        {
-            user2.addr = user_.addr;
-            peermap.insert(user.addr.unwrap(), user.clone()).unwrap();
-        }
+        assert_eq!(
+            next_msg(&mut client).await,
+            MessageType::User(UserMsg::UserJoined {
+                user: client2.user.clone()
+            })
+        );
 
         client2.sync().await.unwrap();
-
         if let MessageType::Server(ServerMsg::Sync { messages, users }) =
-            client2.recv_msg().await.unwrap()
+            next_msg(&mut client2).await
         {
-            assert_eq!(messages[0], sended_msg);
-            assert_eq!(users[0], peermap.get(&user.addr.unwrap()).unwrap().clone());
+            assert_eq!(messages, vec![sent_msg.clone()]);
+            assert!(users.contains(&client.user));
+        } else {
+            panic!("expected sync response");
         }
 
+        let mut sent_msg =
+            TextMessage::new(&client2.user.addr.unwrap(), &room._id, "another message");
         client2
-            .send_msg(Message::from((
-                UserMsg::Normal {
-                    msg: sended_msg.clone(),
-                },
-                room2.passwd.clone(),
-            )))
+            .send_msg(Message::from(UserMsg::Normal {
+                msg: sent_msg.clone(),
+            }))
             .await
             .unwrap();
+        sent_msg.set_sender(client2.user.addr.unwrap(), client2.author());
+        assert_eq!(
+            next_msg(&mut client).await,
+            MessageType::User(UserMsg::Normal {
+                msg: sent_msg.clone()
+            })
+        );
+        assert_eq!(
+            next_msg(&mut client2).await,
+            MessageType::Server(ServerMsg::Ack {
+                timestamp: *sent_msg.timestamp()
+            })
+        );
 
-        if let MessageType::User(UserMsg::Normal { msg }) = client.recv_msg().await.unwrap() {
-            assert_eq!(msg, sended_msg);
-        }
+        server.stop();
+        assert_eq!(
+            next_msg(&mut client).await,
+            MessageType::Server(ServerMsg::ServerShutdown)
+        );
 
-        client2
-            .send_msg(Message::from((
-                UserMsg::Normal {
-                    msg: sended_msg.clone(),
-                },
-                None,
-            )))
+        client.close_connection();
+        client2.close_connection();
+    }
+
+    #[tokio::test]
+    async fn banned_ips_are_refused() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![SocketAddr::from_str("127.0.0.2:4000").unwrap()],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        assert!(connect_from("127.0.0.2", room.addr).await.is_err());
+
+        let mut owner = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "owner".into(),
+                addr: None,
+                color: Some(Color::LightRed),
+                away: false,
+                spectator: false,
+            },
+        );
+        owner.owner_token = Some(server.owner_token().into());
+        join(&mut owner).await;
+
+        let mut peer = connect_from("127.0.0.3", room.addr).await.unwrap();
+        let peer_user = User {
+            _id: "peer".into(),
+            addr: None,
+            color: Some(Color::LightGreen),
+            away: false,
+            spectator: false,
+        };
+        peer.send(Message::from(UserMsg::UserJoined { user: peer_user }).to_ttmessage())
             .await
             .unwrap();
 
+        let peer_addr = loop {
+            if let MessageType::User(UserMsg::UserJoined { user }) = next_msg(&mut owner).await {
+                if user._id == "peer" {
+                    break user.addr.unwrap();
+                }
+            }
+        };
+
+        owner.ban(&peer_addr).await.unwrap();
+        assert_eq!(
+            next_msg(&mut owner).await,
+            MessageType::Server(ServerMsg::BanConfirm { addr: peer_addr })
+        );
+        assert_eq!(
+            next_msg(&mut owner).await,
+            MessageType::Server(ServerMsg::UserLeft { addr: peer_addr })
+        );
+
+        let closed = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(msg)) = peer.next().await {
+                if msg.is_close() {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(closed.is_ok());
+
+        assert!(connect_from("127.0.0.3", room.addr).await.is_err());
+        assert!(connect_from("127.0.0.4", room.addr).await.is_ok());
+
+        // banning someone on the owner's host keeps the owner
+        let mut neighbour = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "neighbour".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        join(&mut neighbour).await;
+        let neighbour_addr = neighbour.user.addr.unwrap();
+        assert_eq!(neighbour_addr.ip(), owner.user.addr.unwrap().ip());
+        owner.ban(&neighbour_addr).await.unwrap();
+        loop {
+            if next_msg(&mut owner).await
+                == MessageType::Server(ServerMsg::UserLeft {
+                    addr: neighbour_addr,
+                })
+            {
+                break;
+            }
+        }
+        owner.sync().await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::Sync { users, .. }) = next_msg(&mut owner).await {
+                assert_eq!(users, vec![owner.user.clone()]);
+                break;
+            }
+        }
+
+        server.stop();
+        owner.close_connection();
+        neighbour.close_connection();
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_ban() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: None,
+            away: false,
+            spectator: false,
+        };
+        let mut owner = ChatClient::new(room.clone(), user("owner"));
+        owner.owner_token = Some(server.owner_token().into());
+        join(&mut owner).await;
+        let mut member = ChatClient::new(room.clone(), user("member"));
+        join(&mut member).await;
+        let mut forger = ChatClient::new(room.clone(), user("forger"));
+        forger.owner_token = Some("sometoken".into());
+        join(&mut forger).await;
+
+        for client in [&mut member, &mut forger] {
+            client.ban(&owner.user.addr.unwrap()).await.unwrap();
+            loop {
+                match next_msg(client).await {
+                    MessageType::Server(ServerMsg::NotOwner) => break,
+                    MessageType::Server(ServerMsg::BanConfirm { .. }) => panic!("banned"),
+                    _ => (),
+                }
+            }
+        }
+
+        // the owner's address isn't banned, so it can still be joined from
+        let mut rejoined = ChatClient::new(room.clone(), user("rejoined"));
+        join(&mut rejoined).await;
+        assert!(!owner.is_disconnected());
+
+        server.stop();
+        for mut client in [owner, member, forger, rejoined] {
+            client.close_connection();
+        }
+    }
+
+    #[tokio::test]
+    async fn password_rooms_encrypt_messages() {
+        let hash = hash_passwd("password");
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: Some(hash.clone()),
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+        let room = Room {
+            passwd: None,
+            ..room
+        };
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+
+        let mut client = ChatClient::new(room.clone(), user("user1"));
+        assert!(matches!(
+            client.connect().await,
+            Err(ClientError::PasswordRequired)
+        ));
+        client.passwd = Some("password".into());
+        join(&mut client).await;
+
+        let mut intruder = ChatClient::new(room.clone(), user("user2"));
+        intruder.passwd = Some("wrongpassword".into());
+        intruder.connect().await.unwrap();
+        assert_eq!(
+            next_msg(&mut intruder).await,
+            MessageType::Server(ServerMsg::AuthFailure)
+        );
+
+        // a proof only answers the challenge it was made for
+        let key = auth_key(&hash).unwrap();
+        let mut replayer = connect_from("127.0.0.5", room.addr).await.unwrap();
+        handshake_challenge(&mut replayer).await;
+        replayer
+            .send(
+                Message::from(UserReqMsg::AuthReq {
+                    proof: auth_proof(&key, "anotherchallenge"),
+                })
+                .to_ttmessage(),
+            )
+            .await
+            .unwrap();
         assert_eq!(
-            client.recv_msg().await.unwrap(),
+            next_server_msg(&mut replayer).await,
             MessageType::Server(ServerMsg::AuthFailure)
         );
 
+        // a peer which knows the stored hash, like the room, but not the password
+        let mut observer = connect_from("127.0.0.5", room.addr).await.unwrap();
+        let challenge = handshake_challenge(&mut observer).await;
+        for msg in [
+            Message::from(UserReqMsg::AuthReq {
+                proof: auth_proof(&key, &challenge),
+            }),
+            Message::from(UserMsg::UserJoined {
+                user: user("user3"),
+            }),
+        ] {
+            observer.send(msg.to_ttmessage()).await.unwrap();
+        }
+
+        let sent_msg = TextMessage::new(&client.user.addr.unwrap(), &room._id, "secret");
         client
-            .send_msg(Message::from((
-                UserReqMsg::BanReq {
-                    addr: user2.addr.unwrap(),
-                },
-                room.passwd,
-            )))
+            .send_msg(Message::from(UserMsg::Normal { msg: sent_msg }))
             .await
             .unwrap();
 
+        let content = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(msg)) = observer.next().await {
+                if let Ok(Message {
+                    msg_type: MessageType::User(UserMsg::Normal { msg }),
+                }) = Message::try_from(msg)
+                {
+                    return msg.content().clone();
+                }
+            }
+            panic!("connection closed");
+        })
+        .await
+        .unwrap();
+
+        assert!(!content.contains("secret"));
+        let salt = passwd_salt(&hash).unwrap();
         assert_eq!(
-            client.recv_msg().await.unwrap(),
-            MessageType::Server(ServerMsg::BanConfirm {
-                addr: user2.addr.unwrap()
-            })
+            decrypt(&derive_key("password", &salt).unwrap(), &content).unwrap(),
+            "secret"
+        );
+
+        // what it can't seal is unreadable to the others, but doesn't disconnect them
+        let forged = "A".repeat(64);
+        let unsealed = TextMessage::new(&client.user.addr.unwrap(), &room._id, &forged);
+        observer
+            .send(Message::from(UserMsg::Normal { msg: unsealed }).to_ttmessage())
+            .await
+            .unwrap();
+        loop {
+            if let MessageType::User(UserMsg::Normal { msg }) = next_msg(&mut client).await {
+                assert_eq!(msg.content(), UNREADABLE_CONTENT);
+                break;
+            }
+        }
+        client.sync().await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::Sync { messages, .. }) =
+                next_msg(&mut client).await
+            {
+                let contents = messages
+                    .iter()
+                    .map(TextMessage::content)
+                    .collect::<Vec<_>>();
+                assert_eq!(contents, ["secret", UNREADABLE_CONTENT]);
+                break;
+            }
+        }
+        assert!(!client.is_disconnected());
+
+        server.stop();
+        client.close_connection();
+    }
+
+    #[tokio::test]
+    async fn client_reconnects_after_dropped_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sent_msg = TextMessage::new(&addr, "someroom", "after reconnect");
+
+        let server_msg = sent_msg.clone();
+        let server = tokio::spawn(async move {
+            for attempt in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws_stream = accept_async(stream).await.unwrap();
+                ws_stream
+                    .send(
+                        Message::from(ServerMsg::Handshake {
+                            salt: None,
+                            topic: None,
+                            challenge: String::new(),
+                        })
+                        .to_ttmessage(),
+                    )
+                    .await
+                    .unwrap();
+
+                if attempt == 0 {
+                    ws_stream.close(None).await.unwrap();
+                } else {
+                    ws_stream
+                        .send(
+                            Message::from(UserMsg::Normal {
+                                msg: server_msg.clone(),
+                            })
+                            .to_ttmessage(),
+                        )
+                        .await
+                        .unwrap();
+                    while let Some(Ok(_)) = ws_stream.next().await {}
+                }
+            }
+        });
+
+        let mut client = ChatClient::new(
+            Room {
+                _id: "someroom".into(),
+                addr,
+                passwd: None,
+                banned_addrs: vec![],
+                is_owner: false,
+                max_users: None,
+                topic: None,
+                created_at: SystemTime::UNIX_EPOCH,
+                host: None,
+                last_joined: None,
+                filter_words: vec![],
+            },
+            User {
+                _id: "user1".into(),
+                addr: None,
+                color: Some(Color::LightRed),
+                away: false,
+                spectator: false,
+            },
         );
+        client.connect().await.unwrap();
 
+        timeout(Duration::from_secs(5), async {
+            while !client.is_disconnected() {
+                client.recv_msg().await;
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // the reconnect runs in the background, polling only checks on it
+        assert!(client.poll_reconnect().is_none());
+        assert!(client.is_reconnecting() && client.is_disconnected());
+        timeout(Duration::from_secs(5), async {
+            loop {
+                match client.poll_reconnect() {
+                    Some(result) => return result.unwrap(),
+                    None => sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!client.is_reconnecting() && !client.is_disconnected());
         assert_eq!(
-            client.recv_msg().await.unwrap(),
-            MessageType::Server(ServerMsg::UserLeft {
-                addr: user2.addr.unwrap(),
+            next_msg(&mut client).await,
+            MessageType::User(UserMsg::Normal { msg: sent_msg })
+        );
+
+        client.close_connection();
+        assert!(!client.is_disconnected());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn other_protocol_versions_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            let mut frame = encode(&Message::from(ServerMsg::Handshake {
+                salt: None,
+                topic: None,
+                challenge: String::new(),
+            }));
+            frame[0] = PROTOCOL_VERSION + 1;
+            ws_stream.send(TtMessage::binary(frame)).await.unwrap();
+            while let Some(Ok(_)) = ws_stream.next().await {}
+        });
+
+        let mut client = ChatClient::new(
+            Room {
+                _id: "someroom".into(),
+                addr,
+                passwd: None,
+                banned_addrs: vec![],
+                is_owner: false,
+                max_users: None,
+                topic: None,
+                created_at: SystemTime::UNIX_EPOCH,
+                host: None,
+                last_joined: None,
+                filter_words: vec![],
+            },
+            User {
+                _id: "user1".into(),
+                addr: None,
+                color: Some(Color::LightRed),
+                away: false,
+                spectator: false,
+            },
+        );
+        assert!(matches!(
+            client.connect().await,
+            Err(ClientError::VersionMismatch { expected, found })
+                if expected == PROTOCOL_VERSION && found == PROTOCOL_VERSION + 1
+        ));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn flooding_clients_are_throttled() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let db = DbRepo::memory_init().unwrap();
+        db.local_data
+            .insert_one(LocalData {
+                default_user_id: "owner".into(),
+                default_room_addr: room.addr,
+                default_color: Color::White,
+                remember_passwords: false,
+                light_mode: false,
+                timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+                bell: false,
+                bell_mentions_only: false,
+                msg_rate: 1,
+                msg_burst: 3,
+                max_msg_len: DEFAULT_MAX_MSG_LEN,
+                ping_interval: DEFAULT_PING_INTERVAL,
+                max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+                away_after: DEFAULT_AWAY_AFTER,
+                timezone: Timezone::Local,
+                ignored: vec![],
+                markdown: false,
+                input_border: None,
+                input_highlight: None,
+                input_placeholder: None,
+                retention_messages: 0,
+                retention_days: 0,
+                theme: None,
+                alt_screen: true,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                mention_prefix: DEFAULT_MENTION_PREFIX,
+                audit_log: false,
+                reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+                max_file_size: DEFAULT_MAX_FILE_SIZE,
+                layout_ratio: DEFAULT_LAYOUT_RATIO,
+            })
+            .unwrap();
+        let mut server = ChatServer::new(room.clone(), Arc::new(Mutex::new(db)))
+            .await
+            .unwrap();
+        server.run().await.unwrap();
+        let room = Room {
+            addr: server.local_addr().unwrap(),
+            ..room
+        };
+
+        let mut flooder = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "flooder".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        join(&mut flooder).await;
+        let mut polite = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "polite".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        join(&mut polite).await;
+
+        let text = |client: &ChatClient, content: &str| {
+            Message::from(UserMsg::Normal {
+                msg: TextMessage::new(&client.user.addr.unwrap(), &room._id, content),
             })
+        };
+
+        for i in 0..10 {
+            let msg = text(&flooder, &format!("flood {}", i));
+            flooder.send_msg(msg).await.unwrap();
+        }
+        polite.send_msg(text(&polite, "hello")).await.unwrap();
+
+        let (mut rate_limited, mut polite_received) = (0, false);
+        while let Ok(msg_type) = timeout(Duration::from_millis(500), next_msg(&mut flooder)).await {
+            match msg_type {
+                MessageType::Server(ServerMsg::RateLimited) => rate_limited += 1,
+                MessageType::User(UserMsg::Normal { msg }) => {
+                    polite_received = msg.content() == "hello"
+                }
+                _ => (),
+            }
+        }
+        assert!(polite_received);
+
+        let mut relayed = 0;
+        while let Ok(msg_type) = timeout(Duration::from_millis(500), next_msg(&mut polite)).await {
+            if let MessageType::User(UserMsg::Normal { .. }) = msg_type {
+                relayed += 1;
+            }
+        }
+        assert!(relayed <= 4, "{} messages got through", relayed);
+        assert_eq!(relayed + rate_limited, 10);
+
+        server.stop();
+        flooder.close_connection();
+        polite.close_connection();
+    }
+
+    #[tokio::test]
+    async fn full_rooms_refuse_users() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: Some(2),
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let mut clients = vec![];
+        for username in ["user1", "user2"] {
+            let mut client = ChatClient::new(
+                room.clone(),
+                User {
+                    _id: username.into(),
+                    addr: None,
+                    color: None,
+                    away: false,
+                    spectator: false,
+                },
+            );
+            join(&mut client).await;
+            clients.push(client);
+        }
+
+        let mut third = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "user3".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
         );
+        assert!(matches!(third.connect().await, Err(ClientError::RoomFull)));
+
+        server.stop();
+        for client in clients.iter_mut() {
+            client.close_connection();
+        }
+    }
+
+    #[tokio::test]
+    async fn kicked_users_can_rejoin() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+
+        let mut owner = ChatClient::new(room.clone(), user("owner"));
+        owner.owner_token = Some(server.owner_token().into());
+        join(&mut owner).await;
+        let mut kicked = ChatClient::new(room.clone(), user("user2"));
+        join(&mut kicked).await;
+        let kicked_addr = kicked.user.addr.unwrap();
+
+        // only the owner kicks
+        kicked.kick("owner").await.unwrap();
+        loop {
+            match next_msg(&mut kicked).await {
+                MessageType::Server(ServerMsg::NotOwner) => break,
+                MessageType::Server(ServerMsg::UserLeft { .. }) => panic!("kicked"),
+                _ => (),
+            }
+        }
+
+        owner.kick("user2").await.unwrap();
+
+        // told before the connection closes
+        let mut was_told = false;
+        timeout(Duration::from_secs(5), async {
+            while !kicked.is_disconnected() {
+                if let Some(MessageType::Server(ServerMsg::Kicked)) = kicked.recv_msg().await {
+                    was_told = true;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(was_told);
+
+        loop {
+            if let MessageType::Server(ServerMsg::UserLeft { addr }) = next_msg(&mut owner).await {
+                assert_eq!(addr, kicked_addr);
+                break;
+            }
+        }
+
+        let mut rejoined = ChatClient::new(room.clone(), user("user2"));
+        join(&mut rejoined).await;
+        assert!(!owner.is_disconnected());
 
         server.stop();
+        owner.close_connection();
+        rejoined.close_connection();
+    }
+
+    #[tokio::test]
+    async fn joins_and_bans_are_audited() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let path = env::temp_dir().join(format!("kioto-audit-{}.log", Uuid::new_v4()));
+        let (server, room) = start_audited_server(room, Some(AuditLog::open(&path).unwrap())).await;
+
+        let mut owner = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "owner".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        owner.owner_token = Some(server.owner_token().into());
+        join(&mut owner).await;
+        let banned_addr = SocketAddr::from_str("10.9.9.9:4000").unwrap();
+        owner.ban(&banned_addr).await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::BanConfirm { .. }) = next_msg(&mut owner).await {
+                break;
+            }
+        }
+        owner.leave().await;
+        sleep(Duration::from_millis(100)).await;
 
+        let content = fs::read_to_string(&path).unwrap();
+        let events = content
+            .lines()
+            .map(|line| line.split_once("] ").unwrap().1)
+            .collect::<Vec<&str>>();
         assert_eq!(
-            client.recv_msg().await.unwrap(),
-            MessageType::Server(ServerMsg::ServerShutdown)
+            events,
+            [
+                format!("join owner {}", owner.user.addr.unwrap()),
+                "ban 10.9.9.9 by owner".into(),
+                format!("leave owner {}", owner.user.addr.unwrap()),
+            ]
         );
 
-        client.close_connection();
+        fs::remove_file(path).unwrap();
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn spectators_only_read_the_room() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let mut spectator = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "watcher".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: true,
+            },
+        );
+        join(&mut spectator).await;
+        let mut member = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "member".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        join(&mut member).await;
+
+        let msg = TextMessage::new(&member.user.addr.unwrap(), &room._id, "hello");
+        member
+            .send_msg(Message::from(UserMsg::Normal { msg }))
+            .await
+            .unwrap();
+        loop {
+            if let MessageType::User(UserMsg::Normal { msg }) = next_msg(&mut spectator).await {
+                assert_eq!(msg.content(), "hello");
+                break;
+            }
+        }
+
+        let msg = TextMessage::new(&spectator.user.addr.unwrap(), &room._id, "psst");
+        spectator
+            .send_msg(Message::from(UserMsg::Normal { msg }))
+            .await
+            .unwrap();
+        loop {
+            match next_msg(&mut spectator).await {
+                MessageType::Server(ServerMsg::ReadOnly) => break,
+                MessageType::Server(ServerMsg::Ack { .. }) => panic!("spectator message accepted"),
+                _ => (),
+            }
+        }
+
+        member.sync().await.unwrap();
+        loop {
+            match next_msg(&mut member).await {
+                MessageType::Server(ServerMsg::Sync { messages, users }) => {
+                    let ids = users
+                        .iter()
+                        .map(|user| user._id.as_str())
+                        .collect::<Vec<_>>();
+                    assert_eq!(ids, ["member"]);
+                    assert_eq!(messages.len(), 1);
+                    break;
+                }
+                MessageType::User(UserMsg::UserJoined { user }) => {
+                    assert!(!user.spectator, "spectator announced");
+                }
+                MessageType::User(UserMsg::Normal { .. }) => panic!("spectator message relayed"),
+                _ => (),
+            }
+        }
+
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn silent_rooms_time_out() {
+        // the connection is queued by the os but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let room = Room {
+            _id: "someroom".into(),
+            addr: listener.local_addr().unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: false,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let mut client = ChatClient::new(
+            room,
+            User {
+                _id: "user".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        client.connect_timeout = Duration::from_millis(200);
+
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            client.connect().await,
+            Err(ClientError::Timeout(after)) if after == Duration::from_millis(200)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn latency_is_measured_with_pings() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let mut client = ChatClient::new(
+            room,
+            User {
+                _id: "pinger".into(),
+                addr: None,
+                color: Some(Color::LightRed),
+                away: false,
+                spectator: false,
+            },
+        );
+        join(&mut client).await;
+
+        let rtt = timeout(Duration::from_secs(2), async {
+            loop {
+                if let Latency::Rtt(rtt) = client.latency() {
+                    return rtt;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(rtt < Duration::from_secs(1));
+
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn received_contents_are_stripped_of_escapes() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let mut sender = ChatClient::new(room.clone(), user("sender"));
+        join(&mut sender).await;
+        let mut reader = ChatClient::new(room.clone(), user("reader"));
+        join(&mut reader).await;
+
+        let msg = TextMessage::new(
+            &sender.user.addr.unwrap(),
+            &room._id,
+            "\x1b[2J\x1b[31mhi\x1b[0m\nthere\x07",
+        );
+        sender
+            .send_msg(Message::from(UserMsg::Normal { msg }))
+            .await
+            .unwrap();
+
+        loop {
+            if let MessageType::User(UserMsg::Normal { msg }) = next_msg(&mut reader).await {
+                assert_eq!(msg.content(), "hi\nthere");
+                break;
+            }
+        }
+
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn taken_names_get_a_suffix() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let mut first = ChatClient::new(room.clone(), user("alice"));
+        join(&mut first).await;
+
+        let mut joined = vec![];
+        for id in ["alice", "Alice"] {
+            let mut client = ChatClient::new(room.clone(), user(id));
+            client.connect().await.unwrap();
+            let renamed = loop {
+                if let MessageType::Server(ServerMsg::Renamed { user_id }) =
+                    next_msg(&mut client).await
+                {
+                    break user_id;
+                }
+            };
+            // then joins under it
+            loop {
+                if let MessageType::User(UserMsg::UserJoined { user }) = next_msg(&mut client).await
+                {
+                    assert_eq!(user._id, renamed);
+                    break;
+                }
+            }
+            client.user._id = renamed;
+            // kept connected so the next one finds the name taken
+            joined.push(client);
+        }
+        let joined_ids = joined
+            .iter()
+            .map(|client| client.user._id.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(joined_ids, ["alice2", "Alice3"]);
+
+        // the first one sees the newcomers under their new names
+        let mut others = vec![];
+        while others.len() < 2 {
+            if let MessageType::User(UserMsg::UserJoined { user }) = next_msg(&mut first).await {
+                others.push(user._id);
+            }
+        }
+        assert_eq!(others, ["alice2", "Alice3"]);
+
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn blank_messages_are_rejected() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let mut sender = ChatClient::new(room.clone(), user("sender"));
+        join(&mut sender).await;
+        let mut reader = ChatClient::new(room.clone(), user("reader"));
+        join(&mut reader).await;
+
+        for content in [" \n ", "hi"] {
+            let msg = TextMessage::new(&sender.user.addr.unwrap(), &room._id, content);
+            sender
+                .send_msg(Message::from(UserMsg::Normal { msg }))
+                .await
+                .unwrap();
+        }
+
+        let mut replies = vec![];
+        while replies.len() < 2 {
+            match next_msg(&mut sender).await {
+                MessageType::Server(ServerMsg::EmptyMessage) => replies.push("empty"),
+                MessageType::Server(ServerMsg::Ack { .. }) => replies.push("ack"),
+                _ => (),
+            }
+        }
+        assert_eq!(replies, ["empty", "ack"]);
+
+        loop {
+            if let MessageType::User(UserMsg::Normal { msg }) = next_msg(&mut reader).await {
+                assert_eq!(msg.content(), "hi");
+                break;
+            }
+        }
+
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn files_are_relayed_sealed_within_the_size_limit() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: Some(hash_passwd("password")),
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let mut sender = ChatClient::new(room.clone(), user("sender"));
+        sender.passwd = Some("password".into());
+        join(&mut sender).await;
+        let mut reader = ChatClient::new(room.clone(), user("reader"));
+        reader.passwd = Some("password".into());
+        join(&mut reader).await;
+
+        let mut too_large = split("big.bin", b"tiny", u64::MAX).unwrap();
+        too_large[0].size = DEFAULT_MAX_FILE_SIZE as u64 + 1;
+        sender.send_file(too_large).await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::FileTooLarge { max_size }) =
+                next_msg(&mut sender).await
+            {
+                assert_eq!(max_size, DEFAULT_MAX_FILE_SIZE as u64);
+                break;
+            }
+        }
+
+        let data = (0..CHUNK_SIZE * 2).map(|i| i as u8).collect::<Vec<u8>>();
+        let chunks = split("notes.txt", &data, u64::MAX).unwrap();
+        sender.send_file(chunks).await.unwrap();
+
+        let mut file = None;
+        loop {
+            if let MessageType::User(UserMsg::File { addr, chunk }) = next_msg(&mut reader).await {
+                assert_eq!(Some(addr), sender.user.addr);
+                assert_eq!(chunk.name, "notes.txt");
+                let file = file.get_or_insert_with(|| IncomingFile::new(&chunk, u64::MAX).unwrap());
+                if file.add(&chunk).unwrap() {
+                    break;
+                }
+            }
+        }
+        assert_eq!(file.unwrap().assemble().unwrap(), data);
+
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn filtered_words_are_redacted_before_relaying() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec!["heck".into()],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let mut sender = ChatClient::new(room.clone(), user("sender"));
+        join(&mut sender).await;
+        let mut reader = ChatClient::new(room.clone(), user("reader"));
+        join(&mut reader).await;
+
+        for content in ["what the Heck is this", "hecking clean"] {
+            let msg = TextMessage::new(&sender.user.addr.unwrap(), &room._id, content);
+            sender
+                .send_msg(Message::from(UserMsg::Normal { msg }))
+                .await
+                .unwrap();
+        }
+
+        let mut relayed = vec![];
+        while relayed.len() < 2 {
+            if let MessageType::User(UserMsg::Normal { msg }) = next_msg(&mut reader).await {
+                relayed.push(msg.content().clone());
+            }
+        }
+        assert_eq!(relayed, ["what the **** is this", "hecking clean"]);
+
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn only_authors_can_edit_their_messages() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let mut author = ChatClient::new(room.clone(), user("author"));
+        join(&mut author).await;
+        let mut other = ChatClient::new(room.clone(), user("other"));
+        join(&mut other).await;
+
+        let sent_msg = TextMessage::new(&author.user.addr.unwrap(), &room._id, "tpyo");
+        let id = sent_msg.id().clone();
+        author
+            .send_msg(Message::from(UserMsg::Normal { msg: sent_msg }))
+            .await
+            .unwrap();
+
+        let edit = |content: &str| {
+            Message::from(UserMsg::Edit {
+                id: id.clone(),
+                content: content.into(),
+            })
+        };
+
+        author.send_msg(edit("typo")).await.unwrap();
+        loop {
+            if let MessageType::User(UserMsg::Edit {
+                id: edited,
+                content,
+            }) = next_msg(&mut other).await
+            {
+                assert_eq!((edited, content), (id.clone(), "typo".to_string()));
+                break;
+            }
+        }
+
+        other.send_msg(edit("spoofed")).await.unwrap();
+        other
+            .send_msg(Message::from(UserMsg::Delete { id: id.clone() }))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            loop {
+                if let MessageType::Server(ServerMsg::EditRejected { id: rejected }) =
+                    next_msg(&mut other).await
+                {
+                    assert_eq!(rejected, id);
+                    break;
+                }
+            }
+        }
+
+        other.sync().await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::Sync { messages, .. }) =
+                next_msg(&mut other).await
+            {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].content(), "typo");
+                assert!(messages[0].is_edited());
+                break;
+            }
+        }
+
+        author
+            .send_msg(Message::from(UserMsg::Delete { id: id.clone() }))
+            .await
+            .unwrap();
+        loop {
+            if let MessageType::User(UserMsg::Delete { id: deleted }) = next_msg(&mut other).await {
+                assert_eq!(deleted, id);
+                break;
+            }
+        }
+
+        server.stop();
+        author.close_connection();
+        other.close_connection();
+    }
+
+    #[tokio::test]
+    async fn rooms_sharing_a_database_keep_their_messages() {
+        let db = Arc::new(Mutex::new(DbRepo::memory_init().unwrap()));
+        let host = |id: &str| Room {
+            _id: id.into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let mut first_server = ChatServer::new(host("first"), db.clone()).await.unwrap();
+        first_server.run().await.unwrap();
+        let mut second_server = ChatServer::new(host("second"), db).await.unwrap();
+        second_server.run().await.unwrap();
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let room = |id: &str, server: &ChatServer| Room {
+            addr: server.local_addr().unwrap(),
+            is_owner: false,
+            ..host(id)
+        };
+        let mut author = ChatClient::new(room("first", &first_server), user("author"));
+        join(&mut author).await;
+        let mut intruder = ChatClient::new(room("second", &second_server), user("intruder"));
+        join(&mut intruder).await;
+        let mut other = ChatClient::new(room("second", &second_server), user("other"));
+        join(&mut other).await;
+
+        let sent_msg = TextMessage::new(&author.user.addr.unwrap(), "first", "original");
+        let id = sent_msg.id().clone();
+        author
+            .send_msg(Message::from(UserMsg::Normal { msg: sent_msg }))
+            .await
+            .unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::Ack { .. }) = next_msg(&mut author).await {
+                break;
+            }
+        }
+
+        // a taken id is refused, a claimed room is replaced with the hosted one
+        let mut reused = serde_json::to_value(TextMessage::new(
+            &intruder.user.addr.unwrap(),
+            "first",
+            "hijacked",
+        ))
+        .unwrap();
+        reused["id"] = id.clone().into();
+        let reused: TextMessage = serde_json::from_value(reused).unwrap();
+        intruder
+            .send_msg(Message::from(UserMsg::Normal { msg: reused }))
+            .await
+            .unwrap();
+        intruder
+            .send_msg(Message::from(UserMsg::Normal {
+                msg: TextMessage::new(&intruder.user.addr.unwrap(), "first", "fresh"),
+            }))
+            .await
+            .unwrap();
+        loop {
+            if let MessageType::User(UserMsg::Normal { msg }) = next_msg(&mut other).await {
+                assert_eq!(msg.content(), "fresh");
+                assert_eq!(msg.room_id(), "second");
+                break;
+            }
+        }
+
+        for (client, content) in [(&mut author, "original"), (&mut other, "fresh")] {
+            client.sync().await.unwrap();
+            loop {
+                if let MessageType::Server(ServerMsg::Sync { messages, .. }) =
+                    next_msg(client).await
+                {
+                    let contents: Vec<_> = messages.iter().map(|msg| msg.content()).collect();
+                    assert_eq!(contents, [content]);
+                    break;
+                }
+            }
+        }
+
+        first_server.stop();
+        second_server.stop();
+        author.close_connection();
+        intruder.close_connection();
+        other.close_connection();
+    }
+
+    #[tokio::test]
+    async fn authors_keep_their_messages_after_reconnecting() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let mut author = ChatClient::new(room.clone(), user("author"));
+        join(&mut author).await;
+        let mut other = ChatClient::new(room.clone(), user("other"));
+        join(&mut other).await;
+
+        // claiming another sender changes nothing
+        let sent_msg = TextMessage::new(&other.user.addr.unwrap(), &room._id, "tpyo");
+        let id = sent_msg.id().clone();
+        author
+            .send_msg(Message::from(UserMsg::Normal { msg: sent_msg }))
+            .await
+            .unwrap();
+        loop {
+            if let MessageType::User(UserMsg::Normal { msg }) = next_msg(&mut other).await {
+                assert_eq!(msg.sender_addr(), &author.user.addr.unwrap());
+                assert_eq!(msg.author(), author.author().as_ref());
+                break;
+            }
+        }
+
+        // the new connection has another port, the session stays the same
+        let old_addr = author.user.addr;
+        author.close_connection();
+        join(&mut author).await;
+        assert_ne!(author.user.addr, old_addr);
+
+        author
+            .send_msg(Message::from(UserMsg::Edit {
+                id: id.clone(),
+                content: "typo".into(),
+            }))
+            .await
+            .unwrap();
+        loop {
+            match next_msg(&mut author).await {
+                MessageType::User(UserMsg::Edit {
+                    id: edited,
+                    content,
+                }) => {
+                    assert_eq!((edited, content), (id.clone(), "typo".to_string()));
+                    break;
+                }
+                MessageType::Server(ServerMsg::EditRejected { .. }) => panic!("rejected"),
+                _ => (),
+            }
+        }
+
+        server.stop();
+        author.close_connection();
+        other.close_connection();
+    }
+
+    #[tokio::test]
+    async fn leaving_users_are_removed_at_once() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+
+        let mut staying = ChatClient::new(room.clone(), user("user1"));
+        join(&mut staying).await;
+        let mut leaving = ChatClient::new(room.clone(), user("user2"));
+        join(&mut leaving).await;
+        let leaving_addr = leaving.user.addr.unwrap();
+
+        // well within the keepalive timeout
+        leaving.leave().await;
+        assert!(!leaving.is_disconnected());
+        loop {
+            if let MessageType::Server(ServerMsg::UserLeft { addr }) = next_msg(&mut staying).await
+            {
+                assert_eq!(addr, leaving_addr);
+                break;
+            }
+        }
+
+        staying.sync().await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::Sync { users, .. }) = next_msg(&mut staying).await
+            {
+                assert_eq!(users, vec![staying.user.clone()]);
+                break;
+            }
+        }
+
+        server.stop();
+        staying.close_connection();
+    }
+
+    #[tokio::test]
+    async fn frames_after_leaving_are_ignored() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let user = |id: &str| User {
+            _id: id.into(),
+            addr: None,
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+
+        let mut leaving = connect_from("127.0.0.1", room.addr).await.unwrap();
+        handshake_challenge(&mut leaving).await;
+        let addr = SocketAddr::from_str("127.0.0.1:4000").unwrap();
+        for msg_type in [
+            MessageType::User(UserMsg::UserJoined {
+                user: user("leaving"),
+            }),
+            MessageType::UserReq(UserReqMsg::LeaveReq),
+            MessageType::User(UserMsg::Normal {
+                msg: TextMessage::new(&addr, &room._id, "after leaving"),
+            }),
+            MessageType::UserReq(UserReqMsg::SyncReq { history: None }),
+        ] {
+            // the connection may be closed before the last ones are sent
+            let _ = leaving.send(Message { msg_type }.to_ttmessage()).await;
+        }
+        while let Ok(Some(Ok(_))) = timeout(Duration::from_secs(5), leaving.next()).await {}
+
+        // the room still takes new users
+        let mut joining = ChatClient::new(room.clone(), user("joining"));
+        join(&mut joining).await;
+        joining.sync().await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::Sync { messages, users }) =
+                next_msg(&mut joining).await
+            {
+                assert!(messages.is_empty());
+                assert_eq!(users, vec![joining.user.clone()]);
+                break;
+            }
+        }
+
+        server.stop();
+        joining.close_connection();
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_connections_are_kept_out() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: Some(hash_passwd("password")),
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: Some(1),
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let db = DbRepo::memory_init().unwrap();
+        db.local_data
+            .insert_one(LocalData {
+                default_user_id: "owner".into(),
+                default_room_addr: room.addr,
+                default_color: Color::White,
+                remember_passwords: false,
+                light_mode: false,
+                timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+                bell: false,
+                bell_mentions_only: false,
+                msg_rate: DEFAULT_MSG_RATE,
+                msg_burst: DEFAULT_MSG_BURST,
+                max_msg_len: DEFAULT_MAX_MSG_LEN,
+                ping_interval: 1,
+                max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+                away_after: DEFAULT_AWAY_AFTER,
+                timezone: Timezone::Local,
+                ignored: vec![],
+                markdown: false,
+                input_border: None,
+                input_highlight: None,
+                input_placeholder: None,
+                retention_messages: 0,
+                retention_days: 0,
+                theme: None,
+                alt_screen: true,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                mention_prefix: DEFAULT_MENTION_PREFIX,
+                audit_log: false,
+                reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+                max_file_size: DEFAULT_MAX_FILE_SIZE,
+                layout_ratio: DEFAULT_LAYOUT_RATIO,
+            })
+            .unwrap();
+        let mut server = ChatServer::new(room.clone(), Arc::new(Mutex::new(db)))
+            .await
+            .unwrap();
+        server.run().await.unwrap();
+        let room = Room {
+            addr: server.local_addr().unwrap(),
+            passwd: None,
+            ..room
+        };
+
+        // never answers the challenge
+        let mut silent = connect_from("127.0.0.1", room.addr).await.unwrap();
+        handshake_challenge(&mut silent).await;
+
+        // the only place of the room is still free
+        let mut member = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "member".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        member.passwd = Some("password".into());
+        join(&mut member).await;
+
+        // nothing is broadcast to it, and it's dropped after a ping interval
+        assert_eq!(
+            next_server_msg(&mut silent).await,
+            MessageType::Server(ServerMsg::AuthFailure)
+        );
+        assert!(timeout(Duration::from_secs(5), silent.next())
+            .await
+            .unwrap()
+            .is_none_or(|msg| msg.is_err() || msg.is_ok_and(|msg| msg.is_close())));
+
+        server.stop();
+        member.close_connection();
+    }
+
+    #[tokio::test]
+    async fn unresponsive_clients_are_dropped() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let db = DbRepo::memory_init().unwrap();
+        db.local_data
+            .insert_one(LocalData {
+                default_user_id: "owner".into(),
+                default_room_addr: room.addr,
+                default_color: Color::White,
+                remember_passwords: false,
+                light_mode: false,
+                timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+                bell: false,
+                bell_mentions_only: false,
+                msg_rate: DEFAULT_MSG_RATE,
+                msg_burst: DEFAULT_MSG_BURST,
+                max_msg_len: DEFAULT_MAX_MSG_LEN,
+                ping_interval: 1,
+                max_missed_pongs: 1,
+                away_after: DEFAULT_AWAY_AFTER,
+                timezone: Timezone::Local,
+                ignored: vec![],
+                markdown: false,
+                input_border: None,
+                input_highlight: None,
+                input_placeholder: None,
+                retention_messages: 0,
+                retention_days: 0,
+                theme: None,
+                alt_screen: true,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                mention_prefix: DEFAULT_MENTION_PREFIX,
+                audit_log: false,
+                reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+                max_file_size: DEFAULT_MAX_FILE_SIZE,
+                layout_ratio: DEFAULT_LAYOUT_RATIO,
+            })
+            .unwrap();
+        let mut server = ChatServer::new(room.clone(), Arc::new(Mutex::new(db)))
+            .await
+            .unwrap();
+        server.run().await.unwrap();
+        let room = Room {
+            addr: server.local_addr().unwrap(),
+            ..room
+        };
+
+        let mut observer = ChatClient::new(
+            room.clone(),
+            User {
+                _id: "observer".into(),
+                addr: None,
+                color: None,
+                away: false,
+                spectator: false,
+            },
+        );
+        join(&mut observer).await;
+
+        // joins and then never reads, so the pings stay unanswered
+        let mut sleeper = connect_from("127.0.0.1", room.addr).await.unwrap();
+        sleeper
+            .send(
+                Message::from(UserMsg::UserJoined {
+                    user: User {
+                        _id: "sleeper".into(),
+                        addr: None,
+                        color: None,
+                        away: false,
+                        spectator: false,
+                    },
+                })
+                .to_ttmessage(),
+            )
+            .await
+            .unwrap();
+
+        let sleeper_addr = loop {
+            if let MessageType::User(UserMsg::UserJoined { user }) = next_msg(&mut observer).await {
+                if user._id == "sleeper" {
+                    break user.addr.unwrap();
+                }
+            }
+        };
+        loop {
+            if let MessageType::Server(ServerMsg::UserLeft { addr }) = next_msg(&mut observer).await
+            {
+                assert_eq!(addr, sleeper_addr);
+                break;
+            }
+        }
+
+        // the observer answered the pings all along
+        observer.sync().await.unwrap();
+        loop {
+            if let MessageType::Server(ServerMsg::Sync { users, .. }) =
+                next_msg(&mut observer).await
+            {
+                let ids = users
+                    .iter()
+                    .map(|user| user._id.as_str())
+                    .collect::<Vec<_>>();
+                assert_eq!(ids, ["observer"]);
+                break;
+            }
+        }
+
+        server.stop();
+        observer.close_connection();
+    }
+
+    #[tokio::test]
+    async fn server_assigns_distinct_colors() {
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: true,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let (server, room) = start_server(room).await;
+
+        let mut clients = vec![];
+        for username in ["user1", "user2", "user3"] {
+            let mut client = ChatClient::new(
+                room.clone(),
+                User {
+                    _id: username.into(),
+                    addr: None,
+                    color: None,
+                    away: false,
+                    spectator: false,
+                },
+            );
+            client.connect().await.unwrap();
+            let color = loop {
+                if let MessageType::User(UserMsg::UserJoined { user }) = next_msg(&mut client).await
+                {
+                    if user._id == username {
+                        break user.color;
+                    }
+                }
+            };
+            client.user.color = color;
+            clients.push(client);
+        }
+
+        let colors = clients
+            .iter()
+            .map(|client| client.user.color.clone().unwrap())
+            .collect::<Vec<Color>>();
+        assert!(colors.iter().all(|color| Color::ASSIGNABLE.contains(color)));
+        assert_ne!(colors[0], colors[1]);
+        assert_ne!(colors[0], colors[2]);
+        assert_ne!(colors[1], colors[2]);
+
+        clients[2].sync().await.unwrap();
+        if let MessageType::Server(ServerMsg::Sync { users, .. }) = next_msg(&mut clients[2]).await
+        {
+            for client in &clients {
+                assert!(users
+                    .iter()
+                    .any(|user| user._id == client.user._id && user.color == client.user.color));
+            }
+        } else {
+            panic!("expected sync response");
+        }
+
+        server.stop();
     }
 }
@@ -0,0 +1,198 @@
+use super::message::Message;
+use thiserror::Error;
+
+/// Bumped on every incompatible change of the message format.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Version byte followed by the big-endian payload length.
+const HEADER_LEN: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("Protocol version mismatch, expected {expected} but got {found}.")]
+    VersionMismatch { expected: u8, found: u8 },
+    #[error("Frame is truncated.")]
+    Truncated,
+    #[error("Frame length {declared} doesn't match the payload length {actual}.")]
+    LengthMismatch { declared: usize, actual: usize },
+    #[error("Unexpected frame type.")]
+    UnexpectedFrame,
+    #[error("{0}")]
+    Malformed(serde_json::Error),
+}
+
+impl From<serde_json::Error> for ProtocolError {
+    fn from(value: serde_json::Error) -> Self {
+        ProtocolError::Malformed(value)
+    }
+}
+
+pub fn encode(msg: &Message) -> Vec<u8> {
+    let payload = serde_json::to_vec(msg).unwrap();
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.push(PROTOCOL_VERSION);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+pub fn decode(frame: &[u8]) -> Result<Message, ProtocolError> {
+    if frame.len() < HEADER_LEN {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let (header, payload) = frame.split_at(HEADER_LEN);
+    if header[0] != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            found: header[0],
+        });
+    }
+
+    let declared = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    if declared != payload.len() {
+        return Err(ProtocolError::LengthMismatch {
+            declared,
+            actual: payload.len(),
+        });
+    }
+
+    Ok(serde_json::from_slice(payload)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode, ProtocolError, PROTOCOL_VERSION};
+    use crate::{
+        network::{
+            message::{Message, MessageType, ServerMsg, UserMsg, UserReqMsg},
+            transfer::split,
+            User,
+        },
+        schema::{Color, TextMessage},
+    };
+    use std::{net::SocketAddr, str::FromStr, time::SystemTime};
+
+    fn all_variants() -> Vec<MessageType> {
+        let addr = SocketAddr::from_str("127.0.0.1:4000").unwrap();
+        let user = User {
+            _id: "user1".into(),
+            addr: Some(addr),
+            color: Some(Color::LightRed),
+            away: false,
+            spectator: false,
+        };
+        let text_msg = TextMessage::new(&addr, "someroom", "some message");
+
+        vec![
+            MessageType::User(UserMsg::Normal {
+                msg: text_msg.clone(),
+            }),
+            MessageType::User(UserMsg::UserJoined { user: user.clone() }),
+            MessageType::User(UserMsg::Typing {
+                addr,
+                is_typing: true,
+            }),
+            MessageType::User(UserMsg::Status { addr, away: true }),
+            MessageType::User(UserMsg::Edit {
+                id: "someid".into(),
+                content: "fixed".into(),
+            }),
+            MessageType::User(UserMsg::Delete {
+                id: "someid".into(),
+            }),
+            MessageType::User(UserMsg::File {
+                addr,
+                chunk: split("notes.txt", b"some notes", 100).unwrap().remove(0),
+            }),
+            MessageType::UserReq(UserReqMsg::AuthReq {
+                proof: "someproof".into(),
+            }),
+            MessageType::UserReq(UserReqMsg::SyncReq { history: Some(10) }),
+            MessageType::UserReq(UserReqMsg::PageReq {
+                offset: 100,
+                limit: 50,
+            }),
+            MessageType::UserReq(UserReqMsg::OwnerReq {
+                proof: "someproof".into(),
+            }),
+            MessageType::UserReq(UserReqMsg::ResumeReq {
+                token: "sometoken".into(),
+            }),
+            MessageType::UserReq(UserReqMsg::BanReq { addr }),
+            MessageType::UserReq(UserReqMsg::KickReq {
+                user_id: "user1".into(),
+            }),
+            MessageType::UserReq(UserReqMsg::LeaveReq),
+            MessageType::Server(ServerMsg::Handshake {
+                salt: None,
+                topic: None,
+                challenge: "somechallenge".into(),
+            }),
+            MessageType::Server(ServerMsg::Handshake {
+                salt: Some("somesalt".into()),
+                topic: Some("some topic".into()),
+                challenge: "somechallenge".into(),
+            }),
+            MessageType::Server(ServerMsg::AuthFailure),
+            MessageType::Server(ServerMsg::RoomFull),
+            MessageType::Server(ServerMsg::RateLimited),
+            MessageType::Server(ServerMsg::EmptyMessage),
+            MessageType::Server(ServerMsg::ReadOnly),
+            MessageType::Server(ServerMsg::NotOwner),
+            MessageType::Server(ServerMsg::Kicked),
+            MessageType::Server(ServerMsg::Session {
+                token: "sometoken".into(),
+            }),
+            MessageType::Server(ServerMsg::Renamed {
+                user_id: "alice2".into(),
+            }),
+            MessageType::Server(ServerMsg::MessageTooLong { max_len: 2000 }),
+            MessageType::Server(ServerMsg::FileTooLarge { max_size: 1024 }),
+            MessageType::Server(ServerMsg::Ack {
+                timestamp: SystemTime::UNIX_EPOCH,
+            }),
+            MessageType::Server(ServerMsg::EditRejected {
+                id: "someid".into(),
+            }),
+            MessageType::Server(ServerMsg::Sync {
+                messages: vec![text_msg.clone()],
+                users: vec![user],
+            }),
+            MessageType::Server(ServerMsg::Page {
+                offset: 100,
+                messages: vec![text_msg],
+            }),
+            MessageType::Server(ServerMsg::UserLeft { addr }),
+            MessageType::Server(ServerMsg::BanConfirm { addr }),
+            MessageType::Server(ServerMsg::ServerShutdown),
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for msg_type in all_variants() {
+            let msg = Message::from(msg_type);
+            assert_eq!(decode(&encode(&msg)).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn mismatched_frames_are_rejected() {
+        let mut frame = encode(&Message::from(ServerMsg::AuthFailure));
+
+        assert!(matches!(decode(&frame[..3]), Err(ProtocolError::Truncated)));
+        assert!(matches!(
+            decode(&frame[..frame.len() - 1]),
+            Err(ProtocolError::LengthMismatch { .. })
+        ));
+
+        frame[0] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            decode(&frame),
+            Err(ProtocolError::VersionMismatch { expected, found })
+                if expected == PROTOCOL_VERSION && found == PROTOCOL_VERSION + 1
+        ));
+    }
+}
@@ -1,45 +1,133 @@
 use super::{
+    audit::{AuditEvent, AuditLog},
+    crypto::{auth_key, author_key, challenge, passwd_salt, plain_len, sealed_len, verify_proof},
     message::{Message, MessageType, ServerMsg, UserMsg, UserReqMsg},
+    protocol::ProtocolError,
+    transfer::MAX_CHUNK_DATA,
     User,
 };
 use crate::{
-    db::DbRepo,
-    schema::{Room, TextMessage},
+    db::{DbRepo, Retention},
+    schema::{Color, Room},
+    util::{
+        DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_MISSED_PONGS, DEFAULT_MAX_MSG_LEN, DEFAULT_MSG_BURST,
+        DEFAULT_MSG_RATE, DEFAULT_PING_INTERVAL,
+    },
 };
 use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, StreamExt, TryStreamExt};
+use futures_util::{
+    future::{self, Either},
+    pin_mut, SinkExt, StreamExt, TryStreamExt,
+};
+use polodb_core::bson::{doc, to_bson};
+use regex::{Captures, Regex};
 use std::{
-    collections::HashMap,
-    io,
+    collections::{HashMap, HashSet},
+    io, iter,
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
 };
 use tokio::{
     net::{TcpListener, TcpStream},
-    task::JoinHandle,
+    signal,
+    task::{AbortHandle, JoinHandle},
+    time::{self, timeout, Duration, Instant},
 };
 use tokio_tungstenite::{
     accept_async,
     tungstenite::{Error as TtError, Message as TtMessage},
 };
 
+/// How often a running room drops the stored messages past the retention limits.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
 type Tx = UnboundedSender<TtMessage>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, (Tx, Option<User>)>>>;
+type PeerMap = Arc<Mutex<HashMap<SocketAddr, Peer>>>;
+
+/// A connection of the room.
+struct Peer {
+    tx: Tx,
+    /// Known once the connection introduced itself.
+    user: Option<User>,
+    /// Proved holding the owner token, allowed to ban and kick.
+    is_owner: bool,
+    /// Author key of the session, which outlives the connection.
+    author: Option<String>,
+}
+
+impl Peer {
+    fn new(tx: Tx) -> Self {
+        Self {
+            tx,
+            user: None,
+            is_owner: false,
+            author: None,
+        }
+    }
+}
 
 pub struct ChatServer {
     room: Arc<Mutex<Room>>,
     peer_map: PeerMap,
     event_loop_handle: Option<JoinHandle<()>>,
+    local_addr: Option<SocketAddr>,
+    limits: ConnectionLimits,
+    retention: Retention,
     db: Arc<Mutex<DbRepo>>,
+    /// Handed to the hosting client only, proving ownership of the room.
+    owner_token: String,
+    /// Records joins, leaves, bans and kicks when set.
+    pub audit: Option<AuditLog>,
+}
+
+/// Settings every connection starts with.
+#[derive(Debug, Clone)]
+struct ConnectionLimits {
+    msg_bucket: TokenBucket,
+    max_msg_len: u32,
+    max_file_size: u64,
+    ping_interval: Duration,
+    max_missed_pongs: u32,
 }
 
 impl ChatServer {
     pub async fn new(room: Room, db: Arc<Mutex<DbRepo>>) -> io::Result<Self> {
+        let local_data = db.lock().unwrap().local_data.find_one(None);
+        let retention = match &local_data {
+            Ok(Some(local_data)) => Retention::from(local_data),
+            _ => Retention::default(),
+        };
+        let limits = match local_data {
+            Ok(Some(local_data)) => ConnectionLimits {
+                msg_bucket: TokenBucket::new(local_data.msg_rate, local_data.msg_burst),
+                max_msg_len: local_data.max_msg_len,
+                max_file_size: local_data.max_file_size.into(),
+                ping_interval: Duration::from_secs(local_data.ping_interval.into()),
+                max_missed_pongs: local_data.max_missed_pongs,
+            },
+            _ => ConnectionLimits {
+                msg_bucket: TokenBucket::new(DEFAULT_MSG_RATE, DEFAULT_MSG_BURST),
+                max_msg_len: DEFAULT_MAX_MSG_LEN,
+                max_file_size: DEFAULT_MAX_FILE_SIZE.into(),
+                ping_interval: Duration::from_secs(DEFAULT_PING_INTERVAL.into()),
+                max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+            },
+        };
+
         Ok(Self {
             peer_map: PeerMap::new(Mutex::new(HashMap::new())),
             room: Arc::new(Mutex::new(room)),
             event_loop_handle: None,
+            local_addr: None,
+            limits,
+            retention,
             db,
+            owner_token: challenge(),
+            audit: None,
         })
     }
 
@@ -47,18 +135,45 @@ impl ChatServer {
         let peer_map = self.peer_map.clone();
         let room = self.room.clone();
         let db = self.db.clone();
+        let limits = self.limits.clone();
+        let retention = self.retention;
+        let audit = self.audit.clone();
+        let owner_token = self.owner_token.clone();
         let addr = self.room.lock().unwrap().addr;
 
+        let listener = TcpListener::bind(&addr).await?;
+        self.local_addr = Some(listener.local_addr()?);
+
         let joinhandle = tokio::spawn(async move {
-            let listener = TcpListener::bind(&addr).await.unwrap();
+            // pruned on startup already
+            let mut prune = time::interval_at(Instant::now() + PRUNE_INTERVAL, PRUNE_INTERVAL);
+            loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(_) => return,
+                    },
+                    _ = prune.tick() => {
+                        let pruned = db.lock().unwrap().prune_messages(retention, SystemTime::now());
+                        if let Err(err) = pruned {
+                            log::warn!("Failed to prune the stored messages: {}", err);
+                        }
+                        continue;
+                    }
+                };
+                if room.lock().unwrap().is_banned(&addr) {
+                    continue;
+                }
 
-            while let Ok((stream, addr)) = listener.accept().await {
                 tokio::spawn(Self::handle_conection(
                     peer_map.clone(),
                     stream,
                     addr,
                     room.clone(),
                     db.clone(),
+                    limits.clone(),
+                    owner_token.clone(),
+                    audit.clone(),
                 ));
                 tokio::task::yield_now().await;
             }
@@ -69,59 +184,290 @@ impl ChatServer {
         Ok(())
     }
 
+    pub fn owner_token(&self) -> &str {
+        &self.owner_token
+    }
+
+    /// Address the server is listening on, known once it runs.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
     pub fn stop(&self) {
-        Self::send_to_all(
-            Message::from((ServerMsg::ServerShutdown, None)),
+        Self::shutdown(
             self.peer_map.clone(),
-            None,
+            self.event_loop_handle
+                .as_ref()
+                .map(JoinHandle::abort_handle),
         );
+    }
 
-        if let Some(joinhandle) = &self.event_loop_handle {
-            joinhandle.abort();
+    /// Closes the room for everyone once the process is interrupted.
+    pub fn stop_on_interrupt(&self) {
+        let peer_map = self.peer_map.clone();
+        let accept_loop = self
+            .event_loop_handle
+            .as_ref()
+            .map(JoinHandle::abort_handle);
+        tokio::spawn(async move {
+            if signal::ctrl_c().await.is_ok() {
+                log::info!("Interrupted, closing the room");
+                Self::shutdown(peer_map, accept_loop);
+            }
+        });
+    }
+
+    fn shutdown(peer_map: PeerMap, accept_loop: Option<AbortHandle>) {
+        Self::send_to_all(Message::from(ServerMsg::ServerShutdown), peer_map, None);
+
+        if let Some(accept_loop) = accept_loop {
+            accept_loop.abort();
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_conection(
         peer_map: PeerMap,
         stream: TcpStream,
         addr: SocketAddr,
         room: Arc<Mutex<Room>>,
         db: Arc<Mutex<DbRepo>>,
+        limits: ConnectionLimits,
+        owner_token: String,
+        audit: Option<AuditLog>,
     ) -> Result<(), TtError> {
-        let ws_stream = accept_async(stream).await?;
+        let ConnectionLimits {
+            mut msg_bucket,
+            max_msg_len,
+            max_file_size,
+            ping_interval,
+            max_missed_pongs,
+        } = limits;
+        let mut ws_stream = accept_async(stream).await?;
+
+        let (salt, key, topic, max_users) = {
+            let room = room.lock().unwrap();
+            (
+                room.passwd.as_deref().and_then(passwd_salt),
+                room.passwd.as_deref().and_then(auth_key),
+                room.topic.clone(),
+                room.max_users,
+            )
+        };
+        let is_sealed = salt.is_some();
+        let is_full = |peers: &HashMap<SocketAddr, Peer>| {
+            max_users.is_some_and(|max| peers.len() >= max as usize)
+        };
+        if is_full(&peer_map.lock().unwrap()) {
+            ws_stream
+                .send(Message::from(ServerMsg::RoomFull).to_ttmessage())
+                .await?;
+            return ws_stream.close(None).await;
+        }
+
+        let challenge = challenge();
+        ws_stream
+            .send(
+                Message::from(ServerMsg::Handshake {
+                    salt,
+                    topic,
+                    challenge: challenge.clone(),
+                })
+                .to_ttmessage(),
+            )
+            .await?;
+
+        // protected rooms take nothing else, nor broadcast anything, before the challenge is
+        // answered within a ping interval
+        if let Some(key) = &key {
+            let answer = timeout(ping_interval, async {
+                while let Some(Ok(msg)) = ws_stream.next().await {
+                    match Message::try_from(msg) {
+                        Ok(Message {
+                            msg_type: MessageType::UserReq(UserReqMsg::AuthReq { proof }),
+                        }) => return verify_proof(key, &challenge, &proof),
+                        Err(ProtocolError::UnexpectedFrame) => (),
+                        _ => return false,
+                    }
+                }
+                false
+            })
+            .await;
+            if answer != Ok(true) {
+                ws_stream
+                    .send(Message::from(ServerMsg::AuthFailure).to_ttmessage())
+                    .await?;
+                return ws_stream.close(None).await;
+            }
+        }
 
         let (tx, rx) = unbounded();
-        peer_map.lock().unwrap().insert(addr, (tx, None));
+        let joined = {
+            let mut peers = peer_map.lock().unwrap();
+            // others may have joined while it authenticated
+            let joined = !is_full(&peers);
+            if joined {
+                peers.insert(addr, Peer::new(tx));
+            }
+            joined
+        };
+        if !joined {
+            ws_stream
+                .send(Message::from(ServerMsg::RoomFull).to_ttmessage())
+                .await?;
+            return ws_stream.close(None).await;
+        }
 
         let (outgoing, incoming) = ws_stream.split();
 
+        // any frame, not only a pong, shows the peer is alive
+        let missed_pongs = AtomicU32::new(0);
+        let spectating = AtomicBool::new(false);
+
         tokio::task::yield_now().await;
         let broadcast_incoming = incoming.try_for_each(|msg| {
-            Self::handle_message(
-                Message::from(msg),
-                peer_map.clone(),
-                addr,
-                room.clone(),
-                db.clone(),
-            );
-
-            future::ok(())
+            missed_pongs.store(0, Ordering::Relaxed);
+            // left, kicked or banned, nothing more is taken from it
+            if !peer_map.lock().unwrap().contains_key(&addr) {
+                return future::err(TtError::ConnectionClosed);
+            }
+            match Message::try_from(msg) {
+                Ok(Message {
+                    msg_type: MessageType::UserReq(UserReqMsg::OwnerReq { proof }),
+                }) => {
+                    if verify_proof(owner_token.as_bytes(), &challenge, &proof) {
+                        if let Some(peer) = peer_map.lock().unwrap().get_mut(&addr) {
+                            peer.is_owner = true;
+                        }
+                    }
+                    future::ok(())
+                }
+                Ok(msg) => {
+                    if let MessageType::User(UserMsg::UserJoined { user }) = &msg.msg_type {
+                        spectating.store(user.spectator, Ordering::Relaxed);
+                    }
+                    let text = match &msg.msg_type {
+                        MessageType::User(UserMsg::Normal { msg }) => Some(msg.content()),
+                        MessageType::User(UserMsg::Edit { content, .. }) => Some(content),
+                        _ => None,
+                    };
+                    let chunk = match &msg.msg_type {
+                        MessageType::User(UserMsg::File { chunk, .. }) => Some(chunk),
+                        _ => None,
+                    };
+                    // a file counts as one message
+                    let is_counted = text.is_some() || chunk.is_some_and(|chunk| chunk.index == 0);
+                    if is_counted && !msg_bucket.try_take(Instant::now()) {
+                        Self::send_to_one(
+                            Message::from(ServerMsg::RateLimited),
+                            peer_map.clone(),
+                            addr,
+                        );
+                    } else if text.is_some_and(|text| is_blank(text, is_sealed)) {
+                        Self::send_to_one(
+                            Message::from(ServerMsg::EmptyMessage),
+                            peer_map.clone(),
+                            addr,
+                        );
+                    } else if text.is_some_and(|text| !content_fits(text, max_msg_len, is_sealed)) {
+                        Self::send_to_one(
+                            Message::from(ServerMsg::MessageTooLong {
+                                max_len: max_msg_len,
+                            }),
+                            peer_map.clone(),
+                            addr,
+                        );
+                    } else if chunk.is_some_and(|chunk| {
+                        chunk.size > max_file_size || !chunk_fits(&chunk.data, is_sealed)
+                    }) {
+                        // the rest of the file is dropped too, it's only reported once
+                        if chunk.is_some_and(|chunk| chunk.index == 0) {
+                            Self::send_to_one(
+                                Message::from(ServerMsg::FileTooLarge {
+                                    max_size: max_file_size,
+                                }),
+                                peer_map.clone(),
+                                addr,
+                            );
+                        }
+                    } else {
+                        Self::handle_message(
+                            msg,
+                            peer_map.clone(),
+                            addr,
+                            room.clone(),
+                            db.clone(),
+                            audit.as_ref(),
+                        );
+                    }
+                    future::ok(())
+                }
+                Err(ProtocolError::UnexpectedFrame) => future::ok(()),
+                Err(err) => {
+                    log::warn!("Dropping connection from {}: {}", addr, err);
+                    future::err(TtError::ConnectionClosed)
+                }
+            }
         });
 
         let receive_from_others = rx.map(Ok).forward(outgoing);
 
-        pin_mut!(broadcast_incoming, receive_from_others);
-        future::select(broadcast_incoming, receive_from_others).await;
+        let keepalive = async {
+            let mut interval = time::interval(ping_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if missed_pongs.fetch_add(1, Ordering::Relaxed) >= max_missed_pongs {
+                    log::info!("Dropping unresponsive connection from {}", addr);
+                    return;
+                }
 
-        peer_map.lock().unwrap().remove(&addr);
-        Self::send_to_all(
-            Message {
-                msg_type: MessageType::Server(ServerMsg::UserLeft { addr }),
-                passwd: None,
-            },
-            peer_map,
-            None,
-        );
+                // a missing sender means the connection is being closed already
+                let sent =
+                    peer_map.lock().unwrap().get(&addr).is_some_and(|peer| {
+                        peer.tx.unbounded_send(TtMessage::Ping(vec![])).is_ok()
+                    });
+                if !sent {
+                    return;
+                }
+            }
+        };
+
+        pin_mut!(broadcast_incoming, receive_from_others, keepalive);
+        let closed = future::select(
+            broadcast_incoming,
+            future::select(receive_from_others, keepalive),
+        )
+        .await;
+        // a removed peer still gets what was queued for it, such as being kicked
+        if let Either::Left((_, sending)) = closed {
+            if !peer_map.lock().unwrap().contains_key(&addr) {
+                sending.await;
+            }
+        }
+
+        let left = peer_map.lock().unwrap().remove(&addr);
+        // kicked, banned and leaving peers are removed and recorded already
+        if let (
+            Some(audit),
+            Some(Peer {
+                user: Some(user), ..
+            }),
+        ) = (&audit, left)
+        {
+            audit.record(AuditEvent::Left {
+                user_id: user._id,
+                addr,
+            });
+        }
+        // spectators were never announced
+        if !spectating.load(Ordering::Relaxed) {
+            Self::send_to_all(
+                Message::from(ServerMsg::UserLeft { addr }),
+                peer_map.clone(),
+                None,
+            );
+        }
 
         Ok(())
     }
@@ -138,17 +484,92 @@ impl ChatServer {
                     true
                 }
             })
-            .map(|(_, ws_sink)| ws_sink);
+            .map(|(_, peer)| &peer.tx);
 
-        for (recp, _) in broadcast_recipients {
-            recp.unbounded_send(msg.to_ttmessage()).unwrap();
+        for recp in broadcast_recipients {
+            // closing connections don't take anything anymore
+            let _ = recp.unbounded_send(msg.to_ttmessage());
         }
     }
 
+    /// Users of connections which have already introduced themselves, spectators left out.
+    fn connected_users(peer_map: PeerMap) -> Vec<User> {
+        peer_map
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|peer| peer.user.clone())
+            .filter(|user| !user.spectator)
+            .collect()
+    }
+
+    /// Id the connection introduced itself with, its address until then.
+    fn user_id(peer_map: PeerMap, addr: SocketAddr) -> String {
+        peer_map
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .and_then(|peer| peer.user.as_ref())
+            .map_or_else(|| addr.to_string(), |user| user._id.clone())
+    }
+
+    fn is_owner(peer_map: PeerMap, addr: SocketAddr) -> bool {
+        peer_map
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .is_some_and(|peer| peer.is_owner)
+    }
+
+    fn author(peer_map: PeerMap, addr: SocketAddr) -> Option<String> {
+        peer_map
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .and_then(|peer| peer.author.clone())
+    }
+
+    fn is_spectator(peer_map: PeerMap, addr: SocketAddr) -> bool {
+        peer_map
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .and_then(|peer| peer.user.as_ref())
+            .is_some_and(|user| user.spectator)
+    }
+
+    /// `user_id`, or else it with the first number suffix no other connection goes by.
+    /// Names differing only in case are taken too, as mentions ignore it.
+    fn free_user_id(peer_map: PeerMap, addr: SocketAddr, user_id: &str) -> String {
+        let taken = peer_map
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(peer_addr, _)| **peer_addr != addr)
+            .filter_map(|(_, peer)| peer.user.as_ref().map(|user| user._id.to_lowercase()))
+            .collect::<HashSet<String>>();
+
+        iter::once(user_id.to_string())
+            .chain((2..).map(|n| format!("{}{}", user_id, n)))
+            .find(|id| !taken.contains(&id.to_lowercase()))
+            .unwrap()
+    }
+
+    /// First assignable color no connected user has, cycling once all are taken.
+    fn free_color(peer_map: PeerMap) -> Color {
+        let users = Self::connected_users(peer_map);
+        Color::ASSIGNABLE
+            .iter()
+            .find(|color| !users.iter().any(|user| user.color.as_ref() == Some(color)))
+            .unwrap_or(&Color::ASSIGNABLE[users.len() % Color::ASSIGNABLE.len()])
+            .clone()
+    }
+
+    /// Nothing is sent to peers which left or were removed already.
     fn send_to_one(msg: Message, peer_map: PeerMap, addr: SocketAddr) {
-        let peers = peer_map.lock().unwrap();
-        let recp = &peers.get(&addr).unwrap().0;
-        recp.unbounded_send(msg.to_ttmessage()).unwrap();
+        if let Some(peer) = peer_map.lock().unwrap().get(&addr) {
+            let _ = peer.tx.unbounded_send(msg.to_ttmessage());
+        }
     }
 
     fn handle_message(
@@ -157,77 +578,442 @@ impl ChatServer {
         addr: SocketAddr,
         room: Arc<Mutex<Room>>,
         db: Arc<Mutex<DbRepo>>,
+        audit: Option<&AuditLog>,
     ) {
-        let room = room.lock().unwrap();
+        let mut room = room.lock().unwrap();
 
-        if let Some(passwd) = &room.passwd {
-            if let Some(msg_passwd) = &msg.passwd {
-                if msg_passwd != passwd || msg.passwd.is_none() {
-                    Self::send_to_one(
-                        Message {
-                            msg_type: MessageType::Server(ServerMsg::AuthFailure),
-                            passwd: None,
-                        },
-                        peer_map.clone(),
-                        addr,
-                    );
-                }
-            }
+        // sealed content can't be read by the server
+        let msg = if room.passwd.is_none() {
+            filter_msg(msg, &room.filter_words)
+        } else {
+            msg
+        };
+
+        let is_sending = matches!(
+            &msg.msg_type,
+            MessageType::User(user_msg) if !matches!(user_msg, UserMsg::UserJoined { .. })
+        );
+        if is_sending && Self::is_spectator(peer_map.clone(), addr) {
+            Self::send_to_one(Message::from(ServerMsg::ReadOnly), peer_map, addr);
+            return;
         }
 
         match &msg.msg_type {
             MessageType::User(user_msg) => match user_msg {
                 UserMsg::Normal { msg: text_msg, .. } => {
+                    // the id is picked by the sender, a taken one would change another message
+                    let db = db.lock().unwrap();
+                    if db
+                        .messages
+                        .find_one(doc! {"id": text_msg.id()})
+                        .unwrap()
+                        .is_some()
+                    {
+                        log::warn!("Dropping a message from {} with a taken id", addr);
+                        return;
+                    }
+
+                    // the connection tells who sent it and where, not the message
+                    let mut text_msg = text_msg.clone();
+                    text_msg.set_sender(addr, Self::author(peer_map.clone(), addr));
+                    text_msg.set_room_id(&room._id);
+                    Self::send_to_all(
+                        Message::from(UserMsg::Normal {
+                            msg: text_msg.clone(),
+                        }),
+                        peer_map.clone(),
+                        Some(addr),
+                    );
+                    db.messages.insert_one(&text_msg).unwrap();
+                    Self::send_to_one(
+                        Message::from(ServerMsg::Ack {
+                            timestamp: *text_msg.timestamp(),
+                        }),
+                        peer_map.clone(),
+                        addr,
+                    );
+                }
+                UserMsg::Typing { .. } => {
                     Self::send_to_all(msg.clone(), peer_map.clone(), Some(addr));
-                    db.lock().unwrap().messages.insert_one(text_msg).unwrap();
                 }
-                UserMsg::UserJoined { user } => {
-                    let mut updated_user = user.clone();
-                    updated_user.addr = Some(addr);
+                UserMsg::Edit { id, .. } | UserMsg::Delete { id } => {
+                    let author = Self::author(peer_map.clone(), addr);
+                    let db = db.lock().unwrap();
+                    // only the session that sent the message may change it, reconnected or not
+                    let is_author = author.is_some()
+                        && db
+                            .messages
+                            .find_one(doc! {"id": id, "room_id": &room._id})
+                            .unwrap()
+                            .is_some_and(|stored| stored.author() == author.as_ref());
+                    if !is_author {
+                        Self::send_to_one(
+                            Message::from(ServerMsg::EditRejected { id: id.clone() }),
+                            peer_map.clone(),
+                            addr,
+                        );
+                        return;
+                    }
+
+                    if let UserMsg::Edit { content, .. } = user_msg {
+                        db.messages
+                            .update_one(
+                                doc! {"id": id, "room_id": &room._id},
+                                doc! {"$set": {"content": content, "edited": true}},
+                            )
+                            .unwrap();
+                    } else {
+                        db.messages
+                            .delete_one(doc! {"id": id, "room_id": &room._id})
+                            .unwrap();
+                    }
+                    Self::send_to_all(msg.clone(), peer_map.clone(), None);
+                }
+                UserMsg::File { chunk, .. } => {
                     Self::send_to_all(
-                        Message::from((
-                            UserMsg::UserJoined { user: updated_user },
-                            room.passwd.clone(),
-                        )),
+                        Message::from(UserMsg::File {
+                            addr,
+                            chunk: chunk.clone(),
+                        }),
                         peer_map.clone(),
-                        None,
+                        Some(addr),
+                    );
+                }
+                UserMsg::Status { away, .. } => {
+                    if let Some(Peer {
+                        user: Some(user), ..
+                    }) = peer_map.lock().unwrap().get_mut(&addr)
+                    {
+                        user.away = *away;
+                    }
+                    Self::send_to_all(
+                        Message::from(UserMsg::Status { addr, away: *away }),
+                        peer_map.clone(),
+                        Some(addr),
                     );
-                    peer_map.clone().lock().unwrap().get_mut(&addr).unwrap().1 = Some(user.clone());
+                }
+                UserMsg::UserJoined { user } => {
+                    let mut updated_user = user.clone();
+                    updated_user.addr = Some(addr);
+                    let user_id = Self::free_user_id(peer_map.clone(), addr, &user._id);
+                    if user_id != user._id {
+                        Self::send_to_one(
+                            Message::from(ServerMsg::Renamed {
+                                user_id: user_id.clone(),
+                            }),
+                            peer_map.clone(),
+                            addr,
+                        );
+                        updated_user._id = user_id;
+                    }
+                    if updated_user.color.is_none() {
+                        updated_user.color = Some(Self::free_color(peer_map.clone()));
+                    }
+                    let new_session = {
+                        let mut peers = peer_map.lock().unwrap();
+                        let Some(peer) = peers.get_mut(&addr) else {
+                            return;
+                        };
+                        peer.user = Some(updated_user.clone());
+                        // unless an earlier session was resumed
+                        peer.author.is_none().then(|| {
+                            let token = challenge();
+                            peer.author = Some(author_key(&token));
+                            token
+                        })
+                    };
+                    if let Some(token) = new_session {
+                        Self::send_to_one(
+                            Message::from(ServerMsg::Session { token }),
+                            peer_map.clone(),
+                            addr,
+                        );
+                    }
+                    let is_spectator = updated_user.spectator;
+                    if let Some(audit) = audit {
+                        audit.record(AuditEvent::Joined {
+                            user_id: updated_user._id.clone(),
+                            addr,
+                            spectator: is_spectator,
+                        });
+                    }
+                    let joined = Message::from(UserMsg::UserJoined { user: updated_user });
+                    // spectators only learn their own address
+                    if is_spectator {
+                        Self::send_to_one(joined, peer_map.clone(), addr);
+                    } else {
+                        Self::send_to_all(joined, peer_map.clone(), None);
+                    }
                 }
             },
             MessageType::UserReq(user_req) => match user_req {
-                UserReqMsg::SyncReq => {
+                UserReqMsg::SyncReq { history } => {
                     let messages = db
                         .lock()
                         .unwrap()
-                        .messages
-                        .find(None)
+                        .room_messages(&room._id, *history)
+                        .unwrap();
+
+                    Self::send_to_one(
+                        Message::from(ServerMsg::Sync {
+                            messages,
+                            users: Self::connected_users(peer_map.clone()),
+                        }),
+                        peer_map,
+                        addr,
+                    );
+                }
+                UserReqMsg::PageReq { offset, limit } => {
+                    let messages = db
+                        .lock()
                         .unwrap()
-                        .into_iter()
-                        .map(|msg| msg.unwrap())
-                        .collect::<Vec<TextMessage>>();
+                        .room_messages_page(&room._id, *offset, *limit)
+                        .unwrap();
 
                     Self::send_to_one(
-                        Message::from((
-                            ServerMsg::Sync {
-                                messages,
-                                users: peer_map
-                                    .clone()
-                                    .lock()
-                                    .unwrap()
-                                    .iter()
-                                    .map(|(_, (_, user))| user.clone().unwrap())
-                                    .collect(),
-                            },
-                            room.passwd.clone(),
-                        )),
+                        Message::from(ServerMsg::Page {
+                            offset: *offset,
+                            messages,
+                        }),
                         peer_map,
                         addr,
                     );
                 }
-                UserReqMsg::BanReq { addr } => todo!(),
+                UserReqMsg::BanReq { .. } | UserReqMsg::KickReq { .. }
+                    if !Self::is_owner(peer_map.clone(), addr) =>
+                {
+                    Self::send_to_one(Message::from(ServerMsg::NotOwner), peer_map, addr);
+                }
+                UserReqMsg::BanReq { addr: banned_addr } => {
+                    if !room.is_banned(banned_addr) {
+                        room.banned_addrs.push(*banned_addr);
+                        db.lock().unwrap().rooms.update_one(
+                            doc! {"_id": &room._id},
+                            doc! {"$set": {"banned_addrs": to_bson(&room.banned_addrs).unwrap()}},
+                        ).unwrap();
+                    }
+
+                    Self::send_to_all(
+                        Message::from(ServerMsg::BanConfirm { addr: *banned_addr }),
+                        peer_map.clone(),
+                        None,
+                    );
+
+                    if let Some(audit) = audit {
+                        audit.record(AuditEvent::Banned {
+                            addr: *banned_addr,
+                            by: Self::user_id(peer_map.clone(), addr),
+                        });
+                    }
+
+                    // dropping the senders closes the connections of the banned peers, the owner
+                    // stays even when sharing the address, as on a LAN or behind a NAT
+                    peer_map.lock().unwrap().retain(|peer_addr, _| {
+                        peer_addr == &addr || peer_addr.ip() != banned_addr.ip()
+                    });
+                }
+                UserReqMsg::KickReq { user_id } => {
+                    let by = Self::user_id(peer_map.clone(), addr);
+                    // the closed connection announces the user as left
+                    let mut peers = peer_map.lock().unwrap();
+                    let connected = peers.len();
+                    peers.retain(|_, Peer { tx, user, .. }| {
+                        let stays = user.as_ref().is_none_or(|user| &user._id != user_id);
+                        if !stays {
+                            // still sent before the connection closes, so it isn't reopened
+                            let _ =
+                                tx.unbounded_send(Message::from(ServerMsg::Kicked).to_ttmessage());
+                        }
+                        stays
+                    });
+                    if let Some(audit) = audit.filter(|_| peers.len() < connected) {
+                        audit.record(AuditEvent::Kicked {
+                            user_id: user_id.clone(),
+                            by,
+                        });
+                    }
+                }
+                // answered as they arrive
+                UserReqMsg::AuthReq { .. } | UserReqMsg::OwnerReq { .. } => (),
+                UserReqMsg::ResumeReq { token } => {
+                    if let Some(peer) = peer_map.lock().unwrap().get_mut(&addr) {
+                        peer.author = Some(author_key(token));
+                    }
+                }
+                UserReqMsg::LeaveReq => {
+                    let left = peer_map.lock().unwrap().remove(&addr);
+                    if let (
+                        Some(audit),
+                        Some(Peer {
+                            user: Some(user), ..
+                        }),
+                    ) = (audit, left)
+                    {
+                        audit.record(AuditEvent::Left {
+                            user_id: user._id,
+                            addr,
+                        });
+                    }
+                }
             },
             _ => (),
         }
     }
 }
+
+/// Protected rooms only see the sealed content, so its encoded length is bounded instead,
+/// allowing the widest characters.
+fn content_fits(content: &str, max_len: u32, is_sealed: bool) -> bool {
+    if is_sealed {
+        content.len() <= sealed_len(max_len as usize * 4)
+    } else {
+        content.chars().count() <= max_len as usize
+    }
+}
+
+/// Chunks are bounded by their encoded length, sealed ones by that of the sealed chunk.
+fn chunk_fits(data: &str, is_sealed: bool) -> bool {
+    if is_sealed {
+        data.len() <= sealed_len(MAX_CHUNK_DATA)
+    } else {
+        data.len() <= MAX_CHUNK_DATA
+    }
+}
+
+/// Redacts the filtered words from the text a message carries.
+fn filter_msg(mut msg: Message, words: &[String]) -> Message {
+    match &mut msg.msg_type {
+        MessageType::User(UserMsg::Normal { msg: text_msg }) => {
+            text_msg.set_content(redact(text_msg.content(), words));
+        }
+        MessageType::User(UserMsg::Edit { content, .. }) => {
+            *content = redact(content, words);
+        }
+        _ => (),
+    }
+    msg
+}
+
+/// Replaces every whole-word occurrence of `words` with asterisks, ignoring case.
+fn redact(content: &str, words: &[String]) -> String {
+    let words = words
+        .iter()
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())
+        .map(regex::escape)
+        .collect::<Vec<String>>();
+    if words.is_empty() {
+        return content.into();
+    }
+
+    Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|")))
+        .unwrap()
+        .replace_all(content, |caps: &Captures| {
+            "*".repeat(caps[0].chars().count())
+        })
+        .into_owned()
+}
+
+/// Sealed content only tells whether anything was sealed, whitespace can't be seen in it.
+fn is_blank(content: &str, is_sealed: bool) -> bool {
+    if is_sealed {
+        plain_len(content) == 0
+    } else {
+        content.trim().is_empty()
+    }
+}
+
+/// Limits the rate of the text messages of a connection, allowing short bursts.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate: f64::from(rate),
+            burst,
+            tokens: burst,
+            last_refill: None,
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        if let Some(last_refill) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        }
+        self.last_refill = Some(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{content_fits, is_blank, redact, TokenBucket};
+    use crate::network::crypto::encrypt;
+    use chacha20poly1305::Key;
+    use tokio::time::{Duration, Instant};
+
+    #[test]
+    fn bucket_allows_bursts_then_refills() {
+        let mut bucket = TokenBucket::new(2, 3);
+        let start = Instant::now();
+
+        let taken = (0..10).filter(|_| bucket.try_take(start)).count();
+        assert_eq!(taken, 3);
+
+        assert!(!bucket.try_take(start + Duration::from_millis(100)));
+        assert!(bucket.try_take(start + Duration::from_millis(600)));
+        assert!(!bucket.try_take(start + Duration::from_millis(600)));
+
+        let refilled = start + Duration::from_secs(60);
+        let taken = (0..10).filter(|_| bucket.try_take(refilled)).count();
+        assert_eq!(taken, 3);
+    }
+
+    #[test]
+    fn message_length_is_limited() {
+        assert!(content_fits("héllo", 5, false));
+        assert!(!content_fits("héllo!", 5, false));
+
+        let key = Key::default();
+        assert!(content_fits(&encrypt(&key, "🦀🦀🦀🦀🦀"), 5, true));
+        assert!(!content_fits(&encrypt(&key, "🦀🦀🦀🦀🦀!"), 5, true));
+    }
+
+    #[test]
+    fn blank_content_is_detected() {
+        assert!(is_blank("", false));
+        assert!(is_blank(" \n\t", false));
+        assert!(!is_blank(" hi ", false));
+
+        let key = Key::default();
+        assert!(is_blank(&encrypt(&key, ""), true));
+        assert!(!is_blank(&encrypt(&key, "hi"), true));
+        assert!(is_blank("", true));
+    }
+
+    #[test]
+    fn filtered_words_are_redacted() {
+        let words = ["heck".to_string(), "darn it".to_string()];
+
+        assert_eq!(
+            redact("What the HECK, heckler? Darn it!", &words),
+            "What the ****, heckler? *******!"
+        );
+        assert_eq!(redact("all clean here", &words), "all clean here");
+        assert_eq!(redact("heck", &[]), "heck");
+    }
+}
@@ -0,0 +1,260 @@
+use base64ct::{Base64, Encoding};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Bytes of a file carried by one chunk.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+/// Encoded length of a full chunk.
+pub const MAX_CHUNK_DATA: usize = CHUNK_SIZE.div_ceil(3) * 4;
+
+/// Piece of a sent file, every chunk repeats what the whole file is.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileChunk {
+    pub transfer_id: String,
+    pub name: String,
+    /// Bytes of the whole file.
+    pub size: u64,
+    /// Hex SHA-256 of the whole file.
+    pub hash: String,
+    pub index: u32,
+    pub count: u32,
+    /// Base64 of the chunk's bytes.
+    pub data: String,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    #[error("The file has {size} bytes, at most {max_size} are allowed.")]
+    TooLarge { size: u64, max_size: u64 },
+    #[error("A chunk doesn't fit the file.")]
+    InvalidChunk,
+    #[error("The file doesn't match its hash.")]
+    HashMismatch,
+}
+
+pub fn file_hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Chunks a file needs, an empty one is still sent as one chunk.
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE as u64).max(1)
+}
+
+/// Cuts the file into the chunks to send, in order.
+pub fn split(name: &str, data: &[u8], max_size: u64) -> Result<Vec<FileChunk>, TransferError> {
+    let size = data.len() as u64;
+    if size > max_size {
+        return Err(TransferError::TooLarge { size, max_size });
+    }
+
+    let transfer_id = Uuid::new_v4().to_string();
+    let hash = file_hash(data);
+    let count = chunk_count(size) as u32;
+    let pieces = match data.len() {
+        0 => vec![data],
+        _ => data.chunks(CHUNK_SIZE).collect(),
+    };
+    Ok(pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, piece)| FileChunk {
+            transfer_id: transfer_id.clone(),
+            name: name.into(),
+            size,
+            hash: hash.clone(),
+            index: index as u32,
+            count,
+            data: Base64::encode_string(piece),
+        })
+        .collect())
+}
+
+/// A file being received, its chunks may arrive in any order.
+#[derive(Debug)]
+pub struct IncomingFile {
+    pub name: String,
+    pub size: u64,
+    hash: String,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl IncomingFile {
+    /// Starts receiving the file `chunk` belongs to, unless it's too large.
+    pub fn new(chunk: &FileChunk, max_size: u64) -> Result<Self, TransferError> {
+        if chunk.size > max_size {
+            return Err(TransferError::TooLarge {
+                size: chunk.size,
+                max_size,
+            });
+        }
+        if chunk.count as u64 != chunk_count(chunk.size) {
+            return Err(TransferError::InvalidChunk);
+        }
+
+        Ok(Self {
+            name: chunk.name.clone(),
+            size: chunk.size,
+            hash: chunk.hash.clone(),
+            chunks: vec![None; chunk.count as usize],
+        })
+    }
+
+    /// Stores the chunk, returns whether all of them arrived.
+    pub fn add(&mut self, chunk: &FileChunk) -> Result<bool, TransferError> {
+        let fits = chunk.name == self.name
+            && chunk.size == self.size
+            && chunk.hash == self.hash
+            && chunk.count as usize == self.chunks.len();
+        let slot = self
+            .chunks
+            .get_mut(chunk.index as usize)
+            .filter(|_| fits)
+            .ok_or(TransferError::InvalidChunk)?;
+        let data = Base64::decode_vec(&chunk.data).map_err(|_| TransferError::InvalidChunk)?;
+        if data.len() > CHUNK_SIZE {
+            return Err(TransferError::InvalidChunk);
+        }
+
+        *slot = Some(data);
+        Ok(self.chunks.iter().all(Option::is_some))
+    }
+
+    /// Joins the received chunks, checking the result against the hash.
+    pub fn assemble(self) -> Result<Vec<u8>, TransferError> {
+        let data = self
+            .chunks
+            .into_iter()
+            .collect::<Option<Vec<Vec<u8>>>>()
+            .ok_or(TransferError::InvalidChunk)?
+            .concat();
+        if data.len() as u64 != self.size || file_hash(&data) != self.hash {
+            return Err(TransferError::HashMismatch);
+        }
+        Ok(data)
+    }
+}
+
+/// Writes a received file into `dir` under its base name, never replacing an existing file.
+pub fn save(dir: &Path, name: &str, data: &[u8]) -> io::Result<PathBuf> {
+    let name = Path::new(name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or("file");
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    let mut attempt = 1;
+    loop {
+        let path = match attempt {
+            1 => dir.join(name),
+            _ => dir.join(format!("{} ({}){}", stem, attempt, extension)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(data)?;
+                return Ok(path);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{file_hash, save, split, IncomingFile, TransferError, CHUNK_SIZE};
+    use base64ct::{Base64, Encoding};
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    fn receive(chunks: &[super::FileChunk]) -> Result<Vec<u8>, TransferError> {
+        let mut file = IncomingFile::new(&chunks[0], u64::MAX)?;
+        let mut complete = false;
+        // the order they arrive in doesn't matter
+        for chunk in chunks.iter().rev() {
+            complete = file.add(chunk)?;
+        }
+        assert!(complete);
+        file.assemble()
+    }
+
+    #[test]
+    fn chunks_reassemble_into_the_file() {
+        let data = (0..CHUNK_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+
+        let chunks = split("notes.txt", &data, u64::MAX).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.count == 3
+            && chunk.hash == file_hash(&data)
+            && chunk.transfer_id == chunks[0].transfer_id));
+        assert_eq!(receive(&chunks).unwrap(), data);
+
+        let empty = split("empty", &[], u64::MAX).unwrap();
+        assert_eq!(empty.len(), 1);
+        assert_eq!(receive(&empty).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn corrupted_chunks_fail_the_hash() {
+        let data = vec![7; CHUNK_SIZE + 10];
+        let mut chunks = split("image.png", &data, u64::MAX).unwrap();
+        chunks[1].data = Base64::encode_string(&[8; 10]);
+        assert_eq!(receive(&chunks), Err(TransferError::HashMismatch));
+
+        let mut chunks = split("image.png", &data, u64::MAX).unwrap();
+        chunks[1].data = "not base64!".into();
+        assert_eq!(receive(&chunks), Err(TransferError::InvalidChunk));
+
+        let mut chunks = split("image.png", &data, u64::MAX).unwrap();
+        chunks[1].index = 5;
+        assert_eq!(receive(&chunks), Err(TransferError::InvalidChunk));
+    }
+
+    #[test]
+    fn files_over_the_limit_are_rejected() {
+        let too_large = TransferError::TooLarge {
+            size: 11,
+            max_size: 10,
+        };
+        assert_eq!(split("big", &[0; 11], 10), Err(too_large));
+
+        let chunks = split("big", &[0; 11], 11).unwrap();
+        assert_eq!(
+            IncomingFile::new(&chunks[0], 10).unwrap_err(),
+            TransferError::TooLarge {
+                size: 11,
+                max_size: 10
+            }
+        );
+    }
+
+    #[test]
+    fn saving_keeps_existing_files() {
+        let dir = env::temp_dir().join(format!("kioto-transfer-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+
+        let first = save(&dir, "../notes.txt", b"first").unwrap();
+        let second = save(&dir, "notes.txt", b"second").unwrap();
+        assert_eq!(first, dir.join("notes.txt"));
+        assert_eq!(second, dir.join("notes (2).txt"));
+        assert_eq!(fs::read(first).unwrap(), b"first");
+        assert_eq!(fs::read(second).unwrap(), b"second");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
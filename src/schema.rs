@@ -1,9 +1,14 @@
-use enum_stringify::EnumStringify;
+use chrono::FixedOffset;
 use ratatui::style::Color as ratColor;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, time::SystemTime};
+use std::{fmt, net::SocketAddr, str::FromStr, time::SystemTime};
+use uuid::Uuid;
 
-use crate::network::User;
+use crate::util::{
+    DEFAULT_AWAY_AFTER, DEFAULT_CONNECT_TIMEOUT, DEFAULT_LAYOUT_RATIO, DEFAULT_MAX_FILE_SIZE,
+    DEFAULT_MAX_MISSED_PONGS, DEFAULT_MAX_MSG_LEN, DEFAULT_MENTION_PREFIX, DEFAULT_MSG_BURST,
+    DEFAULT_MSG_RATE, DEFAULT_PING_INTERVAL, DEFAULT_RECONNECT_ATTEMPTS, DEFAULT_TIMESTAMP_FORMAT,
+};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct Room {
@@ -12,30 +17,160 @@ pub struct Room {
     pub passwd: Option<String>,
     pub banned_addrs: Vec<SocketAddr>,
     pub is_owner: bool,
+    /// Most users connected at once, unlimited when `None`.
+    #[serde(default)]
+    pub max_users: Option<u32>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default = "unix_epoch")]
+    pub created_at: SystemTime,
+    /// `hostname:port` the room was given as, resolved again on every join.
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub last_joined: Option<SystemTime>,
+    /// Words a hosted room redacts from the messages, matched whole and ignoring case.
+    #[serde(default)]
+    pub filter_words: Vec<String>,
+}
+
+/// Creation time of rooms stored before it was recorded.
+fn unix_epoch() -> SystemTime {
+    SystemTime::UNIX_EPOCH
+}
+
+impl Room {
+    /// Bans are matched by ip only, as clients connect from ephemeral ports.
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.banned_addrs
+            .iter()
+            .any(|banned| banned.ip() == addr.ip())
+    }
+}
+
+/// Portable form of a [`Room`], the password hash is kept only when explicitly requested.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct RoomExport {
+    pub id: String,
+    pub addr: SocketAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub banned_addrs: Vec<SocketAddr>,
+    pub is_owner: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_users: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filter_words: Vec<String>,
+    pub has_password: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passwd: Option<String>,
+}
+
+impl RoomExport {
+    pub fn new(room: &Room, with_secret: bool) -> Self {
+        Self {
+            id: room._id.clone(),
+            addr: room.addr,
+            host: room.host.clone(),
+            banned_addrs: room.banned_addrs.clone(),
+            is_owner: room.is_owner,
+            max_users: room.max_users,
+            topic: room.topic.clone(),
+            filter_words: room.filter_words.clone(),
+            has_password: room.passwd.is_some(),
+            passwd: if with_secret {
+                room.passwd.clone()
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Snapshot of the local settings and every room, password hashes included.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Backup {
+    pub local_data: Option<LocalData>,
+    #[serde(default)]
+    pub rooms: Vec<Room>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct IgnoredUser {
+    pub room_id: String,
+    pub user_id: String,
+}
+
+/// Layout version of the stored documents, kept under the `schema` id.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Meta {
+    pub _id: String,
+    pub schema_version: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct TextMessage {
+    /// Identifies the message for edits and deletions.
+    #[serde(default = "new_msg_id")]
+    id: String,
     room_id: String,
     sender_addr: SocketAddr,
     content: String,
     timestamp: SystemTime,
+    #[serde(default)]
+    edited: bool,
+    /// Author key of the session that sent it, filled in by the room.
+    #[serde(default)]
+    author: Option<String>,
 }
 
 impl TextMessage {
     pub fn new(user_addr: &SocketAddr, room_id: &str, msg: &str) -> Self {
         Self {
+            id: new_msg_id(),
             sender_addr: *user_addr,
             room_id: room_id.into(),
             content: msg.into(),
             timestamp: SystemTime::now(),
+            edited: false,
+            author: None,
         }
     }
 
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    pub fn is_edited(&self) -> bool {
+        self.edited
+    }
+
+    /// Replaces the content as its sender asked.
+    pub fn edit(&mut self, content: String) {
+        self.content = content;
+        self.edited = true;
+    }
+
     pub fn sender_addr(&self) -> &SocketAddr {
         &self.sender_addr
     }
 
+    pub fn author(&self) -> Option<&String> {
+        self.author.as_ref()
+    }
+
+    /// Sets who sent it as the room saw, whatever the sender claimed.
+    pub fn set_sender(&mut self, addr: SocketAddr, author: Option<String>) {
+        self.sender_addr = addr;
+        self.author = author;
+    }
+
+    pub fn set_room_id(&mut self, room_id: &str) {
+        self.room_id = room_id.into();
+    }
+
     pub fn room_id(&self) -> &String {
         &self.room_id
     }
@@ -44,11 +179,22 @@ impl TextMessage {
         &self.content
     }
 
+    pub fn set_content(&mut self, content: String) {
+        self.content = content;
+    }
+
     pub fn timestamp(&self) -> &SystemTime {
         &self.timestamp
     }
 }
 
+/// Password hash of a room the user has already authenticated to, kept when `remember_passwords` is on.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct RememberedPasswd {
+    pub _id: String,
+    pub passwd: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct LocalData {
     pub default_user_id: String,
@@ -56,10 +202,144 @@ pub struct LocalData {
     pub default_color: Color,
     pub remember_passwords: bool,
     pub light_mode: bool,
+    #[serde(default = "default_timestamp_format")]
+    pub timestamp_format: String,
+    #[serde(default)]
+    pub bell: bool,
+    /// Rings the bell only for messages mentioning the user.
+    #[serde(default)]
+    pub bell_mentions_only: bool,
+    /// Messages per second a hosted room accepts from each connection.
+    #[serde(default = "default_msg_rate")]
+    pub msg_rate: u32,
+    #[serde(default = "default_msg_burst")]
+    pub msg_burst: u32,
+    /// Characters a message may have, checked when sending and by hosted rooms.
+    #[serde(default = "default_max_msg_len")]
+    pub max_msg_len: u32,
+    /// Seconds between the pings of a hosted room, a connection missing
+    /// `max_missed_pongs` of them in a row is dropped.
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u32,
+    #[serde(default = "default_max_missed_pongs")]
+    pub max_missed_pongs: u32,
+    /// Seconds without input until the user is shown as away, 0 never does.
+    #[serde(default = "default_away_after")]
+    pub away_after: u32,
+    #[serde(default)]
+    pub timezone: Timezone,
+    /// Users whose messages are hidden, per room.
+    #[serde(default)]
+    pub ignored: Vec<IgnoredUser>,
+    /// Renders `*bold*`, `_italic_` and `` `code` `` in messages.
+    #[serde(default)]
+    pub markdown: bool,
+    /// Colors of the input area, the theme's ones when unset.
+    #[serde(default)]
+    pub input_border: Option<Color>,
+    #[serde(default)]
+    pub input_highlight: Option<Color>,
+    #[serde(default)]
+    pub input_placeholder: Option<Color>,
+    /// Newest stored messages each hosted room keeps, 0 keeps all.
+    #[serde(default)]
+    pub retention_messages: u32,
+    /// Days stored messages are kept for, 0 keeps them forever.
+    #[serde(default)]
+    pub retention_days: u32,
+    /// Palette of the chat, `light_mode` picks dark or light when unset.
+    #[serde(default)]
+    pub theme: Option<Theme>,
+    /// Draws the chat on the alternate screen, off it stays in the scrollback.
+    #[serde(default = "default_alt_screen")]
+    pub alt_screen: bool,
+    /// Seconds a join waits for the room to answer.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u32,
+    /// Symbol starting the mentions, `@name` by default.
+    #[serde(default = "default_mention_prefix")]
+    pub mention_prefix: char,
+    /// Hosted rooms record joins, leaves, bans and kicks to `audit.log`.
+    #[serde(default)]
+    pub audit_log: bool,
+    /// Attempts at getting a dropped connection back before giving up.
+    #[serde(default = "default_reconnect_attempts")]
+    pub reconnect_attempts: u32,
+    /// Bytes a sent or received file may have, also checked by hosted rooms.
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u32,
+    /// Percent of the screen the messages take, the input gets the rest.
+    #[serde(default = "default_layout_ratio")]
+    pub layout_ratio: u16,
+}
+
+impl LocalData {
+    pub fn current_theme(&self) -> Theme {
+        self.theme.unwrap_or(if self.light_mode {
+            Theme::Light
+        } else {
+            Theme::Dark
+        })
+    }
+}
+
+fn new_msg_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+fn default_alt_screen() -> bool {
+    true
+}
+
+fn default_timestamp_format() -> String {
+    DEFAULT_TIMESTAMP_FORMAT.into()
+}
+
+fn default_msg_rate() -> u32 {
+    DEFAULT_MSG_RATE
+}
+
+fn default_msg_burst() -> u32 {
+    DEFAULT_MSG_BURST
+}
+
+fn default_max_msg_len() -> u32 {
+    DEFAULT_MAX_MSG_LEN
+}
+
+fn default_ping_interval() -> u32 {
+    DEFAULT_PING_INTERVAL
+}
+
+fn default_max_missed_pongs() -> u32 {
+    DEFAULT_MAX_MISSED_PONGS
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, EnumStringify)]
-#[enum_stringify(case = "lower")]
+fn default_away_after() -> u32 {
+    DEFAULT_AWAY_AFTER
+}
+
+fn default_connect_timeout() -> u32 {
+    DEFAULT_CONNECT_TIMEOUT
+}
+
+fn default_reconnect_attempts() -> u32 {
+    DEFAULT_RECONNECT_ATTEMPTS
+}
+
+fn default_max_file_size() -> u32 {
+    DEFAULT_MAX_FILE_SIZE
+}
+
+fn default_layout_ratio() -> u16 {
+    DEFAULT_LAYOUT_RATIO
+}
+
+fn default_mention_prefix() -> char {
+    DEFAULT_MENTION_PREFIX
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
 pub enum Color {
     Black,
     Red,
@@ -76,12 +356,186 @@ pub enum Color {
     LightBlue,
     LightMagenta,
     LightCyan,
+    #[default]
     White,
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    const NAMED: [Color; 16] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::Gray,
+        Color::DarkGray,
+        Color::LightRed,
+        Color::LightGreen,
+        Color::LightYellow,
+        Color::LightBlue,
+        Color::LightMagenta,
+        Color::LightCyan,
+        Color::White,
+    ];
+
+    /// Colors handed out to users who didn't pick one, readable on both themes.
+    pub const ASSIGNABLE: [Color; 12] = [
+        Color::LightRed,
+        Color::LightGreen,
+        Color::LightYellow,
+        Color::LightBlue,
+        Color::LightMagenta,
+        Color::LightCyan,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+    ];
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Color::Rgb(r, g, b) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            named => write!(f, "{}", format!("{:?}", named).to_lowercase()),
+        }
+    }
+}
+
+/// Accepts a color name, `#rrggbb` or `rgb(r, g, b)`.
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_lowercase();
+        let invalid = || {
+            let names = Color::NAMED.map(|color| color.to_string()).join(", ");
+            format!(
+                "{} is not a color, expected one of {}, #rrggbb or rgb(r, g, b)",
+                s, names
+            )
+        };
+
+        if let Some(hex) = value.strip_prefix('#') {
+            if hex.len() != 6 || !hex.is_ascii() {
+                return Err(invalid());
+            }
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+            return Ok(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+        }
+
+        if let Some(args) = value
+            .strip_prefix("rgb(")
+            .and_then(|value| value.strip_suffix(')'))
+        {
+            let channels = args
+                .split(',')
+                .map(|channel| channel.trim().parse::<u8>().map_err(|_| invalid()))
+                .collect::<Result<Vec<u8>, String>>()?;
+            return match channels[..] {
+                [r, g, b] => Ok(Color::Rgb(r, g, b)),
+                _ => Err(invalid()),
+            };
+        }
+
+        Color::NAMED
+            .into_iter()
+            .find(|color| color.to_string() == value)
+            .ok_or_else(invalid)
+    }
+}
+
+/// Built-in palettes of the chat.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    Dark,
+    Light,
+    HighContrast,
+    /// Blue and orange instead of red and green hues.
+    Deuteranopia,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 4] = [
+        Theme::Dark,
+        Theme::Light,
+        Theme::HighContrast,
+        Theme::Deuteranopia,
+    ];
+}
+
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Theme::Dark => write!(f, "dark"),
+            Theme::Light => write!(f, "light"),
+            Theme::HighContrast => write!(f, "high_contrast"),
+            Theme::Deuteranopia => write!(f, "deuteranopia"),
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase().replace('-', "_");
+        Theme::ALL
+            .into_iter()
+            .find(|theme| theme.to_string() == name)
+            .ok_or_else(|| {
+                let names = Theme::ALL.map(|theme| theme.to_string());
+                format!("{} is not a theme, pick one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Zone the timestamps are shown in.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum Timezone {
+    #[default]
+    Local,
+    Utc,
+    /// Seconds east of UTC.
+    Fixed(i32),
 }
 
-impl Into<ratColor> for Color {
-    fn into(self) -> ratColor {
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Timezone::Local => write!(f, "local"),
+            Timezone::Utc => write!(f, "utc"),
+            Timezone::Fixed(secs) => match FixedOffset::east_opt(*secs) {
+                Some(offset) => write!(f, "{}", offset),
+                None => write!(f, "{}s", secs),
+            },
+        }
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "local" => Ok(Timezone::Local),
+            "utc" => Ok(Timezone::Utc),
+            offset => FixedOffset::from_str(offset)
+                .map(|offset| Timezone::Fixed(offset.local_minus_utc()))
+                .map_err(|_| format!("{} is neither local, utc nor an offset like +02:00", s)),
+        }
+    }
+}
+
+impl From<Color> for ratColor {
+    fn from(value: Color) -> Self {
+        match value {
             Color::Black => ratColor::Black,
             Color::Red => ratColor::Red,
             Color::Green => ratColor::Green,
@@ -98,6 +552,54 @@ impl Into<ratColor> for Color {
             Color::LightMagenta => ratColor::LightMagenta,
             Color::LightCyan => ratColor::LightCyan,
             Color::White => ratColor::White,
+            Color::Rgb(r, g, b) => ratColor::Rgb(r, g, b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Color, Theme};
+    use std::str::FromStr;
+
+    #[test]
+    fn color_parsing() {
+        assert_eq!(Color::from_str("#ff8000"), Ok(Color::Rgb(255, 128, 0)));
+        assert_eq!(Color::from_str("#FF8000"), Ok(Color::Rgb(255, 128, 0)));
+        assert_eq!(
+            Color::from_str("rgb(12, 34, 56)"),
+            Ok(Color::Rgb(12, 34, 56))
+        );
+        assert_eq!(Color::from_str("LightRed"), Ok(Color::LightRed));
+        assert_eq!(Color::from_str("white"), Ok(Color::White));
+
+        assert!(Color::from_str("#ff80").is_err());
+        assert!(Color::from_str("#gg8000").is_err());
+        assert!(Color::from_str("rgb(256, 0, 0)").is_err());
+        assert!(Color::from_str("rgb(1, 2)").is_err());
+        assert!(Color::from_str("orange").is_err());
+    }
+
+    #[test]
+    fn color_display_round_trip() {
+        for color in [Color::Rgb(1, 2, 3), Color::DarkGray, Color::White] {
+            assert_eq!(Color::from_str(&color.to_string()), Ok(color));
+        }
+    }
+
+    #[test]
+    fn theme_parsing() {
+        assert_eq!(Theme::from_str("high-contrast"), Ok(Theme::HighContrast));
+        assert_eq!(Theme::from_str(" Deuteranopia"), Ok(Theme::Deuteranopia));
+        for theme in Theme::ALL {
+            assert_eq!(Theme::from_str(&theme.to_string()), Ok(theme));
         }
+        assert_eq!(
+            Theme::from_str("solarized"),
+            Err(
+                "solarized is not a theme, pick one of dark, light, high_contrast, deuteranopia"
+                    .into()
+            )
+        );
     }
 }
@@ -1,81 +1,781 @@
 use crate::network::client::ChatClient;
 use crate::network::{
-    message::{Message, MessageType, ServerMsg, UserMsg, UserReqMsg},
+    message::{Message, MessageType, ServerMsg, UserMsg},
+    transfer::{save, split, FileChunk, IncomingFile, TransferError},
     User,
 };
-use crate::schema::TextMessage;
-use crate::tui::ui::{ChatStyle, MsgItem, PopupState, StatefulArea, StatefulList, Tui};
+use crate::schema::{IgnoredUser, LocalData, TextMessage};
+use crate::tui::ui::{
+    search_messages, ChatStyle, Deadline, Delivery, MsgItem, PopupState, StatefulArea,
+    StatefulList, Tui,
+};
+use crate::util::LAYOUT_RATIOS;
+use copypasta::{ClipboardContext, ClipboardProvider};
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
-use ratatui::{prelude::*, style::Style};
+use ratatui::prelude::*;
 use regex::Regex;
-use std::collections::HashMap;
-use std::io;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
 use std::net::SocketAddr;
-use tokio::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::sync::mpsc::error::SendError;
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message as TtMessage;
 use tui_textarea::CursorMove;
 
-pub struct ChatApp<'a> {
-    pub running: bool,
-    pub style: ChatStyle,
+const POPUP_DISPLAY_TIME: Duration = Duration::from_secs(3);
+const TYPING_DEBOUNCE: Duration = Duration::from_secs(1);
+const BELL_THROTTLE: Duration = Duration::from_secs(2);
+/// Stored messages fetched at once when joining or scrolling.
+const SCROLLBACK_PAGE: usize = 100;
+/// Most messages kept in memory per room.
+const SCROLLBACK_WINDOW: usize = 500;
+/// How long a sent message may wait for the room to acknowledge it.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Sent inputs kept for recalling.
+const INPUT_HISTORY_LEN: usize = 100;
+
+/// A joined room with its own history and users.
+pub struct RoomSession<'a> {
     pub client: ChatClient,
     pub users: HashMap<SocketAddr, User>,
     pub messages: StatefulList<Text<'a>>,
+    pub typing_users: HashSet<SocketAddr>,
+    /// Messages received while the room wasn't the active one.
+    pub unseen: usize,
+    /// The user messages by their index in `messages`.
+    user_msgs: HashMap<usize, TextMessage>,
+    /// Sent messages not acknowledged yet by their index in `messages`.
+    outgoing: HashMap<usize, Outgoing>,
+    /// Failed messages to send again.
+    resend: Vec<TextMessage>,
+    /// Messages typed while disconnected, sent in order once reconnected.
+    queued: Vec<TextMessage>,
+    typing: TypingNotifier,
+    scrollback: Scrollback,
+    /// Ids of the users whose messages aren't shown.
+    pub ignored: HashSet<String>,
+    /// Files being received by their sender and transfer id, `None` once rejected.
+    incoming: HashMap<(SocketAddr, String), Option<IncomingFile>>,
+}
+
+impl<'a> RoomSession<'a> {
+    pub fn new(mut client: ChatClient) -> Self {
+        client.history = client.history.or(Some(SCROLLBACK_PAGE));
+        Self {
+            client,
+            users: HashMap::new(),
+            messages: StatefulList::default(),
+            typing_users: HashSet::new(),
+            unseen: 0,
+            user_msgs: HashMap::new(),
+            outgoing: HashMap::new(),
+            resend: vec![],
+            queued: vec![],
+            typing: TypingNotifier::default(),
+            scrollback: Scrollback::default(),
+            ignored: HashSet::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    fn set_typing(&mut self, addr: SocketAddr, is_typing: bool) {
+        if is_typing {
+            self.typing_users.insert(addr);
+        } else {
+            self.typing_users.remove(&addr);
+        }
+    }
+
+    /// Line naming the users currently composing a message.
+    pub fn typing_indicator(&self) -> Option<String> {
+        let mut user_ids = self
+            .typing_users
+            .iter()
+            .filter_map(|addr| self.users.get(addr))
+            .map(|user| user._id.as_str())
+            .collect::<Vec<&str>>();
+        user_ids.sort();
+
+        match user_ids[..] {
+            [] => None,
+            [user_id] => Some(format!("{} is typing…", user_id)),
+            _ => Some(format!("{} are typing…", user_ids.join(", "))),
+        }
+    }
+
+    fn is_ignored(&self, msg: &TextMessage) -> bool {
+        self.ignored.contains(&self.sender(msg.sender_addr())._id)
+    }
+
+    /// Senders who are no longer connected are shown by their address.
+    fn sender(&self, addr: &SocketAddr) -> User {
+        self.users.get(addr).cloned().unwrap_or_else(|| User {
+            _id: addr.to_string(),
+            addr: Some(*addr),
+            color: None,
+            away: false,
+            spectator: false,
+        })
+    }
+
+    fn user_item(&self, msg: &TextMessage, style: &ChatStyle) -> Text<'a> {
+        let user = self.sender(msg.sender_addr());
+        MsgItem::user_msg(
+            msg,
+            user.color.unwrap_or_default(),
+            user._id,
+            &self.client.user._id,
+            style,
+        )
+    }
+
+    fn push_user_msg(&mut self, msg: &TextMessage, user: &User, style: &ChatStyle) {
+        self.user_msgs
+            .insert(self.messages.items.len(), msg.clone());
+        self.messages.push(MsgItem::user_msg(
+            msg,
+            user.color.clone().unwrap_or_default(),
+            user._id.clone(),
+            &self.client.user._id,
+            style,
+        ));
+    }
+
+    /// Shows a message stored after the loaded ones, unless the newest ones were dropped or
+    /// the window is full while the user reads older ones.
+    fn push_new_msg(&mut self, msg: &TextMessage, user: &User, style: &ChatStyle) {
+        self.scrollback.older += 1;
+        if self.scrollback.newer > 0
+            || (!self.messages.is_following() && self.messages.items.len() >= SCROLLBACK_WINDOW)
+        {
+            self.scrollback.newer += 1;
+            self.messages.unread += 1;
+            return;
+        }
+        if self.ignored.contains(&user._id) {
+            return;
+        }
+
+        self.push_user_msg(msg, user, style);
+        self.trim_oldest();
+    }
+
+    /// Shows the requested page, answers to earlier requests are ignored.
+    fn load_page(&mut self, offset: usize, msgs: Vec<TextMessage>, style: &ChatStyle) {
+        match self.scrollback.pending {
+            Some((page, expected)) if expected == offset => {
+                self.scrollback.pending = None;
+                match page {
+                    Page::Older => self.prepend_msgs(msgs, style),
+                    Page::Newer => self.append_msgs(msgs, style),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Puts older messages above the loaded ones, dropping the newest ones past the window.
+    fn prepend_msgs(&mut self, msgs: Vec<TextMessage>, style: &ChatStyle) {
+        self.scrollback.older += msgs.len();
+        self.scrollback.exhausted = msgs.len() < SCROLLBACK_PAGE;
+
+        let msgs = msgs
+            .into_iter()
+            .filter(|msg| !self.is_ignored(msg))
+            .collect::<Vec<TextMessage>>();
+        let count = msgs.len();
+
+        let items = msgs
+            .iter()
+            .map(|msg| self.user_item(msg, style))
+            .collect::<Vec<Text>>();
+        self.reindex(|i| Some(i + count));
+        self.user_msgs.extend(msgs.into_iter().enumerate());
+        self.messages.items.splice(0..0, items);
+        match self.messages.state.selected() {
+            Some(i) => self.messages.state.select(Some(i + count)),
+            None => self.messages.select_last(),
+        }
+
+        let len = self.messages.items.len();
+        if len > SCROLLBACK_WINDOW {
+            let dropped = self
+                .user_msgs
+                .keys()
+                .filter(|i| **i >= SCROLLBACK_WINDOW)
+                .count();
+            self.scrollback.newer += dropped;
+            self.reindex(|i| (i < SCROLLBACK_WINDOW).then_some(i));
+            self.messages.items.truncate(SCROLLBACK_WINDOW);
+            if let Some(i) = self.messages.state.selected() {
+                self.messages
+                    .state
+                    .select(Some(i.min(SCROLLBACK_WINDOW - 1)));
+            }
+            self.messages.unread = self.scrollback.newer;
+        }
+    }
+
+    /// Puts messages dropped earlier back below the loaded ones.
+    fn append_msgs(&mut self, msgs: Vec<TextMessage>, style: &ChatStyle) {
+        self.scrollback.newer = self.scrollback.newer.saturating_sub(msgs.len());
+        for msg in msgs {
+            if self.is_ignored(&msg) {
+                continue;
+            }
+            let item = self.user_item(&msg, style);
+            self.user_msgs.insert(self.messages.items.len(), msg);
+            self.messages.items.push(item);
+        }
+        self.messages.unread = self.scrollback.newer;
+        self.trim_oldest();
+    }
+
+    /// Drops the oldest messages past the window, scrolling up loads them again.
+    fn trim_oldest(&mut self) {
+        let excess = self.messages.items.len().saturating_sub(SCROLLBACK_WINDOW);
+        if excess == 0 {
+            return;
+        }
+
+        let dropped = self.user_msgs.keys().filter(|i| **i < excess).count();
+        self.scrollback.older = self.scrollback.older.saturating_sub(dropped);
+        self.scrollback.exhausted &= dropped == 0;
+        self.reindex(|i| i.checked_sub(excess));
+        self.messages.items.drain(..excess);
+        if let Some(i) = self.messages.state.selected() {
+            self.messages.state.select(Some(i.saturating_sub(excess)));
+        }
+    }
+
+    /// Moves the state kept by message index along with the messages, `None` drops it.
+    fn reindex(&mut self, index: impl Fn(usize) -> Option<usize>) {
+        self.user_msgs = self
+            .user_msgs
+            .drain()
+            .filter_map(|(i, msg)| Some((index(i)?, msg)))
+            .collect();
+        self.outgoing = self
+            .outgoing
+            .drain()
+            .filter_map(|(i, outgoing)| Some((index(i)?, outgoing)))
+            .collect();
+    }
+
+    /// Waits for the acknowledgement of the message just shown as the last one.
+    fn track_outgoing(
+        &mut self,
+        msg: TextMessage,
+        delivery: Delivery,
+        now: Instant,
+        style: &ChatStyle,
+    ) {
+        let Some(index) = self.messages.items.len().checked_sub(1) else {
+            return;
+        };
+        self.outgoing.insert(
+            index,
+            Outgoing {
+                msg,
+                delivery,
+                sent_at: now,
+            },
+        );
+        self.render_outgoing(index, style);
+    }
+
+    fn render_outgoing(&mut self, index: usize, style: &ChatStyle) {
+        let Some(outgoing) = self.outgoing.get(&index) else {
+            return;
+        };
+        let user = &self.client.user;
+        let text = MsgItem::user_msg(
+            &outgoing.msg,
+            user.color.clone().unwrap_or_default(),
+            user._id.clone(),
+            &user._id,
+            style,
+        );
+        self.messages.items[index] = MsgItem::mark_delivery(text, outgoing.delivery, style);
+    }
+
+    fn set_delivery(&mut self, index: usize, delivery: Delivery, style: &ChatStyle) {
+        if let Some(outgoing) = self.outgoing.get_mut(&index) {
+            outgoing.delivery = delivery;
+            self.render_outgoing(index, style);
+        }
+        if delivery == Delivery::Sent {
+            self.outgoing.remove(&index);
+        }
+    }
+
+    /// Marks the message sent at `timestamp` as delivered.
+    fn acknowledge(&mut self, timestamp: SystemTime, style: &ChatStyle) {
+        let index = self
+            .outgoing
+            .iter()
+            .find(|(_, outgoing)| *outgoing.msg.timestamp() == timestamp)
+            .map(|(i, _)| *i);
+        if let Some(index) = index {
+            self.set_delivery(index, Delivery::Sent, style);
+        }
+    }
+
+    /// Marks the messages waiting longer than `ACK_TIMEOUT` as failed.
+    fn expire_outgoing(&mut self, now: Instant, style: &ChatStyle) {
+        let expired = self
+            .outgoing
+            .iter()
+            .filter(|(_, outgoing)| {
+                outgoing.delivery == Delivery::Pending
+                    && now.duration_since(outgoing.sent_at) >= ACK_TIMEOUT
+            })
+            .map(|(i, _)| *i)
+            .collect::<Vec<usize>>();
+        for index in expired {
+            self.set_delivery(index, Delivery::Failed, style);
+        }
+    }
+
+    /// Marks the oldest pending message as failed, the room dropped it.
+    fn reject_outgoing(&mut self, style: &ChatStyle) {
+        let oldest = self
+            .outgoing
+            .iter()
+            .filter(|(_, outgoing)| outgoing.delivery == Delivery::Pending)
+            .map(|(i, _)| *i)
+            .min();
+        if let Some(index) = oldest {
+            self.set_delivery(index, Delivery::Failed, style);
+        }
+    }
+
+    /// Queues the failed messages to be sent again.
+    fn retry_failed(&mut self, now: Instant, style: &ChatStyle) {
+        let mut failed = self
+            .outgoing
+            .iter()
+            .filter(|(_, outgoing)| outgoing.delivery == Delivery::Failed)
+            .map(|(i, _)| *i)
+            .collect::<Vec<usize>>();
+        failed.sort_unstable();
+        for index in failed {
+            if let Some(outgoing) = self.outgoing.get_mut(&index) {
+                outgoing.sent_at = now;
+                self.resend.push(outgoing.msg.clone());
+            }
+            self.set_delivery(index, Delivery::Pending, style);
+        }
+    }
+
+    async fn send_text_msg(&self, msg: &TextMessage) -> Result<(), SendError<TtMessage>> {
+        self.send_user_msg(UserMsg::Normal { msg: msg.clone() })
+            .await
+    }
+
+    /// Stores a chunk of a file sent by `addr`, returns the file once all its chunks arrived.
+    /// The remaining chunks of a rejected file are dropped.
+    fn receive_chunk(
+        &mut self,
+        addr: SocketAddr,
+        chunk: &FileChunk,
+        max_size: u64,
+    ) -> Result<Option<IncomingFile>, TransferError> {
+        let key = (addr, chunk.transfer_id.clone());
+        let is_last = chunk.index + 1 >= chunk.count;
+        let received = match self.incoming.remove(&key) {
+            Some(Some(file)) => Ok(file),
+            Some(None) => {
+                if !is_last {
+                    self.incoming.insert(key, None);
+                }
+                return Ok(None);
+            }
+            None => IncomingFile::new(chunk, max_size),
+        }
+        .and_then(|mut file| Ok((file.add(chunk)?, file)));
+
+        match received {
+            Ok((true, file)) => Ok(Some(file)),
+            Ok((false, file)) => {
+                self.incoming.insert(key, Some(file));
+                Ok(None)
+            }
+            Err(err) => {
+                if !is_last {
+                    self.incoming.insert(key, None);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn send_user_msg(&self, user_msg: UserMsg) -> Result<(), SendError<TtMessage>> {
+        self.client.send_msg(Message::from(user_msg)).await
+    }
+
+    /// Reconnects in the background once the connection dropped, then sends the messages
+    /// queued meanwhile in their order. They're marked failed when the room can't be reached
+    /// anymore.
+    async fn keep_connected(&mut self, style: &ChatStyle) {
+        match self.client.poll_reconnect() {
+            None => (),
+            Some(Ok(())) => {
+                // sent before the sync so the history comes with them
+                for msg in std::mem::take(&mut self.queued) {
+                    if let Err(err) = self.send_text_msg(&msg).await {
+                        log::warn!("Failed to send a queued message: {}", err);
+                    }
+                }
+                // the server sends the whole state again
+                self.clear_messages();
+                self.users.clear();
+                self.typing_users.clear();
+                if let Err(err) = self.client.sync().await {
+                    log::warn!("Failed to request the messages: {}", err);
+                }
+            }
+            Some(Err(err)) => {
+                self.messages.items.push(MsgItem::info_msg(
+                    format!("Connection lost: {}", err),
+                    style,
+                ));
+                let dropped = std::mem::take(&mut self.queued).len();
+                if dropped > 0 {
+                    log::warn!("Dropped {} queued messages", dropped);
+                    self.fail_queued(style);
+                    self.messages.items.push(MsgItem::info_msg(
+                        format!("{} queued messages couldn't be sent.", dropped),
+                        style,
+                    ));
+                }
+                self.messages.select_last();
+            }
+        }
+    }
+
+    /// Tells the user what couldn't be sent, the connection is closed.
+    fn report_unsent(&mut self, what: &str, err: SendError<TtMessage>, style: &ChatStyle) {
+        log::warn!("Failed to send {}: {}", what, err);
+        self.messages.push(MsgItem::info_msg(
+            format!("Not connected, {} hasn't been sent.", what),
+            style,
+        ));
+    }
+
+    fn fail_queued(&mut self, style: &ChatStyle) {
+        let queued = self
+            .outgoing
+            .iter()
+            .filter(|(_, outgoing)| outgoing.delivery == Delivery::Queued)
+            .map(|(i, _)| *i)
+            .collect::<Vec<usize>>();
+        for index in queued {
+            self.set_delivery(index, Delivery::Failed, style);
+        }
+    }
+
+    /// Replaces the loaded messages with the newest page.
+    fn reload_latest(&mut self) {
+        self.clear_messages();
+        self.scrollback.want(Page::Older);
+    }
+
+    /// Plain content of the selected message, info lines have none.
+    fn selected_content(&self) -> Option<&String> {
+        self.messages
+            .state
+            .selected()
+            .and_then(|i| self.user_msgs.get(&i))
+            .map(TextMessage::content)
+    }
+
+    fn msg_index(&self, id: &str) -> Option<usize> {
+        self.user_msgs
+            .iter()
+            .find(|(_, msg)| msg.id() == id)
+            .map(|(i, _)| *i)
+    }
+
+    /// The user's message an edit applies to, the selected one or else the newest.
+    fn own_msg_id(&self) -> Result<String, String> {
+        // the messages sent before reconnecting came from another address
+        let author = self.client.author();
+        let is_own = |msg: &&TextMessage| {
+            Some(msg.sender_addr()) == self.client.user.addr.as_ref()
+                || author.is_some() && msg.author() == author.as_ref()
+        };
+        let selected = self
+            .messages
+            .state
+            .selected()
+            .filter(|_| self.messages.is_highlighted);
+
+        let msg = match selected {
+            Some(i) => self
+                .user_msgs
+                .get(&i)
+                .filter(is_own)
+                .ok_or("Only your own messages can be changed.")?,
+            None => self
+                .user_msgs
+                .iter()
+                .filter(|(_, msg)| is_own(msg))
+                .max_by_key(|(i, _)| **i)
+                .map(|(_, msg)| msg)
+                .ok_or("You haven't sent any message yet.")?,
+        };
+        Ok(msg.id().clone())
+    }
+
+    /// Shows the new content of an edited message, if it's loaded.
+    fn apply_edit(&mut self, id: &str, content: String, style: &ChatStyle) {
+        let Some(index) = self.msg_index(id) else {
+            return;
+        };
+        if let Some(msg) = self.user_msgs.get_mut(&index) {
+            msg.edit(content);
+        }
+        let item = self.user_item(&self.user_msgs[&index], style);
+        self.messages.items[index] = item;
+    }
+
+    /// Removes a deleted message, if it's loaded.
+    fn apply_delete(&mut self, id: &str) {
+        let Some(index) = self.msg_index(id) else {
+            return;
+        };
+        self.messages.items.remove(index);
+        self.reindex(|i| match i.cmp(&index) {
+            Ordering::Less => Some(i),
+            Ordering::Equal => None,
+            Ordering::Greater => Some(i - 1),
+        });
+        self.scrollback.older = self.scrollback.older.saturating_sub(1);
+        if let Some(selected) = self.messages.state.selected() {
+            if selected > index || selected >= self.messages.items.len() {
+                self.messages.state.select(selected.checked_sub(1));
+            }
+        }
+    }
+
+    fn clear_messages(&mut self) {
+        self.messages.items.clear();
+        self.user_msgs.clear();
+        self.outgoing.clear();
+        self.messages.select_last();
+        self.scrollback = Scrollback::default();
+    }
+
+    fn user_addr(&self, user_id: &str) -> Option<SocketAddr> {
+        self.users
+            .values()
+            .find(|user| user._id == user_id)
+            .and_then(|user| user.addr)
+    }
+}
+
+pub struct ChatApp<'a> {
+    pub running: bool,
+    pub style: ChatStyle,
+    pub sessions: Vec<RoomSession<'a>>,
+    /// Index of the session being shown.
+    pub active: usize,
     pub current_popup: PopupState,
+    pub popup_deadline: Option<Deadline>,
     pub msg_area: StatefulArea<'a>,
     pub commands: Vec<Command>,
+    pub search: Option<Search>,
+    /// Set for the room's own server, the chat quits once it shuts down.
+    pub hosting: bool,
+    /// Where received files are saved.
+    pub download_dir: PathBuf,
+    input_history: InputHistory,
+    clipboard: Option<ClipboardContext>,
+    bell: Bell,
+    idle: IdleTracker,
+    max_msg_len: u32,
+    max_file_size: u64,
+    /// Complete files waiting for the user to save or drop them, oldest first.
+    received: VecDeque<ReceivedFile>,
+    alt_screen: bool,
 }
 
 impl<'a> ChatApp<'a> {
-    pub fn new(client: ChatClient, light_mode: bool) -> Self {
-        let mut style = ChatStyle::new(
-            Style::new().bg(Color::Rgb(0, 0, 0)).fg(Color::White),
-            Style::new().fg(Color::Yellow),
-            Style::new().fg(Color::Rgb(0, 0, 0)).bg(Color::White).bold(),
-        );
-
-        if light_mode {
-            style.reverse_colors();
+    pub fn new(clients: Vec<ChatClient>, local_data: &LocalData) -> Self {
+        let mut style = ChatStyle::themed(local_data.current_theme());
+        style.timestamp_format = local_data.timestamp_format.clone();
+        style.timezone = local_data.timezone;
+        style.markdown = local_data.markdown;
+        style.mention_prefix = local_data.mention_prefix;
+        style.layout_ratio = local_data
+            .layout_ratio
+            .clamp(*LAYOUT_RATIOS.start(), *LAYOUT_RATIOS.end());
+        if let Some(color) = local_data.input_border.clone() {
+            style.input_border = style.input_border.fg(color.into());
+        }
+        if let Some(color) = local_data.input_highlight.clone() {
+            style.input_highlight = style.input_highlight.bg(color.into());
+        }
+        if let Some(color) = local_data.input_placeholder.clone() {
+            style.placeholder = style.placeholder.fg(color.into());
         }
 
         Self {
             running: true,
             style: style.clone(),
-            client,
-            users: HashMap::new(),
-            messages: StatefulList::default(),
+            sessions: clients
+                .into_iter()
+                .map(|client| {
+                    let mut session = RoomSession::new(client);
+                    let room_id = session.client.room.lock().unwrap()._id.clone();
+                    session.ignored = local_data
+                        .ignored
+                        .iter()
+                        .filter(|ignored| ignored.room_id == room_id)
+                        .map(|ignored| ignored.user_id.clone())
+                        .collect();
+                    session
+                })
+                .collect(),
+            active: 0,
             msg_area: StatefulArea::new(style),
             current_popup: PopupState::None,
-            commands: vec![(Regex::new(r"/ban\s+(\S+)").unwrap(), Action::Ban)],
+            popup_deadline: None,
+            commands: vec![
+                (Regex::new(r"^/ban\s+@?(\S+)$").unwrap(), Action::Ban),
+                (Regex::new(r"^/kick\s+@?(\S+)$").unwrap(), Action::Kick),
+                (Regex::new(r"^/ignore\s+@?(\S+)$").unwrap(), Action::Ignore),
+                (
+                    Regex::new(r"^/unignore\s+@?(\S+)$").unwrap(),
+                    Action::Unignore,
+                ),
+                (Regex::new(r"(?s)^/me\s+(.+)$").unwrap(), Action::Me),
+                (Regex::new(r"(?s)^/edit\s+(.+)$").unwrap(), Action::Edit),
+                (Regex::new(r"^/delete$").unwrap(), Action::Delete),
+                (Regex::new(r"^/send\s+(.+)$").unwrap(), Action::Send),
+                (Regex::new(r"^/help$").unwrap(), Action::Help),
+                (Regex::new(r"^/clear$").unwrap(), Action::Clear),
+            ],
+            search: None,
+            hosting: false,
+            download_dir: dirs::download_dir().unwrap_or_else(|| PathBuf::from(".")),
+            input_history: InputHistory::default(),
+            clipboard: None,
+            bell: Bell::new(local_data.bell, local_data.bell_mentions_only),
+            idle: IdleTracker::new(
+                Some(Duration::from_secs(local_data.away_after.into()))
+                    .filter(|after| !after.is_zero()),
+                Instant::now(),
+            ),
+            max_msg_len: local_data.max_msg_len,
+            max_file_size: local_data.max_file_size.into(),
+            received: VecDeque::new(),
+            alt_screen: local_data.alt_screen,
+        }
+    }
+
+    /// The ignore lists of the joined rooms along with the `stored` ones of the others.
+    pub fn ignored_users(&self, stored: &[IgnoredUser]) -> Vec<IgnoredUser> {
+        let room_ids = self
+            .sessions
+            .iter()
+            .map(|session| session.client.room.lock().unwrap()._id.clone())
+            .collect::<Vec<String>>();
+
+        let mut ignored = stored
+            .iter()
+            .filter(|ignored| !room_ids.contains(&ignored.room_id))
+            .cloned()
+            .collect::<Vec<IgnoredUser>>();
+        for (session, room_id) in self.sessions.iter().zip(room_ids) {
+            let mut user_ids = session.ignored.iter().collect::<Vec<&String>>();
+            user_ids.sort();
+            ignored.extend(user_ids.into_iter().map(|user_id| IgnoredUser {
+                room_id: room_id.clone(),
+                user_id: user_id.clone(),
+            }));
         }
+        ignored
+    }
+
+    pub fn session(&self) -> &RoomSession<'a> {
+        &self.sessions[self.active]
+    }
+
+    pub fn session_mut(&mut self) -> &mut RoomSession<'a> {
+        &mut self.sessions[self.active]
     }
 
     pub async fn run(&mut self) -> io::Result<()> {
-        let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        let terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
         let mut tui = Tui::new(terminal);
+        tui.alt_screen = self.alt_screen;
         tui.term_init()?;
 
         while self.running {
-            self.handle_msgs().await;
+            self.update().await;
             tui.draw(self)?;
             self.handle_input().await?;
         }
 
-        tui.term_restore()?;
-        Ok(())
+        for session in self.sessions.iter_mut() {
+            session.client.leave().await;
+        }
+        tui.term_restore()
+    }
+
+    /// Everything the loop does besides drawing and reading the input.
+    async fn update(&mut self) {
+        self.fetch_scrollback().await;
+        self.resend_failed().await;
+        self.keep_connected().await;
+        self.dismiss_expired_popup();
+        self.offer_received_file();
+        for session in self.sessions.iter_mut() {
+            session.expire_outgoing(Instant::now(), &self.style);
+        }
+        self.update_away(false).await;
+        self.handle_msgs().await;
     }
 
     async fn handle_input(&mut self) -> io::Result<()> {
         if event::poll(Duration::from_millis(10))? {
             let key_event = event::read()?;
+            if let Event::Key(_) | Event::Paste(_) = key_event {
+                self.update_away(true).await;
+            }
+
+            if let Event::Key(KeyEvent {
+                code, modifiers, ..
+            }) = key_event
+            {
+                if self.current_popup == PopupState::ConfirmQuit {
+                    self.confirm_quit(code, modifiers);
+                    return Ok(());
+                }
+                if matches!(self.current_popup, PopupState::SaveFile(..)) {
+                    self.answer_save(code);
+                    return Ok(());
+                }
+            }
 
             // this has to be fixed
             if let Event::Key(_) = key_event {
                 if self.current_popup != PopupState::None {
                     self.current_popup = PopupState::None;
+                    self.popup_deadline = None;
                 }
             }
 
             match key_event {
+                Event::Key(KeyEvent {
+                    code, modifiers, ..
+                }) if self.search.is_some() => self.handle_search_key(code, modifiers),
                 Event::Key(KeyEvent {
                     code, modifiers, ..
                 }) => match code {
@@ -85,213 +785,2453 @@ impl<'a> ChatApp<'a> {
                     KeyCode::Right => {
                         self.msg_area.textarea.move_cursor(CursorMove::Forward);
                     }
+                    // the history is only browsed from the edges of the input
+                    KeyCode::Up if self.msg_area.textarea.cursor().0 == 0 => {
+                        self.recall_older();
+                    }
                     KeyCode::Up => {
                         self.msg_area.textarea.move_cursor(CursorMove::Up);
                     }
+                    KeyCode::Down
+                        if self.msg_area.textarea.cursor().0 + 1
+                            >= self.msg_area.textarea.lines().len() =>
+                    {
+                        self.recall_newer();
+                    }
                     KeyCode::Down => {
                         self.msg_area.textarea.move_cursor(CursorMove::Down);
                     }
-                    KeyCode::Char('k') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.messages.is_highlighted = true;
-                        self.messages.previous();
-                    }
-                    KeyCode::Char('j') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.messages.is_highlighted = true;
-                        self.messages.next();
-                    }
-                    KeyCode::Char('q') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.client.close_connection();
-                        self.running = false;
-                    }
                     KeyCode::Enter => {
                         self.handle_text_buffer().await;
                     }
-                    KeyCode::Char('l') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.current_popup = PopupState::List;
-                    }
-                    KeyCode::Char('h') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.current_popup = PopupState::Help;
+                    KeyCode::Tab if modifiers.contains(KeyModifiers::CONTROL) => {
+                        self.handle_key_action(KeyAction::NextRoom);
                     }
-                    KeyCode::Char('y') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.msg_area.textarea.copy();
-                    }
-                    KeyCode::Char('p') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        _ = self.msg_area.textarea.paste();
+                    KeyCode::Char(c) if modifiers.contains(KeyModifiers::CONTROL) => {
+                        if let Some(binding) = Keybinding::find(c) {
+                            self.handle_key_action(binding.action);
+                        }
                     }
                     KeyCode::Backspace => {
                         self.handle_deleting_chars();
                     }
                     _ => {
-                        self.messages.is_highlighted = false;
+                        self.session_mut().messages.is_highlighted = false;
                         self.msg_area.on_input_update(key_event.into());
                     }
                 },
+                Event::Paste(text) if self.search.is_none() => {
+                    self.session_mut().messages.is_highlighted = false;
+                    self.msg_area.paste(&text);
+                }
                 _ => (),
             }
+
+            self.notify_typing().await;
         }
         Ok(())
     }
 
-    async fn handle_text_buffer(&mut self) {
-        self.msg_area.height = 0;
+    fn handle_search_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
 
-        if let Some(text) = self.msg_area.get_text() {
-            if !self.parse_commands(&text).await {
-                let user = self.client.user.clone();
-                let room = self.client.room.lock().unwrap();
-                let msg = TextMessage::new(&user.addr.unwrap(), &room._id, &text);
-
-                self.client
-                    .send_msg(Message::from((
-                        UserMsg::Normal { msg: msg.clone() },
-                        room.passwd.clone(),
-                    )))
-                    .await
-                    .unwrap();
+        match code {
+            KeyCode::Esc => {
+                self.search = None;
+                let messages = &mut self.session_mut().messages;
+                messages.is_highlighted = false;
+                messages.select_last();
+            }
+            KeyCode::Enter => {
+                // moves to the previous, older match
+                if !search.matches.is_empty() {
+                    search.current = search
+                        .current
+                        .checked_sub(1)
+                        .unwrap_or(search.matches.len() - 1);
+                }
+                self.select_search_match();
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                self.update_search();
+            }
+            KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => {
+                search.query.push(c);
+                self.update_search();
+            }
+            _ => (),
+        }
+    }
 
-                self.messages.items.push(MsgItem::user_msg(
-                    &msg,
-                    user.color.clone(),
-                    user._id.clone(),
-                ));
-                self.messages.select_last();
+    /// Finds the matches of the current query, starting from the most recent one.
+    fn update_search(&mut self) {
+        if let Some(search) = &mut self.search {
+            search.matches =
+                search_messages(&self.sessions[self.active].messages.items, &search.query);
+            search.current = search.matches.len().saturating_sub(1);
+        }
+        self.select_search_match();
+    }
+
+    fn select_search_match(&mut self) {
+        let selected = self
+            .search
+            .as_ref()
+            .and_then(|search| search.matches.get(search.current).copied());
+
+        let messages = &mut self.session_mut().messages;
+        messages.is_highlighted = selected.is_some();
+        if selected.is_some() {
+            messages.state.select(selected);
+        }
+    }
+
+    /// Lets the rooms know once the input starts or stops holding an unsent message,
+    /// which only ever concerns the active one.
+    async fn notify_typing(&mut self) {
+        let is_typing = !self.msg_area.is_empty();
+        for (index, session) in self.sessions.iter_mut().enumerate() {
+            if let Some(is_typing) = session
+                .typing
+                .update(is_typing && index == self.active, Instant::now())
+            {
+                if let Err(err) = session.client.typing(is_typing).await {
+                    log::warn!("Failed to send the typing status: {}", err);
+                }
             }
         }
     }
 
-    async fn handle_msgs(&mut self) {
-        if let Some(msg_type) = self.client.recv_msg().await.take() {
-            match msg_type {
-                MessageType::User(user_msg) => match user_msg {
-                    UserMsg::Normal { msg } => {
-                        let user = self.users.get(&msg.sender_addr()).unwrap();
-                        self.messages.items.push(MsgItem::user_msg(
-                            &msg,
-                            user.color.clone(),
-                            user._id.clone(),
-                        ));
+    /// Asks the active room for the page scrolled to.
+    async fn fetch_scrollback(&mut self) {
+        let session = self.session_mut();
+        if let Some((offset, limit)) = session.scrollback.next_request() {
+            if let Err(err) = session.client.request_page(offset, limit).await {
+                log::warn!("Failed to request the message history: {}", err);
+            }
+        }
+    }
 
-                        self.messages.select_last();
-                    }
-                    UserMsg::UserJoined { user } => {
-                        if user._id == self.client.user._id {
-                            self.client.user.addr = user.addr;
-                        }
-                        self.users.insert(user.addr.unwrap(), user.clone());
+    /// Sends the messages queued by a retry again.
+    async fn resend_failed(&mut self) {
+        for session in self.sessions.iter_mut() {
+            for msg in std::mem::take(&mut session.resend) {
+                if let Err(err) = session.send_text_msg(&msg).await {
+                    log::warn!("Failed to resend a message: {}", err);
+                }
+            }
+        }
+    }
 
-                        self.messages.items.push(MsgItem::info_msg(
-                            format!("{} has joined", user._id),
-                            Color::Rgb(50, 50, 50).into(),
-                        ));
+    /// Lets the rooms know once the user goes idle or comes back.
+    async fn update_away(&mut self, had_input: bool) {
+        let Some(away) = self.idle.update(had_input, Instant::now()) else {
+            return;
+        };
 
-                        self.client.sync().await.unwrap();
+        for session in self.sessions.iter_mut() {
+            if let Err(err) = session.client.set_away(away).await {
+                log::warn!("Failed to send the away status: {}", err);
+            }
+            if let Some(user) = session
+                .client
+                .user
+                .addr
+                .and_then(|addr| session.users.get_mut(&addr))
+            {
+                user.away = away;
+            }
+        }
+    }
 
-                        self.messages.select_last();
-                    }
-                },
-                MessageType::Server(server_msg) => match server_msg {
-                    ServerMsg::AuthFailure => panic!("Authentication failure"),
-                    ServerMsg::Sync { messages, users } => {
-                        self.messages.items.append(
-                            &mut messages
-                                .iter()
-                                .map(|msg| {
-                                    let user = self.users.get(&msg.sender_addr()).unwrap();
-                                    MsgItem::user_msg(&msg, user.color.clone(), user._id.clone())
-                                })
-                                .collect::<Vec<Text>>(),
-                        );
-
-                        self.users.extend(
-                            users
-                                .into_iter()
-                                .map(|user| (user.addr.unwrap(), user))
-                                .collect::<HashMap<SocketAddr, User>>(),
-                        );
-
-                        self.messages.select_last();
-                    }
-                    ServerMsg::UserLeft { addr } => {
-                        self.messages.items.push(MsgItem::info_msg(
-                            format!("{} has left", self.users.get(&addr).unwrap()._id),
-                            Color::Rgb(50, 50, 50).into(),
-                        ));
-                        self.messages.select_last();
-                    }
-                    ServerMsg::BanConfirm { addr } => {
-                        self.messages.items.push(MsgItem::info_msg(
-                            format!("{} has been banned", self.users.get(&addr).unwrap()._id),
-                            Color::Rgb(50, 50, 50).into(),
-                        ));
-                        self.messages.select_last();
-                    }
-                    ServerMsg::ServerShutdown => {
-                        self.client.close_connection();
+    /// The popup stays while any room is reconnecting.
+    async fn keep_connected(&mut self) {
+        for session in self.sessions.iter_mut() {
+            session.keep_connected(&self.style).await;
+        }
 
-                        self.messages.items.push(MsgItem::info_msg(
-                            String::from("Server has been shutted down."),
-                            Color::Rgb(50, 50, 50).into(),
-                        ));
-                    }
-                },
-                _ => (),
+        let reconnecting = self
+            .sessions
+            .iter()
+            .any(|session| session.client.is_reconnecting());
+        match self.current_popup {
+            PopupState::None if reconnecting => {
+                self.current_popup = PopupState::Reconnecting;
+                self.popup_deadline = None;
             }
+            PopupState::Reconnecting if !reconnecting => {
+                self.current_popup = PopupState::None;
+            }
+            _ => (),
         }
     }
 
-    fn handle_deleting_chars(&mut self) {
-        if self.msg_area.textarea.cursor().1 == 0 && self.msg_area.textarea.cursor().0 > 0 {
-            self.msg_area.textarea.delete_newline();
-            self.msg_area.height -= 1;
-        } else {
-            self.msg_area.textarea.delete_char();
+    fn show_timed_popup(&mut self, popup: PopupState) {
+        self.current_popup = popup;
+        self.popup_deadline = Some(Deadline::new(POPUP_DISPLAY_TIME));
+    }
+
+    fn dismiss_expired_popup(&mut self) {
+        if self
+            .popup_deadline
+            .is_some_and(|deadline| deadline.has_time_passed())
+        {
+            self.current_popup = PopupState::None;
+            self.popup_deadline = None;
         }
     }
 
-    async fn parse_commands(&self, haystack: &str) -> bool {
-        for command in self.commands.iter() {
-            if let Some(args) = Self::parse_command(command, haystack) {
-                match command.1 {
-                    Action::Ban => self
-                        .client
-                        .ban(
-                            &self
-                                .users
-                                .iter()
-                                .find(|(_, user)| user._id == args[0])
-                                .unwrap()
-                                .1
-                                .addr
-                                .unwrap(),
-                        )
-                        .await
-                        .unwrap(),
+    fn handle_key_action(&mut self, action: KeyAction) {
+        match action {
+            KeyAction::UserList => self.current_popup = PopupState::List,
+            KeyAction::ScrollDown => {
+                let session = self.session_mut();
+                if session.messages.is_at_bottom() {
+                    session.scrollback.want(Page::Newer);
+                }
+                session.messages.is_highlighted = true;
+                session.messages.next();
+            }
+            KeyAction::ScrollUp => {
+                let session = self.session_mut();
+                if session.messages.items.is_empty() || session.messages.state.selected() == Some(0)
+                {
+                    session.scrollback.want(Page::Older);
+                }
+                session.messages.is_highlighted = true;
+                session.messages.previous();
+            }
+            KeyAction::Copy => self.msg_area.textarea.copy(),
+            KeyAction::CopyMessage => self.copy_selected_msg(),
+            KeyAction::Paste => {
+                let yanked = self.msg_area.textarea.yank_text();
+                self.msg_area.paste(&yanked);
+            }
+            KeyAction::Help => self.current_popup = PopupState::Help,
+            KeyAction::Search => self.search = Some(Search::default()),
+            KeyAction::JumpToLatest => {
+                let session = self.session_mut();
+                if session.scrollback.newer > 0 {
+                    session.reload_latest();
+                }
+                session.messages.is_highlighted = false;
+                session.messages.select_last();
+            }
+            KeyAction::ToggleSelect => {
+                let messages = &mut self.session_mut().messages;
+                messages.is_highlighted = !messages.is_highlighted;
+                if !messages.is_highlighted {
+                    messages.select_last();
                 }
-                return true;
             }
+            KeyAction::Retry => {
+                let session = &mut self.sessions[self.active];
+                session.retry_failed(Instant::now(), &self.style);
+            }
+            KeyAction::NextRoom => self.switch_room((self.active + 1) % self.sessions.len()),
+            KeyAction::Exit if !self.msg_area.textarea.is_empty() => {
+                self.current_popup = PopupState::ConfirmQuit;
+                self.popup_deadline = None;
+            }
+            KeyAction::Exit => self.exit(),
         }
+    }
 
-        false
+    /// Shows another room, the search only applies to the one it was started in.
+    fn switch_room(&mut self, index: usize) {
+        self.active = index;
+        self.search = None;
+        self.session_mut().unseen = 0;
     }
 
-    fn parse_command(command: &Command, haystack: &str) -> Option<Vec<String>> {
-        if let Some(captures) = command.0.captures(haystack) {
-            return Some(
-                captures
-                    .iter()
-                    .map(|cap| cap.unwrap().as_str().to_string())
-                    .collect::<Vec<String>>(),
+    /// Quits on a second ctrl+q or `y`, any other key only dismisses the popup.
+    fn confirm_quit(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        self.current_popup = PopupState::None;
+        match code {
+            KeyCode::Char('q') if modifiers.contains(KeyModifiers::CONTROL) => self.exit(),
+            KeyCode::Char('y') if !modifiers.contains(KeyModifiers::CONTROL) => self.exit(),
+            _ => (),
+        }
+    }
+
+    /// Asks about the oldest received file once no other popup is shown.
+    fn offer_received_file(&mut self) {
+        if self.current_popup != PopupState::None {
+            return;
+        }
+        if let Some(file) = self.received.front() {
+            self.current_popup = PopupState::SaveFile(
+                file.sender.clone(),
+                file.name.clone(),
+                file.data.len() as u64,
             );
+            self.popup_deadline = None;
         }
-        None
     }
-}
+
+    /// Saves the offered file into the download directory on `y`, any other key drops it.
+    fn answer_save(&mut self, code: KeyCode) {
+        self.current_popup = PopupState::None;
+        let Some(file) = self.received.pop_front() else {
+            return;
+        };
+
+        let info = match code {
+            KeyCode::Char('y') => match save(&self.download_dir, &file.name, &file.data) {
+                Ok(path) => format!("Saved {}.", path.display()),
+                Err(err) => format!("Couldn't save {}: {}", file.name, err),
+            },
+            _ => format!("Dropped {}.", file.name),
+        };
+        let session = &mut self.sessions[self.active];
+        session.messages.push(MsgItem::info_msg(info, &self.style));
+    }
+
+    /// Sends the file at `path` to the active room.
+    async fn send_file(&mut self, path: &str) {
+        let path = Path::new(path.trim());
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let max_size = self.max_file_size;
+        // the size is checked before reading, so large files aren't loaded for nothing
+        let chunks = fs::metadata(path)
+            .map_err(|err| err.to_string())
+            .and_then(|metadata| match metadata.len() {
+                size if size > max_size => {
+                    Err(TransferError::TooLarge { size, max_size }.to_string())
+                }
+                _ => fs::read(path).map_err(|err| err.to_string()),
+            })
+            .and_then(|data| split(&name, &data, max_size).map_err(|err| err.to_string()));
+
+        let session = &mut self.sessions[self.active];
+        let info = match chunks {
+            Ok(_) if session.client.is_disconnected() => {
+                format!("Not connected, {} hasn't been sent.", name)
+            }
+            Ok(chunks) => {
+                let size = chunks[0].size;
+                match session.client.send_file(chunks).await {
+                    Ok(()) => format!("Sent {} ({} bytes).", name, size),
+                    Err(err) => {
+                        log::warn!("Failed to send a file: {}", err);
+                        format!("Not connected, {} hasn't been sent.", name)
+                    }
+                }
+            }
+            Err(err) => format!("Couldn't send {}: {}", path.display(), err),
+        };
+        session.messages.push(MsgItem::info_msg(info, &self.style));
+    }
+
+    /// Stops the chat, the rooms are left once the loop ends.
+    fn exit(&mut self) {
+        self.running = false;
+    }
+
+    /// Shows the input sent before the one shown, keeping the unsent one as the draft.
+    fn recall_older(&mut self) {
+        let current = self.msg_area.textarea.lines().join("\n");
+        if let Some(text) = self.input_history.older(&current) {
+            self.msg_area.set_text(text);
+        }
+    }
+
+    /// Shows the input sent after the one shown, or the draft past the newest.
+    fn recall_newer(&mut self) {
+        if let Some(text) = self.input_history.newer() {
+            self.msg_area.set_text(text);
+        }
+    }
+
+    async fn handle_text_buffer(&mut self) {
+        if let Some(text) = self.msg_area.get_text() {
+            self.input_history.push(text.clone());
+            if !self.parse_commands(&text).await {
+                self.send_text(&text).await;
+            }
+        }
+    }
+
+    async fn handle_msgs(&mut self) {
+        for index in 0..self.sessions.len() {
+            if let Some(msg_type) = self.sessions[index].client.recv_msg().await.take() {
+                self.handle_msg(index, msg_type).await;
+            }
+        }
+    }
+
+    /// Handles a message of the session at `index`, popups are only shown for the active one.
+    async fn handle_msg(&mut self, index: usize, msg_type: MessageType) {
+        let is_active = index == self.active;
+        let session = &mut self.sessions[index];
+
+        match msg_type {
+            MessageType::User(user_msg) => match user_msg {
+                UserMsg::Normal { msg } => {
+                    // the room echoing a message shown on sending only confirms it
+                    if let Some(index) = session.msg_index(msg.id()) {
+                        session.set_delivery(index, Delivery::Sent, &self.style);
+                        return;
+                    }
+                    session.set_typing(*msg.sender_addr(), false);
+                    let user = session.sender(msg.sender_addr());
+                    session.push_new_msg(&msg, &user, &self.style);
+                    if session.ignored.contains(&user._id) {
+                        return;
+                    }
+                    if !is_active {
+                        session.unseen += 1;
+                    }
+
+                    let mentioned =
+                        MsgItem::mentions(msg.content(), &session.client.user._id, &self.style);
+                    if self.bell.should_ring(mentioned, Instant::now()) {
+                        Bell::ring();
+                    }
+                }
+                UserMsg::UserJoined { user } => {
+                    if user._id == session.client.user._id {
+                        session.client.user.addr = user.addr;
+                        session.client.user.color = user.color.clone();
+                    }
+                    session.users.insert(user.addr.unwrap(), user.clone());
+
+                    session.messages.push(MsgItem::info_msg(
+                        format!("{} has joined", user._id),
+                        &self.style,
+                    ));
+
+                    if let Err(err) = session.client.sync().await {
+                        log::warn!("Failed to request the messages: {}", err);
+                    }
+                    if is_active && !session.ignored.contains(&user._id) {
+                        self.show_timed_popup(PopupState::JoinedLeft(user._id, true));
+                    }
+                }
+                UserMsg::Typing { addr, is_typing } => session.set_typing(addr, is_typing),
+                UserMsg::Edit { id, content } => session.apply_edit(&id, content, &self.style),
+                UserMsg::Delete { id } => session.apply_delete(&id),
+                UserMsg::File { addr, chunk } => {
+                    let user = session.sender(&addr);
+                    if session.ignored.contains(&user._id) {
+                        return;
+                    }
+                    let received = session
+                        .receive_chunk(addr, &chunk, self.max_file_size)
+                        .and_then(|file| file.map(IncomingFile::assemble).transpose());
+                    let info = match received {
+                        Ok(None) => return,
+                        Ok(Some(data)) => {
+                            let info = format!(
+                                "{} sent the file {} ({} bytes).",
+                                user._id,
+                                chunk.name,
+                                data.len()
+                            );
+                            self.received.push_back(ReceivedFile {
+                                sender: user._id,
+                                name: chunk.name,
+                                data,
+                            });
+                            info
+                        }
+                        Err(err) => {
+                            format!("Dropped the file {} from {}. {}", chunk.name, user._id, err)
+                        }
+                    };
+                    session.messages.push(MsgItem::info_msg(info, &self.style));
+                }
+                UserMsg::Status { addr, away } => {
+                    if let Some(user) = session.users.get_mut(&addr) {
+                        user.away = away;
+                    }
+                }
+            },
+            MessageType::Server(server_msg) => match server_msg {
+                // both only arrive while connecting
+                ServerMsg::Handshake { .. } | ServerMsg::RoomFull => (),
+                // kept by the client for reconnecting
+                ServerMsg::Session { .. } => (),
+                ServerMsg::AuthFailure => {
+                    session.client.close_connection();
+
+                    session.messages.push(MsgItem::info_msg(
+                        String::from("Authentication failed, wrong room password."),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::MessageTooLong { max_len } => {
+                    session.reject_outgoing(&self.style);
+                    session.messages.push(MsgItem::info_msg(
+                        format!(
+                            "The room accepts at most {} characters, the message has been dropped.",
+                            max_len
+                        ),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::FileTooLarge { max_size } => {
+                    session.messages.push(MsgItem::info_msg(
+                        format!(
+                            "The room relays files of at most {} bytes, the file has been dropped.",
+                            max_size
+                        ),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::RateLimited => {
+                    session.reject_outgoing(&self.style);
+                    session.messages.push(MsgItem::info_msg(
+                        String::from("Sending too fast, the message has been dropped."),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::Renamed { user_id } => {
+                    session.messages.push(MsgItem::info_msg(
+                        format!(
+                            "The name {} is taken in this room, you're {} here.",
+                            session.client.user._id, user_id
+                        ),
+                        &self.style,
+                    ));
+                    session.client.user._id = user_id;
+                }
+                ServerMsg::EmptyMessage => {
+                    session.reject_outgoing(&self.style);
+                    session.messages.push(MsgItem::info_msg(
+                        String::from(
+                            "Empty messages aren't accepted, the message has been dropped.",
+                        ),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::ReadOnly => {
+                    session.reject_outgoing(&self.style);
+                    session.messages.push(MsgItem::info_msg(
+                        String::from("Spectators can't send messages."),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::NotOwner => {
+                    session.messages.push(MsgItem::info_msg(
+                        String::from("Only the owner of the room can do that."),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::Kicked => {
+                    // closed on purpose, so it isn't reconnected
+                    session.client.close_connection();
+
+                    session.messages.push(MsgItem::info_msg(
+                        String::from("You've been kicked from the room."),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::Ack { timestamp } => session.acknowledge(timestamp, &self.style),
+                ServerMsg::EditRejected { .. } => {
+                    session.messages.push(MsgItem::info_msg(
+                        String::from("The message can't be changed, only your own ones can."),
+                        &self.style,
+                    ));
+                }
+                ServerMsg::Sync { messages, users } => {
+                    // the connected users of the room, replacing any stale ones
+                    session.users = users
+                        .into_iter()
+                        .filter_map(|user| Some((user.addr?, user)))
+                        .collect::<HashMap<SocketAddr, User>>();
+
+                    for msg in messages.iter() {
+                        let user = session.sender(msg.sender_addr());
+                        if !session.ignored.contains(&user._id) {
+                            session.push_user_msg(msg, &user, &self.style);
+                        }
+                    }
+                    session.scrollback.older = session.scrollback.older.max(messages.len());
+                    session.trim_oldest();
+                }
+                ServerMsg::Page { offset, messages } => {
+                    session.load_page(offset, messages, &self.style)
+                }
+                ServerMsg::UserLeft { addr } => {
+                    session.set_typing(addr, false);
+                    session.incoming.retain(|(sender, _), _| *sender != addr);
+                    if let Some(user) = session.users.remove(&addr) {
+                        session.messages.push(MsgItem::info_msg(
+                            format!("{} has left", user._id),
+                            &self.style,
+                        ));
+                        if is_active && !session.ignored.contains(&user._id) {
+                            self.show_timed_popup(PopupState::JoinedLeft(user._id, false));
+                        }
+                    }
+                }
+                ServerMsg::BanConfirm { addr } => {
+                    let user_id = session.sender(&addr)._id;
+                    session.messages.push(MsgItem::info_msg(
+                        format!("{} has been banned", user_id),
+                        &self.style,
+                    ));
+                    if is_active {
+                        self.show_timed_popup(PopupState::Banned(user_id));
+                    }
+                }
+                ServerMsg::ServerShutdown => {
+                    session.client.close_connection();
+                    if self.hosting {
+                        self.running = false;
+                    }
+
+                    session.messages.push(MsgItem::info_msg(
+                        String::from("Server has been shutted down."),
+                        &self.style,
+                    ));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn copy_selected_msg(&mut self) {
+        let Some(content) = self.session().selected_content().cloned() else {
+            return;
+        };
+
+        let copied = match &mut self.clipboard {
+            Some(clipboard) => Ok(clipboard),
+            None => ClipboardContext::new().map(|clipboard| self.clipboard.insert(clipboard)),
+        }
+        .and_then(|clipboard| clipboard.set_contents(content));
+
+        match copied {
+            Ok(()) => self.show_timed_popup(PopupState::Copied),
+            Err(err) => {
+                log::error!("Failed to copy the message: {}", err);
+                self.show_timed_popup(PopupState::CopyFailed(err.to_string()));
+            }
+        }
+    }
+
+    fn handle_deleting_chars(&mut self) {
+        if self.msg_area.textarea.cursor().1 == 0 && self.msg_area.textarea.cursor().0 > 0 {
+            self.msg_area.textarea.delete_newline();
+        } else {
+            self.msg_area.textarea.delete_char();
+        }
+        self.msg_area.recompute_height();
+    }
+
+    /// Too long messages are put back into the input to be shortened.
+    async fn send_text(&mut self, text: &str) {
+        if text.chars().count() > self.max_msg_len as usize {
+            self.msg_area.textarea.insert_str(text);
+            self.msg_area.recompute_height();
+            self.show_timed_popup(PopupState::MessageTooLong(self.max_msg_len));
+            return;
+        }
+
+        let session = &mut self.sessions[self.active];
+        let user = session.client.user.clone();
+        if user.spectator {
+            session.messages.push(MsgItem::info_msg(
+                String::from("You're spectating, messages can't be sent."),
+                &self.style,
+            ));
+            return;
+        }
+        let room_id = session.client.room.lock().unwrap()._id.clone();
+        let msg = TextMessage::new(&user.addr.unwrap(), &room_id, text);
+
+        let (delivery, unsent) = if session.client.is_disconnected() {
+            session.queued.push(msg.clone());
+            (Delivery::Queued, None)
+        } else {
+            match session.send_text_msg(&msg).await {
+                Ok(()) => (Delivery::Pending, None),
+                Err(err) => (Delivery::Failed, Some(err)),
+            }
+        };
+
+        if session.scrollback.newer > 0 {
+            // the sent message comes with the newest page
+            session.reload_latest();
+        } else {
+            session.messages.select_last();
+            session.push_new_msg(&msg, &user, &self.style);
+            session.track_outgoing(msg, delivery, Instant::now(), &self.style);
+        }
+        if let Some(err) = unsent {
+            session.report_unsent("the message", err, &self.style);
+        }
+    }
+
+    /// Runs the input as a command if it starts with `/`, returns whether it did.
+    async fn parse_commands(&mut self, haystack: &str) -> bool {
+        let (action, args) = match self.find_command(haystack) {
+            None => return false,
+            Some(Ok(command)) => command,
+            Some(Err(err)) => {
+                let session = &mut self.sessions[self.active];
+                session.messages.push(MsgItem::info_msg(err, &self.style));
+                return true;
+            }
+        };
+
+        match action {
+            Action::Ban => {
+                let session = &mut self.sessions[self.active];
+                if let Some(addr) = session.user_addr(&args[0]) {
+                    if let Err(err) = session.client.ban(&addr).await {
+                        session.report_unsent("the ban", err, &self.style);
+                    }
+                }
+            }
+            Action::Kick => {
+                let session = &mut self.sessions[self.active];
+                if let Err(err) = session.client.kick(&args[0]).await {
+                    session.report_unsent("the kick", err, &self.style);
+                }
+            }
+            Action::Ignore => {
+                let session = &mut self.sessions[self.active];
+                let info = if session.ignored.insert(args[0].clone()) {
+                    format!("Ignoring {}.", args[0])
+                } else {
+                    format!("{} is already ignored.", args[0])
+                };
+                session.messages.push(MsgItem::info_msg(info, &self.style));
+            }
+            Action::Unignore => {
+                let session = &mut self.sessions[self.active];
+                let info = if session.ignored.remove(&args[0]) {
+                    format!("No longer ignoring {}.", args[0])
+                } else {
+                    format!("{} isn't ignored.", args[0])
+                };
+                session.messages.push(MsgItem::info_msg(info, &self.style));
+            }
+            Action::Me => {
+                let text = format!("* {} {}", self.session().client.user._id, args[0]);
+                self.send_text(&text).await;
+            }
+            Action::Edit | Action::Delete => {
+                let session = &mut self.sessions[self.active];
+                match session.own_msg_id() {
+                    Ok(id) => {
+                        let user_msg = match action {
+                            Action::Edit => UserMsg::Edit {
+                                id,
+                                content: args[0].clone(),
+                            },
+                            _ => UserMsg::Delete { id },
+                        };
+                        if let Err(err) = session.send_user_msg(user_msg).await {
+                            session.report_unsent("the change", err, &self.style);
+                        }
+                    }
+                    Err(err) => session.messages.push(MsgItem::info_msg(err, &self.style)),
+                }
+            }
+            Action::Send => self.send_file(&args[0]).await,
+            Action::Help => {
+                let session = &mut self.sessions[self.active];
+                session
+                    .messages
+                    .push(MsgItem::info_msg(COMMANDS_HELP.into(), &self.style));
+            }
+            Action::Clear => {
+                self.session_mut().clear_messages();
+                self.search = None;
+            }
+        }
+        true
+    }
+
+    /// `None` for chat messages, an error for inputs starting with `/` matching no command.
+    fn find_command(&self, haystack: &str) -> Option<Result<(Action, Vec<String>), String>> {
+        let haystack = haystack.trim();
+        if !haystack.starts_with('/') {
+            return None;
+        }
+
+        let found = self.commands.iter().find_map(|command| {
+            Self::parse_command(command, haystack).map(|args| (command.1, args))
+        });
+        Some(found.ok_or_else(|| {
+            let name = haystack.split_whitespace().next().unwrap_or(haystack);
+            format!("Invalid command {}, see /help.", name)
+        }))
+    }
+
+    fn parse_command(command: &Command, haystack: &str) -> Option<Vec<String>> {
+        if let Some(captures) = command.0.captures(haystack) {
+            return Some(
+                captures
+                    .iter()
+                    .skip(1)
+                    .map(|cap| cap.unwrap().as_str().to_string())
+                    .collect::<Vec<String>>(),
+            );
+        }
+        None
+    }
+}
+
+/// Ctrl-modified keys handled by the chat, also used to render the help popup.
+pub const KEYBINDINGS: &[Keybinding] = &[
+    Keybinding::new('l', KeyAction::UserList, "user list"),
+    Keybinding::new('j', KeyAction::ScrollDown, "scroll down"),
+    Keybinding::new('k', KeyAction::ScrollUp, "scroll up"),
+    Keybinding::new('y', KeyAction::Copy, "copy"),
+    Keybinding::new('p', KeyAction::Paste, "paste"),
+    Keybinding::new('c', KeyAction::CopyMessage, "copy selected message"),
+    Keybinding::new('f', KeyAction::Search, "search messages"),
+    Keybinding::new('g', KeyAction::JumpToLatest, "jump to latest"),
+    Keybinding::new('s', KeyAction::ToggleSelect, "browse or follow messages"),
+    Keybinding::new('r', KeyAction::Retry, "retry undelivered messages"),
+    Keybinding::new('n', KeyAction::NextRoom, "next room (also ctrl+tab)"),
+    Keybinding::new('h', KeyAction::Help, "help"),
+    Keybinding::new('q', KeyAction::Exit, "exit"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    UserList,
+    ScrollDown,
+    ScrollUp,
+    Copy,
+    Paste,
+    CopyMessage,
+    Search,
+    JumpToLatest,
+    ToggleSelect,
+    Retry,
+    NextRoom,
+    Help,
+    Exit,
+}
+
+#[derive(Debug)]
+pub struct Keybinding {
+    pub key: char,
+    pub action: KeyAction,
+    pub description: &'static str,
+}
+
+impl Keybinding {
+    const fn new(key: char, action: KeyAction, description: &'static str) -> Self {
+        Self {
+            key,
+            action,
+            description,
+        }
+    }
+
+    pub fn find(key: char) -> Option<&'static Keybinding> {
+        KEYBINDINGS.iter().find(|binding| binding.key == key)
+    }
+}
+
+/// A message of the user waiting for the room's acknowledgement.
+#[derive(Debug)]
+struct Outgoing {
+    msg: TextMessage,
+    delivery: Delivery,
+    sent_at: Instant,
+}
+
+/// A received file waiting for the user to save or drop it.
+#[derive(Debug)]
+struct ReceivedFile {
+    sender: String,
+    name: String,
+    data: Vec<u8>,
+}
+
+/// Scrollback search, `current` indexes into `matches`.
+#[derive(Debug, Default)]
+pub struct Search {
+    pub query: String,
+    pub matches: Vec<usize>,
+    pub current: usize,
+}
+
+/// Terminal bell for incoming messages, rung at most once per `BELL_THROTTLE`.
+#[derive(Debug)]
+struct Bell {
+    enabled: bool,
+    mentions_only: bool,
+    last_rung: Option<Instant>,
+}
+
+impl Bell {
+    fn new(enabled: bool, mentions_only: bool) -> Self {
+        Self {
+            enabled,
+            mentions_only,
+            last_rung: None,
+        }
+    }
+
+    fn should_ring(&mut self, mentioned: bool, now: Instant) -> bool {
+        if !self.enabled || (self.mentions_only && !mentioned) {
+            return false;
+        }
+        if self
+            .last_rung
+            .is_some_and(|rung| now.duration_since(rung) < BELL_THROTTLE)
+        {
+            return false;
+        }
+
+        self.last_rung = Some(now);
+        true
+    }
+
+    fn ring() {
+        let mut stdout = io::stdout();
+        if let Err(err) = stdout.write_all(b"\x07").and_then(|_| stdout.flush()) {
+            log::warn!("Failed to ring the bell: {}", err);
+        }
+    }
+}
+
+/// Sent inputs browsed like a shell's history.
+#[derive(Debug, Default)]
+struct InputHistory {
+    entries: VecDeque<String>,
+    /// Index of the entry shown, `None` while editing the draft.
+    position: Option<usize>,
+    draft: String,
+}
+
+impl InputHistory {
+    fn push(&mut self, text: String) {
+        self.position = None;
+        self.draft.clear();
+        if self.entries.back() != Some(&text) {
+            self.entries.push_back(text);
+            if self.entries.len() > INPUT_HISTORY_LEN {
+                self.entries.pop_front();
+            }
+        }
+    }
+
+    /// The entry before the shown one, `current` is kept as the draft when starting to browse.
+    fn older(&mut self, current: &str) -> Option<&str> {
+        let position = match self.position {
+            None => {
+                let newest = self.entries.len().checked_sub(1)?;
+                self.draft = current.into();
+                newest
+            }
+            Some(0) => return None,
+            Some(position) => position - 1,
+        };
+        self.position = Some(position);
+        Some(&self.entries[position])
+    }
+
+    /// The entry after the shown one, the draft once past the newest.
+    fn newer(&mut self) -> Option<&str> {
+        let position = self.position?;
+        if position + 1 < self.entries.len() {
+            self.position = Some(position + 1);
+            Some(&self.entries[position + 1])
+        } else {
+            self.position = None;
+            Some(&self.draft)
+        }
+    }
+}
+
+/// Debounces the typing notifications of the local input.
+#[derive(Debug, Default)]
+struct TypingNotifier {
+    is_typing: bool,
+    last_sent: Option<Instant>,
+}
+
+impl TypingNotifier {
+    /// Returns the state to send when it changed, starting at most once per `TYPING_DEBOUNCE`.
+    fn update(&mut self, is_typing: bool, now: Instant) -> Option<bool> {
+        if is_typing == self.is_typing {
+            return None;
+        }
+        if is_typing
+            && self
+                .last_sent
+                .is_some_and(|sent| now.duration_since(sent) < TYPING_DEBOUNCE)
+        {
+            return None;
+        }
+
+        self.is_typing = is_typing;
+        self.last_sent = Some(now);
+        Some(is_typing)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Page {
+    Older,
+    Newer,
+}
+
+/// The loaded part of a room's stored messages, counted from the newest one.
+#[derive(Debug, Default)]
+struct Scrollback {
+    /// Stored messages from the oldest loaded one on, the offset of the next older page.
+    older: usize,
+    /// Stored messages after the loaded ones, dropped to make room for older ones.
+    newer: usize,
+    /// Whether the oldest stored message is loaded.
+    exhausted: bool,
+    wanted: Option<Page>,
+    /// Requested page with its offset.
+    pending: Option<(Page, usize)>,
+}
+
+impl Scrollback {
+    fn want(&mut self, page: Page) {
+        let needed = match page {
+            Page::Older => !self.exhausted,
+            Page::Newer => self.newer > 0,
+        };
+        if needed && self.pending.is_none() {
+            self.wanted = Some(page);
+        }
+    }
+
+    /// Offset and limit of the wanted page, which is then pending.
+    fn next_request(&mut self) -> Option<(usize, usize)> {
+        let page = self.wanted.take()?;
+        let (offset, limit) = match page {
+            Page::Older => (self.older, SCROLLBACK_PAGE),
+            Page::Newer => (
+                self.newer.saturating_sub(SCROLLBACK_PAGE),
+                self.newer.min(SCROLLBACK_PAGE),
+            ),
+        };
+        self.pending = Some((page, offset));
+        Some((offset, limit))
+    }
+}
+
+/// Marks the user away after `away_after` without input, `None` never does.
+#[derive(Debug)]
+struct IdleTracker {
+    away_after: Option<Duration>,
+    last_input: Instant,
+    away: bool,
+}
+
+impl IdleTracker {
+    fn new(away_after: Option<Duration>, now: Instant) -> Self {
+        Self {
+            away_after,
+            last_input: now,
+            away: false,
+        }
+    }
+
+    /// Returns the status to send when it changed.
+    fn update(&mut self, had_input: bool, now: Instant) -> Option<bool> {
+        if had_input {
+            self.last_input = now;
+        }
+        let away = self
+            .away_after
+            .is_some_and(|after| now.duration_since(self.last_input) >= after);
+        if away == self.away {
+            return None;
+        }
+
+        self.away = away;
+        Some(away)
+    }
+}
 
 type Command = (Regex, Action);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Action {
     Ban,
+    Kick,
+    Ignore,
+    Unignore,
+    Me,
+    Edit,
+    Delete,
+    Send,
+    Help,
+    Clear,
 }
 
+const COMMANDS_HELP: &str = "Commands: /ban <user>, /kick <user>, /ignore <user>, \
+    /unignore <user>, /me <action>, /edit <text>, /delete (the selected or last own message), \
+    /send <path>, /clear (local only), /help";
+
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::{
+        Action, Bell, ChatApp, IdleTracker, InputHistory, KeyAction, Page, RoomSession,
+        TypingNotifier, ACK_TIMEOUT, BELL_THROTTLE, INPUT_HISTORY_LEN, SCROLLBACK_PAGE,
+        SCROLLBACK_WINDOW, TYPING_DEBOUNCE,
+    };
+    use crate::tui::ui::Tui;
+    use crate::{
+        network::{
+            client::ChatClient,
+            crypto::author_key,
+            message::{Message, MessageType, ServerMsg, UserMsg},
+            transfer::{split, FileChunk, CHUNK_SIZE},
+            User,
+        },
+        schema::{Color, IgnoredUser, LocalData, Room, TextMessage, Timezone},
+        tui::ui::{Delivery, MsgItem, PopupState},
+        util::{
+            DEFAULT_AWAY_AFTER, DEFAULT_CONNECT_TIMEOUT, DEFAULT_LAYOUT_RATIO,
+            DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_MISSED_PONGS, DEFAULT_MAX_MSG_LEN,
+            DEFAULT_MENTION_PREFIX, DEFAULT_MSG_BURST, DEFAULT_MSG_RATE, DEFAULT_PING_INTERVAL,
+            DEFAULT_RECONNECT_ATTEMPTS, DEFAULT_TIMESTAMP_FORMAT,
+        },
+    };
+    use base64ct::{Base64, Encoding};
+    use crossterm::event::{KeyCode, KeyModifiers};
+    use futures_util::{SinkExt, StreamExt};
+    use ratatui::{
+        backend::{CrosstermBackend, TestBackend},
+        Terminal,
+    };
+    use std::{env, fs, net::SocketAddr, str::FromStr, time::SystemTime};
+    use tokio::{
+        net::TcpListener,
+        sync::mpsc,
+        task::JoinHandle,
+        time::{sleep, timeout, Duration, Instant},
+    };
+    use tokio_tungstenite::accept_async;
+    use uuid::Uuid;
+
+    fn chat_app<'a>(user_id: &str, addr: &str) -> ChatApp<'a> {
+        chat_app_ignoring(user_id, addr, vec![])
+    }
+
+    fn chat_app_ignoring<'a>(user_id: &str, addr: &str, ignored: Vec<IgnoredUser>) -> ChatApp<'a> {
+        let addr = SocketAddr::from_str(addr).unwrap();
+        let room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: false,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        let user = User {
+            _id: user_id.into(),
+            addr: Some(addr),
+            color: Some(Color::Red),
+            away: false,
+            spectator: false,
+        };
+        let local_data = LocalData {
+            default_user_id: user_id.into(),
+            default_room_addr: room.addr,
+            default_color: Color::White,
+            remember_passwords: false,
+            light_mode: false,
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+            bell: false,
+            bell_mentions_only: false,
+            msg_rate: DEFAULT_MSG_RATE,
+            msg_burst: DEFAULT_MSG_BURST,
+            max_msg_len: DEFAULT_MAX_MSG_LEN,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+            away_after: DEFAULT_AWAY_AFTER,
+            timezone: Timezone::Local,
+            ignored,
+            markdown: false,
+            input_border: None,
+            input_highlight: None,
+            input_placeholder: None,
+            retention_messages: 0,
+            retention_days: 0,
+            theme: None,
+            alt_screen: true,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            mention_prefix: DEFAULT_MENTION_PREFIX,
+            audit_log: false,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            layout_ratio: DEFAULT_LAYOUT_RATIO,
+        };
+
+        let mut app = ChatApp::new(vec![ChatClient::new(room, user.clone())], &local_data);
+        app.session_mut().users.insert(addr, user);
+        app
+    }
+
+    #[test]
+    fn typing_notifications_are_debounced() {
+        let mut notifier = TypingNotifier::default();
+        let start = Instant::now();
+
+        assert_eq!(notifier.update(false, start), None);
+        assert_eq!(notifier.update(true, start), Some(true));
+        assert_eq!(notifier.update(true, start), None);
+        assert_eq!(notifier.update(false, start), Some(false));
+
+        let retyped = start + Duration::from_millis(100);
+        assert_eq!(notifier.update(true, retyped), None);
+        assert_eq!(notifier.update(true, start + TYPING_DEBOUNCE), Some(true));
+    }
+
+    #[test]
+    fn bell_is_throttled() {
+        let mut bell = Bell::new(true, false);
+        let start = Instant::now();
+
+        let rings = (0..10)
+            .filter(|i| bell.should_ring(false, start + Duration::from_millis(i * 100)))
+            .count();
+        assert_eq!(rings, 1);
+        assert!(bell.should_ring(false, start + BELL_THROTTLE));
+
+        let mut disabled = Bell::new(false, false);
+        assert!(!disabled.should_ring(true, start));
+    }
+
+    #[test]
+    fn bell_can_ring_only_for_mentions() {
+        let mut bell = Bell::new(true, true);
+        let start = Instant::now();
+
+        assert!(!bell.should_ring(false, start));
+        assert!(bell.should_ring(true, start));
+        assert!(!bell.should_ring(true, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn selected_message_content_is_plain() {
+        let mut app = chat_app("alice", "127.0.0.1:4001");
+        let user = app.session().client.user.clone();
+        let msg = TextMessage::new(&user.addr.unwrap(), "someroom", "hi @bob\nsecond line");
+
+        app.sessions[0]
+            .messages
+            .items
+            .push(MsgItem::info_msg("bob has joined".into(), &app.style));
+        app.sessions[0].push_user_msg(&msg, &user, &app.style);
+
+        app.session_mut().messages.state.select(Some(0));
+        assert_eq!(app.session().selected_content(), None);
+
+        app.session_mut().messages.select_last();
+        assert_eq!(
+            app.session().selected_content(),
+            Some(&String::from("hi @bob\nsecond line"))
+        );
+    }
+
+    #[tokio::test]
+    async fn typing_indicator_is_cleared_after_send() {
+        let mut sender = chat_app("alice", "127.0.0.1:4001");
+        let mut receiver = chat_app("bob", "127.0.0.1:4002");
+        let sender_user = sender.session().client.user.clone();
+        let sender_addr = sender_user.addr.unwrap();
+        receiver
+            .session_mut()
+            .users
+            .insert(sender_addr, sender_user);
+
+        sender.msg_area.textarea.insert_str("hello");
+        sender.notify_typing().await;
+        assert!(sender.session().typing.is_typing);
+
+        receiver
+            .session_mut()
+            .set_typing(sender_addr, sender.session().typing.is_typing);
+        assert_eq!(
+            receiver.session().typing_indicator(),
+            Some("alice is typing…".into())
+        );
+
+        sender.handle_text_buffer().await;
+        sender.notify_typing().await;
+        assert!(!sender.session().typing.is_typing);
+
+        receiver
+            .session_mut()
+            .set_typing(sender_addr, sender.session().typing.is_typing);
+        assert_eq!(receiver.session().typing_indicator(), None);
+    }
+
+    #[test]
+    fn jumping_to_latest_clears_unread() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let user = app.session().client.user.clone();
+        for content in ["first", "second", "third"] {
+            app.sessions[0].push_user_msg(
+                &TextMessage::new(&user.addr.unwrap(), "someroom", content),
+                &user,
+                &app.style,
+            );
+        }
+
+        app.handle_key_action(KeyAction::ScrollUp);
+        app.sessions[0].push_user_msg(
+            &TextMessage::new(&user.addr.unwrap(), "someroom", "fourth"),
+            &user,
+            &app.style,
+        );
+        assert_eq!(app.session().messages.unread, 1);
+        assert_eq!(app.session().messages.state.selected(), Some(1));
+
+        app.handle_key_action(KeyAction::JumpToLatest);
+        assert_eq!(app.session().messages.unread, 0);
+        assert_eq!(app.session().messages.state.selected(), Some(3));
+    }
+
+    #[test]
+    fn toggling_selection_switches_between_browsing_and_following() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let user = app.session().client.user.clone();
+        let push = |app: &mut ChatApp, content: &str| {
+            app.sessions[0].push_user_msg(
+                &TextMessage::new(&user.addr.unwrap(), "someroom", content),
+                &user,
+                &app.style,
+            );
+        };
+        for content in ["first", "second", "third"] {
+            push(&mut app, content);
+        }
+
+        app.handle_key_action(KeyAction::ToggleSelect);
+        assert!(app.session().messages.is_highlighted);
+        app.handle_key_action(KeyAction::ScrollUp);
+        push(&mut app, "fourth");
+        assert_eq!(app.session().messages.state.selected(), Some(1));
+
+        app.handle_key_action(KeyAction::ToggleSelect);
+        assert!(!app.session().messages.is_highlighted);
+        assert_eq!(app.session().messages.state.selected(), Some(3));
+
+        // following even when the selection was left behind
+        app.session_mut().messages.state.select(Some(0));
+        push(&mut app, "fifth");
+        assert_eq!(app.session().messages.state.selected(), Some(4));
+        assert_eq!(app.session().messages.unread, 0);
+    }
+
+    #[test]
+    fn slash_commands_are_parsed() {
+        let app = chat_app("alice", "127.0.0.1:4000");
+        let command = |input: &str| app.find_command(input);
+
+        assert_eq!(
+            command("/ban @bob"),
+            Some(Ok((Action::Ban, vec!["bob".into()])))
+        );
+        assert_eq!(
+            command("  /kick bob "),
+            Some(Ok((Action::Kick, vec!["bob".into()])))
+        );
+        assert_eq!(
+            command("/me waves at @bob"),
+            Some(Ok((Action::Me, vec!["waves at @bob".into()])))
+        );
+        assert_eq!(command("/help"), Some(Ok((Action::Help, vec![]))));
+        assert_eq!(command("/clear"), Some(Ok((Action::Clear, vec![]))));
+        assert_eq!(
+            command("/ignore @bob"),
+            Some(Ok((Action::Ignore, vec!["bob".into()])))
+        );
+        assert_eq!(
+            command("/unignore bob"),
+            Some(Ok((Action::Unignore, vec!["bob".into()])))
+        );
+
+        assert!(matches!(command("/ban"), Some(Err(_))));
+        assert!(matches!(command("/dance now"), Some(Err(err)) if err.contains("/dance")));
+
+        assert_eq!(command("hello /ban bob"), None);
+        assert_eq!(command("hello"), None);
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_stay_local() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let user = app.session().client.user.clone();
+        app.sessions[0].push_user_msg(
+            &TextMessage::new(&user.addr.unwrap(), "someroom", "hi"),
+            &user,
+            &app.style,
+        );
+
+        assert!(app.parse_commands("/dance").await);
+        assert_eq!(app.session().messages.items.len(), 2);
+        assert!(app.session().selected_content().is_none());
+
+        assert!(app.parse_commands("/clear").await);
+        assert!(app.session().messages.items.is_empty());
+
+        assert!(app.parse_commands("/me waves").await);
+        assert_eq!(
+            app.session().selected_content(),
+            Some(&String::from("* alice waves"))
+        );
+        assert!(!app.parse_commands("waves").await);
+    }
+
+    #[tokio::test]
+    async fn user_list_follows_joins_and_leaves() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let user = |id: &str, addr: &str| User {
+            _id: id.into(),
+            addr: Some(SocketAddr::from_str(addr).unwrap()),
+            color: Some(Color::Blue),
+            away: false,
+            spectator: false,
+        };
+        let (bob, carol) = (
+            user("bob", "127.0.0.1:4001"),
+            user("carol", "127.0.0.1:4002"),
+        );
+
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Sync {
+                messages: vec![TextMessage::new(&carol.addr.unwrap(), "someroom", "hi")],
+                users: vec![app.session().client.user.clone(), bob.clone()],
+            }),
+        )
+        .await;
+        assert_eq!(app.session().users.len(), 2);
+
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::UserJoined {
+                user: carol.clone(),
+            }),
+        )
+        .await;
+        assert_eq!(app.session().users.get(&carol.addr.unwrap()), Some(&carol));
+
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::UserLeft {
+                addr: bob.addr.unwrap(),
+            }),
+        )
+        .await;
+        let mut ids = app
+            .session()
+            .users
+            .values()
+            .map(|user| &user._id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, ["alice", "carol"]);
+
+        // a repeated leave is ignored
+        let shown = app.session().messages.items.len();
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::UserLeft {
+                addr: bob.addr.unwrap(),
+            }),
+        )
+        .await;
+        assert_eq!(app.session().messages.items.len(), shown);
+    }
+
+    #[tokio::test]
+    async fn long_messages_are_kept_in_the_input() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        app.max_msg_len = 5;
+
+        app.send_text("héllo").await;
+        assert_eq!(
+            app.session().selected_content(),
+            Some(&String::from("héllo"))
+        );
+        assert!(app.msg_area.textarea.is_empty());
+
+        app.send_text("héllo!").await;
+        assert_eq!(app.session().messages.items.len(), 1);
+        assert_eq!(app.current_popup, PopupState::MessageTooLong(5));
+        assert_eq!(app.msg_area.textarea.lines(), ["héllo!"]);
+    }
+
+    fn with_other_room(mut app: ChatApp<'_>) -> ChatApp<'_> {
+        let client = &app.session().client;
+        let room = Room {
+            _id: "otherroom".into(),
+            ..client.room.lock().unwrap().clone()
+        };
+        let other = ChatClient::new(room, client.user.clone());
+        app.sessions.push(RoomSession::new(other));
+        app
+    }
+
+    fn rendered(app: &mut ChatApp) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(60, 20)).unwrap();
+        terminal
+            .draw(|frame| Tui::<CrosstermBackend<Vec<u8>>>::render(app, frame))
+            .unwrap();
+        terminal
+            .backend()
+            .buffer()
+            .content
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rooms_keep_their_own_state() {
+        let mut app = with_other_room(chat_app("alice", "127.0.0.1:4000"));
+        let bob = User {
+            _id: "bob".into(),
+            addr: Some(SocketAddr::from_str("127.0.0.1:4001").unwrap()),
+            color: Some(Color::Blue),
+            away: false,
+            spectator: false,
+        };
+
+        app.handle_msg(
+            1,
+            MessageType::User(UserMsg::UserJoined { user: bob.clone() }),
+        )
+        .await;
+        app.handle_msg(
+            1,
+            MessageType::User(UserMsg::Normal {
+                msg: TextMessage::new(&bob.addr.unwrap(), "otherroom", "psst"),
+            }),
+        )
+        .await;
+
+        // background rooms don't show popups but count their messages
+        assert_eq!(app.current_popup, PopupState::None);
+        assert_eq!(app.sessions[1].unseen, 1);
+        assert_eq!(app.sessions[1].users.len(), 1);
+        assert!(app.session().messages.items.is_empty());
+        assert!(!app.session().users.contains_key(&bob.addr.unwrap()));
+
+        app.handle_key_action(KeyAction::NextRoom);
+        assert_eq!(app.active, 1);
+        assert_eq!(app.session().unseen, 0);
+        assert_eq!(app.session().messages.items.len(), 2);
+
+        app.handle_key_action(KeyAction::NextRoom);
+        assert_eq!(app.active, 0);
+    }
+
+    #[tokio::test]
+    async fn only_the_active_room_is_rendered() {
+        let mut app = with_other_room(chat_app("alice", "127.0.0.1:4000"));
+        let alice = app.session().client.user.clone();
+        app.handle_msg(
+            1,
+            MessageType::User(UserMsg::Normal {
+                msg: TextMessage::new(&alice.addr.unwrap(), "otherroom", "psst"),
+            }),
+        )
+        .await;
+
+        let screen = rendered(&mut app);
+        assert!(screen.contains("someroom | otherroom (1)"));
+        assert!(!screen.contains("psst"));
+
+        app.handle_key_action(KeyAction::NextRoom);
+        let screen = rendered(&mut app);
+        assert!(screen.contains("someroom | otherroom"));
+        assert!(screen.contains("psst"));
+    }
+
+    #[test]
+    fn idle_users_go_away_until_the_next_input() {
+        let start = Instant::now();
+        let mut idle = IdleTracker::new(Some(Duration::from_secs(60)), start);
+
+        assert_eq!(idle.update(false, start + Duration::from_secs(59)), None);
+        assert_eq!(
+            idle.update(false, start + Duration::from_secs(60)),
+            Some(true)
+        );
+        assert_eq!(idle.update(false, start + Duration::from_secs(90)), None);
+
+        let back = start + Duration::from_secs(100);
+        assert_eq!(idle.update(true, back), Some(false));
+        assert_eq!(idle.update(true, back + Duration::from_secs(30)), None);
+        assert_eq!(idle.update(false, back + Duration::from_secs(89)), None);
+        assert_eq!(
+            idle.update(false, back + Duration::from_secs(90)),
+            Some(true)
+        );
+
+        let mut never = IdleTracker::new(None, start);
+        assert_eq!(
+            never.update(false, start + Duration::from_secs(86400)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn away_status_is_shown_in_the_user_list() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let alice = app.session().client.user.clone();
+        let bob = User {
+            _id: "bob".into(),
+            addr: Some(SocketAddr::from_str("127.0.0.1:4001").unwrap()),
+            color: None,
+            away: false,
+            spectator: false,
+        };
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::UserJoined { user: bob.clone() }),
+        )
+        .await;
+
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::Status {
+                addr: bob.addr.unwrap(),
+                away: true,
+            }),
+        )
+        .await;
+        assert!(app.session().users[&bob.addr.unwrap()].away);
+
+        // the own status is kept locally, the server doesn't echo it
+        app.idle = IdleTracker::new(Some(Duration::ZERO), Instant::now());
+        app.update_away(false).await;
+        assert!(app.session().users[&alice.addr.unwrap()].away);
+        assert!(app.session().client.user.away);
+
+        app.idle.away_after = Some(Duration::from_secs(60));
+        app.update_away(true).await;
+        assert!(!app.session().users[&alice.addr.unwrap()].away);
+    }
+
+    #[test]
+    fn quitting_with_unsent_input_asks_first() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        app.handle_key_action(KeyAction::Exit);
+        assert!(!app.running);
+
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        app.msg_area.textarea.insert_str("half typed");
+        app.handle_key_action(KeyAction::Exit);
+        assert!(app.running);
+        assert_eq!(app.current_popup, PopupState::ConfirmQuit);
+
+        app.confirm_quit(KeyCode::Char('x'), KeyModifiers::NONE);
+        assert!(app.running);
+        assert_eq!(app.current_popup, PopupState::None);
+
+        app.handle_key_action(KeyAction::Exit);
+        app.confirm_quit(KeyCode::Char('q'), KeyModifiers::CONTROL);
+        assert!(!app.running);
+
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        app.msg_area.textarea.insert_str("half typed");
+        app.handle_key_action(KeyAction::Exit);
+        app.confirm_quit(KeyCode::Char('y'), KeyModifiers::NONE);
+        assert!(!app.running);
+    }
+
+    fn numbered_msgs(count: usize) -> Vec<TextMessage> {
+        let addr = SocketAddr::from_str("127.0.0.1:4001").unwrap();
+        (0..count)
+            .map(|i| TextMessage::new(&addr, "someroom", &format!("message {}", i)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn scrolling_past_the_top_loads_older_pages() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let msgs = numbered_msgs(5);
+
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Sync {
+                messages: msgs[3..].to_vec(),
+                users: vec![],
+            }),
+        )
+        .await;
+        assert_eq!(app.session().messages.state.selected(), Some(1));
+
+        app.handle_key_action(KeyAction::ScrollUp);
+        assert_eq!(app.session().scrollback.wanted, None);
+        app.handle_key_action(KeyAction::ScrollUp);
+        app.fetch_scrollback().await;
+        assert_eq!(app.session().scrollback.pending, Some((Page::Older, 2)));
+
+        // answers to other requests are ignored
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Page {
+                offset: 0,
+                messages: msgs[3..].to_vec(),
+            }),
+        )
+        .await;
+        assert_eq!(app.session().messages.items.len(), 2);
+
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Page {
+                offset: 2,
+                messages: msgs[..3].to_vec(),
+            }),
+        )
+        .await;
+        let session = app.session();
+        assert_eq!(session.messages.items.len(), 5);
+        assert_eq!(session.messages.state.selected(), Some(3));
+        assert_eq!(session.selected_content(), Some(msgs[3].content()));
+        assert!(session.scrollback.exhausted);
+
+        // nothing older is left to ask for
+        app.session_mut().messages.state.select(Some(0));
+        app.handle_key_action(KeyAction::ScrollUp);
+        assert_eq!(app.session_mut().scrollback.next_request(), None);
+    }
+
+    #[tokio::test]
+    async fn scrollback_stays_within_the_window() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+
+        for msg in numbered_msgs(SCROLLBACK_WINDOW + 10) {
+            app.handle_msg(0, MessageType::User(UserMsg::Normal { msg }))
+                .await;
+        }
+        let session = app.session_mut();
+        assert_eq!(session.messages.items.len(), SCROLLBACK_WINDOW);
+        assert_eq!(session.scrollback.older, SCROLLBACK_WINDOW);
+
+        session.messages.state.select(Some(0));
+        session.scrollback.want(Page::Older);
+        assert_eq!(
+            session.scrollback.next_request(),
+            Some((SCROLLBACK_WINDOW, SCROLLBACK_PAGE))
+        );
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Page {
+                offset: SCROLLBACK_WINDOW,
+                messages: numbered_msgs(SCROLLBACK_PAGE),
+            }),
+        )
+        .await;
+
+        // the newest ones make room and new ones wait until scrolled back down
+        let session = app.session_mut();
+        assert_eq!(session.messages.items.len(), SCROLLBACK_WINDOW);
+        assert_eq!(session.scrollback.newer, SCROLLBACK_PAGE);
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::Normal {
+                msg: numbered_msgs(1).remove(0),
+            }),
+        )
+        .await;
+        let session = app.session_mut();
+        assert_eq!(session.messages.items.len(), SCROLLBACK_WINDOW);
+        assert_eq!(session.messages.unread, SCROLLBACK_PAGE + 1);
+
+        session.messages.select_last();
+        app.handle_key_action(KeyAction::ScrollDown);
+        assert_eq!(
+            app.session_mut().scrollback.next_request(),
+            Some((1, SCROLLBACK_PAGE))
+        );
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Page {
+                offset: 1,
+                messages: numbered_msgs(SCROLLBACK_PAGE),
+            }),
+        )
+        .await;
+        let session = app.session();
+        assert_eq!(session.messages.items.len(), SCROLLBACK_WINDOW);
+        assert_eq!(session.scrollback.newer, 1);
+        assert_eq!(session.scrollback.older, SCROLLBACK_WINDOW + 1);
+
+        // jumping to the latest reloads the newest page
+        app.handle_key_action(KeyAction::JumpToLatest);
+        assert!(app.session().messages.items.is_empty());
+        assert_eq!(
+            app.session_mut().scrollback.next_request(),
+            Some((0, SCROLLBACK_PAGE))
+        );
+    }
+
+    #[tokio::test]
+    async fn ignored_senders_are_filtered() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let user = |id: &str, port| User {
+            _id: id.into(),
+            addr: Some(SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap()),
+            color: None,
+            away: false,
+            spectator: false,
+        };
+        let (bob, carol) = (user("bob", 4001), user("carol", 4002));
+        let msg = |user: &User, content| TextMessage::new(&user.addr.unwrap(), "someroom", content);
+
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::UserJoined { user: bob.clone() }),
+        )
+        .await;
+        assert!(app.parse_commands("/ignore @bob").await);
+        app.current_popup = PopupState::None;
+        let shown = app.session().messages.items.len();
+
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::UserJoined {
+                user: carol.clone(),
+            }),
+        )
+        .await;
+        assert_eq!(
+            app.current_popup,
+            PopupState::JoinedLeft("carol".into(), true)
+        );
+        app.current_popup = PopupState::None;
+
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Sync {
+                messages: vec![msg(&bob, "old from bob"), msg(&carol, "old from carol")],
+                users: vec![bob.clone(), carol.clone()],
+            }),
+        )
+        .await;
+        for (user, content) in [(&bob, "hi from bob"), (&carol, "hi from carol")] {
+            app.handle_msg(
+                0,
+                MessageType::User(UserMsg::Normal {
+                    msg: msg(user, content),
+                }),
+            )
+            .await;
+        }
+
+        // carol's join line and her two messages
+        let contents = app
+            .session()
+            .user_msgs
+            .values()
+            .map(TextMessage::content)
+            .collect::<Vec<&String>>();
+        assert_eq!(app.session().messages.items.len(), shown + 3);
+        assert!(!contents.iter().any(|content| content.contains("bob")));
+        assert!(contents.contains(&&"hi from carol".to_string()));
+
+        // leaving doesn't pop up either
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::UserLeft {
+                addr: bob.addr.unwrap(),
+            }),
+        )
+        .await;
+        assert_eq!(app.current_popup, PopupState::None);
+
+        assert!(app.parse_commands("/unignore bob").await);
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::UserJoined { user: bob.clone() }),
+        )
+        .await;
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::Normal {
+                msg: msg(&bob, "back from bob"),
+            }),
+        )
+        .await;
+        assert_eq!(
+            app.session().selected_content(),
+            Some(&"back from bob".to_string())
+        );
+    }
+
+    #[test]
+    fn ignore_lists_are_kept_per_room() {
+        let ignored = |room_id: &str, user_id: &str| IgnoredUser {
+            room_id: room_id.into(),
+            user_id: user_id.into(),
+        };
+        let mut app = with_other_room(chat_app_ignoring(
+            "alice",
+            "127.0.0.1:4000",
+            vec![ignored("someroom", "bob"), ignored("faraway", "carol")],
+        ));
+
+        assert!(app.session().ignored.contains("bob"));
+        assert!(app.sessions[1].ignored.is_empty());
+
+        app.sessions[1].ignored.insert("dave".into());
+        app.session_mut().ignored.clear();
+        assert_eq!(
+            app.ignored_users(&[ignored("someroom", "bob"), ignored("faraway", "carol")]),
+            [ignored("faraway", "carol"), ignored("otherroom", "dave")]
+        );
+    }
+
+    fn last_header(app: &ChatApp) -> String {
+        app.session().messages.items.last().unwrap().lines[0]
+            .spans
+            .iter()
+            .map(|span| span.content.as_ref())
+            .collect()
+    }
+
+    fn delivery(app: &ChatApp) -> Option<Delivery> {
+        let last = app.session().messages.items.len() - 1;
+        app.session()
+            .outgoing
+            .get(&last)
+            .map(|outgoing| outgoing.delivery)
+    }
+
+    #[tokio::test]
+    async fn acknowledged_messages_are_sent() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+
+        app.send_text("hello").await;
+        assert_eq!(delivery(&app), Some(Delivery::Pending));
+        assert!(last_header(&app).ends_with("sending…"));
+
+        let timestamp = *app
+            .session()
+            .outgoing
+            .values()
+            .next()
+            .unwrap()
+            .msg
+            .timestamp();
+        app.handle_msg(0, MessageType::Server(ServerMsg::Ack { timestamp }))
+            .await;
+        assert_eq!(delivery(&app), None);
+        assert!(!last_header(&app).contains("sending…"));
+        assert!(app.session().outgoing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn renamed_users_are_mentioned_by_their_new_name() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+
+        app.handle_msg(
+            0,
+            MessageType::Server(ServerMsg::Renamed {
+                user_id: "alice2".into(),
+            }),
+        )
+        .await;
+        assert_eq!(app.session().client.user._id, "alice2");
+        let info = &app.session().messages.items.last().unwrap().lines[0].spans[0];
+        assert_eq!(
+            info.content,
+            "The name alice is taken in this room, you're alice2 here."
+        );
+        assert!(MsgItem::mentions(
+            "hi @alice2",
+            &app.session().client.user._id,
+            &app.style
+        ));
+    }
+
+    #[tokio::test]
+    async fn nothing_is_sent_after_failing_to_authenticate() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        app.handle_msg(0, MessageType::Server(ServerMsg::AuthFailure))
+            .await;
+
+        app.send_text("hello").await;
+        assert_eq!(
+            app.session().messages.items.last().unwrap().lines[0].spans[0].content,
+            "Not connected, the message hasn't been sent."
+        );
+        assert!(app
+            .session()
+            .outgoing
+            .values()
+            .all(|outgoing| outgoing.delivery == Delivery::Failed));
+    }
+
+    #[tokio::test]
+    async fn echoed_messages_are_shown_once() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+
+        app.send_text("hello").await;
+        let shown = app.session().messages.items.len();
+        assert_eq!(delivery(&app), Some(Delivery::Pending));
+
+        let msg = app.session().outgoing.values().next().unwrap().msg.clone();
+        app.handle_msg(0, MessageType::User(UserMsg::Normal { msg }))
+            .await;
+        assert_eq!(app.session().messages.items.len(), shown);
+        assert_eq!(app.session().user_msgs.len(), 1);
+        assert_eq!(delivery(&app), None);
+        assert!(!last_header(&app).contains("sending…"));
+    }
+
+    /// Drops the first connection right after the handshake, then reports the contents of
+    /// the messages sent over the next one.
+    fn flaky_room(listener: TcpListener) -> (JoinHandle<()>, mpsc::UnboundedReceiver<String>) {
+        let (contents_tx, contents) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            for attempt in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws_stream = accept_async(stream).await.unwrap();
+                let handshake = ServerMsg::Handshake {
+                    salt: None,
+                    topic: None,
+                    challenge: String::new(),
+                };
+                ws_stream
+                    .send(Message::from(handshake).to_ttmessage())
+                    .await
+                    .unwrap();
+                if attempt == 0 {
+                    ws_stream.close(None).await.unwrap();
+                    continue;
+                }
+                while let Some(Ok(msg)) = ws_stream.next().await {
+                    if let Ok(Message {
+                        msg_type: MessageType::User(UserMsg::Normal { msg }),
+                        ..
+                    }) = Message::try_from(msg)
+                    {
+                        contents_tx.send(msg.content().clone()).unwrap();
+                    }
+                }
+            }
+        });
+        (server, contents)
+    }
+
+    async fn disconnect(app: &mut ChatApp<'_>, addr: SocketAddr) {
+        let client = &mut app.session_mut().client;
+        client.room.lock().unwrap().addr = addr;
+        client.connect().await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while !client.is_disconnected() {
+                client.recv_msg().await;
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// Runs the loop until the reconnect is over, the popup shows meanwhile.
+    async fn reconnect(app: &mut ChatApp<'_>) {
+        app.update().await;
+        assert!(app.session().client.is_reconnecting());
+        assert_eq!(app.current_popup, PopupState::Reconnecting);
+        timeout(Duration::from_secs(5), async {
+            while app.session().client.is_reconnecting() {
+                sleep(Duration::from_millis(10)).await;
+                app.update().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(app.current_popup, PopupState::None);
+    }
+
+    /// Types the text into the input and submits it, as Enter does.
+    async fn type_text(app: &mut ChatApp<'_>, text: &str) {
+        app.msg_area.textarea.insert_str(text);
+        app.handle_text_buffer().await;
+    }
+
+    #[tokio::test]
+    async fn messages_typed_while_disconnected_are_sent_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server, mut contents) = flaky_room(listener);
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        disconnect(&mut app, addr).await;
+
+        for content in ["first", "second", "third"] {
+            type_text(&mut app, content).await;
+            assert_eq!(delivery(&app), Some(Delivery::Queued));
+            assert!(last_header(&app).ends_with("queued"));
+        }
+        assert!(contents.try_recv().is_err());
+
+        reconnect(&mut app).await;
+        for content in ["first", "second", "third"] {
+            let received = timeout(Duration::from_secs(5), contents.recv()).await;
+            assert_eq!(received.unwrap().as_deref(), Some(content));
+        }
+        assert!(app.session().queued.is_empty());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn queued_messages_fail_once_reconnecting_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server, _) = flaky_room(listener);
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        disconnect(&mut app, addr).await;
+        // nothing listens anymore
+        server.abort();
+        let _ = server.await;
+
+        type_text(&mut app, "lost").await;
+        app.session_mut().client.reconnect_attempts = 1;
+        reconnect(&mut app).await;
+
+        let session = app.session();
+        assert!(session.queued.is_empty());
+        let failed = session
+            .outgoing
+            .values()
+            .filter(|outgoing| outgoing.delivery == Delivery::Failed)
+            .count();
+        assert_eq!(failed, 1);
+        assert!(session.messages.items.iter().any(|text| {
+            text.lines[0].spans[0].content == "1 queued messages couldn't be sent."
+        }));
+
+        // the closed connection refuses what's sent afterwards
+        assert!(!session.client.is_disconnected());
+        type_text(&mut app, "after giving up").await;
+        let failed = app
+            .session()
+            .outgoing
+            .values()
+            .filter(|outgoing| outgoing.delivery == Delivery::Failed)
+            .count();
+        assert_eq!(failed, 2);
+        assert!(app.session().messages.items.iter().any(|text| {
+            text.lines[0].spans[0].content == "Not connected, the message hasn't been sent."
+        }));
+        type_text(&mut app, "/kick bob").await;
+        assert!(app.session().messages.items.iter().any(|text| {
+            text.lines[0].spans[0].content == "Not connected, the kick hasn't been sent."
+        }));
+    }
+
+    #[tokio::test]
+    async fn kicked_users_arent_reconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            let handshake = ServerMsg::Handshake {
+                salt: None,
+                topic: None,
+                challenge: String::new(),
+            };
+            for msg in [handshake, ServerMsg::Kicked] {
+                ws_stream
+                    .send(Message::from(msg).to_ttmessage())
+                    .await
+                    .unwrap();
+            }
+            ws_stream.close(None).await.unwrap();
+            // a reconnect would be accepted here
+            let _ = listener.accept().await;
+        });
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let client = &mut app.session_mut().client;
+        client.room.lock().unwrap().addr = addr;
+        client.connect().await.unwrap();
+
+        let was_kicked =
+            |app: &ChatApp| {
+                app.session().messages.items.iter().any(|text| {
+                    text.lines[0].spans[0].content == "You've been kicked from the room."
+                })
+            };
+        timeout(Duration::from_secs(5), async {
+            while !was_kicked(&app) {
+                app.update().await;
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // the closed connection stays closed
+        for _ in 0..20 {
+            app.update().await;
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!app.session().client.is_reconnecting());
+        assert_eq!(app.current_popup, PopupState::None);
+        assert!(!server.is_finished());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_messages_fail_until_retried() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let sent_at = Instant::now();
+
+        app.send_text("hello").await;
+        let style = app.style.clone();
+        app.session_mut()
+            .expire_outgoing(sent_at + ACK_TIMEOUT / 2, &style);
+        assert_eq!(delivery(&app), Some(Delivery::Pending));
+
+        app.session_mut()
+            .expire_outgoing(sent_at + ACK_TIMEOUT * 2, &style);
+        assert_eq!(delivery(&app), Some(Delivery::Failed));
+        assert!(last_header(&app).ends_with("not delivered [ctrl+r]"));
+
+        app.handle_key_action(KeyAction::Retry);
+        assert_eq!(delivery(&app), Some(Delivery::Pending));
+        assert_eq!(app.session().resend.len(), 1);
+        assert_eq!(app.session().resend[0].content(), "hello");
+
+        app.resend_failed().await;
+        assert!(app.session().resend.is_empty());
+
+        // a dropped message fails right away
+        app.handle_msg(0, MessageType::Server(ServerMsg::RateLimited))
+            .await;
+        let failed = app
+            .session()
+            .outgoing
+            .values()
+            .filter(|outgoing| outgoing.delivery == Delivery::Failed)
+            .count();
+        assert_eq!(failed, 1);
+    }
+
+    #[test]
+    fn input_history_is_browsed_like_a_shell() {
+        let mut history = InputHistory::default();
+        assert_eq!(history.older("draft"), None);
+        assert_eq!(history.newer(), None);
+
+        for text in ["first", "second", "second", "third"] {
+            history.push(text.into());
+        }
+
+        assert_eq!(history.older("draft"), Some("third"));
+        assert_eq!(history.older("third"), Some("second"));
+        assert_eq!(history.older("second"), Some("first"));
+        // repeated inputs are kept once and browsing stops at the oldest
+        assert_eq!(history.older("first"), None);
+        assert_eq!(history.newer(), Some("second"));
+        assert_eq!(history.newer(), Some("third"));
+        assert_eq!(history.newer(), Some("draft"));
+        assert_eq!(history.newer(), None);
+
+        for i in 0..INPUT_HISTORY_LEN {
+            history.push(format!("message {}", i));
+        }
+        assert_eq!(history.entries.len(), INPUT_HISTORY_LEN);
+        assert_eq!(history.entries.front().unwrap(), "message 0");
+    }
+
+    #[tokio::test]
+    async fn sent_inputs_are_recalled() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        for text in ["hello", "two\nlines"] {
+            app.msg_area.textarea.insert_str(text);
+            app.handle_text_buffer().await;
+        }
+
+        app.msg_area.textarea.insert_str("unsent");
+        app.recall_older();
+        assert_eq!(app.msg_area.textarea.lines(), ["two", "lines"]);
+        app.recall_older();
+        assert_eq!(app.msg_area.textarea.lines(), ["hello"]);
+        app.recall_newer();
+        app.recall_newer();
+        assert_eq!(app.msg_area.textarea.lines(), ["unsent"]);
+    }
+
+    #[tokio::test]
+    async fn edits_and_deletions_are_applied() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let alice = app.session().client.user.clone();
+        let bob_addr = SocketAddr::from_str("127.0.0.1:4001").unwrap();
+        assert_eq!(
+            app.session().own_msg_id(),
+            Err("You haven't sent any message yet.".into())
+        );
+
+        let own = TextMessage::new(&alice.addr.unwrap(), "someroom", "tpyo");
+        let bobs = TextMessage::new(&bob_addr, "someroom", "hi");
+        for msg in [&own, &bobs] {
+            app.handle_msg(0, MessageType::User(UserMsg::Normal { msg: msg.clone() }))
+                .await;
+        }
+        assert_eq!(app.session().own_msg_id(), Ok(own.id().clone()));
+
+        // a selected message of someone else can't be changed
+        app.handle_key_action(KeyAction::ScrollDown);
+        assert!(app.session().own_msg_id().is_err());
+
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::Edit {
+                id: own.id().clone(),
+                content: "typo".into(),
+            }),
+        )
+        .await;
+        let edited = &app.session().messages.items[0];
+        assert!(edited.lines[0]
+            .spans
+            .iter()
+            .any(|span| span.content == " (edited)"));
+        assert_eq!(edited.lines[1].spans[0].content, "typo");
+
+        app.handle_msg(
+            0,
+            MessageType::User(UserMsg::Delete {
+                id: own.id().clone(),
+            }),
+        )
+        .await;
+        assert_eq!(app.session().messages.items.len(), 1);
+        assert_eq!(app.session().msg_index(bobs.id()), Some(0));
+        assert_eq!(app.session().selected_content(), Some(&"hi".to_string()));
+    }
+
+    #[tokio::test]
+    async fn messages_of_the_session_stay_own_after_reconnecting() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        app.session_mut().client.session_token = Some("sometoken".into());
+        let old_addr = SocketAddr::from_str("127.0.0.1:3999").unwrap();
+
+        let mut own = TextMessage::new(&old_addr, "someroom", "before");
+        own.set_sender(old_addr, Some(author_key("sometoken")));
+        let mut bobs = TextMessage::new(&old_addr, "someroom", "hi");
+        bobs.set_sender(old_addr, Some(author_key("othertoken")));
+        for msg in [&own, &bobs] {
+            app.handle_msg(0, MessageType::User(UserMsg::Normal { msg: msg.clone() }))
+                .await;
+        }
+        assert_eq!(app.session().own_msg_id(), Ok(own.id().clone()));
+    }
+
+    async fn receive_file(app: &mut ChatApp<'_>, chunks: Vec<FileChunk>) {
+        let addr = SocketAddr::from_str("127.0.0.1:4001").unwrap();
+        for chunk in chunks {
+            app.handle_msg(0, MessageType::User(UserMsg::File { addr, chunk }))
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn received_files_are_offered_for_saving() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        let dir = env::temp_dir().join(format!("kioto-received-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        app.download_dir = dir.clone();
+        let data = vec![1; CHUNK_SIZE + 5];
+
+        receive_file(&mut app, split("notes.txt", &data, u64::MAX).unwrap()).await;
+        assert_eq!(
+            last_header(&app),
+            "127.0.0.1:4001 sent the file notes.txt (16389 bytes)."
+        );
+        app.offer_received_file();
+        assert_eq!(
+            app.current_popup,
+            PopupState::SaveFile("127.0.0.1:4001".into(), "notes.txt".into(), 16389)
+        );
+
+        app.answer_save(KeyCode::Char('y'));
+        assert_eq!(app.current_popup, PopupState::None);
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), data);
+
+        // another key drops the file
+        receive_file(&mut app, split("notes.txt", b"again", u64::MAX).unwrap()).await;
+        app.offer_received_file();
+        app.answer_save(KeyCode::Esc);
+        assert_eq!(last_header(&app), "Dropped notes.txt.");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_and_large_files_are_dropped() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+
+        let mut chunks = split("image.png", &[7; 20], u64::MAX).unwrap();
+        chunks[0].data = Base64::encode_string(&[8; 20]);
+        receive_file(&mut app, chunks).await;
+        assert_eq!(
+            last_header(&app),
+            "Dropped the file image.png from 127.0.0.1:4001. The file doesn't match its hash."
+        );
+
+        app.max_file_size = 10;
+        let shown = app.session().messages.items.len();
+        receive_file(
+            &mut app,
+            split("big.bin", &[0; CHUNK_SIZE * 2], u64::MAX).unwrap(),
+        )
+        .await;
+        // reported once for the whole file
+        assert_eq!(app.session().messages.items.len(), shown + 1);
+        assert_eq!(
+            last_header(&app),
+            "Dropped the file big.bin from 127.0.0.1:4001. The file has 32768 bytes, at most 10 are allowed."
+        );
+        assert!(app.session().incoming.is_empty());
+
+        app.offer_received_file();
+        assert_eq!(app.current_popup, PopupState::None);
+    }
+
+    #[tokio::test]
+    async fn files_over_the_limit_are_not_sent() {
+        let mut app = chat_app("alice", "127.0.0.1:4000");
+        app.max_file_size = 4;
+        let path = env::temp_dir().join(format!("kioto-send-{}.txt", Uuid::new_v4()));
+        fs::write(&path, "some notes").unwrap();
+
+        assert!(
+            app.parse_commands(&format!("/send {}", path.display()))
+                .await
+        );
+        assert_eq!(
+            last_header(&app),
+            format!(
+                "Couldn't send {}: The file has 10 bytes, at most 4 are allowed.",
+                path.display()
+            )
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
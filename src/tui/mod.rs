@@ -1,2 +1,3 @@
 pub mod chat_app;
+pub mod room_picker;
 pub mod ui;
@@ -0,0 +1,174 @@
+use crate::schema::Room;
+use crate::tui::ui::{ChatStyle, StatefulList, Tui};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use ratatui::prelude::*;
+use std::cmp::Reverse;
+use std::io;
+use tokio::time::Duration;
+
+/// Lets the user choose one of the saved rooms to join, the most recently joined first.
+pub struct RoomPicker {
+    pub running: bool,
+    pub style: ChatStyle,
+    pub rooms: StatefulList<Room>,
+    /// Id of the chosen room, `None` while choosing or when cancelled.
+    pub picked: Option<String>,
+    pub alt_screen: bool,
+}
+
+impl RoomPicker {
+    pub fn new(mut rooms: Vec<Room>, style: ChatStyle) -> Self {
+        rooms.sort_by(|a, b| {
+            Reverse(a.last_joined)
+                .cmp(&Reverse(b.last_joined))
+                .then_with(|| a._id.cmp(&b._id))
+        });
+
+        let mut list = StatefulList {
+            items: rooms,
+            ..StatefulList::default()
+        };
+        list.state.select((!list.items.is_empty()).then_some(0));
+        list.is_highlighted = true;
+
+        Self {
+            running: true,
+            style,
+            rooms: list,
+            picked: None,
+            alt_screen: true,
+        }
+    }
+
+    /// Returns the id of the chosen room, `None` if the user backed out.
+    pub fn run(mut self) -> io::Result<Option<String>> {
+        let terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+        let mut tui = Tui::new(terminal);
+        tui.alt_screen = self.alt_screen;
+        tui.term_init()?;
+
+        while self.running {
+            tui.draw_picker(&mut self)?;
+            if event::poll(Duration::from_millis(100))? {
+                if let Event::Key(KeyEvent {
+                    code, modifiers, ..
+                }) = event::read()?
+                {
+                    self.handle_key(code, modifiers);
+                }
+            }
+        }
+
+        tui.term_restore()?;
+        Ok(self.picked)
+    }
+
+    pub fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => self.rooms.previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.rooms.next(),
+            KeyCode::Enter => {
+                self.picked = self.selected_id().map(String::from);
+                self.running = false;
+            }
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => self.running = false,
+            KeyCode::Esc | KeyCode::Char('q') => self.running = false,
+            _ => (),
+        }
+    }
+
+    pub fn selected_id(&self) -> Option<&str> {
+        self.rooms
+            .state
+            .selected()
+            .and_then(|i| self.rooms.items.get(i))
+            .map(|room| room._id.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RoomPicker;
+    use crate::{schema::Room, tui::ui::ChatStyle};
+    use crossterm::event::{KeyCode, KeyModifiers};
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, SystemTime},
+    };
+
+    fn room(id: &str, last_joined: Option<u64>) -> Room {
+        Room {
+            _id: id.into(),
+            addr: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: false,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: last_joined.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            filter_words: vec![],
+        }
+    }
+
+    fn picker() -> RoomPicker {
+        RoomPicker::new(
+            vec![
+                room("never", None),
+                room("older", Some(60)),
+                room("recent", Some(120)),
+                room("alsonever", None),
+            ],
+            ChatStyle::dark(),
+        )
+    }
+
+    #[test]
+    fn rooms_are_offered_by_last_join() {
+        let picker = picker();
+        let ids = picker
+            .rooms
+            .items
+            .iter()
+            .map(|room| room._id.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(ids, ["recent", "older", "alsonever", "never"]);
+        assert_eq!(picker.selected_id(), Some("recent"));
+    }
+
+    #[test]
+    fn the_selected_room_is_picked() {
+        let mut picker = picker();
+
+        picker.handle_key(KeyCode::Down, KeyModifiers::NONE);
+        picker.handle_key(KeyCode::Char('j'), KeyModifiers::NONE);
+        picker.handle_key(KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!(picker.selected_id(), Some("older"));
+
+        // moving stops at the last one
+        for _ in 0..10 {
+            picker.handle_key(KeyCode::Down, KeyModifiers::NONE);
+        }
+        assert_eq!(picker.selected_id(), Some("never"));
+
+        picker.handle_key(KeyCode::Enter, KeyModifiers::NONE);
+        assert!(!picker.running);
+        assert_eq!(picker.picked.as_deref(), Some("never"));
+    }
+
+    #[test]
+    fn backing_out_picks_nothing() {
+        let mut picker = picker();
+
+        picker.handle_key(KeyCode::Esc, KeyModifiers::NONE);
+        assert!(!picker.running);
+        assert_eq!(picker.picked, None);
+
+        let mut empty = RoomPicker::new(vec![], ChatStyle::dark());
+        empty.handle_key(KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(empty.picked, None);
+    }
+}
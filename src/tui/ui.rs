@@ -1,9 +1,16 @@
 use crate::{
-    schema::{Color as ChatColor, TextMessage},
-    tui::chat_app::ChatApp,
-    util::systime_to_string,
+    network::{client::Latency, User},
+    schema::{Color as ChatColor, Room, TextMessage, Theme, Timezone},
+    tui::{
+        chat_app::{ChatApp, KEYBINDINGS},
+        room_picker::RoomPicker,
+    },
+    util::{
+        systime_to_string, DEFAULT_LAYOUT_RATIO, DEFAULT_MENTION_PREFIX, DEFAULT_TIMESTAMP_FORMAT,
+    },
 };
 use crossterm::{
+    event::{DisableBracketedPaste, EnableBracketedPaste},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -12,25 +19,103 @@ use ratatui::{
     prelude::*,
     style::{Style, Styled},
     symbols::border,
-    widgets::*,
+    widgets::{block::Title, *},
 };
 use regex::Regex;
-use std::io;
-use tui_pattern_highlighter::highlight_text;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::SocketAddr,
+    panic,
+    time::{Duration, Instant},
+};
 use tui_popup::{Popup, SizedWrapper};
 use tui_textarea::{CursorMove, Input, TextArea};
 
-const HELP_POPUP_CONTENT: &str =
-    "[ctrl+l] user list\n[ctrl+j] scroll down\n[ctrl+j] scroll up\n[ctrl+q] exit";
+pub fn room_title(room: &Room) -> String {
+    match &room.topic {
+        Some(topic) => format!("{} - {}", room._id, topic),
+        None => room._id.clone(),
+    }
+}
+
+/// Round-trip time to the room, nothing until the first one is measured.
+pub fn latency_title(latency: Latency) -> Option<String> {
+    match latency {
+        Latency::Unknown => None,
+        Latency::Rtt(rtt) => Some(format!("ping {}ms", rtt.as_millis())),
+        Latency::Lost => Some("disconnected".into()),
+    }
+}
+
+/// Title of the active room, with the others as tabs once more are joined. Background
+/// rooms show how many messages arrived since they were last active.
+pub fn room_tabs<'a>(app: &ChatApp) -> Line<'a> {
+    if app.sessions.len() == 1 {
+        return Line::from(room_title(&app.session().client.room.lock().unwrap()));
+    }
+
+    let mut tabs = vec![];
+    for (index, session) in app.sessions.iter().enumerate() {
+        if index > 0 {
+            tabs.push(Span::raw(" | "));
+        }
+
+        let room = session.client.room.lock().unwrap();
+        tabs.push(match (index == app.active, session.unseen) {
+            (true, _) => Span::styled(room_title(&room), Style::new().bold()),
+            (false, 0) => Span::raw(room._id.clone()),
+            (false, unseen) => Span::styled(format!("{} ({})", room._id, unseen), app.style.info),
+        });
+    }
+    Line::from(tabs)
+}
+
+/// Connected users sorted by id, each in their color, dimmed while away.
+pub fn user_list<'a>(users: &HashMap<SocketAddr, User>) -> Text<'a> {
+    let mut users = users.values().collect::<Vec<_>>();
+    users.sort_by(|a, b| a._id.cmp(&b._id));
+
+    users
+        .into_iter()
+        .map(|user| {
+            let addr = user
+                .addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default();
+            let style = Style::new().fg(user.color.clone().unwrap_or_default().into());
+            if user.away {
+                Line::styled(format!("{} [{}] away", user._id, addr), style.dim())
+            } else {
+                Line::styled(format!("{} [{}]", user._id, addr), style)
+            }
+        })
+        .collect()
+}
 
+pub fn help_popup_content() -> String {
+    KEYBINDINGS
+        .iter()
+        .map(|binding| format!("[ctrl+{}] {}", binding.key, binding.description))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The backend doubles as the stream the terminal modes are switched on, so drawing,
+/// init and restore all target the same writer.
 #[derive(Debug)]
-pub struct Tui<B: Backend> {
+pub struct Tui<B: Backend + Write> {
     terminal: Terminal<B>,
+    /// Off, the chat is drawn inline and stays in the scrollback.
+    pub alt_screen: bool,
 }
 
-impl<B: Backend> Tui<B> {
+impl<B: Backend + Write> Tui<B> {
     pub fn new(terminal: Terminal<B>) -> Self {
-        Self { terminal }
+        Self {
+            terminal,
+            alt_screen: true,
+        }
     }
 
     pub fn draw(&mut self, app: &mut ChatApp) -> io::Result<()> {
@@ -41,37 +126,66 @@ impl<B: Backend> Tui<B> {
     pub fn render(app: &mut ChatApp, frame: &mut Frame) {
         let layout = Layout::default()
             .direction(Direction::Vertical)
-            .constraints(vec![
-                Constraint::Percentage(90 - app.msg_area.height),
-                Constraint::Length(5 + app.msg_area.height),
-            ])
+            .constraints(layout_constraints(
+                app.style.layout_ratio,
+                app.msg_area.height,
+            ))
             .split(frame.size());
-        app.msg_area.width = layout[0].width;
+        app.msg_area.set_width(layout[0].width);
 
-        let mut msgs_list = List::new(app.messages.items.clone())
-            .block(
-                Block::default()
-                    .title(app.client.room.lock().unwrap()._id.clone())
-                    .borders(Borders::ALL)
-                    .padding(Padding::new(2, 2, 1, 1))
-                    .border_set(border::ROUNDED),
-            )
+        let mut msgs_block = Block::default()
+            .title(room_tabs(app))
+            .borders(Borders::ALL)
+            .padding(Padding::new(2, 2, 1, 1))
+            .border_set(border::ROUNDED);
+        if let Some(latency) = latency_title(app.session().client.latency()) {
+            msgs_block = msgs_block.title(
+                Title::from(Line::styled(latency, app.style.info)).alignment(Alignment::Right),
+            );
+        }
+        if let Some(search) = &app.search {
+            msgs_block = msgs_block.title(
+                Title::from(format!(
+                    "search: {} [{}/{}]",
+                    search.query,
+                    search.matches.len().min(search.current + 1),
+                    search.matches.len()
+                ))
+                .alignment(Alignment::Right),
+            );
+        }
+        if let Some(typing) = app.session().typing_indicator() {
+            msgs_block = msgs_block.title_bottom(Line::styled(typing, app.style.info));
+        }
+        if app.session().messages.unread > 0 {
+            msgs_block = msgs_block.title_bottom(
+                Line::styled(
+                    format!("{} unread [ctrl+g]", app.session().messages.unread),
+                    app.style.info,
+                )
+                .alignment(Alignment::Right),
+            );
+        }
+
+        let mut msgs_list = List::new(app.session().messages.items.clone())
+            .block(msgs_block)
             .style(app.style.block)
             .direction(ListDirection::TopToBottom);
-        if app.messages.is_highlighted {
+        if app.session().messages.is_highlighted {
             msgs_list = msgs_list.highlight_style(app.style.msg_highlight);
         }
 
-        frame.render_stateful_widget(msgs_list, layout[0], &mut app.messages.state);
+        frame.render_stateful_widget(msgs_list, layout[0], &mut app.session_mut().messages.state);
         frame.render_widget(app.msg_area.textarea.widget(), layout[1]);
 
         match app.current_popup.clone() {
             PopupState::Help => {
-                let popup_content = Paragraph::new(Text::from(HELP_POPUP_CONTENT));
+                let content = help_popup_content();
+                let width = content.lines().map(str::len).max().unwrap_or_default() + 1;
                 let help_popup = Popup::new(SizedWrapper {
-                    inner: popup_content,
-                    width: 21,
-                    height: 5,
+                    inner: Paragraph::new(Text::from(content)),
+                    width,
+                    height: KEYBINDINGS.len() + 1,
                 })
                 .style(app.style.block)
                 .border_set(border::ROUNDED)
@@ -79,45 +193,156 @@ impl<B: Backend> Tui<B> {
                 frame.render_widget(&help_popup, frame.size());
             }
             PopupState::List => {
+                let users = user_list(&app.session().users);
                 let user_list_popup = Popup::new(SizedWrapper {
-                    inner: Paragraph::new(
-                        app.users
-                            .iter()
-                            .map(|(_, user)| {
-                                Line::from(format!(
-                                    "{} [{}]",
-                                    user._id,
-                                    user.addr.unwrap().ip().to_string()
-                                ))
-                            })
-                            .collect::<Text>(),
-                    ),
-                    width: 21,
-                    height: 5,
+                    width: users.width().max(20) + 1,
+                    inner: Paragraph::new(users),
+                    height: app.session().users.len().clamp(1, 10),
                 })
                 .style(app.style.block)
                 .border_set(border::ROUNDED)
                 .title("users list");
                 frame.render_widget(&user_list_popup, frame.size());
             }
-            _ => (),
+            PopupState::Banned(user) => {
+                let banned_popup = Popup::new(Text::from(format!("{} has been banned", user)))
+                    .style(app.style.block)
+                    .border_set(border::ROUNDED);
+                frame.render_widget(&banned_popup, frame.size());
+            }
+            PopupState::JoinedLeft(user, joined) => {
+                let action = if joined { "joined" } else { "left" };
+                let joined_left_popup = Popup::new(Text::from(format!("{} has {}", user, action)))
+                    .style(app.style.block)
+                    .border_set(border::ROUNDED);
+                frame.render_widget(&joined_left_popup, frame.size());
+            }
+            PopupState::Reconnecting => {
+                let reconnecting_popup = Popup::new(Text::from("reconnecting..."))
+                    .style(app.style.block)
+                    .border_set(border::ROUNDED);
+                frame.render_widget(&reconnecting_popup, frame.size());
+            }
+            PopupState::Copied => {
+                let copied_popup = Popup::new(Text::from("message copied"))
+                    .style(app.style.block)
+                    .border_set(border::ROUNDED);
+                frame.render_widget(&copied_popup, frame.size());
+            }
+            PopupState::CopyFailed(err) => {
+                let copy_failed_popup = Popup::new(Text::from(format!("copying failed: {}", err)))
+                    .style(app.style.block)
+                    .border_set(border::ROUNDED)
+                    .title("error");
+                frame.render_widget(&copy_failed_popup, frame.size());
+            }
+            PopupState::ConfirmQuit => {
+                let confirm_quit_popup =
+                    Popup::new(Text::from("unsent message, quit anyway? [ctrl+q/y]"))
+                        .style(app.style.block)
+                        .border_set(border::ROUNDED);
+                frame.render_widget(&confirm_quit_popup, frame.size());
+            }
+            PopupState::SaveFile(sender, name, size) => {
+                let save_popup = Popup::new(Text::from(format!(
+                    "{} sent {} ({} bytes), save it? [y/n]",
+                    sender, name, size
+                )))
+                .style(app.style.block)
+                .border_set(border::ROUNDED);
+                frame.render_widget(&save_popup, frame.size());
+            }
+            PopupState::MessageTooLong(max_len) => {
+                let too_long_popup = Popup::new(Text::from(format!(
+                    "message too long, at most {} characters",
+                    max_len
+                )))
+                .style(app.style.block)
+                .border_set(border::ROUNDED)
+                .title("error");
+                frame.render_widget(&too_long_popup, frame.size());
+            }
+            PopupState::None => (),
         }
     }
 
+    pub fn draw_picker(&mut self, picker: &mut RoomPicker) -> io::Result<()> {
+        self.terminal
+            .draw(|frame| Self::render_picker(picker, frame))?;
+        Ok(())
+    }
+
+    pub fn render_picker(picker: &mut RoomPicker, frame: &mut Frame) {
+        let rooms = picker
+            .rooms
+            .items
+            .iter()
+            .map(|room| Line::from(format!("{} [{}]", room_title(room), room.addr)))
+            .collect::<Vec<Line>>();
+
+        let rooms_list = List::new(rooms)
+            .block(
+                Block::default()
+                    .title("join a room")
+                    .title_bottom(
+                        Line::styled("[enter] join [esc] cancel", picker.style.info)
+                            .alignment(Alignment::Right),
+                    )
+                    .borders(Borders::ALL)
+                    .padding(Padding::new(2, 2, 1, 1))
+                    .border_set(border::ROUNDED),
+            )
+            .style(picker.style.block)
+            .highlight_style(picker.style.msg_highlight)
+            .highlight_symbol("> ");
+        frame.render_stateful_widget(rooms_list, frame.size(), &mut picker.rooms.state);
+    }
+
+    /// Also installs a panic hook restoring the terminal on stderr before the panic message is
+    /// printed, the hook can't reach the backend.
     pub fn term_init(&mut self) -> io::Result<()> {
         enable_raw_mode()?;
-        crossterm::execute!(io::stderr(), EnterAlternateScreen)?;
-        self.terminal.clear()?;
+        self.enter_screen()?;
+
+        let alt_screen = self.alt_screen;
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let _ = restore_terminal(&mut io::stderr(), alt_screen);
+            default_hook(info);
+        }));
         Ok(())
     }
 
+    fn enter_screen(&mut self) -> io::Result<()> {
+        if self.alt_screen {
+            execute!(self.terminal.backend_mut(), EnterAlternateScreen)?;
+        }
+        // pastes arrive whole instead of as keys, their line breaks would send the input
+        execute!(self.terminal.backend_mut(), EnableBracketedPaste)?;
+        self.terminal.clear()
+    }
+
     pub fn term_restore(&mut self) -> io::Result<()> {
-        disable_raw_mode()?;
-        execute!(io::stdout(), LeaveAlternateScreen)?;
-        Ok(())
+        restore_terminal(self.terminal.backend_mut(), self.alt_screen)
+    }
+}
+
+/// Restores the terminal when dropped, so early returns and unwinding leave it usable.
+impl<B: Backend + Write> Drop for Tui<B> {
+    fn drop(&mut self) {
+        let _ = self.term_restore();
     }
 }
 
+fn restore_terminal(out: &mut impl Write, alt_screen: bool) -> io::Result<()> {
+    disable_raw_mode()?;
+    execute!(out, DisableBracketedPaste)?;
+    if alt_screen {
+        execute!(out, LeaveAlternateScreen)?;
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct StatefulArea<'a> {
     pub textarea: TextArea<'a>,
@@ -127,6 +352,7 @@ pub struct StatefulArea<'a> {
 
 impl<'a> StatefulArea<'a> {
     const MAX_AREA_HEIGHT: u16 = 20;
+    const HORIZONTAL_PADDING: u16 = 6;
 
     pub fn new(style: ChatStyle) -> Self {
         let mut textarea = TextArea::default();
@@ -135,13 +361,16 @@ impl<'a> StatefulArea<'a> {
             Block::default()
                 .borders(Borders::ALL)
                 .set_style(style.block)
+                .border_style(style.input_border)
                 .padding(Padding::new(2, 2, 1, 1))
                 .border_set(border::ROUNDED),
         );
-        textarea.set_search_pattern(r"@\w+").unwrap();
-        textarea.set_search_style(style.mentioning);
+        textarea
+            .set_search_pattern(style.mention_pattern())
+            .unwrap();
+        textarea.set_search_style(style.input_highlight);
         textarea.set_placeholder_text("Start typing...");
-        textarea.set_placeholder_style(Style::new().fg(Color::Gray));
+        textarea.set_placeholder_style(style.placeholder);
 
         Self {
             textarea,
@@ -154,34 +383,151 @@ impl<'a> StatefulArea<'a> {
         if self.textarea.input_without_shortcuts(input) {
             self.move_last_word_to_new_line();
         }
+        self.recompute_height();
     }
 
-    fn move_last_word_to_new_line(&mut self) {
-        let line = self.textarea.lines()[self.textarea.cursor().0].clone();
-
-        if line.len() >= (self.width - 6).into() {
-            let rlines: String = line.chars().rev().collect();
-            if let Some(caps) = Regex::new(r"\S+").unwrap().captures(&rlines) {
-                let cap = caps.get(0).unwrap();
-                if cap.start() == 0 {
-                    self.textarea.delete_word();
+    /// Fits the area height to the current number of lines, up to `MAX_AREA_HEIGHT`.
+    pub fn recompute_height(&mut self) {
+        let extra_lines = self.textarea.lines().len().saturating_sub(1);
+        self.height = u16::try_from(extra_lines)
+            .unwrap_or(u16::MAX)
+            .min(Self::MAX_AREA_HEIGHT);
+    }
+
+    /// Characters fitting in a line, 0 before the first render.
+    fn max_width(&self) -> usize {
+        usize::from(self.width.saturating_sub(Self::HORIZONTAL_PADDING))
+    }
+
+    /// Wraps the input again once the width changed, keeping the cursor on its character.
+    /// Every line break is taken as a wrapping one, pasted ones included.
+    pub fn set_width(&mut self, width: u16) {
+        if width == self.width {
+            return;
+        }
+        let old_max_width = self.max_width();
+        self.width = width;
+        if self.textarea.is_empty() {
+            return;
+        }
+
+        // lines broken mid-word were exactly full, the others lost the space they were broken at
+        let (row, col) = self.textarea.cursor();
+        let mut text = String::new();
+        let mut cursor = 0;
+        for (i, line) in self.textarea.lines().iter().enumerate() {
+            if i > 0 && self.textarea.lines()[i - 1].chars().count() != old_max_width {
+                text.push(' ');
+            }
+            if i == row {
+                cursor = text.chars().count() + col;
+            }
+            text.push_str(line);
+        }
+
+        self.clear_buffer();
+        for c in text.chars() {
+            self.textarea.insert_char(c);
+            self.move_last_word_to_new_line();
+        }
+        self.recompute_height();
+
+        let chars = text.chars().collect::<Vec<char>>();
+        let lines = self.textarea.lines();
+        let mut consumed = 0;
+        for (row, line) in lines.iter().enumerate() {
+            let len = line.chars().count();
+            if cursor <= consumed + len || row + 1 == lines.len() {
+                let col = cursor.saturating_sub(consumed).min(len);
+                self.textarea
+                    .move_cursor(CursorMove::Jump(row as u16, col as u16));
+                break;
+            }
+            consumed += len;
+            let next_starts_with_space = lines[row + 1].starts_with(' ');
+            if chars.get(consumed) == Some(&' ') && !next_starts_with_space {
+                consumed += 1;
+            }
+        }
+    }
+
+    /// Inserts a whole block of text at the cursor, keeping its line breaks. Like typing,
+    /// lines are only wrapped when inserted at the end of a line.
+    pub fn paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let (row, col) = self.textarea.cursor();
+        let line = self.textarea.lines()[row].clone();
+
+        if col != line.chars().count() {
+            self.textarea.insert_str(text);
+        } else {
+            self.textarea.delete_line_by_head();
+            let max_width = self.max_width();
+            for (i, pasted) in text.split('\n').enumerate() {
+                if i > 0 {
                     self.textarea.insert_newline();
-                    let rword: String = cap.as_str().chars().rev().collect();
-                    self.textarea.insert_str(&rword);
-                } else {
-                    self.textarea.delete_char();
                 }
+                let pasted = match i {
+                    0 => format!("{}{}", line, pasted),
+                    _ => pasted.to_string(),
+                };
+                self.textarea
+                    .insert_str(wrap(&pasted, max_width).join("\n"));
             }
+        }
+        self.recompute_height();
+    }
 
-            if self.height <= Self::MAX_AREA_HEIGHT && !line.ends_with(' ') {
-                self.height += 1;
+    fn move_last_word_to_new_line(&mut self) {
+        let max_width = self.max_width();
+        let (row, col) = self.textarea.cursor();
+        let line = &self.textarea.lines()[row];
+        let line_len = line.chars().count();
+
+        if max_width == 0 || line_len < max_width || col != line_len {
+            return;
+        }
+
+        let word_len = line
+            .chars()
+            .rev()
+            .take_while(|c| !c.is_whitespace())
+            .count();
+        if word_len == 0 {
+            // the trailing whitespace becomes the line break
+            self.textarea.delete_char();
+            self.textarea.insert_newline();
+        } else if word_len < line_len && word_len < max_width {
+            for _ in 0..word_len {
+                self.textarea.move_cursor(CursorMove::Back);
             }
+            self.textarea.delete_char();
+            self.textarea.insert_newline();
+            self.textarea.move_cursor(CursorMove::End);
+        } else {
+            // the token can't fit in a single line, so it's broken mid-word
+            self.textarea.insert_newline();
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.textarea
+            .lines()
+            .iter()
+            .all(|line| line.trim().is_empty())
+    }
+
+    /// Replaces the input, leaving the cursor at its end.
+    pub fn set_text(&mut self, text: &str) {
+        self.clear_buffer();
+        self.textarea.insert_str(text);
+        self.recompute_height();
+    }
+
     pub fn get_text(&mut self) -> Option<String> {
         let buffer = self.get_buffer();
         self.clear_buffer();
+        self.recompute_height();
         if let Some(buf) = buffer {
             return Some(buf);
         }
@@ -217,11 +563,36 @@ impl<'a> StatefulArea<'a> {
     }
 }
 
+/// Splits `line` the way `StatefulArea::move_last_word_to_new_line` does while typing it.
+fn wrap(line: &str, max_width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = line.chars().collect::<Vec<char>>();
+    while max_width > 0 && rest.len() >= max_width {
+        let word_len = rest[..max_width]
+            .iter()
+            .rev()
+            .take_while(|c| !c.is_whitespace())
+            .count();
+        let (end, next) = match word_len {
+            0 => (max_width - 1, max_width),
+            len if len < max_width => (max_width - len - 1, max_width - len),
+            // the token can't fit in a single line, so it's broken mid-word
+            _ => (max_width, max_width),
+        };
+        lines.push(rest[..end].iter().collect());
+        rest.drain(..next);
+    }
+    lines.push(rest.into_iter().collect());
+    lines
+}
+
 #[derive(Debug)]
 pub struct StatefulList<T> {
     pub items: Vec<T>,
     pub state: ListState,
     pub is_highlighted: bool,
+    /// Items pushed while scrolled up from the newest one.
+    pub unread: usize,
 }
 
 impl<T> Default for StatefulList<T> {
@@ -230,13 +601,39 @@ impl<T> Default for StatefulList<T> {
             items: Vec::new(),
             state: ListState::default(),
             is_highlighted: false,
+            unread: 0,
         }
     }
 }
 
 impl<T> StatefulList<T> {
     pub fn select_last(&mut self) {
-        self.state.select(Some(self.items.len()));
+        self.state.select(self.items.len().checked_sub(1));
+        self.unread = 0;
+    }
+
+    /// Whether the newest item is selected, or none is yet.
+    pub fn is_at_bottom(&self) -> bool {
+        self.state
+            .selected()
+            .is_none_or(|i| i + 1 >= self.items.len())
+    }
+
+    /// Whether pushed items get selected, always so when not browsing with the highlight.
+    pub fn is_following(&self) -> bool {
+        !self.is_highlighted || self.is_at_bottom()
+    }
+
+    /// Keeps following the newest items, counts the pushed one as unread while browsing
+    /// older ones.
+    pub fn push(&mut self, item: T) {
+        let following = self.is_following();
+        self.items.push(item);
+        if following {
+            self.select_last();
+        } else {
+            self.unread += 1;
+        }
     }
 
     pub fn next(&mut self) {
@@ -273,57 +670,294 @@ impl<T> StatefulList<T> {
     }
 }
 
+/// Heights of the messages and the input, the messages giving up a percent for every line
+/// the input grows by. The input takes the rest, but never less than its lines.
+pub fn layout_constraints(ratio: u16, input_height: u16) -> [Constraint; 2] {
+    [
+        Constraint::Percentage(ratio.saturating_sub(input_height)),
+        Constraint::Min(5 + input_height),
+    ]
+}
+
+/// Indices of the messages whose rendered text contains `query`, ignoring case.
+pub fn search_messages(items: &[Text], query: &str) -> Vec<usize> {
+    if query.is_empty() {
+        return vec![];
+    }
+    let query = query.to_lowercase();
+
+    items
+        .iter()
+        .enumerate()
+        .filter(|(_, text)| {
+            text.lines.iter().any(|line| {
+                line.spans
+                    .iter()
+                    .map(|span| span.content.as_ref())
+                    .collect::<String>()
+                    .to_lowercase()
+                    .contains(&query)
+            })
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// How far a message sent by the user got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Waiting for the connection to come back.
+    Queued,
+    Pending,
+    Sent,
+    Failed,
+}
+
 #[derive(Debug)]
 pub struct MsgItem;
 
 impl MsgItem {
-    pub fn info_msg<'a>(msg: String, color: Color) -> Text<'a> {
+    pub fn info_msg<'a>(msg: String, style: &ChatStyle) -> Text<'a> {
         let mut text = Text::from(msg);
         text.push_line("");
-        text.style(Style::new().fg(color.into()).italic())
+        text.style(style.info)
     }
 
-    pub fn user_msg<'a>(text_msg: &TextMessage, color: ChatColor, user_id: String) -> Text<'a> {
+    pub fn user_msg<'a>(
+        text_msg: &TextMessage,
+        color: ChatColor,
+        user_id: String,
+        local_user_id: &str,
+        style: &ChatStyle,
+    ) -> Text<'a> {
         let mut text = Text::from(Line::from(vec![
             Span::from(user_id).bold(),
-            Span::from(format!(" {}", systime_to_string(*text_msg.timestamp())))
-                .fg(Color::Rgb(50, 50, 50))
-                .italic(),
+            Span::from(format!(
+                " {}",
+                systime_to_string(
+                    *text_msg.timestamp(),
+                    &style.timestamp_format,
+                    style.timezone,
+                )
+            ))
+            .style(style.info),
         ]));
-        let content = highlight_text(
-            text_msg.content().into(),
-            r"@(\w+)",
-            Style::new().bg(Color::White).fg(Color::Black).bold(),
-        );
-        content
-            .lines
-            .iter()
-            .for_each(|line| text.push_line(line.clone()));
+        if text_msg.is_edited() {
+            text.lines[0]
+                .spans
+                .push(Span::styled(" (edited)", style.info));
+        }
+        text_msg
+            .content()
+            .split('\n')
+            .for_each(|line| text.push_line(Self::highlight_line(line, local_user_id, style)));
         text.push_line("");
         text.style(Style::new().fg(color.into()))
     }
+
+    /// Notes on the header line whether a sent message reached the room yet.
+    pub fn mark_delivery<'a>(
+        mut text: Text<'a>,
+        delivery: Delivery,
+        style: &ChatStyle,
+    ) -> Text<'a> {
+        let mark = match delivery {
+            Delivery::Queued => Span::styled(" queued", style.info),
+            Delivery::Pending => Span::styled(" sending…", style.info),
+            Delivery::Sent => return text,
+            Delivery::Failed => Span::styled(" not delivered [ctrl+r]", style.failed),
+        };
+        if let Some(header) = text.lines.first_mut() {
+            header.spans.push(mark);
+        }
+        text
+    }
+
+    pub fn mentions(content: &str, user_id: &str, style: &ChatStyle) -> bool {
+        Regex::new(&style.mention_pattern())
+            .unwrap()
+            .captures_iter(content)
+            .any(|caps| caps["mention"].eq_ignore_ascii_case(user_id))
+    }
+
+    /// Styles links and mentions, giving mentions of the local user a distinct style,
+    /// and with markdown enabled `*bold*`, `_italic_` and `` `code` `` too. Every part of the
+    /// line is matched once, so mentions inside code or links aren't styled again.
+    fn highlight_line<'a>(line: &str, local_user_id: &str, style: &ChatStyle) -> Line<'a> {
+        let mut pattern = format!("{}|{}", URL_PATTERN, style.mention_pattern());
+        if style.markdown {
+            pattern = format!("{}|{}", pattern, MARKDOWN_PATTERN);
+        }
+        let mut spans = Vec::new();
+        let mut last_end = 0;
+
+        for caps in Regex::new(&pattern).unwrap().captures_iter(line) {
+            let part = caps.get(0).unwrap();
+            spans.push(Span::from(line[last_end..part.start()].to_string()));
+            last_end = part.end();
+
+            let span = if caps.name("url").is_some() {
+                Span::styled(part.as_str().to_string(), style.link)
+            } else if let Some(user_id) = caps.name("mention") {
+                let mention_style = if user_id.as_str().eq_ignore_ascii_case(local_user_id) {
+                    style.self_mentioning
+                } else {
+                    style.mentioning
+                };
+                Span::styled(part.as_str().to_string(), mention_style)
+            } else if let Some(bold) = caps.name("bold") {
+                Span::from(bold.as_str().to_string()).bold()
+            } else if let Some(italic) = caps.name("italic") {
+                Span::from(italic.as_str().to_string()).italic()
+            } else {
+                Span::styled(caps["code"].to_string(), style.code)
+            };
+            spans.push(span);
+        }
+        spans.push(Span::from(line[last_end..].to_string()));
+
+        Line::from(spans)
+    }
 }
 
+/// Trailing punctuation is left out, it usually ends the sentence rather than the link.
+const URL_PATTERN: &str = r#"(?<url>https?://[^\s<>]*[^\s<>.,;:!?'")\]])"#;
+/// Underscores inside words, like in snake_case, don't start italics.
+const MARKDOWN_PATTERN: &str =
+    r"\*(?<bold>[^*\s][^*]*)\*|\b_(?<italic>[^_\s][^_]*)_\b|`(?<code>[^`]+)`";
+
 #[derive(Clone, Debug)]
 pub struct ChatStyle {
     pub block: Style,
     pub msg_highlight: Style,
     pub mentioning: Style,
+    pub self_mentioning: Style,
+    pub info: Style,
+    pub placeholder: Style,
+    pub input_border: Style,
+    /// Mentions typed into the input.
+    pub input_highlight: Style,
+    pub code: Style,
+    pub link: Style,
+    pub failed: Style,
+    pub timestamp_format: String,
+    pub timezone: Timezone,
+    /// Renders the markdown subset of the messages.
+    pub markdown: bool,
+    /// Starts the mentions, both in the input and in the messages.
+    pub mention_prefix: char,
+    /// Percent of the screen the messages take.
+    pub layout_ratio: u16,
 }
 
 impl ChatStyle {
-    pub fn new(block: Style, msg_highlight: Style, mentioning: Style) -> Self {
+    /// Matches a mention, the name being in the `mention` group.
+    pub fn mention_pattern(&self) -> String {
+        format!(
+            r"{}(?<mention>\w+)",
+            regex::escape(&self.mention_prefix.to_string())
+        )
+    }
+
+    pub fn themed(theme: Theme) -> Self {
+        match theme {
+            Theme::Dark => Self::dark(),
+            Theme::Light => Self::light(),
+            Theme::HighContrast => Self::high_contrast(),
+            Theme::Deuteranopia => Self::deuteranopia(),
+        }
+    }
+
+    pub fn dark() -> Self {
         Self {
-            block,
-            msg_highlight,
-            mentioning,
+            block: Style::new().bg(Color::Rgb(0, 0, 0)).fg(Color::White),
+            msg_highlight: Style::new().fg(Color::Yellow),
+            mentioning: Style::new().fg(Color::Rgb(0, 0, 0)).bg(Color::White).bold(),
+            self_mentioning: Style::new()
+                .fg(Color::Rgb(0, 0, 0))
+                .bg(Color::Yellow)
+                .bold(),
+            info: Style::new().fg(Color::Rgb(50, 50, 50)).italic(),
+            placeholder: Style::new().fg(Color::Gray),
+            input_border: Style::new().bg(Color::Rgb(0, 0, 0)).fg(Color::White),
+            input_highlight: Style::new().fg(Color::Rgb(0, 0, 0)).bg(Color::White).bold(),
+            code: Style::new().bg(Color::Rgb(40, 40, 40)),
+            link: Style::new().fg(Color::Cyan).underlined(),
+            failed: Style::new().fg(Color::LightRed).italic(),
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+            timezone: Timezone::Local,
+            markdown: false,
+            mention_prefix: DEFAULT_MENTION_PREFIX,
+            layout_ratio: DEFAULT_LAYOUT_RATIO,
         }
     }
 
-    pub fn reverse_colors(&mut self) {
-        self.block = self.block.reversed();
-        self.msg_highlight = self.msg_highlight.reversed();
-        self.mentioning = self.mentioning.reversed();
+    pub fn light() -> Self {
+        Self {
+            block: Style::new().bg(Color::Rgb(255, 255, 255)).fg(Color::Black),
+            msg_highlight: Style::new().fg(Color::Blue),
+            mentioning: Style::new()
+                .fg(Color::Rgb(255, 255, 255))
+                .bg(Color::Black)
+                .bold(),
+            self_mentioning: Style::new()
+                .fg(Color::Rgb(255, 255, 255))
+                .bg(Color::Blue)
+                .bold(),
+            info: Style::new().fg(Color::Rgb(150, 150, 150)).italic(),
+            placeholder: Style::new().fg(Color::DarkGray),
+            input_border: Style::new().bg(Color::Rgb(255, 255, 255)).fg(Color::Black),
+            input_highlight: Style::new()
+                .fg(Color::Rgb(255, 255, 255))
+                .bg(Color::Black)
+                .bold(),
+            code: Style::new().bg(Color::Rgb(225, 225, 225)),
+            link: Style::new().fg(Color::Blue).underlined(),
+            failed: Style::new().fg(Color::Red).italic(),
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+            timezone: Timezone::Local,
+            markdown: false,
+            mention_prefix: DEFAULT_MENTION_PREFIX,
+            layout_ratio: DEFAULT_LAYOUT_RATIO,
+        }
+    }
+
+    /// Pure black and white with bright accents, no dimmed text.
+    pub fn high_contrast() -> Self {
+        Self {
+            block: Style::new().bg(Color::Black).fg(Color::White),
+            msg_highlight: Style::new().fg(Color::Black).bg(Color::White).bold(),
+            mentioning: Style::new().fg(Color::Black).bg(Color::LightCyan).bold(),
+            self_mentioning: Style::new().fg(Color::Black).bg(Color::LightYellow).bold(),
+            info: Style::new().fg(Color::White).italic(),
+            placeholder: Style::new().fg(Color::White).italic(),
+            input_border: Style::new().bg(Color::Black).fg(Color::White).bold(),
+            input_highlight: Style::new().fg(Color::Black).bg(Color::LightCyan).bold(),
+            code: Style::new().fg(Color::White).bg(Color::DarkGray),
+            link: Style::new().fg(Color::LightCyan).underlined(),
+            failed: Style::new().fg(Color::LightRed).bold(),
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.into(),
+            timezone: Timezone::Local,
+            markdown: false,
+            mention_prefix: DEFAULT_MENTION_PREFIX,
+            layout_ratio: DEFAULT_LAYOUT_RATIO,
+        }
+    }
+
+    /// The dark palette with blue and orange accents, told apart without red or green.
+    pub fn deuteranopia() -> Self {
+        let sky_blue = Color::Rgb(86, 180, 233);
+        let orange = Color::Rgb(230, 159, 0);
+        Self {
+            msg_highlight: Style::new().fg(sky_blue).bold(),
+            mentioning: Style::new().fg(Color::Rgb(0, 0, 0)).bg(sky_blue).bold(),
+            self_mentioning: Style::new().fg(Color::Rgb(0, 0, 0)).bg(orange).bold(),
+            info: Style::new().fg(Color::Rgb(150, 150, 150)).italic(),
+            input_highlight: Style::new().fg(Color::Rgb(0, 0, 0)).bg(sky_blue).bold(),
+            link: Style::new().fg(sky_blue).underlined(),
+            failed: Style::new().fg(orange).italic(),
+            ..Self::dark()
+        }
     }
 }
 
@@ -331,5 +965,639 @@ impl ChatStyle {
 pub enum PopupState {
     Help,
     List,
+    Banned(String),
+    JoinedLeft(String, bool),
+    Reconnecting,
+    Copied,
+    CopyFailed(String),
+    MessageTooLong(u32),
+    /// Sender, name and size of a received file.
+    SaveFile(String, String, u64),
+    ConfirmQuit,
     None,
 }
+
+/// Point in wall-clock time after which a timed popup should disappear.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    start: Instant,
+    duration: Duration,
+}
+
+impl Deadline {
+    pub fn new(duration: Duration) -> Self {
+        Self::starting_at(Instant::now(), duration)
+    }
+
+    pub fn starting_at(start: Instant, duration: Duration) -> Self {
+        Self { start, duration }
+    }
+
+    pub fn has_time_passed(&self) -> bool {
+        self.has_passed_at(Instant::now())
+    }
+
+    pub fn has_passed_at(&self, now: Instant) -> bool {
+        now.checked_duration_since(self.start)
+            .is_some_and(|elapsed| elapsed >= self.duration)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        help_popup_content, latency_title, layout_constraints, room_title, search_messages,
+        user_list, ChatStyle, Deadline, MsgItem, StatefulArea, StatefulList, Tui,
+    };
+    use crate::{
+        network::{client::Latency, User},
+        schema::{Color as ChatColor, Room, TextMessage, Theme},
+        tui::chat_app::KEYBINDINGS,
+    };
+    use ratatui::{prelude::*, Terminal, TerminalOptions, Viewport};
+    use std::time::{Duration, Instant, SystemTime};
+    use std::{collections::HashMap, net::SocketAddr, str::FromStr};
+    use tui_textarea::{CursorMove, Input, Key};
+
+    fn type_str(area: &mut StatefulArea, text: &str) {
+        for c in text.chars() {
+            area.on_input_update(Input {
+                key: Key::Char(c),
+                ..Default::default()
+            });
+        }
+    }
+
+    #[test]
+    fn light_and_dark_palettes_differ() {
+        let dark = ChatStyle::themed(Theme::Dark);
+        let light = ChatStyle::themed(Theme::Light);
+
+        assert_ne!(dark.block.bg, light.block.bg);
+        assert_ne!(dark.block.fg, light.block.fg);
+        assert_ne!(dark.mentioning, light.mentioning);
+        assert_ne!(dark.info, light.info);
+    }
+
+    #[test]
+    fn themes_set_their_own_palette() {
+        assert_eq!(
+            ChatStyle::themed(Theme::Dark).block,
+            ChatStyle::dark().block
+        );
+        assert_eq!(
+            ChatStyle::themed(Theme::Light).block,
+            ChatStyle::light().block
+        );
+
+        let contrast = ChatStyle::themed(Theme::HighContrast);
+        assert_eq!(
+            contrast.block,
+            Style::new().bg(Color::Black).fg(Color::White)
+        );
+        assert_eq!(contrast.info.fg, Some(Color::White));
+        assert_eq!(contrast.msg_highlight.bg, Some(Color::White));
+        assert_eq!(contrast.self_mentioning.bg, Some(Color::LightYellow));
+
+        // neither red nor green hues
+        let deuteranopia = ChatStyle::themed(Theme::Deuteranopia);
+        let dark = ChatStyle::dark();
+        assert_eq!(deuteranopia.block, dark.block);
+        assert_eq!(
+            deuteranopia.msg_highlight.fg,
+            Some(Color::Rgb(86, 180, 233))
+        );
+        assert_eq!(deuteranopia.mentioning.bg, Some(Color::Rgb(86, 180, 233)));
+        assert_eq!(
+            deuteranopia.self_mentioning.bg,
+            Some(Color::Rgb(230, 159, 0))
+        );
+        assert_eq!(deuteranopia.failed.fg, Some(Color::Rgb(230, 159, 0)));
+        assert_ne!(deuteranopia.info, dark.info);
+    }
+
+    #[test]
+    fn messages_are_searched_ignoring_case() {
+        let style = ChatStyle::dark();
+        let addr = SocketAddr::from_str("127.0.0.1:4000").unwrap();
+        let items = vec![
+            MsgItem::user_msg(
+                &TextMessage::new(&addr, "someroom", "Hello there"),
+                ChatColor::Red,
+                "alice".into(),
+                "bob",
+                &style,
+            ),
+            MsgItem::info_msg("carol has joined".into(), &style),
+            MsgItem::user_msg(
+                &TextMessage::new(&addr, "someroom", "first line\nsay HELLO again"),
+                ChatColor::Red,
+                "alice".into(),
+                "bob",
+                &style,
+            ),
+        ];
+
+        assert_eq!(search_messages(&items, "hello"), vec![0, 2]);
+        assert_eq!(search_messages(&items, "JOINED"), vec![1]);
+        assert_eq!(search_messages(&items, "say hello"), vec![2]);
+        assert!(search_messages(&items, "missing").is_empty());
+        assert!(search_messages(&items, "").is_empty());
+    }
+
+    #[test]
+    fn room_title_includes_topic() {
+        let mut room = Room {
+            _id: "someroom".into(),
+            addr: SocketAddr::from_str("127.0.0.1:4000").unwrap(),
+            passwd: None,
+            banned_addrs: vec![],
+            is_owner: false,
+            max_users: None,
+            topic: None,
+            created_at: SystemTime::UNIX_EPOCH,
+            host: None,
+            last_joined: None,
+            filter_words: vec![],
+        };
+        assert_eq!(room_title(&room), "someroom");
+
+        room.topic = Some("weekly sync".into());
+        assert_eq!(room_title(&room), "someroom - weekly sync");
+    }
+
+    #[test]
+    fn latency_title_shows_rtt_or_disconnection() {
+        assert_eq!(latency_title(Latency::Unknown), None);
+        assert_eq!(
+            latency_title(Latency::Rtt(Duration::from_micros(42_900))).as_deref(),
+            Some("ping 42ms")
+        );
+        assert_eq!(
+            latency_title(Latency::Lost).as_deref(),
+            Some("disconnected")
+        );
+    }
+
+    #[test]
+    fn help_lists_every_keybinding() {
+        let content = help_popup_content();
+
+        for binding in KEYBINDINGS {
+            assert!(content.contains(&format!("[ctrl+{}] {}", binding.key, binding.description)));
+        }
+        assert_eq!(content.lines().count(), KEYBINDINGS.len());
+    }
+
+    #[test]
+    fn deadline_passes_after_duration() {
+        let start = Instant::now();
+        let deadline = Deadline::starting_at(start, Duration::from_secs(3));
+
+        assert!(!deadline.has_passed_at(start));
+        assert!(!deadline.has_passed_at(start + Duration::from_millis(2999)));
+        assert!(deadline.has_passed_at(start + Duration::from_secs(3)));
+        assert!(deadline.has_passed_at(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn deadline_is_not_passed_before_start() {
+        let start = Instant::now() + Duration::from_secs(10);
+        let deadline = Deadline::starting_at(start, Duration::ZERO);
+
+        assert!(!deadline.has_passed_at(start - Duration::from_secs(1)));
+        assert!(deadline.has_passed_at(start));
+    }
+
+    #[test]
+    fn message_scrolling_is_clamped() {
+        let mut messages = StatefulList::<usize>::default();
+        messages.items.extend(0..50);
+        messages.select_last();
+        assert_eq!(messages.state.selected(), Some(49));
+
+        messages.next();
+        assert_eq!(messages.state.selected(), Some(49));
+
+        messages.previous();
+        assert_eq!(messages.state.selected(), Some(48));
+
+        for _ in 0..60 {
+            messages.previous();
+        }
+        assert_eq!(messages.state.selected(), Some(0));
+
+        for _ in 0..10 {
+            messages.next();
+        }
+        assert_eq!(messages.state.selected(), Some(10));
+
+        for _ in 0..60 {
+            messages.next();
+        }
+        assert_eq!(messages.state.selected(), Some(49));
+    }
+
+    #[test]
+    fn select_last_on_empty_list_selects_nothing() {
+        let mut messages = StatefulList::<usize>::default();
+        messages.select_last();
+        assert_eq!(messages.state.selected(), None);
+    }
+
+    #[test]
+    fn long_token_is_hard_wrapped() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        area.width = 20;
+        let token = "a".repeat(200);
+
+        type_str(&mut area, &token);
+
+        let lines = area.textarea.lines();
+        assert_eq!(lines.concat(), token);
+        assert!(lines.iter().all(|line| line.chars().count() <= 14));
+        assert_eq!(usize::from(area.height), lines.len() - 1);
+    }
+
+    #[test]
+    fn words_are_moved_to_new_line() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        area.width = 20;
+        let text = "some words that should wrap nicely";
+
+        type_str(&mut area, text);
+
+        let lines = area.textarea.lines();
+        assert_eq!(lines.join(" "), text);
+        assert!(lines.iter().all(|line| line.chars().count() <= 14));
+    }
+
+    #[test]
+    fn input_is_rewrapped_on_resize() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        area.set_width(30);
+        let text = "some words that should wrap nicely, then a longtokenthatdoesntfitanywhere";
+        type_str(&mut area, text);
+        let fits = |area: &StatefulArea, max: usize| {
+            area.textarea
+                .lines()
+                .iter()
+                .all(|line| line.chars().count() <= max)
+        };
+        let non_blank = |area: &StatefulArea| {
+            area.textarea
+                .lines()
+                .concat()
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect::<String>()
+        };
+        let typed = text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        assert!(fits(&area, 24));
+
+        area.set_width(15);
+        assert!(fits(&area, 9));
+        assert_eq!(non_blank(&area), typed);
+        assert_eq!(usize::from(area.height), area.textarea.lines().len() - 1);
+        // still at the end, so typing goes on after the last character
+        type_str(&mut area, "!");
+        assert!(area.textarea.lines().last().unwrap().ends_with('!'));
+
+        area.set_width(200);
+        assert_eq!(area.textarea.lines(), [format!("{}!", text)]);
+        assert_eq!(area.height, 0);
+    }
+
+    #[test]
+    fn cursor_stays_on_its_character_on_resize() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        area.set_width(200);
+        type_str(&mut area, "first second third fourth");
+        for _ in 0.."third fourth".len() {
+            area.textarea.move_cursor(CursorMove::Back);
+        }
+
+        area.set_width(16);
+        let (row, col) = area.textarea.cursor();
+        assert!(area.textarea.lines()[row][col..].starts_with("third"));
+    }
+
+    #[test]
+    fn pasted_lines_are_kept() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        area.set_width(40);
+
+        area.paste("first line\r\nsecond line\nthird line");
+        assert_eq!(
+            area.textarea.lines(),
+            ["first line", "second line", "third line"]
+        );
+        assert_eq!(area.height, 2);
+        assert_eq!(area.textarea.cursor(), (2, 10));
+
+        // pasting at the end of the input continues its last line
+        type_str(&mut area, " and");
+        area.paste(" more");
+        assert_eq!(area.textarea.lines()[2], "third line and more");
+    }
+
+    #[test]
+    fn pasted_text_is_wrapped_as_if_typed() {
+        let text = "some words that should wrap nicely, then a longtokenthatdoesntfitanywhere";
+        let mut typed = StatefulArea::new(ChatStyle::dark());
+        typed.set_width(20);
+        type_str(&mut typed, text);
+
+        let mut pasted = StatefulArea::new(ChatStyle::dark());
+        pasted.set_width(20);
+        pasted.paste(&format!("{}\nshort", text));
+
+        let lines = pasted.textarea.lines();
+        assert_eq!(lines[..lines.len() - 1], typed.textarea.lines()[..]);
+        assert_eq!(lines.last().unwrap(), "short");
+        assert_eq!(usize::from(pasted.height), lines.len() - 1);
+    }
+
+    #[test]
+    fn pasting_inside_a_line_keeps_its_rest() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        area.set_width(40);
+        type_str(&mut area, "before after");
+        for _ in 0.."after".len() {
+            area.textarea.move_cursor(CursorMove::Back);
+        }
+
+        area.paste("one\ntwo ");
+        assert_eq!(area.textarea.lines(), ["before one", "two after"]);
+        assert_eq!(area.height, 1);
+    }
+
+    #[test]
+    fn no_wrapping_before_first_render() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        type_str(&mut area, "text");
+
+        assert_eq!(area.textarea.lines(), ["text"]);
+        assert_eq!(area.height, 0);
+    }
+
+    #[test]
+    fn area_height_is_capped_and_shrinks() {
+        let mut area = StatefulArea::new(ChatStyle::dark());
+        area.width = 20;
+
+        type_str(&mut area, &"a".repeat(1000));
+        assert!(area.textarea.lines().len() > usize::from(StatefulArea::MAX_AREA_HEIGHT));
+        assert_eq!(area.height, StatefulArea::MAX_AREA_HEIGHT);
+
+        assert!(area.get_text().is_some());
+        assert_eq!(area.height, 0);
+
+        type_str(&mut area, &"a".repeat(30));
+        assert_eq!(area.height, 2);
+
+        area.textarea.delete_line_by_head();
+        area.textarea.delete_newline();
+        area.recompute_height();
+        assert_eq!(area.height, 1);
+    }
+
+    fn span_style(text: &Text, content: &str) -> ratatui::style::Style {
+        text.lines
+            .iter()
+            .flat_map(|line| line.spans.iter())
+            .find(|span| span.content == content)
+            .unwrap()
+            .style
+    }
+
+    #[test]
+    fn self_mentions_are_styled_differently() {
+        let style = ChatStyle::dark();
+        let msg = TextMessage::new(
+            &SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            "someroom",
+            "hi @Alice, @bob\nand @alicia",
+        );
+
+        let text = MsgItem::user_msg(&msg, ChatColor::White, "bob".into(), "alice", &style);
+
+        assert_eq!(span_style(&text, "@Alice"), style.self_mentioning);
+        assert_eq!(span_style(&text, "@bob"), style.mentioning);
+        assert_eq!(span_style(&text, "@alicia"), style.mentioning);
+        assert_eq!(span_style(&text, ", "), Default::default());
+        assert_ne!(style.self_mentioning, style.mentioning);
+    }
+
+    #[test]
+    fn mention_prefix_is_configurable() {
+        let mut style = ChatStyle::dark();
+        style.mention_prefix = '#';
+        let msg = TextMessage::new(
+            &SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            "someroom",
+            "hi #alice and @bob",
+        );
+
+        let text = MsgItem::user_msg(&msg, ChatColor::White, "bob".into(), "alice", &style);
+        assert_eq!(span_style(&text, "#alice"), style.self_mentioning);
+        assert_eq!(span_style(&text, " and @bob"), Default::default());
+        assert!(MsgItem::mentions("hi #Alice", "alice", &style));
+        assert!(!MsgItem::mentions("hi @alice", "alice", &style));
+
+        // the input highlights the same mentions
+        let area = StatefulArea::new(style);
+        let pattern = area.textarea.search_pattern().unwrap();
+        assert!(pattern.is_match("#alice"));
+        assert!(!pattern.is_match("@alice"));
+    }
+
+    #[test]
+    fn markdown_is_rendered_when_enabled() {
+        let mut style = ChatStyle::dark();
+        let msg = TextMessage::new(
+            &SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            "someroom",
+            "*really* run `cargo @bob` for _all_ of snake_case_name",
+        );
+        let text = |style: &ChatStyle| {
+            MsgItem::user_msg(&msg, ChatColor::White, "bob".into(), "alice", style)
+        };
+
+        let plain = text(&style);
+        assert_eq!(
+            plain.lines[1].to_string(),
+            "*really* run `cargo @bob` for _all_ of snake_case_name"
+        );
+        assert_eq!(span_style(&plain, "@bob"), style.mentioning);
+
+        style.markdown = true;
+        let rendered = text(&style);
+        assert_eq!(span_style(&rendered, "really"), Style::new().bold());
+        assert_eq!(span_style(&rendered, "cargo @bob"), style.code);
+        assert_eq!(span_style(&rendered, "all"), Style::new().italic());
+        assert_eq!(
+            span_style(&rendered, " of snake_case_name"),
+            Style::default()
+        );
+    }
+
+    #[test]
+    fn urls_are_styled_without_trailing_punctuation() {
+        let style = ChatStyle::dark();
+        let msg = TextMessage::new(
+            &SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+            "someroom",
+            "see https://example.com/a_b?q=1@x, (http://x.org/y).",
+        );
+
+        let text = MsgItem::user_msg(&msg, ChatColor::White, "bob".into(), "alice", &style);
+
+        let links = text
+            .lines
+            .iter()
+            .flat_map(|line| line.spans.iter())
+            .filter(|span| span.style == style.link)
+            .map(|span| span.content.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(links, ["https://example.com/a_b?q=1@x", "http://x.org/y"]);
+        assert!(style.link.add_modifier.contains(Modifier::UNDERLINED));
+        assert_eq!(span_style(&text, ", ("), Style::default());
+    }
+
+    #[test]
+    fn user_list_is_sorted_and_colored() {
+        let users = [
+            ("zoe", "127.0.0.1:4001", ChatColor::Red, false),
+            ("al", "10.0.0.2:4002", ChatColor::Blue, true),
+        ]
+        .into_iter()
+        .map(|(id, addr, color, away)| {
+            let addr = SocketAddr::from_str(addr).unwrap();
+            (
+                addr,
+                User {
+                    _id: id.into(),
+                    addr: Some(addr),
+                    color: Some(color),
+                    away,
+                    spectator: false,
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+        let text = user_list(&users);
+
+        assert_eq!(text.lines[0].to_string(), "al [10.0.0.2] away");
+        assert_eq!(text.lines[0].style, Style::new().fg(Color::Blue).dim());
+        assert_eq!(text.lines[1].to_string(), "zoe [127.0.0.1]");
+        assert_eq!(text.lines[1].style, Style::new().fg(Color::Red));
+    }
+
+    #[test]
+    fn init_and_restore_share_the_writer() {
+        let mut out = Vec::new();
+        let options = TerminalOptions {
+            viewport: Viewport::Fixed(Rect::new(0, 0, 10, 10)),
+        };
+        let mut tui =
+            Tui::new(Terminal::with_options(CrosstermBackend::new(&mut out), options).unwrap());
+
+        tui.enter_screen().unwrap();
+        // restored on drop
+        drop(tui);
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b[?1049h"));
+        assert!(out.ends_with("\x1b[?1049l"));
+    }
+
+    #[test]
+    fn inline_mode_keeps_the_main_screen() {
+        let mut out = Vec::new();
+        let options = TerminalOptions {
+            viewport: Viewport::Fixed(Rect::new(0, 0, 10, 10)),
+        };
+        let mut tui =
+            Tui::new(Terminal::with_options(CrosstermBackend::new(&mut out), options).unwrap());
+        tui.alt_screen = false;
+
+        tui.enter_screen().unwrap();
+        tui.term_restore().unwrap();
+        drop(tui);
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains("\x1b[?1049h"));
+        assert!(!out.contains("\x1b[?1049l"));
+    }
+
+    #[test]
+    fn unread_items_are_counted_while_scrolled_up() {
+        let mut list = StatefulList::default();
+        list.push("first");
+        list.push("second");
+        assert_eq!(list.state.selected(), Some(1));
+        assert_eq!(list.unread, 0);
+
+        // browsing older items, as scrolling does
+        list.is_highlighted = true;
+        list.previous();
+        list.push("third");
+        list.push("fourth");
+        assert_eq!(list.state.selected(), Some(0));
+        assert_eq!(list.unread, 2);
+
+        list.select_last();
+        assert_eq!(list.unread, 0);
+        list.push("fifth");
+        assert_eq!(list.state.selected(), Some(4));
+        assert_eq!(list.unread, 0);
+    }
+
+    #[test]
+    fn input_area_takes_its_styles_from_the_theme() {
+        let mut style = ChatStyle::dark();
+        style.input_border = style.input_border.fg(Color::Magenta);
+        style.input_highlight = style.input_highlight.bg(Color::Green);
+        style.placeholder = style.placeholder.fg(Color::Blue);
+
+        let area = StatefulArea::new(style.clone());
+        assert_eq!(area.textarea.search_style(), style.input_highlight);
+        assert_eq!(area.textarea.placeholder_style(), Some(style.placeholder));
+
+        let mut terminal = Terminal::new(backend::TestBackend::new(20, 5)).unwrap();
+        terminal
+            .draw(|frame| frame.render_widget(area.textarea.widget(), frame.size()))
+            .unwrap();
+        assert_eq!(terminal.backend().buffer().get(0, 0).fg, Color::Magenta);
+    }
+
+    #[test]
+    fn layout_ratio_splits_the_screen() {
+        let screen = Rect::new(0, 0, 80, 40);
+        let heights = |ratio, input_height| {
+            let constraints = layout_constraints(ratio, input_height);
+            let areas = Layout::vertical(constraints).split(screen);
+            (constraints, areas[0].height, areas[1].height)
+        };
+
+        assert_eq!(
+            heights(60, 0),
+            ([Constraint::Percentage(60), Constraint::Min(5)], 24, 16)
+        );
+        // the messages shrink as the input grows
+        assert_eq!(
+            heights(60, 3),
+            ([Constraint::Percentage(57), Constraint::Min(8)], 23, 17)
+        );
+        // a short screen still fits the whole input
+        assert_eq!(
+            heights(90, 0),
+            ([Constraint::Percentage(90), Constraint::Min(5)], 35, 5)
+        );
+    }
+}
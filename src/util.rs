@@ -1,12 +1,30 @@
-use argon2::{password_hash::Salt, Argon2, PasswordHasher};
-use chrono::{DateTime, Utc};
+use crate::{error::AppError, schema::Timezone};
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, FixedOffset, Local, Utc,
+};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    style::Stylize,
+    terminal::{disable_raw_mode, enable_raw_mode},
+};
 use dirs::data_dir;
 use fern::Dispatch;
 use humantime::format_rfc3339_seconds;
+use log::LevelFilter;
 use std::{
-    fs::create_dir_all,
-    io::{self, Write},
+    env,
+    ffi::OsString,
+    fs::{self, create_dir_all},
+    io::{self, BufRead, Write},
+    net::{IpAddr, Ipv4Addr, UdpSocket},
+    ops::RangeInclusive,
     path::{Path, PathBuf},
+    sync::OnceLock,
     time::SystemTime,
 };
 use uuid::Uuid;
@@ -15,46 +33,174 @@ pub fn get_unique_id() -> String {
     format!("user{}", Uuid::new_v4())
 }
 
-pub fn passwd_input() -> String {
+/// Source of key presses, abstracted so password reading can be driven without a terminal.
+pub trait KeySource {
+    fn next_key(&mut self) -> io::Result<KeyEvent>;
+}
+
+struct TerminalKeys;
+
+impl KeySource for TerminalKeys {
+    fn next_key(&mut self) -> io::Result<KeyEvent> {
+        loop {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Release {
+                    return Ok(key);
+                }
+            }
+        }
+    }
+}
+
+pub fn passwd_input() -> io::Result<String> {
+    let mut stdout = io::stdout();
     print!("password: ");
-    io::stdout().flush().unwrap();
-    let passwd = rpassword::read_password().unwrap();
-    hash_passwd(&passwd)
+    stdout.flush()?;
+
+    enable_raw_mode()?;
+    let passwd = read_passwd(&mut TerminalKeys, &mut stdout);
+    disable_raw_mode()?;
+    println!();
+
+    passwd
+}
+
+/// Reads a password echoing `*` for every character, ctrl+c or esc cancels the input.
+pub fn read_passwd(keys: &mut impl KeySource, out: &mut impl Write) -> io::Result<String> {
+    let mut passwd = String::new();
+
+    loop {
+        let key = keys.next_key()?;
+        let cancelled = key.code == KeyCode::Esc
+            || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+        if cancelled {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Password input cancelled.",
+            ));
+        }
+
+        match key.code {
+            KeyCode::Enter => break,
+            KeyCode::Backspace => {
+                if passwd.pop().is_some() {
+                    out.write_all(b"\x08 \x08")?;
+                }
+            }
+            KeyCode::Char(c) => {
+                passwd.push(c);
+                out.write_all(b"*")?;
+            }
+            _ => continue,
+        }
+        out.flush()?;
+    }
+
+    Ok(passwd)
 }
 
+/// Asks a yes/no question, anything other than `y` or `yes` counts as no.
+pub fn confirm(prompt: &str, input: &mut impl BufRead, out: &mut impl Write) -> io::Result<bool> {
+    write!(out, "{} [y/N] ", prompt)?;
+    out.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Hashes the password with a random salt, returning the PHC string which embeds the salt.
 pub fn hash_passwd(passwd: &str) -> String {
     Argon2::default()
-        .hash_password(
-            passwd.as_bytes(),
-            Salt::from_b64("c3VwZXJzZWNyZXRzYWx0").unwrap(),
-        )
-        .unwrap()
-        .hash
+        .hash_password(passwd.as_bytes(), &SaltString::generate(&mut OsRng))
         .unwrap()
         .to_string()
 }
 
-pub fn create_env_dir(dir_name: &str) -> Result<PathBuf, io::Error> {
-    let data_dir = match data_dir() {
-        Some(dir) => dir,
-        None => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unable to determine data directory",
-            ));
+pub fn verify_passwd(passwd: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(passwd.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Overrides the platform data directory, used as is without appending the directory name.
+pub const DATA_DIR_VAR: &str = "KIOTO_DATA_DIR";
+/// Room password for scripted commands, used when `--password` is not given.
+pub const PASSWD_VAR: &str = "KIOTO_PASSWORD";
+/// Password of a room being created, used instead of the prompt.
+pub const ROOM_PASSWD_VAR: &str = "KIOTO_ROOM_PASSWORD";
+
+/// Held by the tests changing the data directory variables, the environment is process wide.
+#[cfg(test)]
+pub static ENV_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+pub fn create_env_dir(dir_name: &str) -> Result<PathBuf, AppError> {
+    let dir_path = match env::var_os(DATA_DIR_VAR) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => data_dir()
+            .ok_or_else(|| io::Error::other("Unable to determine data directory"))?
+            .join(dir_name),
+    };
+
+    ensure_writable_dir(&dir_path).map_err(|err| {
+        AppError::DataDirNotWritable(dir_path.display().to_string(), err.to_string())
+    })?;
+
+    Ok(dir_path)
+}
+
+fn ensure_writable_dir(path: &Path) -> io::Result<()> {
+    create_dir_all(path)?;
+
+    let probe = path.join(".write-test");
+    fs::write(&probe, []).and_then(|_| fs::remove_file(&probe))
+}
+
+/// Logs to stderr alone when the log file can't be opened, fails only if a logger is set already.
+/// Without `stderr`, for the commands drawing on the terminal, only the file is logged to.
+pub fn setup_logger(
+    log_path: &Path,
+    level: LevelFilter,
+    stderr: bool,
+) -> Result<(), log::SetLoggerError> {
+    let dispatch = match logger(log_path, level, stderr) {
+        Ok(dispatch) => dispatch,
+        Err(err) => {
+            eprintln!(
+                "Unable to open the log file {}, {}: {}",
+                log_path.display(),
+                if stderr {
+                    "logging to stderr"
+                } else {
+                    "logging is off"
+                },
+                err
+            );
+            if !stderr {
+                return Ok(());
+            }
+            base_logger(level).chain(io::stderr())
         }
     };
 
-    let dir_path = data_dir.join(dir_name);
+    dispatch.apply()
+}
+
+/// Logs to the file at `level`, mirroring to stderr when allowed and more verbose than errors.
+fn logger(log_path: &Path, level: LevelFilter, stderr: bool) -> Result<Dispatch, fern::InitError> {
+    let mut dispatch = base_logger(level).chain(fern::log_file(log_path)?);
 
-    if !dir_path.exists() {
-        create_dir_all(&dir_path)?;
+    if stderr && level > LevelFilter::Error {
+        dispatch = dispatch.chain(io::stderr());
     }
 
-    Ok(dir_path)
+    Ok(dispatch)
 }
 
-pub fn setup_logger(log_path: &Path) -> Result<(), fern::InitError> {
+fn base_logger(level: LevelFilter) -> Dispatch {
     Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -65,16 +211,359 @@ pub fn setup_logger(log_path: &Path) -> Result<(), fern::InitError> {
                 message
             ))
         })
-        .level(log::LevelFilter::Error)
-        .chain(std::io::stdout())
-        .chain(fern::log_file(&log_path)?)
-        .apply()?;
+        .level(level)
+}
+
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M";
+pub const DEFAULT_MENTION_PREFIX: char = '@';
+pub const DEFAULT_MSG_RATE: u32 = 5;
+pub const DEFAULT_MSG_BURST: u32 = 10;
+/// Characters of a single message.
+pub const DEFAULT_MAX_MSG_LEN: u32 = 2000;
+/// Seconds between the pings of a hosted room.
+pub const DEFAULT_PING_INTERVAL: u32 = 15;
+pub const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+/// Seconds without input until the user is shown as away.
+pub const DEFAULT_AWAY_AFTER: u32 = 300;
+/// Seconds a room has to answer a join attempt.
+pub const DEFAULT_CONNECT_TIMEOUT: u32 = 5;
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 8;
+/// Bytes of a single sent file.
+pub const DEFAULT_MAX_FILE_SIZE: u32 = 1024 * 1024;
+/// Percent of the screen the messages take, less the lines the input grows by.
+pub const DEFAULT_LAYOUT_RATIO: u16 = 90;
+pub const LAYOUT_RATIOS: RangeInclusive<u16> = 30..=95;
 
-    Ok(())
+/// Formats the time with a strftime-like `format` in the given zone, falling back to the default
+/// format when it's invalid.
+pub fn systime_to_string(time: SystemTime, format: &str, timezone: Timezone) -> String {
+    let format = if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        log::warn!(
+            "Invalid timestamp format {:?}, using the default one.",
+            format
+        );
+        DEFAULT_TIMESTAMP_FORMAT
+    } else {
+        format
+    };
+
+    let time = DateTime::<Utc>::from(time);
+    match timezone {
+        Timezone::Local if local_timezone_is_known() => {
+            time.with_timezone(&Local).format(format).to_string()
+        }
+        Timezone::Fixed(secs) => match FixedOffset::east_opt(secs) {
+            Some(offset) => time.with_timezone(&offset).format(format).to_string(),
+            None => time.format(format).to_string(),
+        },
+        _ => time.format(format).to_string(),
+    }
 }
 
-pub fn systime_to_string(time: SystemTime) -> String {
-    DateTime::<Utc>::from(time)
-        .format("%Y-%m-%d %H:%M")
-        .to_string()
+/// chrono quietly uses UTC when the local zone can't be read, so it's checked once up front.
+fn local_timezone_is_known() -> bool {
+    static KNOWN: OnceLock<bool> = OnceLock::new();
+    *KNOWN.get_or_init(|| {
+        let known =
+            !cfg!(unix) || env::var_os("TZ").is_some() || Path::new("/etc/localtime").exists();
+        if !known {
+            log::warn!("The local timezone can't be determined, using UTC.");
+        }
+        known
+    })
+}
+
+/// Detects the primary non-loopback IPv4 address by routing a UDP socket, no packet is sent.
+pub fn lan_ip() -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9))?;
+
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) if !ip.is_loopback() && !ip.is_unspecified() => Ok(ip),
+        ip => Err(io::Error::other(format!("{} is not a LAN address", ip))),
+    }
+}
+
+/// Whether to color the output for a `--color` choice, `auto` only does on a terminal
+/// and when `NO_COLOR` isn't set.
+pub fn use_color(choice: &str, no_color: Option<OsString>, is_terminal: bool) -> bool {
+    match choice {
+        "always" => true,
+        "never" => false,
+        _ => is_terminal && no_color.is_none_or(|value| value.is_empty()),
+    }
+}
+
+pub fn error_text(err: &str, color: bool) -> String {
+    if color {
+        err.red().to_string()
+    } else {
+        err.to_string()
+    }
+}
+
+/// Drops the ANSI escape sequences and control characters a peer could mess up the
+/// terminal with, keeping line breaks and turning tabs into spaces.
+pub fn strip_control(content: &str) -> String {
+    let mut stripped = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => stripped.push(c),
+            '\t' => stripped.push(' '),
+            '\x1b' => match chars.next() {
+                // parameters and intermediates up to the final byte
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if !(' '..='?').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // strings up to their terminator
+                Some(']' | 'P' | 'X' | '^' | '_') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => (),
+            },
+            c if c.is_control() => (),
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        confirm, create_env_dir, error_text, hash_passwd, lan_ip, logger, read_passwd,
+        strip_control, systime_to_string, use_color, verify_passwd, KeySource, DATA_DIR_VAR,
+        DEFAULT_TIMESTAMP_FORMAT, ENV_LOCK,
+    };
+    use crate::schema::Timezone;
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use log::{Level, LevelFilter, Metadata};
+    use std::{
+        collections::VecDeque,
+        env, fs, io,
+        time::{Duration, SystemTime},
+    };
+    use uuid::Uuid;
+
+    struct ScriptedKeys(VecDeque<KeyEvent>);
+
+    impl ScriptedKeys {
+        fn new(keys: &[KeyEvent]) -> Self {
+            Self(keys.iter().copied().collect())
+        }
+    }
+
+    impl KeySource for ScriptedKeys {
+        fn next_key(&mut self) -> io::Result<KeyEvent> {
+            self.0
+                .pop_front()
+                .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn password_input_is_masked() {
+        let mut keys = ScriptedKeys::new(&[
+            key(KeyCode::Char('p')),
+            key(KeyCode::Char('a')),
+            key(KeyCode::Char('x')),
+            key(KeyCode::Backspace),
+            key(KeyCode::Char('s')),
+            key(KeyCode::Char('s')),
+            key(KeyCode::Enter),
+        ]);
+        let mut out = Vec::new();
+
+        assert_eq!(read_passwd(&mut keys, &mut out).unwrap(), "pass");
+        assert_eq!(String::from_utf8(out).unwrap(), "***\x08 \x08**");
+    }
+
+    #[test]
+    fn password_input_can_be_cancelled() {
+        let mut keys = ScriptedKeys::new(&[
+            key(KeyCode::Char('p')),
+            KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            key(KeyCode::Enter),
+        ]);
+
+        let err = read_passwd(&mut keys, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn confirmation_defaults_to_no() {
+        for (answer, expected) in [
+            ("y\n", true),
+            ("Yes\n", true),
+            ("n\n", false),
+            ("\n", false),
+        ] {
+            let mut out = Vec::new();
+            assert_eq!(
+                confirm("Proceed?", &mut answer.as_bytes(), &mut out).unwrap(),
+                expected
+            );
+            assert_eq!(String::from_utf8(out).unwrap(), "Proceed? [y/N] ");
+        }
+    }
+
+    #[test]
+    fn passwords_are_salted_and_verifiable() {
+        let first = hash_passwd("password");
+        let second = hash_passwd("password");
+
+        assert_ne!(first, second);
+        assert!(!first.contains("password"));
+        assert!(verify_passwd("password", &first));
+        assert!(verify_passwd("password", &second));
+        assert!(!verify_passwd("wrongpassword", &first));
+        assert!(!verify_passwd("password", "password"));
+    }
+
+    #[test]
+    fn timestamp_format_is_configurable() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        assert_eq!(
+            systime_to_string(time, DEFAULT_TIMESTAMP_FORMAT, Timezone::Utc),
+            "2023-11-14 22:13"
+        );
+        assert_eq!(
+            systime_to_string(time, "%H:%M:%S", Timezone::Utc),
+            "22:13:20"
+        );
+        assert_eq!(
+            systime_to_string(time, "%Q invalid", Timezone::Utc),
+            systime_to_string(time, DEFAULT_TIMESTAMP_FORMAT, Timezone::Utc)
+        );
+    }
+
+    #[test]
+    fn timestamps_follow_the_timezone() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        assert_eq!(
+            systime_to_string(time, "%Y-%m-%d %H:%M %:z", Timezone::Utc),
+            "2023-11-14 22:13 +00:00"
+        );
+        assert_eq!(
+            systime_to_string(time, "%Y-%m-%d %H:%M %:z", Timezone::Fixed(2 * 3600)),
+            "2023-11-15 00:13 +02:00"
+        );
+        assert_eq!(
+            systime_to_string(time, "%H:%M", Timezone::Fixed(-(5 * 3600 + 30 * 60))),
+            "16:43"
+        );
+    }
+
+    #[test]
+    fn logger_respects_level() {
+        let log_path = env::temp_dir().join(format!("kioto-test-{}.log", Uuid::new_v4()));
+        let enabled = |level: LevelFilter, msg_level: Level| {
+            let (max_level, log) = logger(&log_path, level, true).unwrap().into_log();
+            let metadata = Metadata::builder().level(msg_level).build();
+            (max_level, log.enabled(&metadata))
+        };
+
+        assert_eq!(
+            enabled(LevelFilter::Error, Level::Error),
+            (LevelFilter::Error, true)
+        );
+        assert_eq!(
+            enabled(LevelFilter::Error, Level::Warn),
+            (LevelFilter::Error, false)
+        );
+        assert_eq!(
+            enabled(LevelFilter::Debug, Level::Debug),
+            (LevelFilter::Debug, true)
+        );
+        assert_eq!(
+            enabled(LevelFilter::Debug, Level::Trace),
+            (LevelFilter::Debug, false)
+        );
+
+        fs::remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn env_dir_is_created_when_missing() {
+        let base = env::temp_dir().join(format!("kioto-test-{}", Uuid::new_v4()));
+        let _env = ENV_LOCK.blocking_lock();
+        let (data_home, data_dir) = (env::var_os("XDG_DATA_HOME"), env::var_os(DATA_DIR_VAR));
+        env::set_var("XDG_DATA_HOME", &base);
+        env::remove_var(DATA_DIR_VAR);
+
+        let path = create_env_dir("kioto");
+        for (var, value) in [("XDG_DATA_HOME", data_home), (DATA_DIR_VAR, data_dir)] {
+            match value {
+                Some(value) => env::set_var(var, value),
+                None => env::remove_var(var),
+            }
+        }
+
+        let path = path.unwrap();
+        assert!(path.ends_with("kioto"));
+        assert!(path.is_dir());
+
+        if path.starts_with(&base) {
+            fs::remove_dir_all(base).unwrap();
+        }
+    }
+
+    #[test]
+    fn lan_ip_is_not_loopback() {
+        // Sandboxes and CI runners may have no network at all.
+        if let Ok(ip) = lan_ip() {
+            assert!(!ip.is_loopback());
+            assert!(!ip.is_unspecified());
+        }
+    }
+
+    #[test]
+    fn control_sequences_are_stripped() {
+        assert_eq!(strip_control("\x1b[2Jcleared"), "cleared");
+        assert_eq!(
+            strip_control("\x1b[1;31mred\x1b[0m and \x1b]0;title\x07plain"),
+            "red and plain"
+        );
+        assert_eq!(strip_control("\x1b]8;;http://x\x1b\\link"), "link");
+        assert_eq!(strip_control("bell\x07\x08\r\x7f\u{9b}2J"), "bell2J");
+        assert_eq!(strip_control("a\tb\nsecond line"), "a b\nsecond line");
+    }
+
+    #[test]
+    fn unicode_text_is_kept() {
+        let text = "zażółć gęślą jaźń, 日本語 🦀\nnext line";
+        assert_eq!(strip_control(text), text);
+    }
+
+    #[test]
+    fn no_color_is_honored_unless_forced() {
+        assert!(use_color("auto", None, true));
+        assert!(use_color("auto", Some("".into()), true));
+        assert!(!use_color("auto", Some("1".into()), true));
+        assert!(!use_color("auto", None, false));
+        assert!(use_color("always", Some("1".into()), false));
+        assert!(!use_color("never", None, true));
+    }
+
+    #[test]
+    fn uncolored_errors_have_no_escapes() {
+        let err = "The room is full.";
+        assert_eq!(error_text(err, false), err);
+        assert!(!error_text(err, false).contains('\x1b'));
+    }
 }